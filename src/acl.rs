@@ -0,0 +1,392 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::msg::MessageType;
+use crate::srv;
+
+/*
+ * Per-client access control.
+ *
+ * An access list is an ordered sequence of rules, each of the form
+ *
+ *     <allow|deny> <rrq|wrq|any> <network|any> [<path prefix>]
+ *
+ * The first rule matching a request decides its fate; requests matching no
+ * rule are allowed.
+ *
+ * Path prefixes are compared with the filename as the server will resolve
+ * it (see srv::normalise), so that "./secret/x", "//secret/x" and
+ * "a/../secret/x" are all caught by a rule for "secret".
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum AclError {
+    InvalidAction(String),
+    InvalidRequestType(String),
+    InvalidNetwork(String),
+    MissingField,
+    TrailingGarbage
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclError::InvalidAction(s) =>
+                write!(f, "Invalid action '{}' (expected allow or deny)", s),
+            AclError::InvalidRequestType(s) =>
+                write!(f, "Invalid request type '{}' (expected rrq, wrq or \
+                       any)", s),
+            AclError::InvalidNetwork(s) =>
+                write!(f, "Invalid network '{}' (expected an address, CIDR \
+                       block or any)", s),
+            AclError::MissingField => write!(f, "Rule is missing fields \
+                (expected <action> <request type> <network> [<path>])"),
+            AclError::TrailingGarbage =>
+                write!(f, "Unexpected text after path prefix")
+        }
+    }
+}
+
+/* maps IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) onto plain IPv4 so that
+    rules apply the same way to dual-stack sockets */
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] =>
+                IpAddr::V4(Ipv4Addr::new((hi >> 8) as u8, hi as u8,
+                                         (lo >> 8) as u8, lo as u8)),
+            _ => addr
+        },
+        IpAddr::V4(_) => addr
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len: u8 = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        if prefix_len > max_len {
+            return None;
+        }
+
+        Some(Cidr {
+            addr: canonical(addr),
+            prefix_len
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask: u32 =
+                    u32::MAX.checked_shl(32 - self.prefix_len as u32)
+                        .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask: u128 =
+                    u128::MAX.checked_shl(128 - self.prefix_len as u32)
+                        .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AclError::InvalidNetwork(s.to_string());
+
+        let (addr, prefix_len): (&str, Option<&str>) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None)
+        };
+
+        let addr: IpAddr = if let Ok(v4) = addr.parse::<Ipv4Addr>() {
+            IpAddr::V4(v4)
+        } else {
+            IpAddr::V6(addr.parse::<Ipv6Addr>().map_err(|_| invalid())?)
+        };
+
+        let prefix_len: u8 = match prefix_len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128
+            }
+        };
+
+        Cidr::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    action: Action,
+    request: Option<MessageType>,   /* None matches both RRQs and WRQs */
+    network: Option<Cidr>,          /* None matches every client */
+    prefix: Option<String>
+}
+
+impl Rule {
+    pub fn new(action: Action, request: Option<MessageType>,
+               network: Option<Cidr>, prefix: Option<String>) -> Self {
+        Rule {
+            action,
+            request,
+            network,
+            prefix
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn request(&self) -> Option<MessageType> {
+        self.request
+    }
+
+    pub fn network(&self) -> Option<Cidr> {
+        self.network
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    pub fn matches(&self, request: MessageType, client: IpAddr,
+                   filename: &str) -> bool {
+        if let Some(kind) = self.request {
+            if kind != request {
+                return false;
+            }
+        }
+
+        if let Some(network) = self.network {
+            if !network.contains(client) {
+                return false;
+            }
+        }
+
+        match &self.prefix {
+            /* a filename that climbs out of the root matches no prefix, as
+                the server refuses it regardless */
+            Some(prefix) => match (srv::normalise(filename),
+                                   srv::normalise(prefix)) {
+                (Some(path), Some(prefix)) => path.starts_with(prefix),
+                _ => false
+            },
+            None => true
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+
+        let action: Action = match fields.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            Some(other) =>
+                return Err(AclError::InvalidAction(other.to_string())),
+            None => return Err(AclError::MissingField)
+        };
+
+        let request: Option<MessageType> = match fields.next() {
+            Some("rrq") => Some(MessageType::ReadRequest),
+            Some("wrq") => Some(MessageType::WriteRequest),
+            Some("any") => None,
+            Some(other) =>
+                return Err(AclError::InvalidRequestType(other.to_string())),
+            None => return Err(AclError::MissingField)
+        };
+
+        let network: Option<Cidr> = match fields.next() {
+            Some("any") => None,
+            Some(network) => Some(network.parse()?),
+            None => return Err(AclError::MissingField)
+        };
+
+        let prefix: Option<String> = fields.next().map(|s| s.to_string());
+
+        if fields.next().is_some() {
+            return Err(AclError::TrailingGarbage);
+        }
+
+        Ok(Rule::new(action, request, network, prefix))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    rules: Vec<Rule>
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        AccessList {
+            rules: Vec::new()
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn permits(&self, request: MessageType, client: IpAddr,
+                   filename: &str) -> bool {
+        match self.rules.iter()
+            .find(|rule| rule.matches(request, client, filename)) {
+            Some(rule) => rule.action == Action::Allow,
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn acl(rules: &[&str]) -> AccessList {
+        let mut acl: AccessList = AccessList::new();
+
+        for rule in rules {
+            acl.push(rule.parse().unwrap());
+        }
+
+        acl
+    }
+
+    #[test]
+    fn prefixes_match_the_resolved_filename() {
+        let acl: AccessList = acl(&["deny any any secret"]);
+
+        for filename in ["secret", "secret/x", "/secret/x", "./secret/x",
+                         "//secret/x", "secret//x", "a/../secret/x",
+                         "./a/./../secret"] {
+            assert!(!acl.permits(MessageType::ReadRequest, CLIENT, filename),
+                    "{} was permitted", filename);
+        }
+
+        for filename in ["secretive", "public/secret", "a/secret/.."] {
+            assert!(acl.permits(MessageType::ReadRequest, CLIENT, filename),
+                    "{} was denied", filename);
+        }
+    }
+
+    #[test]
+    fn prefixes_are_normalised_too() {
+        for prefix in ["/secret", "./secret/", "secret//", "a/../secret"] {
+            let acl: AccessList = acl(&[&format!("deny any any {}", prefix)]);
+
+            assert!(!acl.permits(MessageType::ReadRequest, CLIENT,
+                                 "secret/x"), "{} missed", prefix);
+        }
+    }
+
+    #[test]
+    fn filenames_escaping_the_root_match_no_prefix() {
+        let rule: Rule = "allow any any secret".parse().unwrap();
+
+        assert!(!rule.matches(MessageType::ReadRequest, CLIENT,
+                              "secret/../../secret"));
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides() {
+        let acl: AccessList = acl(&["allow rrq 10.0.0.0/8 boot",
+                                    "deny any any boot",
+                                    "allow any any boot/public"]);
+        let outsider: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+
+        assert!(acl.permits(MessageType::ReadRequest, CLIENT, "boot/img"));
+        assert!(!acl.permits(MessageType::WriteRequest, CLIENT, "boot/img"));
+        assert!(!acl.permits(MessageType::ReadRequest, outsider, "boot/img"));
+
+        /* shadowed by the deny before it */
+        assert!(!acl.permits(MessageType::ReadRequest, outsider,
+                             "boot/public/img"));
+    }
+
+    #[test]
+    fn requests_matching_no_rule_are_allowed() {
+        let acl: AccessList = acl(&["deny wrq any"]);
+
+        assert!(acl.permits(MessageType::ReadRequest, CLIENT, "anything"));
+        assert!(!acl.permits(MessageType::WriteRequest, CLIENT, "anything"));
+        assert!(AccessList::new().permits(MessageType::WriteRequest, CLIENT,
+                                          "anything"));
+    }
+
+    #[test]
+    fn networks_match_mapped_addresses() {
+        let acl: AccessList = acl(&["deny any 10.0.0.0/8"]);
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+
+        assert!(!acl.permits(MessageType::ReadRequest, mapped, "file"));
+        assert!(acl.permits(MessageType::ReadRequest,
+                            "2001:db8::1".parse().unwrap(), "file"));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert_eq!("permit any any".parse::<Rule>(),
+                   Err(AclError::InvalidAction("permit".to_string())));
+        assert_eq!("allow get any".parse::<Rule>(),
+                   Err(AclError::InvalidRequestType("get".to_string())));
+        assert_eq!("allow any 10.0.0.0/33".parse::<Rule>(),
+                   Err(AclError::InvalidNetwork("10.0.0.0/33".to_string())));
+        assert_eq!("allow any".parse::<Rule>(), Err(AclError::MissingField));
+        assert_eq!("allow any any a b".parse::<Rule>(),
+                   Err(AclError::TrailingGarbage));
+    }
+}
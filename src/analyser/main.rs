@@ -0,0 +1,187 @@
+extern crate clap;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use clap::{Arg, App};
+use serde_json::{json, Value};
+
+use nettlesoup::analysis::{Analysis, Conversation, Outcome};
+use nettlesoup::pcap::Reader;
+use nettlesoup::srv;
+
+/*
+ * Reads a packet capture, reconstructs the TFTP conversations in it and
+ * reports on each, along with anything amiss. Exits with status 2 if any
+ * conversation shows an anomaly, so that it can be used to sift captures.
+ */
+
+/* the exit status when there's something to report */
+const ANOMALOUS: i32 = 2;
+
+fn main() {
+    let matches = App::new("tftpcap")
+       .version("0.1.0")
+       .about("Analyses the TFTP conversations in a packet capture")
+       .author("Jack McPherson <jmcph4.github@gmail.com>")
+       .arg(Arg::with_name("capture")
+            .required(true)
+            .value_name("CAPTURE")
+            .help("The pcap file to read"))
+       .arg(Arg::with_name("port")
+            .long("port")
+            .short('p')
+            .value_name("port")
+            .help("The UDP port the servers listen on")
+            .takes_value(true))
+       .arg(Arg::with_name("json")
+            .long("json")
+            .help("Prints the analysis as a JSON object"))
+       .get_matches();
+
+    let path: &str = matches.value_of("capture").unwrap();
+    let port: u16 = match matches.value_of("port") {
+        Some(port) => port.parse().unwrap_or_else(|_| {
+            fail(&format!("Invalid port: {}", port))
+        }),
+        None => srv::DEFAULT_PORT
+    };
+
+    let file: File = File::open(path)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let mut reader: Reader<BufReader<File>> = Reader::new(BufReader::new(file))
+        .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let mut analysis: Analysis = Analysis::new(port);
+
+    loop {
+        match reader.next_datagram() {
+            Ok(Some(datagram)) => analysis.add(&datagram),
+            Ok(None) => break,
+            /* a capture cut off part way is still worth a look */
+            Err(e) => {
+                eprintln!("tftpcap: {}: {}", path, e);
+                break;
+            }
+        }
+    }
+
+    analysis.finish();
+
+    if matches.is_present("json") {
+        println!("{}", summary(&analysis, reader.truncated()));
+    } else {
+        report(&analysis, reader.truncated());
+    }
+
+    if analysis.conversations().iter()
+        .any(|conversation| !conversation.anomalies().is_empty()) {
+        process::exit(ANOMALOUS);
+    }
+}
+
+fn report(analysis: &Analysis, truncated: u64) {
+    let started: SystemTime = analysis.started().unwrap_or(SystemTime::now());
+    let conversations: &[Conversation] = analysis.conversations();
+
+    for (index, conversation) in conversations.iter().enumerate() {
+        println!("#{} {}", index + 1, conversation.request());
+        println!("    client {}, server {}{}", conversation.client(),
+                 conversation.server(),
+                 match conversation.server_tid() {
+                     Some(tid) => format!(" (replying from {})", tid),
+                     None => String::new()
+                 });
+
+        if let Some(options) = conversation.acknowledged() {
+            let options: Vec<String> = options.iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            println!("    acknowledged {}", options.join(" "));
+        }
+
+        println!("    at +{:.6}s for {:.3}s: {} bytes in {} blocks, {} \
+                  datagrams", offset(started, conversation.started()),
+                 conversation.duration().as_secs_f64(), conversation.bytes(),
+                 conversation.blocks(), conversation.datagrams());
+        println!("    {}", conversation.outcome());
+
+        for (time, anomaly) in conversation.anomalies() {
+            println!("    +{:.6}s {}", offset(started, *time), anomaly);
+        }
+
+        println!();
+    }
+
+    let count = |outcome: fn(&Outcome) -> bool| conversations.iter()
+        .filter(|conversation| outcome(&conversation.outcome()))
+        .count();
+
+    println!("{} conversations: {} complete, {} aborted, {} incomplete; {} \
+              with anomalies", conversations.len(),
+             count(|outcome| *outcome == Outcome::Complete),
+             count(|outcome| matches!(outcome, Outcome::Aborted(..))),
+             count(|outcome| *outcome == Outcome::Incomplete),
+             conversations.iter()
+                 .filter(|conversation| !conversation.anomalies().is_empty())
+                 .count());
+    println!("{} stray datagrams, {} cut short by the capture",
+             analysis.stray(), truncated);
+}
+
+/* the analysis as a JSON object */
+fn summary(analysis: &Analysis, truncated: u64) -> Value {
+    let started: SystemTime = analysis.started().unwrap_or(SystemTime::now());
+    let conversations: Vec<Value> = analysis.conversations().iter()
+        .map(|conversation| {
+            let (result, error): (&str, Option<String>) =
+                match conversation.outcome() {
+                    Outcome::Complete => ("complete", None),
+                    Outcome::Aborted(code, message) =>
+                        ("aborted", Some(format!("{}: {}", code, message))),
+                    Outcome::Incomplete => ("incomplete", None)
+                };
+            let anomalies: Vec<Value> = conversation.anomalies().iter()
+                .map(|(time, anomaly)| json!({
+                    "offset": offset(started, *time),
+                    "anomaly": anomaly.to_string()
+                }))
+                .collect();
+
+            json!({
+                "request": conversation.request().to_string(),
+                "direction": conversation.direction().to_string(),
+                "client": conversation.client().to_string(),
+                "server": conversation.server().to_string(),
+                "server_tid": conversation.server_tid()
+                    .map(|tid| tid.to_string()),
+                "blksize": conversation.block_size(),
+                "offset": offset(started, conversation.started()),
+                "seconds": conversation.duration().as_secs_f64(),
+                "bytes": conversation.bytes(),
+                "blocks": conversation.blocks(),
+                "datagrams": conversation.datagrams(),
+                "result": result,
+                "error": error,
+                "anomalies": anomalies
+            })
+        })
+        .collect();
+
+    json!({
+        "conversations": conversations,
+        "stray": analysis.stray(),
+        "truncated": truncated
+    })
+}
+
+/* seconds since the start of the capture */
+fn offset(started: SystemTime, time: SystemTime) -> f64 {
+    time.duration_since(started).unwrap_or(Duration::from_secs(0))
+        .as_secs_f64()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("tftpcap: {}", message);
+    process::exit(1);
+}
@@ -0,0 +1,572 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::conn::Connection;
+use crate::msg::{AnyMessage, DataMessageBlockNumber, ErrorMessageCode,
+                 OptionList};
+use crate::options;
+use crate::pcap::Datagram;
+use crate::stats::Direction;
+
+/*
+ * Reconstruction of TFTP conversations from captured datagrams, for working
+ * out after the fact what became of a transfer.
+ *
+ * A conversation starts with a request to the server's well-known port and
+ * is joined by the first reply to the client from anywhere, which fixes the
+ * server's TID; from then on it's followed as a connection between the two.
+ * Each block of data is checked against those that came before it, and
+ * whatever doesn't fit (gaps, duplicates, blocks out of order, a transfer
+ * that never got as far as its final block) is noted as an anomaly.
+ *
+ * Block numbers are taken to roll over to zero, and are counted on past
+ * 65535 accordingly.
+ */
+
+/* something amiss in a conversation */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    Gap(u64, u64),          /* the block expected, and the one that came */
+    Duplicate(u64),
+    OutOfOrder(u64),        /* arrived after a later block */
+    Malformed(String),
+    MissingFinal(u64),      /* the last block to arrive, short of the end */
+    Unacknowledged(u64),    /* the final block */
+    Unanswered
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Anomaly::Gap(expected, received) =>
+                write!(f, "gap: expected block {} but block {} arrived",
+                       expected, received),
+            Anomaly::Duplicate(block) =>
+                write!(f, "duplicate: block {} arrived again", block),
+            Anomaly::OutOfOrder(block) =>
+                write!(f, "out of order: block {} arrived after a later one",
+                       block),
+            Anomaly::Malformed(e) => write!(f, "malformed datagram: {}", e),
+            Anomaly::MissingFinal(block) =>
+                write!(f, "missing final block: the transfer stopped after \
+                           block {}", block),
+            Anomaly::Unacknowledged(block) =>
+                write!(f, "final block {} was never acknowledged", block),
+            Anomaly::Unanswered => write!(f, "the request was never answered")
+        }
+    }
+}
+
+/* how a conversation ended, as far as the capture shows */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Complete,
+    Aborted(ErrorMessageCode, String),
+    Incomplete
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Complete => write!(f, "complete"),
+            Outcome::Aborted(code, message) =>
+                write!(f, "aborted with error {} ({})", code, message),
+            Outcome::Incomplete => write!(f, "incomplete")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Conversation {
+    client: SocketAddr,
+    server: SocketAddr,             /* where the request was sent */
+    connection: Option<Connection>, /* the server's side, once it replies */
+    request: AnyMessage,
+    direction: Direction,
+    acknowledged: Option<OptionList>,
+    block_size: usize,
+    started: SystemTime,
+    ended: SystemTime,
+    datagrams: u64,
+    blocks: u64,
+    bytes: u64,
+    seen: HashSet<u64>,
+    highest: u64,                   /* the latest block to arrive */
+    last_block: Option<u64>,        /* the short one that ends the file */
+    acknowledged_final: bool,
+    error: Option<(ErrorMessageCode, String)>,
+    anomalies: Vec<(SystemTime, Anomaly)>
+}
+
+impl Conversation {
+    fn new(time: SystemTime, client: SocketAddr, server: SocketAddr,
+           request: AnyMessage) -> Self {
+        let direction: Direction = match request {
+            AnyMessage::Wrq(_) => Direction::Write,
+            _ => Direction::Read
+        };
+
+        Conversation {
+            client,
+            server,
+            connection: None,
+            request,
+            direction,
+            acknowledged: None,
+            block_size: options::DEFAULT_BLOCK_SIZE,
+            started: time,
+            ended: time,
+            datagrams: 1,
+            blocks: 0,
+            bytes: 0,
+            seen: HashSet::new(),
+            highest: 0,
+            last_block: None,
+            acknowledged_final: false,
+            error: None,
+            anomalies: Vec::new()
+        }
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /* the address the server carried on the conversation from */
+    pub fn server_tid(&self) -> Option<SocketAddr> {
+        self.connection.as_ref().map(|connection| connection.local_addr())
+    }
+
+    pub fn request(&self) -> &AnyMessage {
+        &self.request
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn acknowledged(&self) -> Option<&OptionList> {
+        self.acknowledged.as_ref()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.ended.duration_since(self.started).unwrap_or_default()
+    }
+
+    pub fn datagrams(&self) -> u64 {
+        self.datagrams
+    }
+
+    /* distinct blocks of data */
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn anomalies(&self) -> &[(SystemTime, Anomaly)] {
+        &self.anomalies
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        match (&self.error, self.last_block) {
+            (Some((code, message)), _) =>
+                Outcome::Aborted(*code, message.clone()),
+            (None, Some(_)) if self.acknowledged_final => Outcome::Complete,
+            _ => Outcome::Incomplete
+        }
+    }
+
+    fn handle(&mut self, time: SystemTime, from_client: bool,
+              payload: &[u8]) {
+        self.ended = time;
+        self.datagrams += 1;
+
+        let message: AnyMessage = match AnyMessage::from_bytes(
+            payload.to_vec()) {
+            Ok(message) => message,
+            Err(e) => return self.note(time, Anomaly::Malformed(e.to_string()))
+        };
+
+        if let Some(connection) = &mut self.connection {
+            connection.add_msg(message.clone());
+        }
+
+        /* the client sends the data when writing, the server when reading */
+        let from_sender: bool =
+            from_client == (self.direction == Direction::Write);
+
+        match message {
+            AnyMessage::Data(data) if from_sender =>
+                self.data(time, data.block_num(), data.data().len()),
+            AnyMessage::Ack(ack) if !from_sender &&
+                self.last_block == Some(self.absolute(ack.block_num())) =>
+                self.acknowledged_final = true,
+            AnyMessage::Oack(oack) if !from_client => {
+                if let Some(size) = options::find(&oack.options(),
+                                                  options::BLKSIZE)
+                    .and_then(options::parse_block_size) {
+                    self.block_size = size;
+                }
+
+                self.acknowledged = Some(oack.options());
+            },
+            AnyMessage::Error(e) => self.error = Some((e.code(), e.message())),
+            _ => {}
+        }
+    }
+
+    fn data(&mut self, time: SystemTime, block: DataMessageBlockNumber,
+            len: usize) {
+        let block: u64 = self.absolute(block);
+
+        if block == 0 {
+            return;
+        }
+
+        if !self.seen.insert(block) {
+            return self.note(time, Anomaly::Duplicate(block));
+        }
+
+        if block > self.highest + 1 {
+            self.note(time, Anomaly::Gap(self.highest + 1, block));
+        } else if block < self.highest {
+            self.note(time, Anomaly::OutOfOrder(block));
+        }
+
+        self.highest = self.highest.max(block);
+        self.blocks += 1;
+        self.bytes += len as u64;
+
+        if len < self.block_size {
+            self.last_block = Some(block);
+        }
+    }
+
+    /* a block number counted on past any rollovers, taking it to be the
+        nearest to the latest block with those low bits */
+    fn absolute(&self, block: DataMessageBlockNumber) -> u64 {
+        let latest: DataMessageBlockNumber = self.highest as
+            DataMessageBlockNumber;
+        let difference: i64 = block.wrapping_sub(latest) as i16 as i64;
+
+        (self.highest as i64 + difference).max(0) as u64
+    }
+
+    /* notes what the capture leaves unfinished */
+    fn finish(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if self.connection.is_none() {
+            self.note(self.started, Anomaly::Unanswered);
+        } else if let Some(block) = self.last_block {
+            if !self.acknowledged_final {
+                self.note(self.ended, Anomaly::Unacknowledged(block));
+            }
+        } else {
+            self.note(self.ended, Anomaly::MissingFinal(self.highest));
+        }
+    }
+
+    fn note(&mut self, time: SystemTime, anomaly: Anomaly) {
+        self.anomalies.push((time, anomaly));
+    }
+}
+
+/* the conversations in a capture, built up a datagram at a time */
+#[derive(Debug)]
+pub struct Analysis {
+    port: u16,                      /* the servers' well-known port */
+    conversations: Vec<Conversation>,
+    pending: HashMap<SocketAddr, usize>,    /* awaiting a first reply, by
+                                                client */
+    active: HashMap<(SocketAddr, SocketAddr), usize>,  /* by client and
+                                                           server TID */
+    started: Option<SystemTime>,
+    stray: u64
+}
+
+impl Analysis {
+    pub fn new(port: u16) -> Self {
+        Analysis {
+            port,
+            conversations: Vec::new(),
+            pending: HashMap::new(),
+            active: HashMap::new(),
+            started: None,
+            stray: 0
+        }
+    }
+
+    pub fn conversations(&self) -> &[Conversation] {
+        &self.conversations
+    }
+
+    /* when the first datagram was captured */
+    pub fn started(&self) -> Option<SystemTime> {
+        self.started
+    }
+
+    /* datagrams that weren't part of any conversation */
+    pub fn stray(&self) -> u64 {
+        self.stray
+    }
+
+    pub fn add(&mut self, datagram: &Datagram) {
+        let time: SystemTime = datagram.time();
+        let source: SocketAddr = datagram.source();
+        let destination: SocketAddr = datagram.destination();
+        let payload: &[u8] = datagram.payload();
+
+        self.started.get_or_insert(time);
+
+        if let Some(&index) = self.active.get(&(source, destination)) {
+            return self.conversations[index].handle(time, true, payload);
+        }
+
+        if let Some(&index) = self.active.get(&(destination, source)) {
+            return self.conversations[index].handle(time, false, payload);
+        }
+
+        if destination.port() == self.port {
+            if let Ok(request @ AnyMessage::Rrq(_)) |
+                Ok(request @ AnyMessage::Wrq(_)) =
+                AnyMessage::from_bytes(payload.to_vec()) {
+                return self.request(time, source, destination, request);
+            }
+        }
+
+        /* the server's first reply comes from its TID for the transfer */
+        if let Some(index) = self.pending.remove(&destination) {
+            let conversation: &mut Conversation =
+                &mut self.conversations[index];
+            conversation.connection =
+                Some(Connection::new(source, destination));
+            conversation.handle(time, false, payload);
+            self.active.insert((destination, source), index);
+            return;
+        }
+
+        self.stray += 1;
+    }
+
+    /* notes what the capture leaves unfinished; nothing more should be
+        added after this */
+    pub fn finish(&mut self) {
+        for conversation in &mut self.conversations {
+            conversation.finish();
+        }
+    }
+
+    fn request(&mut self, time: SystemTime, client: SocketAddr,
+               server: SocketAddr, request: AnyMessage) {
+        /* the same request again is the client retransmitting it */
+        if let Some(&index) = self.pending.get(&client) {
+            let conversation: &mut Conversation =
+                &mut self.conversations[index];
+
+            if conversation.request.to_bytes() == request.to_bytes() {
+                conversation.ended = time;
+                conversation.datagrams += 1;
+                return;
+            }
+        }
+
+        self.conversations.push(Conversation::new(time, client, server,
+                                                  request));
+        self.pending.insert(client, self.conversations.len() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::msg::{AcknowledgementMessage, DataMessage, ErrorMessage,
+                     ReadRequestMessage, ReadWriteRequestMessageMode,
+                     WriteRequestMessage};
+
+    const CLIENT: &str = "192.0.2.1:50000";
+    const SERVER: &str = "192.0.2.2:69";
+    const TID: &str = "192.0.2.2:40000";
+
+    /* the conversations in a capture of datagrams, each given as its source,
+        its destination and its payload */
+    fn analyse(datagrams: &[(&str, &str, Vec<u8>)]) -> Analysis {
+        let mut analysis: Analysis = Analysis::new(69);
+
+        for (i, (source, destination, payload)) in
+            datagrams.iter().enumerate() {
+            analysis.add(&Datagram::new(
+                UNIX_EPOCH + Duration::from_millis(i as u64),
+                source.parse().unwrap(), destination.parse().unwrap(),
+                payload.clone()));
+        }
+
+        analysis.finish();
+        analysis
+    }
+
+    fn rrq() -> Vec<u8> {
+        AnyMessage::Rrq(ReadRequestMessage::new(
+            "boot.img".to_string(), ReadWriteRequestMessageMode::Octet))
+            .to_bytes()
+    }
+
+    fn wrq() -> Vec<u8> {
+        AnyMessage::Wrq(WriteRequestMessage::new(
+            "boot.img".to_string(), ReadWriteRequestMessageMode::Octet))
+            .to_bytes()
+    }
+
+    /* a full block, or the short one that ends the file */
+    fn data(block: DataMessageBlockNumber, last: bool) -> Vec<u8> {
+        let len: usize = if last { 10 } else { options::DEFAULT_BLOCK_SIZE };
+        AnyMessage::Data(DataMessage::new(block, vec![0; len])).to_bytes()
+    }
+
+    fn ack(block: DataMessageBlockNumber) -> Vec<u8> {
+        AnyMessage::Ack(AcknowledgementMessage::new(block)).to_bytes()
+    }
+
+    /* a read with each of `blocks` sent in turn and acknowledged */
+    fn read(blocks: &[(DataMessageBlockNumber, bool)]) -> Analysis {
+        let mut datagrams: Vec<(&str, &str, Vec<u8>)> =
+            vec![(CLIENT, SERVER, rrq())];
+
+        for &(block, last) in blocks {
+            datagrams.push((TID, CLIENT, data(block, last)));
+            datagrams.push((CLIENT, TID, ack(block)));
+        }
+
+        analyse(&datagrams)
+    }
+
+    fn anomalies(analysis: &Analysis) -> Vec<Anomaly> {
+        analysis.conversations()[0].anomalies().iter()
+            .map(|(_, anomaly)| anomaly.clone())
+            .collect()
+    }
+
+    #[test]
+    fn follows_a_complete_read() {
+        let analysis: Analysis = read(&[(1, false), (2, false), (3, true)]);
+        let conversation: &Conversation = &analysis.conversations()[0];
+
+        assert_eq!(analysis.conversations().len(), 1);
+        assert_eq!(analysis.stray(), 0);
+        assert_eq!(conversation.client(), CLIENT.parse().unwrap());
+        assert_eq!(conversation.server_tid(), Some(TID.parse().unwrap()));
+        assert_eq!(conversation.direction(), Direction::Read);
+        assert_eq!(conversation.blocks(), 3);
+        assert_eq!(conversation.bytes(),
+                   2 * options::DEFAULT_BLOCK_SIZE as u64 + 10);
+        assert_eq!(conversation.datagrams(), 7);
+        assert_eq!(conversation.outcome(), Outcome::Complete);
+        assert!(conversation.anomalies().is_empty());
+    }
+
+    #[test]
+    fn follows_a_complete_write() {
+        let analysis: Analysis = analyse(&[
+            (CLIENT, SERVER, wrq()),
+            (TID, CLIENT, ack(0)),
+            (CLIENT, TID, data(1, true)),
+            (TID, CLIENT, ack(1))
+        ]);
+        let conversation: &Conversation = &analysis.conversations()[0];
+
+        assert_eq!(conversation.direction(), Direction::Write);
+        assert_eq!(conversation.blocks(), 1);
+        assert_eq!(conversation.outcome(), Outcome::Complete);
+    }
+
+    #[test]
+    fn notes_gaps_duplicates_and_blocks_out_of_order() {
+        let analysis: Analysis = read(&[(1, false), (3, false), (2, false),
+                                        (2, false), (4, true)]);
+
+        assert_eq!(anomalies(&analysis),
+                   vec![Anomaly::Gap(2, 3), Anomaly::OutOfOrder(2),
+                        Anomaly::Duplicate(2)]);
+        assert_eq!(analysis.conversations()[0].outcome(), Outcome::Complete);
+    }
+
+    #[test]
+    fn counts_block_numbers_on_past_a_rollover() {
+        let mut conversation: Conversation =
+            read(&[(1, false)]).conversations()[0].clone();
+        conversation.highest = 65535;
+
+        assert_eq!(conversation.absolute(0), 65536);
+        assert_eq!(conversation.absolute(1), 65537);
+        assert_eq!(conversation.absolute(65534), 65534);
+
+        conversation.highest = 65536 + 10;
+        assert_eq!(conversation.absolute(65530), 65530);
+        assert_eq!(conversation.absolute(11), 65536 + 11);
+    }
+
+    #[test]
+    fn notes_what_the_capture_leaves_unfinished() {
+        let analysis: Analysis = read(&[(1, false), (2, false)]);
+        assert_eq!(anomalies(&analysis), vec![Anomaly::MissingFinal(2)]);
+        assert_eq!(analysis.conversations()[0].outcome(), Outcome::Incomplete);
+
+        let analysis: Analysis = analyse(&[(CLIENT, SERVER, rrq()),
+                                           (TID, CLIENT, data(1, true))]);
+        assert_eq!(anomalies(&analysis), vec![Anomaly::Unacknowledged(1)]);
+
+        /* the request again is only the client retransmitting it */
+        let analysis: Analysis = analyse(&[(CLIENT, SERVER, rrq()),
+                                           (CLIENT, SERVER, rrq())]);
+        assert_eq!(analysis.conversations().len(), 1);
+        assert_eq!(anomalies(&analysis), vec![Anomaly::Unanswered]);
+    }
+
+    #[test]
+    fn reports_an_aborted_conversation() {
+        let error: Vec<u8> = AnyMessage::Error(ErrorMessage::new(
+            1, "File not found".to_string())).to_bytes();
+        let analysis: Analysis = analyse(&[(CLIENT, SERVER, rrq()),
+                                           (TID, CLIENT, error)]);
+
+        assert_eq!(analysis.conversations()[0].outcome(),
+                   Outcome::Aborted(1, "File not found".to_string()));
+        assert!(anomalies(&analysis).is_empty());
+    }
+
+    #[test]
+    fn notes_malformed_and_stray_datagrams() {
+        let analysis: Analysis = analyse(&[
+            (CLIENT, SERVER, rrq()),
+            (TID, CLIENT, data(1, true)),
+            (CLIENT, TID, vec![0, 9]),
+            (CLIENT, TID, ack(1)),
+            ("192.0.2.3:50001", TID, ack(1))
+        ]);
+
+        assert!(matches!(anomalies(&analysis)[..], [Anomaly::Malformed(_)]));
+        assert_eq!(analysis.conversations()[0].outcome(), Outcome::Complete);
+        assert_eq!(analysis.stray(), 1);
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use serde_json::{json, Value};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::msg::ReadWriteRequestMessageMode;
+use nettlesoup::stats::Direction;
+
+use crate::{Outcome, Report};
+
+/*
+ * Batch transfers, run from a manifest with one transfer per line:
+ *
+ *     get <remote> <local> [octet|netascii]
+ *     put <remote> <local> [octet|netascii]
+ *
+ * Blank lines and anything after a '#' are ignored. Up to the given number
+ * of transfers run at once, each with its own client; once they've all
+ * finished, a table of how each went is printed.
+ */
+
+pub const DEFAULT_JOBS: usize = 4;
+
+/* a line of the manifest */
+#[derive(Clone, Debug)]
+pub struct Entry {
+    line: usize,
+    direction: Direction,
+    remote: String,
+    local: String,
+    mode: Option<ReadWriteRequestMessageMode>   /* overriding the client's */
+}
+
+/* how an entry's transfer went; the error is for when the local file
+    couldn't be opened, so that no transfer was made */
+type Finished = Result<Outcome, String>;
+
+/* reads a manifest, failing with the line at fault if it's malformed */
+pub fn parse(manifest: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = vec![];
+
+    for (index, line) in manifest.lines().enumerate() {
+        let line_num: usize = index + 1;
+        let line: &str = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let (direction, remote, local, mode): (&str, &str, &str, Option<&str>) =
+            match words[..] {
+                [] => continue,
+                [direction, remote, local] => (direction, remote, local, None),
+                [direction, remote, local, mode] =>
+                    (direction, remote, local, Some(mode)),
+                _ => return Err(format!("line {}: expected <get|put> \
+                                         <remote> <local> [<mode>]",
+                                        line_num))
+            };
+
+        let direction: Direction = match direction {
+            "get" => Direction::Read,
+            "put" => Direction::Write,
+            _ => return Err(format!("line {}: unknown direction '{}'",
+                                    line_num, direction))
+        };
+
+        let mode: Option<ReadWriteRequestMessageMode> = match mode {
+            None => None,
+            Some("octet") => Some(ReadWriteRequestMessageMode::Octet),
+            Some("netascii") => Some(ReadWriteRequestMessageMode::NetAscii),
+            Some(mode) => return Err(format!("line {}: unknown mode '{}'",
+                                             line_num, mode))
+        };
+
+        entries.push(Entry {
+            line: line_num,
+            direction,
+            remote: remote.to_string(),
+            local: local.to_string(),
+            mode
+        });
+    }
+
+    Ok(entries)
+}
+
+/* runs every entry, `jobs` at a time, and reports on them; returns whether
+    they all succeeded */
+pub fn run(client: &Client, entries: &[Entry], jobs: usize, report: Report,
+           retries: usize) -> bool {
+    let next: AtomicUsize = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Finished>>> =
+        Mutex::new(entries.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, entries.len().max(1)) {
+            scope.spawn(|| loop {
+                let index: usize = next.fetch_add(1, Ordering::Relaxed);
+
+                let entry: &Entry = match entries.get(index) {
+                    Some(entry) => entry,
+                    None => break
+                };
+
+                let result: Finished = transfer(client, entry, retries);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    let results: Vec<Finished> = results.into_inner().unwrap().into_iter()
+        .map(|result| result.unwrap())
+        .collect();
+    let failed: usize = results.iter()
+        .filter(|result| error(result).is_some())
+        .count();
+
+    match report {
+        Report::Normal => table(entries, &results),
+        Report::Quiet => {
+            for (entry, result) in entries.iter().zip(&results) {
+                if let Some(error) = error(result) {
+                    eprintln!("tftpclient: line {}: {}: {}", entry.line,
+                              entry.remote, error);
+                }
+            }
+        },
+        Report::Json => println!("{}", summary(client, entries, &results))
+    }
+
+    if report == Report::Normal {
+        println!("{} transfers: {} succeeded, {} failed", entries.len(),
+                 entries.len() - failed, failed);
+    }
+
+    failed == 0
+}
+
+fn transfer(client: &Client, entry: &Entry, retries: usize) -> Finished {
+    let mut client: Client = client.clone();
+
+    if let Some(mode) = entry.mode {
+        client.set_mode(mode);
+    }
+
+    match entry.direction {
+        Direction::Read => {
+            /* downloads may be sorted into directories of their own */
+            if let Some(parent) = Path::new(&entry.local).parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("{}: {}", parent.display(), e))?;
+            }
+
+            crate::download(&client, &entry.remote, &entry.local,
+                            Report::Quiet, retries)
+        },
+        Direction::Write => crate::upload(&client, &entry.local,
+                                          &entry.remote, Report::Quiet,
+                                          retries)
+    }
+}
+
+/* what went wrong with a transfer, if anything did */
+fn error(result: &Finished) -> Option<String> {
+    match result {
+        Ok((Ok(_), _)) => None,
+        Ok((Err(e), _)) => Some(e.to_string()),
+        Err(e) => Some(e.clone())
+    }
+}
+
+fn table(entries: &[Entry], results: &[Finished]) {
+    let mut rows: Vec<[String; 8]> = vec![[
+        "STATUS", "DIR", "REMOTE", "LOCAL", "BYTES", "TIME", "ATTEMPTS",
+        "ERROR"
+    ].map(String::from)];
+
+    for (entry, result) in entries.iter().zip(results) {
+        let (bytes, time, attempts): (String, String, String) = match result {
+            Ok((Ok(stats), attempts)) =>
+                (stats.bytes().to_string(),
+                 format!("{:.2}s", stats.duration().as_secs_f64()),
+                 attempts.to_string()),
+            Ok((Err(_), attempts)) =>
+                ("-".to_string(), "-".to_string(), attempts.to_string()),
+            Err(_) => ("-".to_string(), "-".to_string(), "0".to_string())
+        };
+        let error: Option<String> = error(result);
+
+        rows.push([
+            if error.is_none() { "ok" } else { "FAILED" }.to_string(),
+            match entry.direction {
+                Direction::Read => "get",
+                Direction::Write => "put"
+            }.to_string(),
+            entry.remote.clone(),
+            entry.local.clone(),
+            bytes,
+            time,
+            attempts,
+            error.unwrap_or_default()
+        ]);
+    }
+
+    let mut widths: [usize; 8] = [0; 8];
+
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in &rows {
+        let line: Vec<String> = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/* every transfer as a JSON object, as for a single one, along with the
+    tally */
+fn summary(client: &Client, entries: &[Entry], results: &[Finished]) -> Value {
+    let transfers: Vec<Value> = entries.iter().zip(results)
+        .map(|(entry, result)| {
+            let mut value: Value = match result {
+                Ok(outcome) => crate::summary(client, entry.direction,
+                                              &entry.remote, outcome),
+                Err(e) => json!({
+                    "result": "error",
+                    "direction": entry.direction.to_string(),
+                    "file": entry.remote,
+                    "server": client.server().to_string(),
+                    "error": e,
+                    "transient": false,
+                    "attempts": 0
+                })
+            };
+
+            value["local"] = json!(entry.local);
+            value["line"] = json!(entry.line);
+            value
+        })
+        .collect();
+    let failed: usize = results.iter()
+        .filter(|result| error(result).is_some())
+        .count();
+
+    json!({
+        "transfers": transfers,
+        "succeeded": results.len() - failed,
+        "failed": failed
+    })
+}
@@ -1,3 +1,549 @@
+extern crate clap;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Seek, SeekFrom};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use clap::{Arg, App, ArgMatches};
+use serde_json::{json, Value};
+use tracing::level_filters::LevelFilter;
+
+use nettlesoup::clnt::Client;
+use nettlesoup::conn::TransferError;
+use nettlesoup::log::{self, Logging};
+use nettlesoup::msg::ReadWriteRequestMessageMode;
+use nettlesoup::options;
+use nettlesoup::pcap;
+use nettlesoup::policy;
+use nettlesoup::srv;
+use nettlesoup::stats::{Direction, TransferStats};
+use nettlesoup::upload::PendingUpload;
+
+mod batch;
+mod progress;
+mod shell;
+
+use progress::Bar;
+
+/* how long to wait before the first restart of a failed transfer, doubling
+    for each one after up to the maximum */
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/* how a transfer ended up, and after how many attempts */
+type Outcome = (Result<TransferStats, TransferError>, usize);
+
+/* what to say about a transfer */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Report {
+    Normal,     /* a progress bar, then a summary */
+    Quiet,      /* nothing but errors */
+    Json        /* a JSON object, success or not */
+}
+
 fn main() {
-    println!("Client");
+    let matches = App::new("tftpclient")
+       .version("0.1.0")
+       .about("The NettleSoup TFTP client")
+       .author("Jack McPherson <jmcph4.github@gmail.com>")
+       .arg(Arg::with_name("port")
+            .long("port")
+            .short('p')
+            .value_name("port")
+            .help("The UDP port the server listens on")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("mode")
+            .long("mode")
+            .short('m')
+            .value_name("mode")
+            .help("The transfer mode")
+            .takes_value(true)
+            .possible_values(&["octet", "netascii"])
+            .global(true))
+       .arg(Arg::with_name("blksize")
+            .long("blksize")
+            .short('b')
+            .value_name("bytes")
+            .help("Requests a block size other than 512 bytes (RFC 2348)")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("windowsize")
+            .long("windowsize")
+            .short('w')
+            .value_name("blocks")
+            .help("Requests that this many blocks be sent for each \
+                   acknowledgement (RFC 7440)")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .short('t')
+            .value_name("seconds")
+            .help("The retransmission timeout, which is also requested of \
+                   the server (RFC 2349)")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("ipv4")
+            .long("ipv4")
+            .short('4')
+            .help("Only connects to the server over IPv4")
+            .conflicts_with("ipv6")
+            .global(true))
+       .arg(Arg::with_name("ipv6")
+            .long("ipv6")
+            .short('6')
+            .help("Only connects to the server over IPv6")
+            .global(true))
+       .arg(Arg::with_name("bandwidth")
+            .long("bandwidth")
+            .value_name("bytes")
+            .help("The most bytes per second to transfer (accepts K, M and \
+                   G suffixes)")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("retries")
+            .long("retries")
+            .short('r')
+            .value_name("count")
+            .help("How many times to start a transfer again from scratch \
+                   after it fails for want of a reply or with an unspecified \
+                   error, waiting longer before each attempt")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .short('q')
+            .help("Prints nothing but errors")
+            .conflicts_with("json")
+            .global(true))
+       .arg(Arg::with_name("json")
+            .long("json")
+            .help("Prints the outcome of the transfer as a JSON object")
+            .global(true))
+       .arg(Arg::with_name("capture")
+            .long("capture")
+            .value_name("file")
+            .help("Writes every datagram sent and received to a pcap file, \
+                   which tftpcap can analyse")
+            .takes_value(true)
+            .global(true))
+       .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Prints every datagram sent and received, decoded and \
+                   timestamped, to standard error")
+            .global(true))
+       .arg(Arg::with_name("tsize")
+            .long("tsize")
+            .help("Exchanges the transfer size with the server (RFC 2349)")
+            .global(true))
+       .subcommand(App::new("get")
+            .about("Downloads a file from the server")
+            .arg(Arg::with_name("host")
+                 .required(true)
+                 .value_name("HOST")
+                 .help("The server to download from"))
+            .arg(Arg::with_name("remote")
+                 .required(true)
+                 .value_name("REMOTE")
+                 .help("The name of the file on the server"))
+            .arg(Arg::with_name("local")
+                 .value_name("LOCAL")
+                 .help("Where to save the file (defaults to its name on the \
+                        server)"))
+            .arg(Arg::with_name("multicast")
+                 .long("multicast")
+                 .help("Asks to join a multicast transfer (RFC 2090)")))
+       .subcommand(App::new("put")
+            .about("Uploads a file to the server")
+            .arg(Arg::with_name("host")
+                 .required(true)
+                 .value_name("HOST")
+                 .help("The server to upload to"))
+            .arg(Arg::with_name("local")
+                 .required(true)
+                 .value_name("LOCAL")
+                 .help("The file to upload"))
+            .arg(Arg::with_name("remote")
+                 .value_name("REMOTE")
+                 .help("The name to give the file on the server (defaults to \
+                        its local name)")))
+       .subcommand(App::new("batch")
+            .about("Runs the transfers listed in a manifest, each line of \
+                    which reads: <get|put> <remote> <local> [<mode>]")
+            .arg(Arg::with_name("host")
+                 .required(true)
+                 .value_name("HOST")
+                 .help("The server to transfer with"))
+            .arg(Arg::with_name("manifest")
+                 .required(true)
+                 .value_name("MANIFEST")
+                 .help("The file listing the transfers"))
+            .arg(Arg::with_name("jobs")
+                 .long("jobs")
+                 .short('j')
+                 .value_name("count")
+                 .help("How many transfers to run at once (defaults to 4)")
+                 .takes_value(true)))
+       .get_matches();
+
+    let args: Option<&ArgMatches> = matches.subcommand_name()
+        .and_then(|name| matches.subcommand_matches(name));
+
+    if let Some(path) = value_of(&matches, args.unwrap_or(&matches),
+                                 "capture") {
+        if let Err(e) = pcap::capture(Path::new(path)) {
+            fail(&e.to_string());
+        }
+    }
+
+    /* the shell can turn tracing on and off, so sets it up itself */
+    if let Some(args) = args {
+        if matches.is_present("trace") || args.is_present("trace") {
+            let mut logging: Logging = Logging::new();
+            logging.set_level(LevelFilter::OFF);
+            logging.set_trace(true);
+
+            if let Err(e) = log::init(&logging, "tftpclient") {
+                fail(&e.to_string());
+            }
+        }
+    }
+
+    match matches.subcommand_name() {
+        Some("get") => {
+            let args: &ArgMatches = matches.subcommand_matches("get").unwrap();
+            let mut client: Client = client(&matches, args);
+            client.set_multicast(args.is_present("multicast"));
+
+            let remote: &str = args.value_of("remote").unwrap();
+            let local: &str = args.value_of("local").unwrap_or_else(|| {
+                local_name(remote)
+                    .unwrap_or_else(|| fail("Unable to derive a local name"))
+            });
+
+            let report: Report = report(&matches, args);
+            let outcome: Outcome = download(&client, remote, local, report,
+                                            retries(&matches, args))
+                .unwrap_or_else(|e| fail(&e));
+
+            conclude(report, &client, Direction::Read, remote, outcome);
+        },
+        Some("put") => {
+            let args: &ArgMatches = matches.subcommand_matches("put").unwrap();
+            let client: Client = client(&matches, args);
+
+            let local: &str = args.value_of("local").unwrap();
+            let remote: &str = args.value_of("remote").unwrap_or_else(|| {
+                local_name(local)
+                    .unwrap_or_else(|| fail("Unable to derive a remote name"))
+            });
+
+            let report: Report = report(&matches, args);
+            let outcome: Outcome = upload(&client, local, remote, report,
+                                          retries(&matches, args))
+                .unwrap_or_else(|e| fail(&e));
+
+            conclude(report, &client, Direction::Write, remote, outcome);
+        },
+        Some("batch") => {
+            let args: &ArgMatches =
+                matches.subcommand_matches("batch").unwrap();
+            let client: Client = client(&matches, args);
+
+            let manifest: &str = args.value_of("manifest").unwrap();
+            let entries: Vec<batch::Entry> = fs::read_to_string(manifest)
+                .map_err(|e| e.to_string())
+                .and_then(|manifest| batch::parse(&manifest))
+                .unwrap_or_else(|e| fail(&format!("{}: {}", manifest, e)));
+
+            let jobs: usize = match args.value_of("jobs") {
+                Some(jobs) => match jobs.parse() {
+                    Ok(jobs) if jobs > 0 => jobs,
+                    _ => fail(&format!("Invalid job count: {}", jobs))
+                },
+                None => batch::DEFAULT_JOBS
+            };
+
+            if !batch::run(&client, &entries, jobs, report(&matches, args),
+                           retries(&matches, args)) {
+                process::exit(1);
+            }
+        },
+        /* without a subcommand, there's a shell to take commands from */
+        _ => shell::run(&matches)
+    }
+}
+
+/* downloads a file to `local`, starting again as `retries` allows; fails
+    outright only if `local` can't be written to. The file is received
+    alongside `local` and only moved over it once complete, so a failed
+    download leaves whatever was there before as it was */
+fn download(client: &Client, remote: &str, local: &str, report: Report,
+            retries: usize) -> Result<Outcome, String> {
+    let path: &Path = Path::new(local);
+    let mut first: Option<PendingUpload> =
+        Some(PendingUpload::create(path, false)
+             .map_err(|e| format!("{}: {}", local, e))?);
+
+    Ok(attempt(retries, report, || {
+        /* anything a failed attempt left behind went with it, and each
+            attempt after starts over in a file of its own */
+        let mut file: PendingUpload = match first.take() {
+            Some(file) => file,
+            None => PendingUpload::create(path, false)?
+        };
+
+        let stats: TransferStats = watch(report, |progress| {
+            client.get_with_progress(remote, &mut file, progress)
+        })?;
+        file.commit(false)?;
+
+        Ok(stats)
+    }))
+}
+
+/* uploads `local`, starting again as `retries` allows; fails outright only
+    if `local` can't be read */
+fn upload(client: &Client, local: &str, remote: &str, report: Report,
+          retries: usize) -> Result<Outcome, String> {
+    let mut file: File = File::open(local)
+        .map_err(|e| format!("{}: {}", local, e))?;
+    let size: Option<u64> = file.metadata().ok()
+        .map(|metadata| metadata.len());
+
+    Ok(attempt(retries, report, || {
+        file.seek(SeekFrom::Start(0))?;
+
+        watch(report, |progress| {
+            client.put_with_progress(remote, &mut file, size, progress)
+        })
+    }))
+}
+
+/* the last component of a path, to name the file at the other end by */
+fn local_name(path: &str) -> Option<&str> {
+    Path::new(path).file_name().and_then(|name| name.to_str())
+}
+
+/* builds a client from the options common to every subcommand */
+fn client(matches: &ArgMatches, args: &ArgMatches) -> Client {
+    let ipv4: bool = matches.is_present("ipv4") || args.is_present("ipv4");
+    let ipv6: bool = matches.is_present("ipv6") || args.is_present("ipv6");
+    let host: &str = args.value_of("host").unwrap();
+
+    let server: SocketAddr = resolve(host, port(matches, args), ipv4, ipv6)
+        .unwrap_or_else(|| fail(&format!("Unable to resolve {}", host)));
+
+    let mut client: Client = Client::new(server);
+    configure(&mut client, matches, args);
+
+    client
+}
+
+fn port(matches: &ArgMatches, args: &ArgMatches) -> u16 {
+    match value_of(matches, args, "port") {
+        Some(port) => port.parse().unwrap_or_else(|_| {
+            fail(&format!("Invalid port: {}", port))
+        }),
+        None => srv::DEFAULT_PORT
+    }
+}
+
+/* the first address of a host in the permitted families; IPv6 literals may
+    be given in brackets, as they would be in a URL */
+fn resolve(host: &str, port: u16, ipv4: bool, ipv6: bool) ->
+    Option<SocketAddr> {
+    let name: &str = host.trim_start_matches('[').trim_end_matches(']');
+
+    (name, port).to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.find(|addr| {
+            (addr.is_ipv4() || !ipv4) && (addr.is_ipv6() || !ipv6)
+        }))
+}
+
+/* applies the transfer options given on the command line */
+fn configure(client: &mut Client, matches: &ArgMatches, args: &ArgMatches) {
+
+    if let Some(mode) = value_of(matches, args, "mode") {
+        client.set_mode(ReadWriteRequestMessageMode::from_string(
+                mode.to_string()).unwrap());
+    }
+
+    if let Some(size) = value_of(matches, args, "blksize") {
+        match options::parse_block_size(size) {
+            Some(size) => client.set_block_size(Some(size)),
+            None => fail(&format!("Invalid block size: {} (expected {} to {})",
+                                  size, options::MIN_BLOCK_SIZE,
+                                  options::MAX_BLOCK_SIZE))
+        }
+    }
+
+    if let Some(size) = value_of(matches, args, "windowsize") {
+        match options::parse_window_size(size) {
+            Some(size) => client.set_window_size(Some(size)),
+            None => fail(&format!("Invalid window size: {} (expected {} to \
+                                   {})", size, options::MIN_WINDOW_SIZE,
+                                  options::MAX_WINDOW_SIZE))
+        }
+    }
+
+    if let Some(timeout) = value_of(matches, args, "timeout") {
+        match options::parse_timeout(timeout) {
+            Some(timeout) => {
+                client.set_timeout(timeout);
+                client.set_request_timeout(true);
+            },
+            None => fail(&format!("Invalid timeout: {} (expected {} to {} \
+                                   seconds)", timeout,
+                                  options::MIN_TIMEOUT_SECS,
+                                  options::MAX_TIMEOUT_SECS))
+        }
+    }
+
+    if let Some(rate) = value_of(matches, args, "bandwidth") {
+        match policy::parse_size(rate) {
+            Ok(rate) if rate > 0 => client.set_bandwidth(Some(rate)),
+            _ => fail(&format!("Invalid bandwidth: {}", rate))
+        }
+    }
+
+    client.set_request_tsize(matches.is_present("tsize") ||
+                             args.is_present("tsize"));
+}
+
+fn report(matches: &ArgMatches, args: &ArgMatches) -> Report {
+    if matches.is_present("json") || args.is_present("json") {
+        Report::Json
+    } else if matches.is_present("quiet") || args.is_present("quiet") {
+        Report::Quiet
+    } else {
+        Report::Normal
+    }
+}
+
+fn retries(matches: &ArgMatches, args: &ArgMatches) -> usize {
+    match value_of(matches, args, "retries") {
+        Some(count) => count.parse().unwrap_or_else(|_| {
+            fail(&format!("Invalid retry count: {}", count))
+        }),
+        None => 0
+    }
+}
+
+/* runs a transfer under a progress bar, if there's anyone to watch it */
+fn watch<F>(report: Report, transfer: F) -> Result<TransferStats, TransferError>
+    where F: FnOnce(&mut dyn FnMut(u64, Option<u64>)) ->
+        Result<TransferStats, TransferError> {
+    let mut bar: Option<Bar> = match report {
+        Report::Normal if io::stderr().is_terminal() => Some(Bar::new()),
+        _ => None
+    };
+
+    let result: Result<TransferStats, TransferError> =
+        transfer(&mut |bytes, size| {
+            if let Some(bar) = &mut bar {
+                bar.update(bytes, size);
+            }
+        });
+
+    if let Some(bar) = &mut bar {
+        bar.finish();
+    }
+
+    result
+}
+
+/*
+ * Runs a transfer, starting it again from scratch after each transient
+ * failure (see TransferError::is_transient) up to `retries` times, and
+ * backing off exponentially between attempts. Returns the outcome of the
+ * last attempt along with the number made.
+ */
+fn attempt<F>(retries: usize, report: Report, mut transfer: F) -> Outcome
+    where F: FnMut() -> Result<TransferStats, TransferError> {
+    let mut delay: Duration = INITIAL_BACKOFF;
+    let mut attempts: usize = 0;
+
+    loop {
+        attempts += 1;
+
+        match transfer() {
+            Err(e) if e.is_transient() && attempts <= retries => {
+                if report == Report::Normal {
+                    eprintln!("tftpclient: {}; retrying in {}s (retry {} of \
+                               {})", e, delay.as_secs(), attempts, retries);
+                }
+
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_BACKOFF);
+            },
+            result => return (result, attempts)
+        }
+    }
+}
+
+/* reports the outcome of a transfer, exiting unsuccessfully if it failed */
+fn conclude(report: Report, client: &Client, direction: Direction,
+            filename: &str, outcome: Outcome) {
+    match (report, &outcome.0) {
+        (Report::Json, result) => {
+            println!("{}", summary(client, direction, filename, &outcome));
+
+            if result.is_err() {
+                process::exit(1);
+            }
+        },
+        (Report::Normal, Ok(stats)) => println!("Completed {}", stats),
+        (Report::Quiet, Ok(_)) => {},
+        (_, Err(e)) => fail(&e.to_string())
+    }
+}
+
+/* the outcome of a transfer as a JSON object */
+fn summary(client: &Client, direction: Direction, filename: &str,
+           (result, attempts): &Outcome) -> Value {
+    match result {
+        Ok(stats) => json!({
+            "result": "ok",
+            "direction": direction.to_string(),
+            "file": filename,
+            "server": stats.peer().to_string(),
+            "mode": ReadWriteRequestMessageMode::to_string(stats.mode()),
+            "blksize": stats.options().block_size(),
+            "windowsize": stats.options().window_size(),
+            "tsize": stats.options().transfer_size(),
+            "bytes": stats.bytes(),
+            "blocks": stats.blocks(),
+            "retransmissions": stats.retransmissions(),
+            "duplicates": stats.duplicates(),
+            "seconds": stats.duration().as_secs_f64(),
+            "bytes_per_second": stats.throughput(),
+            "attempts": attempts
+        }),
+        Err(e) => json!({
+            "result": "error",
+            "direction": direction.to_string(),
+            "file": filename,
+            "server": client.server().to_string(),
+            "error": e.to_string(),
+            "transient": e.is_transient(),
+            "attempts": attempts
+        })
+    }
+}
+
+/* global options may be given either side of the subcommand */
+fn value_of<'a>(matches: &'a ArgMatches, args: &'a ArgMatches, name: &str) ->
+    Option<&'a str> {
+    args.value_of(name).or_else(|| matches.value_of(name))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("tftpclient: {}", message);
+    process::exit(1);
 }
@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/*
+ * A progress bar, drawn on standard error while a transfer runs. With the
+ * size of the file known (from tsize), it shows how much of it has arrived;
+ * otherwise just the bytes so far. Either way, it shows the average rate.
+ */
+
+/* how often the bar is redrawn at most */
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/* the width of the bar itself, between its brackets */
+const BAR_WIDTH: usize = 30;
+
+pub struct Bar {
+    started: Instant,
+    drawn: Option<Instant>
+}
+
+impl Bar {
+    pub fn new() -> Self {
+        Bar {
+            started: Instant::now(),
+            drawn: None
+        }
+    }
+
+    /* notes the bytes transferred so far out of `size`, if that's known */
+    pub fn update(&mut self, bytes: u64, size: Option<u64>) {
+        let now: Instant = Instant::now();
+
+        if self.drawn.is_some_and(|drawn| now - drawn < REDRAW_INTERVAL) {
+            return;
+        }
+
+        self.drawn = Some(now);
+
+        let secs: f64 = (now - self.started).as_secs_f64();
+        let rate: f64 = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+        let mut line: String = String::from("\r");
+
+        match size {
+            Some(size) if size > 0 => {
+                let fraction: f64 = (bytes as f64 / size as f64).min(1.0);
+                let filled: usize = (fraction * BAR_WIDTH as f64) as usize;
+
+                line += &format!("[{}{}] {:3.0}% {} / {}", "#".repeat(filled),
+                                 "-".repeat(BAR_WIDTH - filled),
+                                 fraction * 100.0, human(bytes as f64),
+                                 human(size as f64));
+            },
+            _ => line += &human(bytes as f64)
+        }
+
+        line += &format!("  {}/s\x1b[K", human(rate));
+
+        /* a progress bar is no reason to fail a transfer */
+        let mut stderr: io::Stderr = io::stderr();
+        let _ = stderr.write_all(line.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    /* clears the bar away, leaving the line free for what comes next */
+    pub fn finish(&mut self) {
+        if self.drawn.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/* a quantity of bytes in the largest unit it makes at least one of */
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value: f64 = bytes;
+    let mut unit: usize = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,319 @@
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ArgMatches;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use tracing_subscriber::filter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{Layer, Registry};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::log;
+use nettlesoup::msg::ReadWriteRequestMessageMode;
+use nettlesoup::options;
+use nettlesoup::stats::{Direction, TransferStats};
+
+use crate::{Outcome, Report};
+
+/*
+ * An interactive shell in the manner of the BSD tftp(1) client, entered when
+ * no subcommand is given. Settings from the command line carry over, and
+ * can be changed from the prompt; the server is chosen with `connect`.
+ * Commands are kept in a history file between sessions.
+ */
+
+const PROMPT: &str = "tftp> ";
+
+/* kept in the home directory */
+const HISTORY_FILE: &str = ".tftpclient_history";
+
+const HELP: &str = "\
+connect <host> [<port>]  choose the server to transfer with
+mode [<mode>]            show or set the transfer mode (ascii or binary)
+ascii                    transfer in netascii mode
+binary                   transfer in octet mode
+get <remote> [<local>]   download a file
+put <local> [<remote>]   upload a file
+verbose                  toggle printing full transfer summaries
+trace                    toggle printing every packet sent and received
+timeout <seconds>        set the retransmission timeout
+blksize [<bytes>|off]    show or set the block size to ask for
+windowsize [<n>|off]     show or set the window size to ask for
+tsize                    toggle exchanging the transfer size
+status                   show the current settings
+help                     show this summary
+quit                     leave the shell";
+
+/* whether packets are being traced, which the subscriber checks for each
+    event */
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+struct Shell {
+    client: Client,
+    host: Option<String>,   /* as given to connect, once it has been */
+    port: u16,
+    ipv4: bool,
+    ipv6: bool,
+    retries: usize,
+    verbose: bool
+}
+
+/* takes commands until told to quit or the input runs out */
+pub fn run(matches: &ArgMatches) {
+    let port: u16 = crate::port(matches, matches);
+
+    /* there's nobody to talk to until connected */
+    let mut client: Client = Client::new(
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+    crate::configure(&mut client, matches, matches);
+
+    let mut shell: Shell = Shell {
+        client,
+        host: None,
+        port,
+        ipv4: matches.is_present("ipv4"),
+        ipv6: matches.is_present("ipv6"),
+        retries: crate::retries(matches, matches),
+        verbose: false
+    };
+
+    TRACE.store(matches.is_present("trace"), Ordering::Relaxed);
+    trace_packets();
+
+    let mut editor: DefaultEditor = DefaultEditor::new()
+        .unwrap_or_else(|e| crate::fail(&e.to_string()));
+    let history: Option<PathBuf> = env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE));
+
+    if let Some(history) = &history {
+        /* there won't be one the first time */
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        match editor.readline(PROMPT) {
+            Ok(line) => {
+                let words: Vec<&str> = line.split_whitespace().collect();
+
+                if words.is_empty() {
+                    continue;
+                }
+
+                let _ = editor.add_history_entry(line.trim());
+
+                if !shell.execute(&words) {
+                    break;
+                }
+            },
+            /* ^C abandons the line, as it would in a shell */
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("tftpclient: {}", e);
+                break;
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+}
+
+impl Shell {
+    /* carries out a command, returning whether to carry on */
+    fn execute(&mut self, words: &[&str]) -> bool {
+        match words {
+            ["connect", host] | ["open", host] => self.connect(host, None),
+            ["connect", host, port] | ["open", host, port] =>
+                match port.parse() {
+                    Ok(port) => self.connect(host, Some(port)),
+                    Err(_) => println!("{}: bad port number", port)
+                },
+            ["mode"] => println!("Using {} mode to transfer files.",
+                                 ReadWriteRequestMessageMode::to_string(
+                                     self.client.mode())),
+            ["mode", mode] => self.set_mode(mode),
+            ["ascii"] => self.set_mode("netascii"),
+            ["binary"] => self.set_mode("octet"),
+            ["get", remote] => match crate::local_name(remote) {
+                Some(local) => self.get(remote, local),
+                None => println!("{}: can't derive a local name", remote)
+            },
+            ["get", remote, local] => self.get(remote, local),
+            ["put", local] => match crate::local_name(local) {
+                Some(remote) => self.put(local, remote),
+                None => println!("{}: can't derive a remote name", local)
+            },
+            ["put", local, remote] => self.put(local, remote),
+            ["verbose"] => {
+                self.verbose = !self.verbose;
+                println!("Verbose mode {}.", on_off(self.verbose));
+            },
+            ["trace"] => {
+                let trace: bool = !TRACE.load(Ordering::Relaxed);
+                TRACE.store(trace, Ordering::Relaxed);
+                println!("Packet tracing {}.", on_off(trace));
+            },
+            ["timeout", seconds] => match options::parse_timeout(seconds) {
+                Some(timeout) => self.client.set_timeout(timeout),
+                None => println!("{}: bad value (expected {} to {} seconds)",
+                                 seconds, options::MIN_TIMEOUT_SECS,
+                                 options::MAX_TIMEOUT_SECS)
+            },
+            ["blksize"] =>
+                println!("Block size: {}.", block_size(&self.client)),
+            ["blksize", "off"] => self.client.set_block_size(None),
+            ["blksize", size] => match options::parse_block_size(size) {
+                Some(size) => self.client.set_block_size(Some(size)),
+                None => println!("{}: bad value (expected {} to {})", size,
+                                 options::MIN_BLOCK_SIZE,
+                                 options::MAX_BLOCK_SIZE)
+            },
+            ["windowsize"] =>
+                println!("Window size: {}.", window_size(&self.client)),
+            ["windowsize", "off"] => self.client.set_window_size(None),
+            ["windowsize", size] => match options::parse_window_size(size) {
+                Some(size) => self.client.set_window_size(Some(size)),
+                None => println!("{}: bad value (expected {} to {})", size,
+                                 options::MIN_WINDOW_SIZE,
+                                 options::MAX_WINDOW_SIZE)
+            },
+            ["tsize"] => {
+                let tsize: bool = !self.client.request_tsize();
+                self.client.set_request_tsize(tsize);
+                println!("Transfer size option {}.", on_off(tsize));
+            },
+            ["status"] => self.status(),
+            ["help"] | ["?"] => println!("{}", HELP),
+            ["quit"] | ["exit"] | ["q"] => return false,
+            [command, ..] => println!("?Invalid command or arguments: {} \
+                                       (try help)", command),
+            [] => {}
+        }
+
+        true
+    }
+
+    fn connect(&mut self, host: &str, port: Option<u16>) {
+        let port: u16 = port.unwrap_or(self.port);
+
+        match crate::resolve(host, port, self.ipv4, self.ipv6) {
+            Some(server) => {
+                self.client.set_server(server);
+                self.host = Some(host.to_string());
+            },
+            None => println!("{}: unknown host", host)
+        }
+    }
+
+    fn set_mode(&mut self, mode: &str) {
+        let mode: ReadWriteRequestMessageMode = match mode {
+            "ascii" | "netascii" => ReadWriteRequestMessageMode::NetAscii,
+            "binary" | "octet" => ReadWriteRequestMessageMode::Octet,
+            _ => return println!("{}: unknown mode", mode)
+        };
+
+        self.client.set_mode(mode);
+    }
+
+    fn get(&self, remote: &str, local: &str) {
+        if self.connected() {
+            self.conclude(Direction::Read,
+                          crate::download(&self.client, remote, local,
+                                          Report::Normal, self.retries));
+        }
+    }
+
+    fn put(&self, local: &str, remote: &str) {
+        if self.connected() {
+            self.conclude(Direction::Write,
+                          crate::upload(&self.client, local, remote,
+                                        Report::Normal, self.retries));
+        }
+    }
+
+    fn connected(&self) -> bool {
+        if self.host.is_none() {
+            println!("No target machine specified.");
+        }
+
+        self.host.is_some()
+    }
+
+    fn conclude(&self, direction: Direction, outcome: Result<Outcome, String>) {
+        let stats: TransferStats = match outcome {
+            Ok((Ok(stats), _)) => stats,
+            Ok((Err(e), _)) => return println!("Transfer failed: {}", e),
+            Err(e) => return println!("{}", e)
+        };
+
+        if self.verbose {
+            println!("Completed {}", stats);
+        } else {
+            println!("{} {} bytes in {:.1} seconds",
+                     match direction {
+                         Direction::Read => "Received",
+                         Direction::Write => "Sent"
+                     },
+                     stats.bytes(), stats.duration().as_secs_f64());
+        }
+    }
+
+    fn status(&self) {
+        match &self.host {
+            Some(host) => println!("Connected to {} ({}).", host,
+                                   self.client.server()),
+            None => println!("Not connected.")
+        }
+
+        println!("Mode: {}  Verbose: {}  Tracing: {}",
+                 ReadWriteRequestMessageMode::to_string(self.client.mode()),
+                 on_off(self.verbose), on_off(TRACE.load(Ordering::Relaxed)));
+        println!("Timeout: {} seconds  Retransmissions: {}  Restarts: {}",
+                 self.client.timeout().as_secs(), self.client.retries(),
+                 self.retries);
+        println!("Block size: {}  Window size: {}  Transfer size option: {}",
+                 block_size(&self.client), window_size(&self.client),
+                 on_off(self.client.request_tsize()));
+    }
+}
+
+/* prints the library's packet records to standard error, while TRACE is
+    set (which is checked every time, rather than once per callsite) */
+fn trace_packets() {
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(false)
+        .with_level(false)
+        .with_target(false)
+        .with_filter(filter::dynamic_filter_fn(|metadata, _| {
+            metadata.target() == log::PACKET && TRACE.load(Ordering::Relaxed)
+        }));
+
+    /* nothing else will have set one up */
+    let _ = tracing::subscriber::set_global_default(
+        Registry::default().with(layer));
+}
+
+fn block_size(client: &Client) -> String {
+    match client.block_size() {
+        Some(size) => size.to_string(),
+        None => format!("{} (not negotiated)", options::DEFAULT_BLOCK_SIZE)
+    }
+}
+
+fn window_size(client: &Client) -> String {
+    match client.window_size() {
+        Some(size) => size.to_string(),
+        None => format!("{} (not negotiated)", options::DEFAULT_WINDOW_SIZE)
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
@@ -0,0 +1,615 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::conn::{Connection, Event, Output, State, Transfer, TransferError};
+use crate::log;
+use crate::msg::{self, AcknowledgementMessage, AnyMessage,
+                 DataMessageBlockNumber, ErrorMessage, OptionList,
+                 ReadRequestMessage, ReadWriteRequestMessageMode,
+                 WriteRequestMessage};
+use crate::netascii::{NetAsciiDecoder, NetAsciiReader};
+use crate::options::{self, MulticastGroup};
+use crate::stats::{Direction, TransferStats};
+use crate::throttle::Throttle;
+use crate::xfer::{self, RECV_BUFFER_SIZE};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RETRIES: usize = 5;
+
+/* how often a multicast receiver switches between its two sockets */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    server: SocketAddr,
+    mode: ReadWriteRequestMessageMode,
+    timeout: Duration,
+    retries: usize,
+    block_size: Option<usize>,      /* requested via RFC 2348, if at all */
+    window_size: Option<usize>,     /* requested via RFC 7440, if at all */
+    request_timeout: bool,          /* ask the server to use our timeout */
+    request_tsize: bool,
+    multicast: bool,
+    bandwidth: Option<u64>          /* bytes per second, if limited */
+}
+
+impl Client {
+    pub fn new(server: SocketAddr) -> Self {
+        Client {
+            server,
+            mode: ReadWriteRequestMessageMode::Octet,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            block_size: None,
+            window_size: None,
+            request_timeout: false,
+            request_tsize: false,
+            multicast: false,
+            bandwidth: None
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn mode(&self) -> ReadWriteRequestMessageMode {
+        self.mode
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    pub fn block_size(&self) -> Option<usize> {
+        self.block_size
+    }
+
+    pub fn window_size(&self) -> Option<usize> {
+        self.window_size
+    }
+
+    pub fn request_timeout(&self) -> bool {
+        self.request_timeout
+    }
+
+    pub fn request_tsize(&self) -> bool {
+        self.request_tsize
+    }
+
+    pub fn multicast(&self) -> bool {
+        self.multicast
+    }
+
+    pub fn bandwidth(&self) -> Option<u64> {
+        self.bandwidth
+    }
+
+    pub fn set_server(&mut self, server: SocketAddr) {
+        self.server = server;
+    }
+
+    pub fn set_mode(&mut self, mode: ReadWriteRequestMessageMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    pub fn set_block_size(&mut self, block_size: Option<usize>) {
+        self.block_size = block_size;
+    }
+
+    pub fn set_window_size(&mut self, window_size: Option<usize>) {
+        self.window_size = window_size;
+    }
+
+    pub fn set_request_timeout(&mut self, request_timeout: bool) {
+        self.request_timeout = request_timeout;
+    }
+
+    pub fn set_request_tsize(&mut self, request_tsize: bool) {
+        self.request_tsize = request_tsize;
+    }
+
+    pub fn set_multicast(&mut self, multicast: bool) {
+        self.multicast = multicast;
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: Option<u64>) {
+        self.bandwidth = bandwidth;
+    }
+
+    /* downloads the named file, returning a summary of the transfer */
+    pub fn get<W: Write + Seek>(&self, filename: &str, output: &mut W) ->
+        Result<TransferStats, TransferError> {
+        self.get_with_progress(filename, output, &mut |_, _| {})
+    }
+
+    /* as for `get`, reporting the bytes received after each block, along
+        with the size of the file if the server gave it */
+    pub fn get_with_progress<W: Write + Seek>(
+        &self, filename: &str, output: &mut W,
+        progress: &mut dyn FnMut(u64, Option<u64>)) ->
+        Result<TransferStats, TransferError> {
+        let started: Instant = Instant::now();
+        let socket: UdpSocket = self.socket()?;
+        let mut requested: OptionList = self.options(Some(0));
+
+        /* multicast transfers arrive out of order, which only makes sense
+            for files that are stored as they're sent */
+        if self.multicast && self.mode == ReadWriteRequestMessageMode::Octet {
+            requested.push((options::MULTICAST.to_string(), String::new()));
+        }
+
+        let request: AnyMessage = AnyMessage::Rrq(
+            ReadRequestMessage::with_options(filename.to_string(), self.mode,
+                                             requested));
+
+        let (reply, peer): (AnyMessage, SocketAddr) =
+            self.request(&socket, &request)?;
+        let mut conn: Connection = Connection::new(socket.local_addr()?, peer);
+        let mut transfer: Transfer = Transfer::request(&request, self.retries);
+        let mut stats: TransferStats =
+            TransferStats::new(peer, filename.to_string(), Direction::Read,
+                               self.mode);
+        conn.add_msg(request);
+
+        /* the file is ours once it's all here, and whoever asked for it
+            needn't wait on the chance that the server missed our last ACK;
+            a server that did gives up on its own */
+        transfer.set_dally(0);
+
+        let result: Result<u64, TransferError> =
+            self.start(&socket, &mut conn, &mut transfer, reply)
+                .and_then(|outputs| {
+                    if let Some(group) = transfer.options().multicast() {
+                        /* multicast transfers are beyond the state machine,
+                            and don't begin with the ACK it would send */
+                        stats.set_options(transfer.options().clone());
+
+                        return receive_multicast(
+                            &socket, peer, group, output,
+                            transfer.options().timeout()
+                                .unwrap_or(self.timeout),
+                            self.retries, &mut stats);
+                    }
+
+                    let mut decoder: Option<NetAsciiDecoder> = match self.mode {
+                        ReadWriteRequestMessageMode::NetAscii =>
+                            Some(NetAsciiDecoder::new()),
+                        _ => None
+                    };
+
+                    let size: Option<u64> =
+                        transfer.options().transfer_size();
+
+                    let result: Result<u64, TransferError> =
+                        xfer::receive_file(&socket, &mut conn, &mut transfer,
+                                           outputs, output, &mut decoder,
+                                           &mut |received| {
+                                               progress(received, size);
+                                               Ok(())
+                                           },
+                                           &mut |output| output.flush()
+                                               .map_err(xfer::reject_io),
+                                           &self.throttle());
+                    stats.record(&transfer);
+                    result
+                });
+
+        reject(&socket, peer, result)?;
+        stats.set_duration(started.elapsed());
+
+        Ok(stats)
+    }
+
+    /* uploads the contents of the reader under the given name, returning a
+        summary of the transfer; `size` is offered to the server as tsize */
+    pub fn put<R: Read>(&self, filename: &str, input: R, size: Option<u64>) ->
+        Result<TransferStats, TransferError> {
+        self.put_with_progress(filename, input, size, &mut |_, _| {})
+    }
+
+    /* as for `put`, reporting the bytes of the input sent so far, along with
+        `size` */
+    pub fn put_with_progress<R: Read>(
+        &self, filename: &str, input: R, size: Option<u64>,
+        progress: &mut dyn FnMut(u64, Option<u64>)) ->
+        Result<TransferStats, TransferError> {
+        let started: Instant = Instant::now();
+        let input: Counted<R> = Counted {
+            inner: input,
+            count: 0,
+            size,
+            progress
+        };
+        let socket: UdpSocket = self.socket()?;
+
+        /* the size of a netascii transfer isn't known until it's been
+            converted */
+        let size: Option<u64> = match self.mode {
+            ReadWriteRequestMessageMode::Octet => size,
+            _ => None
+        };
+
+        let request: AnyMessage = AnyMessage::Wrq(
+            WriteRequestMessage::with_options(filename.to_string(), self.mode,
+                                              self.options(size)));
+
+        let (reply, peer): (AnyMessage, SocketAddr) =
+            self.request(&socket, &request)?;
+        let mut conn: Connection = Connection::new(socket.local_addr()?, peer);
+        let mut transfer: Transfer = Transfer::request(&request, self.retries);
+        let mut stats: TransferStats =
+            TransferStats::new(peer, filename.to_string(), Direction::Write,
+                               self.mode);
+        conn.add_msg(request);
+
+        let mut reader: Box<dyn Read> = match self.mode {
+            ReadWriteRequestMessageMode::NetAscii =>
+                Box::new(NetAsciiReader::new(input)),
+            _ => Box::new(input)
+        };
+
+        let result: Result<u64, TransferError> =
+            self.start(&socket, &mut conn, &mut transfer, reply)
+                .and_then(|outputs| {
+                    xfer::send_file(&socket, &mut conn, &mut transfer, outputs,
+                                    &mut reader, &self.throttle())
+                });
+        stats.record(&transfer);
+
+        reject(&socket, peer, result)?;
+        stats.set_duration(started.elapsed());
+
+        Ok(stats)
+    }
+
+    fn throttle(&self) -> Throttle {
+        match self.bandwidth {
+            Some(rate) => Throttle::with_rate(rate),
+            None => Throttle::new()
+        }
+    }
+
+    /* a socket of the same address family as the server, on an ephemeral
+        port (our TID) */
+    fn socket(&self) -> io::Result<UdpSocket> {
+        let socket: UdpSocket =
+            UdpSocket::bind(SocketAddr::new(unspecified(self.server), 0))?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        Ok(socket)
+    }
+
+    /* the options to send with a request; tsize carries `size` */
+    fn options(&self, size: Option<u64>) -> OptionList {
+        let mut requested: OptionList = Vec::new();
+
+        if let Some(block_size) = self.block_size {
+            requested.push((options::BLKSIZE.to_string(),
+                            block_size.to_string()));
+        }
+
+        if let Some(window_size) = self.window_size {
+            requested.push((options::WINDOWSIZE.to_string(),
+                            window_size.to_string()));
+        }
+
+        if self.request_timeout {
+            requested.push((options::TIMEOUT.to_string(),
+                            self.timeout.as_secs().to_string()));
+        }
+
+        if let (true, Some(size)) = (self.request_tsize, size) {
+            requested.push((options::TSIZE.to_string(), size.to_string()));
+        }
+
+        requested
+    }
+
+    /* sends a request to the server's well-known port, returning the first
+        reply along with the TID it came from */
+    fn request(&self, socket: &UdpSocket, request: &AnyMessage) ->
+        Result<(AnyMessage, SocketAddr), TransferError> {
+        let bytes: Vec<u8> = request.to_bytes();
+        let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+        let mut attempts: usize = 0;
+
+        socket.send_to(&bytes, self.server)?;
+        log::packet(log::Flow::Sent, socket, self.server, &bytes);
+        let mut due: Instant = Instant::now() + self.timeout;
+
+        let result: Result<(AnyMessage, SocketAddr), TransferError> = loop {
+            /* datagrams from elsewhere don't put off the retransmission */
+            let now: Instant = Instant::now();
+            let received: io::Result<(usize, SocketAddr)> = if now < due {
+                socket.set_read_timeout(Some(due - now))?;
+                socket.recv_from(&mut buf)
+            } else {
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            };
+
+            match received {
+                /* replies come from a new port, but ought to come from the
+                    host we asked */
+                Ok((len, from)) if from.ip() == self.server.ip() => {
+                    log::packet(log::Flow::Received, socket, from, &buf[..len]);
+
+                    if let Ok(reply) = AnyMessage::from_bytes(
+                        buf[..len].to_vec()) {
+                        break Ok((reply, from));
+                    }
+                },
+                Ok((len, from)) => log::packet(log::Flow::Received, socket,
+                                               from, &buf[..len]),
+                Err(e) if xfer::is_timeout(&e) => {
+                    attempts += 1;
+
+                    if attempts > self.retries {
+                        break Err(TransferError::TimedOut);
+                    }
+
+                    socket.send_to(&bytes, self.server)?;
+                    log::packet(log::Flow::Resent, socket, self.server, &bytes);
+                    due = Instant::now() + self.timeout;
+                },
+                Err(e) => break Err(TransferError::Io(e))
+            }
+        };
+
+        socket.set_read_timeout(Some(self.timeout))?;
+        result
+    }
+
+    /* feeds the server's first reply to the transfer, adopting any options
+        it acknowledged */
+    fn start(&self, socket: &UdpSocket, conn: &mut Connection,
+             transfer: &mut Transfer, reply: AnyMessage) ->
+        Result<Vec<Output>, TransferError> {
+        conn.add_msg(reply.clone());
+
+        let outputs: Vec<Output> = transfer.handle(Event::Received(reply))?;
+
+        if transfer.state() == State::AwaitingOack {
+            return Err(TransferError::Rejected(msg::ERROR_ILLEGAL_OPERATION,
+                                               "Unexpected reply".to_string()));
+        }
+
+        if let Some(timeout) = transfer.options().timeout() {
+            socket.set_read_timeout(Some(timeout))?;
+        }
+
+        Ok(outputs)
+    }
+}
+
+/* passes reads through, reporting the running total as it goes */
+struct Counted<'a, R> {
+    inner: R,
+    count: u64,
+    size: Option<u64>,
+    progress: &'a mut dyn FnMut(u64, Option<u64>)
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read: usize = self.inner.read(buf)?;
+        self.count += read as u64;
+        (self.progress)(self.count, self.size);
+        Ok(read)
+    }
+}
+
+/* the wildcard address of the same family as the given one */
+fn unspecified(addr: SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    }
+}
+
+/* tells the server why we're abandoning a transfer, if it was our decision */
+fn reject(socket: &UdpSocket, peer: SocketAddr,
+          result: Result<u64, TransferError>) -> Result<u64, TransferError> {
+    if let Err(TransferError::Rejected(code, message)) = &result {
+        let error: ErrorMessage = ErrorMessage::new(*code, message.clone());
+        let bytes: Vec<u8> = AnyMessage::Error(error).to_bytes();
+
+        if socket.send_to(&bytes, peer).is_ok() {
+            log::packet(log::Flow::Sent, socket, peer, &bytes);
+        }
+    }
+
+    result
+}
+
+/*
+ * Receives a file from a multicast session (RFC 2090).
+ *
+ * Blocks arrive on the group in whatever order the current master asks for
+ * them and are written into place as they come. While we are master we
+ * acknowledge the highest block below which we have everything, which tells
+ * the server what to send next; once we hold every block we acknowledge the
+ * last one, which takes us out of the session.
+ */
+fn receive_multicast<W: Write + Seek>(socket: &UdpSocket, peer: SocketAddr,
+                                      mut group: MulticastGroup,
+                                      output: &mut W, timeout: Duration,
+                                      retries: usize,
+                                      stats: &mut TransferStats) ->
+    Result<u64, TransferError> {
+    let block_size: usize = stats.options().block_size();
+    let listener: UdpSocket = join_group(peer, group.group())?;
+    listener.set_read_timeout(Some(POLL_INTERVAL))?;
+    socket.set_nonblocking(true)?;
+
+    let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+    let mut received: Vec<bool> = Vec::new();
+    let mut last: Option<DataMessageBlockNumber> = None;
+    let mut written: u64 = 0;
+    let mut attempts: usize = 0;
+    let mut deadline: Instant = Instant::now();
+    let mut acked: Option<DataMessageBlockNumber> = None;
+
+    loop {
+        let mut blocks: Vec<(DataMessageBlockNumber, Vec<u8>)> = Vec::new();
+
+        match listener.recv_from(&mut buf) {
+            Ok((len, from)) if from.ip() == peer.ip() => {
+                log::packet(log::Flow::Received, &listener, from, &buf[..len]);
+
+                if let Ok(AnyMessage::Data(data)) =
+                    AnyMessage::from_bytes(buf[..len].to_vec()) {
+                    blocks.push((data.block_num(), data.data()));
+                }
+            },
+            Ok(_) => {},
+            Err(e) if xfer::is_timeout(&e) => {},
+            Err(e) => return Err(TransferError::Io(e))
+        }
+
+        /* the server's unicast messages: promotions to master and errors */
+        loop {
+            match xfer::recv_message(socket, peer, &mut buf) {
+                Ok(Some(AnyMessage::Oack(oack))) => {
+                    if let Some(value) = options::find(&oack.options(),
+                                                       options::MULTICAST) {
+                        if let Ok(update) = value.parse::<MulticastGroup>() {
+                            group = update;
+                            acked = None;
+                        }
+                    }
+                },
+                Ok(Some(AnyMessage::Data(data))) =>
+                    blocks.push((data.block_num(), data.data())),
+                Ok(Some(AnyMessage::Error(e))) =>
+                    return Err(TransferError::Aborted(e)),
+                Ok(_) => continue,
+                Err(e) if xfer::is_timeout(&e) => break,
+                Err(e) => return Err(TransferError::Io(e))
+            }
+        }
+
+        for (block, data) in blocks {
+            if block == 0 {
+                continue;
+            }
+
+            let index: usize = block as usize - 1;
+
+            if received.len() <= index {
+                received.resize(index + 1, false);
+            }
+
+            if data.len() < block_size {
+                last = Some(block);
+            }
+
+            if received[index] {
+                stats.set_duplicates(stats.duplicates() + 1);
+            } else {
+                output.seek(SeekFrom::Start(index as u64 * block_size as u64))?;
+                output.write_all(&data).map_err(xfer::reject_io)?;
+                received[index] = true;
+                written += data.len() as u64;
+
+                stats.set_bytes(written);
+                stats.set_blocks(stats.blocks() + 1);
+            }
+
+            attempts = 0;
+            deadline = Instant::now() + timeout;
+        }
+
+        /* the highest block below which nothing is missing */
+        let contiguous: usize = received.iter().take_while(|got| **got)
+            .count();
+
+        if let Some(last) = last {
+            if contiguous >= last as usize {
+                let bytes: Vec<u8> = AnyMessage::Ack(
+                    AcknowledgementMessage::new(last)).to_bytes();
+                socket.send_to(&bytes, peer)?;
+                log::packet(log::Flow::Sent, socket, peer, &bytes);
+                socket.set_nonblocking(false)?;
+
+                return Ok(written);
+            }
+        }
+
+        let now: Instant = Instant::now();
+
+        if group.master() &&
+            (acked != Some(contiguous as DataMessageBlockNumber) ||
+             now >= deadline) {
+            if now >= deadline && acked.is_some() {
+                attempts += 1;
+                stats.set_retransmissions(stats.retransmissions() + 1);
+            }
+
+            let bytes: Vec<u8> = AnyMessage::Ack(AcknowledgementMessage::new(
+                contiguous as DataMessageBlockNumber)).to_bytes();
+            socket.send_to(&bytes, peer)?;
+            log::packet(log::Flow::Sent, socket, peer, &bytes);
+            acked = Some(contiguous as DataMessageBlockNumber);
+            deadline = now + timeout;
+        } else if !group.master() && now >= deadline {
+            /* the wait for a turn as master is open-ended, but a silent
+                group is not */
+            attempts += 1;
+            deadline = now + timeout;
+        }
+
+        if attempts > retries {
+            return Err(TransferError::TimedOut);
+        }
+    }
+}
+
+/* opens a socket on the group's port and joins the group on the interface
+    used to reach the server */
+fn join_group(server: SocketAddr, group: SocketAddr) ->
+    io::Result<UdpSocket> {
+    /* connecting a throwaway socket is the simplest way to learn which local
+        address the route to the server goes by */
+    let probe: UdpSocket =
+        UdpSocket::bind(SocketAddr::new(unspecified(server), 0))?;
+    probe.connect(server)?;
+    let local: IpAddr = probe.local_addr()?.ip();
+
+    let domain: Domain = match group {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6()
+    };
+
+    let listener: Socket = Socket::new(domain, Type::dgram(),
+                                       Some(Protocol::udp()))?;
+
+    /* other clients on this host may be listening to the same group */
+    listener.set_reuse_address(true)?;
+    listener.bind(&SocketAddr::new(unspecified(group), group.port()).into())?;
+
+    match (group.ip(), local) {
+        (IpAddr::V4(group), IpAddr::V4(local)) =>
+            listener.join_multicast_v4(&group, &local)?,
+        (IpAddr::V6(group), _) => listener.join_multicast_v6(&group, 0)?,
+        (IpAddr::V4(group), IpAddr::V6(_)) =>
+            listener.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?
+    }
+
+    Ok(listener.into_udp_socket())
+}
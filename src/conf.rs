@@ -0,0 +1,423 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use thiserror::Error;
+use toml::Spanned;
+
+/*
+ * The server configuration file.
+ *
+ * The file is TOML, and its keys are named after the command line flags they
+ * stand in for:
+ *
+ *     root = "/srv/tftp"
+ *     listen = ["0.0.0.0", "[::]"]
+ *     port = 69
+ *     acl = ["allow rrq any", "allow wrq 192.0.2.0/24 uploads/"]
+ *     write-mode = "create"
+ *     max-file-size = "64M"
+ *
+ *     [log]
+ *     level = "info"
+ *     sink = "syslog"
+ *     trace = false
+ *
+ * Switches, such as `fsync` or `verbose`, are set by giving them as true.
+ * Values are kept as they were written, along with where they were written,
+ * so that they can be checked by the same code as the flags and any problem
+ * reported against the line it's on.
+ */
+
+#[derive(Debug, Error)]
+pub enum ConfError {
+    Io(PathBuf, io::Error),
+    Syntax(PathBuf, toml::de::Error),
+    Invalid(PathBuf, usize, String)     /* the line at fault */
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfError::Syntax(path, e) =>
+                write!(f, "{}: {}", path.display(), e),
+            ConfError::Invalid(path, line, message) =>
+                write!(f, "{}: line {}: {}", path.display(), line, message)
+        }
+    }
+}
+
+/* a single value, as written; numbers and booleans are taken as their text */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting(String);
+
+impl Setting {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Setting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) ->
+        Result<Self, D::Error> {
+        struct SettingVisitor;
+
+        impl<'de> Visitor<'de> for SettingVisitor {
+            type Value = Setting;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string, number or boolean")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) ->
+                Result<Setting, E> {
+                Ok(Setting(value.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) ->
+                Result<Setting, E> {
+                Ok(Setting(value.to_string()))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) ->
+                Result<Setting, E> {
+                Ok(Setting(value.to_string()))
+            }
+
+            fn visit_bool<E: de::Error>(self, value: bool) ->
+                Result<Setting, E> {
+                Ok(Setting(value.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(SettingVisitor)
+    }
+}
+
+type Value = Option<Spanned<Setting>>;
+type Values = Option<Vec<Spanned<Setting>>>;
+
+/* the keys of the [log] table */
+const LOG_KEYS: &[&str] = &["level", "format", "sink", "access", "verbose",
+                            "trace"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSection {
+    level: Value,
+    format: Value,
+    sink: Value,
+    access: Value,
+    verbose: Value,
+    trace: Value
+}
+
+/* the keys outside any table */
+const KEYS: &[&str] = &["root", "chroot", "inetd", "idle-timeout", "user",
+                        "group", "listen", "port", "map-file", "acl",
+                        "write-mode", "max-file-size", "quota", "fsync",
+                        "max-blksize", "max-windowsize", "multicast",
+                        "multicast-ttl",
+                        "rate-limit", "max-client-transfers",
+                        "max-transfers", "verify-client",
+                        "transfer-bandwidth", "subnet-bandwidth",
+                        "bandwidth", "subnet-prefix", "metrics",
+                        "shutdown-timeout", "capture", "log"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Settings {
+    root: Value,
+    chroot: Value,
+    inetd: Value,
+    idle_timeout: Value,
+    user: Value,
+    group: Value,
+    listen: Values,
+    port: Value,
+    map_file: Value,
+    acl: Values,
+    write_mode: Value,
+    max_file_size: Value,
+    quota: Value,
+    fsync: Value,
+    max_blksize: Value,
+    max_windowsize: Value,
+    multicast: Value,
+    multicast_ttl: Value,
+    rate_limit: Value,
+    max_client_transfers: Value,
+    max_transfers: Value,
+    verify_client: Value,
+    transfer_bandwidth: Value,
+    subnet_bandwidth: Value,
+    bandwidth: Value,
+    subnet_prefix: Value,
+    metrics: Value,
+    shutdown_timeout: Value,
+    capture: Value,
+    #[serde(default)]
+    log: LogSection
+}
+
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: PathBuf,
+    source: String,
+    settings: Settings
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfError> {
+        let source: String = fs::read_to_string(path)
+            .map_err(|e| ConfError::Io(path.to_path_buf(), e))?;
+
+        ConfigFile::parse(path, source)
+    }
+
+    /* `path` is only used to report errors */
+    pub fn parse(path: &Path, source: String) -> Result<Self, ConfError> {
+        let settings: Settings = match toml::from_str(&source) {
+            Ok(settings) => settings,
+            Err(e) => return Err(match unknown_key(&source, &e) {
+                Some((line, key)) => ConfError::Invalid(
+                    path.to_path_buf(), line,
+                    format!("Unknown setting '{}'", key)),
+                None => ConfError::Syntax(path.to_path_buf(), e)
+            })
+        };
+
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            source,
+            settings
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /* the value given for the setting named after a command line flag */
+    pub fn get(&self, name: &str) -> Option<&Spanned<Setting>> {
+        let settings: &Settings = &self.settings;
+
+        match name {
+            "root" => settings.root.as_ref(),
+            "chroot" => settings.chroot.as_ref(),
+            "inetd" => settings.inetd.as_ref(),
+            "idle-timeout" => settings.idle_timeout.as_ref(),
+            "user" => settings.user.as_ref(),
+            "group" => settings.group.as_ref(),
+            "port" => settings.port.as_ref(),
+            "map-file" => settings.map_file.as_ref(),
+            "write-mode" => settings.write_mode.as_ref(),
+            "max-file-size" => settings.max_file_size.as_ref(),
+            "quota" => settings.quota.as_ref(),
+            "fsync" => settings.fsync.as_ref(),
+            "max-blksize" => settings.max_blksize.as_ref(),
+            "max-windowsize" => settings.max_windowsize.as_ref(),
+            "multicast" => settings.multicast.as_ref(),
+            "multicast-ttl" => settings.multicast_ttl.as_ref(),
+            "rate-limit" => settings.rate_limit.as_ref(),
+            "max-client-transfers" =>
+                settings.max_client_transfers.as_ref(),
+            "max-transfers" => settings.max_transfers.as_ref(),
+            "verify-client" => settings.verify_client.as_ref(),
+            "transfer-bandwidth" => settings.transfer_bandwidth.as_ref(),
+            "subnet-bandwidth" => settings.subnet_bandwidth.as_ref(),
+            "bandwidth" => settings.bandwidth.as_ref(),
+            "subnet-prefix" => settings.subnet_prefix.as_ref(),
+            "metrics" => settings.metrics.as_ref(),
+            "shutdown-timeout" => settings.shutdown_timeout.as_ref(),
+            "capture" => settings.capture.as_ref(),
+            "log" => settings.log.sink.as_ref(),
+            "log-format" => settings.log.format.as_ref(),
+            "log-level" => settings.log.level.as_ref(),
+            "access-log" => settings.log.access.as_ref(),
+            "verbose" => settings.log.verbose.as_ref(),
+            "trace" => settings.log.trace.as_ref(),
+            _ => None
+        }
+    }
+
+    /* the values given for a setting that may be given more than once */
+    pub fn get_all(&self, name: &str) -> Option<&[Spanned<Setting>]> {
+        match name {
+            "listen" => self.settings.listen.as_deref(),
+            "acl" => self.settings.acl.as_deref(),
+            _ => None
+        }
+    }
+
+    /* the line a value was written on, counting from one */
+    pub fn line<T>(&self, value: &Spanned<T>) -> usize {
+        let start: usize = value.start().min(self.source.len());
+        self.source[..start].matches('\n').count() + 1
+    }
+
+    /* an error in a value, reported against the line it's on */
+    pub fn invalid<T>(&self, value: &Spanned<T>, message: &str) -> ConfError {
+        ConfError::Invalid(self.path.clone(), self.line(value),
+                           message.to_string())
+    }
+}
+
+/* the line and name of the key behind an unknown field error; the parser
+    places the error at the start of the table the key is in, so the key is
+    the first in that table we don't know */
+fn unknown_key(source: &str, e: &toml::de::Error) -> Option<(usize, String)> {
+    let (start, _): (usize, usize) = e.line_col()?;
+    let mut known: &[&str] = KEYS;
+
+    for (index, line) in source.lines().enumerate().skip(start) {
+        let line: &str = line.trim();
+
+        if let Some(header) = line.strip_prefix('[') {
+            /* the table after this one is none of our concern */
+            if index > start {
+                break;
+            }
+
+            let name: &str = header.trim_end_matches(']').trim();
+
+            match name {
+                "log" => known = LOG_KEYS,
+                _ => return Some((index + 1, name.to_string()))
+            }
+
+            continue;
+        }
+
+        /* a line may equally be a comment or part of a value, neither of
+            which starts with a bare key */
+        let key: &str = match line.split_once('=') {
+            Some((key, _)) => key.trim().split('.').next()?.trim(),
+            None => continue
+        };
+
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() ||
+                                                 c == '-' || c == '_') {
+            continue;
+        }
+
+        if !known.contains(&key) {
+            return Some((index + 1, key.to_string()));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<ConfigFile, ConfError> {
+        ConfigFile::parse(Path::new("tftpd.toml"), source.to_string())
+    }
+
+    /* the line and message of a setting reported as invalid */
+    fn invalid(source: &str) -> (usize, String) {
+        match parse(source) {
+            Err(ConfError::Invalid(_, line, message)) => (line, message),
+            other => panic!("expected an invalid setting, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn keeps_values_as_written_along_with_their_lines() {
+        let file: ConfigFile = parse("\
+# where the files are
+root = \"/srv/tftp\"
+port = 69
+fsync = true
+acl = [
+    \"allow rrq any\",
+    \"allow wrq 192.0.2.0/24 uploads/\"
+]
+
+[log]
+level = \"info\"
+verbose = true
+trace = false
+").unwrap();
+
+        let root: &Spanned<Setting> = file.get("root").unwrap();
+        assert_eq!(root.get_ref().as_str(), "/srv/tftp");
+        assert_eq!(file.line(root), 2);
+        assert_eq!(file.get("port").unwrap().get_ref().as_str(), "69");
+        assert_eq!(file.get("fsync").unwrap().get_ref().as_str(), "true");
+
+        let acl: Vec<&str> = file.get_all("acl").unwrap().iter()
+            .map(|rule| rule.get_ref().as_str())
+            .collect();
+        assert_eq!(acl, vec!["allow rrq any",
+                             "allow wrq 192.0.2.0/24 uploads/"]);
+        assert_eq!(file.line(&file.get_all("acl").unwrap()[1]), 7);
+
+        let level: &Spanned<Setting> = file.get("log-level").unwrap();
+        assert_eq!(level.get_ref().as_str(), "info");
+        assert_eq!(file.line(level), 11);
+        assert_eq!(file.get("verbose").unwrap().get_ref().as_str(), "true");
+        assert_eq!(file.get("trace").unwrap().get_ref().as_str(), "false");
+
+        assert!(file.get("chroot").is_none());
+        assert!(file.get_all("listen").is_none());
+    }
+
+    #[test]
+    fn knows_every_setting_it_accepts() {
+        for key in KEYS.iter().filter(|key| **key != "log") {
+            let source: String = match key {
+                &"listen" | &"acl" => format!("{} = [\"x\"]", key),
+                _ => format!("{} = \"x\"", key)
+            };
+            let file: ConfigFile = parse(&source).unwrap();
+
+            assert!(file.get(key).is_some() || file.get_all(key).is_some(),
+                    "{}", key);
+        }
+
+        for key in LOG_KEYS {
+            parse(&format!("[log]\n{} = \"x\"", key)).unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_unknown_settings_against_their_lines() {
+        assert_eq!(invalid("root = \"/srv\"\n\n# a comment = 1\nbogus = 1\n"),
+                   (4, "Unknown setting 'bogus'".to_string()));
+        assert_eq!(invalid("root = \"/srv\"\n\n[log]\nlevel = \"info\"\n\
+                            bogus = 1\n"),
+                   (5, "Unknown setting 'bogus'".to_string()));
+
+        /* a key known elsewhere is no help */
+        assert_eq!(invalid("[log]\nroot = \"/srv\"\n"),
+                   (2, "Unknown setting 'root'".to_string()));
+        assert_eq!(invalid("[log]\nlevel = \"info\"\n\n[bogus]\nroot = 1\n"),
+                   (4, "Unknown setting 'bogus'".to_string()));
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        let e: ConfError = parse("root = \"/srv\"\nport = [69\n").unwrap_err();
+
+        assert!(matches!(e, ConfError::Syntax(_, _)));
+        assert!(e.to_string().starts_with("tftpd.toml: "), "{}", e);
+    }
+
+    #[test]
+    fn reports_invalid_values_against_their_lines() {
+        let file: ConfigFile = parse("root = \"/srv\"\nport = \"x\"\n")
+            .unwrap();
+        let e: ConfError = file.invalid(file.get("port").unwrap(),
+                                        "Invalid port: x");
+
+        assert_eq!(e.to_string(), "tftpd.toml: line 2: Invalid port: x");
+    }
+}
@@ -1,35 +1,52 @@
 #![allow(dead_code)]
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
 
 use crate::msg;
+use crate::options::TransferOptions;
 
 pub type TID = u16;
 pub type SequenceNumber = u16;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Connection {
-    local_tid: TID,                         /* local TID (source port) */
-    remote_tid: TID,                        /* remote TID (destination port) */
+    local: SocketAddr,                      /* local TID (source port) */
+    remote: SocketAddr,                     /* remote TID (destination port) */
     curr_seq: SequenceNumber,               /* current sequence number */
     last_msg: Option<msg::AnyMessage>,      /* latest message */
 }
 
 impl Connection {
-    pub fn new(local_tid: TID, remote_tid: TID) -> Self {
+    /* a TID is only unique to its host, so each is kept alongside the address
+        it belongs to */
+    pub fn new(local: SocketAddr, remote: SocketAddr) -> Self {
         Connection {
-            local_tid: local_tid,
-            remote_tid: remote_tid,
+            local,
+            remote,
             curr_seq: 0,
             last_msg: None
         }
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
     pub fn local_tid(&self) -> TID {
-        self.local_tid
+        self.local.port()
     }
 
     pub fn remote_tid(&self) -> TID {
-        self.remote_tid
+        self.remote.port()
     }
 
     pub fn curr_seq(&self) -> SequenceNumber {
@@ -42,7 +59,982 @@ impl Connection {
 
     pub fn add_msg(&mut self, message: msg::AnyMessage) {
         self.last_msg = Some(message);
-        self.curr_seq += 1;
+        self.curr_seq = self.curr_seq.wrapping_add(1);
+    }
+}
+
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    Io(io::Error),
+    TimedOut,
+    Rejected(msg::ErrorMessageCode, String),   /* we refused the transfer */
+    Aborted(msg::ErrorMessage)                  /* the peer refused it */
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "I/O error: {}", e),
+            TransferError::TimedOut => write!(f, "Timed out waiting for peer"),
+            TransferError::Rejected(code, message) =>
+                write!(f, "Rejected request (error {}: {})", code, message),
+            TransferError::Aborted(e) =>
+                write!(f, "Peer aborted transfer (error {}: {})", e.code(),
+                       e.message())
+        }
+    }
+}
+
+impl TransferError {
+    /* whether the same transfer might succeed if tried again: the network
+        failed or the peer went quiet, or the peer refused without giving a
+        more specific reason than "not defined" (which is how passing
+        troubles such as being too busy are reported) */
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransferError::Io(_) | TransferError::TimedOut => true,
+            TransferError::Rejected(..) => false,
+            TransferError::Aborted(e) => e.code() == msg::ERROR_NOT_DEFINED
+        }
     }
 }
 
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+/* how many timeouts a receiver dallies for: the peer only resends the last
+    block once its own timeout lapses, which needn't be any shorter than
+    ours, and that resend may go astray in turn */
+pub const DALLY_TIMEOUTS: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Reader,     /* reads the file locally and sends it to the peer */
+    Writer      /* receives the file from the peer and writes it locally */
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    AwaitingOack,                           /* awaiting the first reply */
+    Sending(msg::DataMessageBlockNumber),   /* awaiting this block's data */
+    AwaitingAck(msg::DataMessageBlockNumber),
+    Receiving(msg::DataMessageBlockNumber), /* this block has been ACKed */
+    Dallying(msg::DataMessageBlockNumber),  /* this, the last, too */
+    Finished,
+    Failed
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Received(msg::AnyMessage),
+    TimedOut
+}
+
+#[derive(Clone, Debug)]
+pub enum Output {
+    Send(msg::AnyMessage),
+    Deliver(Vec<u8>)            /* the next block of the file, in order */
+}
+
+/*
+ * The progress of a single transfer, as a state machine that performs no I/O
+ * of its own.
+ *
+ * The machine is fed events (a message from the peer, or the lapse of a
+ * timeout) and answers with what should happen as a result: messages to send
+ * and blocks of the file to be written out. Where it needs a block of the
+ * file to send, it waits in `Sending` until given one. Everything to do with
+ * sockets, files and clocks is left to whoever drives it.
+ *
+ * Having acknowledged the last block, a receiver dallies for a while before
+ * finishing, so that it can acknowledge the block again should its ACK have
+ * been lost (RFC 1350, section 6), unless told to finish straight away. The
+ * file is complete from the moment it starts dallying.
+ *
+ * With a window size (RFC 7440) of more than one, a sender sends that many
+ * blocks before waiting, and a receiver acknowledges only the last of them.
+ * A receiver that notices a block go missing acknowledges the last it has in
+ * order, and the sender starts a fresh window from there; a sender that
+ * hears nothing sends the whole window again.
+ */
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    role: Role,
+    state: State,
+    options: TransferOptions,
+    requested: msg::OptionList,         /* options we asked the peer for */
+    retries: usize,
+    attempts: usize,
+    dally: usize,                       /* timeouts to dally for */
+    last_sent: Option<msg::AnyMessage>, /* what a timeout retransmits */
+    window: VecDeque<msg::DataMessage>, /* blocks sent but not yet ACKed */
+    unacked: usize,                     /* blocks received since our ACK */
+    gap_reported: bool,                 /* ACKed since a block went astray */
+    final_block: bool,                  /* the last block sent was short */
+    bytes: u64,                         /* payload sent or received */
+    blocks: u64,
+    retransmissions: u64,
+    duplicates: u64                     /* repeated DATA or ACKs received */
+}
+
+impl Transfer {
+    fn new(role: Role, state: State, options: TransferOptions,
+           retries: usize) -> Self {
+        Transfer {
+            role,
+            state,
+            options,
+            requested: Vec::new(),
+            retries,
+            attempts: 0,
+            dally: DALLY_TIMEOUTS,
+            last_sent: None,
+            window: VecDeque::new(),
+            unacked: 0,
+            gap_reported: false,
+            final_block: false,
+            bytes: 0,
+            blocks: 0,
+            retransmissions: 0,
+            duplicates: 0
+        }
+    }
+
+    /* a client that has just sent the given request (which isn't itself
+        emitted, and is only retransmitted on timeout) */
+    pub fn request(request: &msg::AnyMessage, retries: usize) -> Self {
+        let (role, requested): (Role, msg::OptionList) = match request {
+            msg::AnyMessage::Wrq(wrq) => (Role::Reader, wrq.options()),
+            msg::AnyMessage::Rrq(rrq) => (Role::Writer, rrq.options()),
+            _ => (Role::Writer, Vec::new())
+        };
+
+        let mut transfer: Transfer = Transfer::new(role, State::AwaitingOack,
+                                                   TransferOptions::default(),
+                                                   retries);
+        transfer.requested = requested;
+        transfer.last_sent = Some(request.clone());
+        transfer
+    }
+
+    /* a server that has accepted a request without any options */
+    pub fn accept(role: Role, retries: usize) -> (Self, Vec<Output>) {
+        match role {
+            Role::Reader => (Transfer::new(role, State::Sending(1),
+                                           TransferOptions::default(),
+                                           retries), Vec::new()),
+            Role::Writer => {
+                let mut transfer: Transfer =
+                    Transfer::new(role, State::Receiving(0),
+                                  TransferOptions::default(), retries);
+                let ack: msg::AnyMessage = msg::AnyMessage::Ack(
+                    msg::AcknowledgementMessage::new(0));
+                let outputs: Vec<Output> = transfer.emit(ack);
+
+                (transfer, outputs)
+            }
+        }
+    }
+
+    /* a server that has accepted a request and some of its options, which
+        are acknowledged before anything else happens */
+    pub fn acknowledge(role: Role, acknowledged: msg::OptionList,
+                       options: TransferOptions, retries: usize) ->
+        (Self, Vec<Output>) {
+        let state: State = match role {
+            Role::Reader => State::AwaitingAck(0),
+            Role::Writer => State::Receiving(0)
+        };
+
+        let mut transfer: Transfer = Transfer::new(role, state, options,
+                                                   retries);
+        let oack: msg::AnyMessage = msg::AnyMessage::Oack(
+            msg::OptionAcknowledgementMessage::new(acknowledged));
+        let outputs: Vec<Output> = transfer.emit(oack);
+
+        (transfer, outputs)
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn options(&self) -> &TransferOptions {
+        &self.options
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.options.block_size()
+    }
+
+    pub fn window_size(&self) -> usize {
+        self.options.window_size()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Finished
+    }
+
+    /* whether every block has been sent or received, even if the machine
+        is yet to finish dallying over the last */
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, State::Dallying(_) | State::Finished)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /* how many timeouts to dally for after the last block; with none, the
+        machine finishes as soon as it has acknowledged it */
+    pub fn set_dally(&mut self, timeouts: usize) {
+        self.dally = timeouts;
+    }
+
+    /* supplies the data for the block the machine is waiting to send; a
+        block shorter than the block size ends the transfer */
+    pub fn send_block(&mut self, data: Vec<u8>) -> Vec<Output> {
+        let block: msg::DataMessageBlockNumber = match self.state {
+            State::Sending(block) => block,
+            _ => return Vec::new()
+        };
+
+        self.final_block = data.len() < self.block_size();
+        self.bytes += data.len() as u64;
+        self.blocks += 1;
+
+        let message: msg::DataMessage = msg::DataMessage::new(block, data);
+        self.window.push_back(message.clone());
+        self.state = self.next_to_send(block);
+        self.emit(msg::AnyMessage::Data(message))
+    }
+
+    pub fn handle(&mut self, event: Event) ->
+        Result<Vec<Output>, TransferError> {
+        let result: Result<Vec<Output>, TransferError> = match event {
+            /* the file is already in, so it's too late to abort */
+            Event::Received(msg::AnyMessage::Error(_))
+                if matches!(self.state, State::Dallying(_)) => {
+                self.state = State::Finished;
+                Ok(Vec::new())
+            },
+            Event::Received(msg::AnyMessage::Error(e)) =>
+                Err(TransferError::Aborted(e)),
+            Event::Received(message) => self.receive(message),
+            Event::TimedOut => self.timeout()
+        };
+
+        if result.is_err() {
+            self.state = State::Failed;
+        }
+
+        result
+    }
+
+    fn receive(&mut self, message: msg::AnyMessage) ->
+        Result<Vec<Output>, TransferError> {
+        match (self.state, message) {
+            (State::AwaitingOack, msg::AnyMessage::Oack(oack)) => {
+                self.options = TransferOptions::from_oack(&self.requested,
+                                                          &oack.options())
+                    .map_err(|e| TransferError::Rejected(
+                            msg::ERROR_OPTION_NEGOTIATION, e.to_string()))?;
+                self.progress();
+
+                match self.role {
+                    Role::Reader => {
+                        self.state = State::Sending(1);
+                        Ok(Vec::new())
+                    },
+                    Role::Writer => {
+                        self.state = State::Receiving(0);
+                        Ok(self.emit(msg::AnyMessage::Ack(
+                                    msg::AcknowledgementMessage::new(0))))
+                    }
+                }
+            },
+            /* the peer ignored any options we asked for, so the transfer
+                carries on with the defaults */
+            (State::AwaitingOack, msg::AnyMessage::Ack(ack))
+                if self.role == Role::Reader && ack.block_num() == 0 => {
+                self.progress();
+                self.state = State::Sending(1);
+                Ok(Vec::new())
+            },
+            (State::AwaitingOack, msg::AnyMessage::Data(data))
+                if self.role == Role::Writer => {
+                self.state = State::Receiving(0);
+                self.receive(msg::AnyMessage::Data(data))
+            },
+            (State::AwaitingAck(block), msg::AnyMessage::Ack(ack))
+                if self.covered(ack.block_num()).is_some() => {
+                let covered: usize = self.covered(ack.block_num()).unwrap();
+                self.progress();
+                self.window.drain(..covered);
+
+                if self.window.is_empty() {
+                    self.state = if self.final_block {
+                        State::Finished
+                    } else {
+                        State::Sending(block.wrapping_add(1))
+                    };
+
+                    return Ok(Vec::new());
+                }
+
+                /* the rest of the window went astray, so a new one starts
+                    with what followed the block acknowledged */
+                self.state = self.next_to_send(block);
+                Ok(self.resend_window())
+            },
+            (State::Receiving(block), msg::AnyMessage::Data(data))
+                if data.block_num() == block.wrapping_add(1) &&
+                    data.data().len() <= self.block_size() => {
+                let block: msg::DataMessageBlockNumber = data.block_num();
+                let data: Vec<u8> = data.data();
+                let last: bool = data.len() < self.block_size();
+
+                self.progress();
+                self.bytes += data.len() as u64;
+                self.blocks += 1;
+                self.unacked += 1;
+                self.gap_reported = false;
+                self.state = match (last, self.dally) {
+                    (false, _) => State::Receiving(block),
+                    (true, 0) => State::Finished,
+                    (true, _) => State::Dallying(block)
+                };
+
+                let mut outputs: Vec<Output> = vec![Output::Deliver(data)];
+
+                /* only the last block of each window is acknowledged */
+                if last || self.unacked >= self.window_size() {
+                    outputs.extend(self.ack_up_to(block));
+                }
+
+                Ok(outputs)
+            },
+            /* our previous ACK went missing, so the peer has resent the block
+                we already have */
+            (State::Receiving(block), msg::AnyMessage::Data(data))
+                if data.block_num() == block => {
+                self.duplicates += 1;
+                self.retransmissions += 1;
+                Ok(self.ack_up_to(block))
+            },
+            /* a later block of the window, so one before it went astray; the
+                sender is told where to start again, but only the once */
+            (State::Receiving(block), msg::AnyMessage::Data(data))
+                if self.is_ahead(block, data.block_num()) => {
+                if self.gap_reported {
+                    return Ok(Vec::new());
+                }
+
+                self.gap_reported = true;
+                Ok(self.ack_up_to(block))
+            },
+            /* likewise for the last block, which starts the dallying over */
+            (State::Dallying(block), msg::AnyMessage::Data(data))
+                if data.block_num() == block => {
+                self.duplicates += 1;
+                self.progress();
+                Ok(self.retransmit())
+            },
+            /* an ACK of the block before the window, which our peer has
+                been sent before and may send again */
+            (State::AwaitingAck(block), msg::AnyMessage::Ack(ack))
+                if ack.block_num() == self.window.front()
+                    .map_or(block, |first| first.block_num())
+                    .wrapping_sub(1) => {
+                self.duplicates += 1;
+                Ok(Vec::new())
+            },
+            /* anything else unexpected (stale ACKs, duplicate blocks and
+                garbage alike) is ignored rather than triggering a
+                retransmission (see RFC 1123, 4.2.3.1) */
+            _ => Ok(Vec::new())
+        }
+    }
+
+    fn timeout(&mut self) -> Result<Vec<Output>, TransferError> {
+        match self.state {
+            State::AwaitingOack | State::AwaitingAck(_) |
+            State::Receiving(_) => {
+                self.attempts += 1;
+
+                if self.attempts > self.retries {
+                    return Err(TransferError::TimedOut);
+                }
+
+                match self.state {
+                    State::AwaitingAck(_) if !self.window.is_empty() =>
+                        Ok(self.resend_window()),
+                    /* part of a window is in, but the rest is overdue */
+                    State::Receiving(block) if self.unacked > 0 => {
+                        self.retransmissions += 1;
+                        Ok(self.ack_up_to(block))
+                    },
+                    _ => Ok(self.retransmit())
+                }
+            },
+            /* giving the peer its chance to resend the last block */
+            State::Dallying(_) => {
+                self.attempts += 1;
+
+                if self.attempts >= self.dally {
+                    self.state = State::Finished;
+                }
+
+                Ok(Vec::new())
+            },
+            /* nothing is outstanding, so there's nothing to retransmit */
+            _ => Ok(Vec::new())
+        }
+    }
+
+    fn emit(&mut self, message: msg::AnyMessage) -> Vec<Output> {
+        self.last_sent = Some(message.clone());
+        vec![Output::Send(message)]
+    }
+
+    /* acknowledges everything up to and including the given block */
+    fn ack_up_to(&mut self, block: msg::DataMessageBlockNumber) ->
+        Vec<Output> {
+        self.unacked = 0;
+        self.emit(msg::AnyMessage::Ack(msg::AcknowledgementMessage::new(block)))
+    }
+
+    /* what a sender does after sending the given block: another, unless the
+        window is full or the file has run out */
+    fn next_to_send(&self, block: msg::DataMessageBlockNumber) -> State {
+        if self.final_block || self.window.len() >= self.window_size() {
+            State::AwaitingAck(block)
+        } else {
+            State::Sending(block.wrapping_add(1))
+        }
+    }
+
+    /* how many of the blocks awaiting acknowledgement an ACK of the given
+        block accounts for, if it's one of them (or, before any have been
+        sent, the ACK of the OACK) */
+    fn covered(&self, block: msg::DataMessageBlockNumber) -> Option<usize> {
+        match self.state {
+            State::AwaitingAck(awaited) if self.window.is_empty() =>
+                (block == awaited).then_some(0),
+            _ => self.window.iter()
+                .position(|data| data.block_num() == block)
+                .map(|index| index + 1)
+        }
+    }
+
+    /* whether a block is beyond the next one expected, but within the
+        window the sender could have sent */
+    fn is_ahead(&self, received: msg::DataMessageBlockNumber,
+                block: msg::DataMessageBlockNumber) -> bool {
+        let distance: usize = block.wrapping_sub(received) as usize;
+        distance >= 2 && distance <= self.window_size()
+    }
+
+    fn resend_window(&mut self) -> Vec<Output> {
+        self.retransmissions += self.window.len() as u64;
+        self.window.iter()
+            .map(|data| Output::Send(msg::AnyMessage::Data(data.clone())))
+            .collect()
+    }
+
+    fn retransmit(&mut self) -> Vec<Output> {
+        match &self.last_sent {
+            Some(message) => {
+                self.retransmissions += 1;
+                vec![Output::Send(message.clone())]
+            },
+            None => Vec::new()
+        }
+    }
+
+    fn progress(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRIES: usize = 2;
+
+    fn data(block: msg::DataMessageBlockNumber, len: usize) -> Event {
+        Event::Received(msg::AnyMessage::Data(
+                msg::DataMessage::new(block, vec![block as u8; len])))
+    }
+
+    fn ack(block: msg::DataMessageBlockNumber) -> Event {
+        Event::Received(msg::AnyMessage::Ack(
+                msg::AcknowledgementMessage::new(block)))
+    }
+
+    fn oack(options: &[(&str, &str)]) -> Event {
+        Event::Received(msg::AnyMessage::Oack(
+                msg::OptionAcknowledgementMessage::new(pairs(options))))
+    }
+
+    fn pairs(options: &[(&str, &str)]) -> msg::OptionList {
+        options.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /* the messages among the outputs, as they'd go on the wire */
+    fn sent(outputs: &[Output]) -> Vec<Vec<u8>> {
+        outputs.iter()
+            .filter_map(|output| match output {
+                Output::Send(message) => Some(message.to_bytes()),
+                Output::Deliver(_) => None
+            })
+            .collect()
+    }
+
+    fn delivered(outputs: &[Output]) -> Vec<Vec<u8>> {
+        outputs.iter()
+            .filter_map(|output| match output {
+                Output::Deliver(data) => Some(data.clone()),
+                Output::Send(_) => None
+            })
+            .collect()
+    }
+
+    fn wire(event: Event) -> Vec<u8> {
+        match event {
+            Event::Received(message) => message.to_bytes(),
+            Event::TimedOut => panic!("a timeout has no wire form")
+        }
+    }
+
+    fn writer() -> Transfer {
+        let (transfer, outputs): (Transfer, Vec<Output>) =
+            Transfer::accept(Role::Writer, RETRIES);
+        assert_eq!(sent(&outputs), vec![wire(ack(0))]);
+        transfer
+    }
+
+    /* a reader that has sent the first, full, block */
+    fn reader() -> Transfer {
+        let (mut transfer, outputs): (Transfer, Vec<Output>) =
+            Transfer::accept(Role::Reader, RETRIES);
+        assert!(outputs.is_empty());
+        assert_eq!(transfer.state(), State::Sending(1));
+
+        let outputs: Vec<Output> = transfer.send_block(vec![1; 512]);
+        assert_eq!(sent(&outputs), vec![wire(data(1, 512))]);
+        transfer
+    }
+
+    #[test]
+    fn receives_blocks_in_order_and_dallies_after_the_last() {
+        let mut transfer: Transfer = writer();
+
+        let outputs: Vec<Output> = transfer.handle(data(1, 512)).unwrap();
+        assert_eq!(delivered(&outputs), vec![vec![1; 512]]);
+        assert_eq!(sent(&outputs), vec![wire(ack(1))]);
+        assert_eq!(transfer.state(), State::Receiving(1));
+
+        let outputs: Vec<Output> = transfer.handle(data(2, 100)).unwrap();
+        assert_eq!(delivered(&outputs), vec![vec![2; 100]]);
+        assert_eq!(sent(&outputs), vec![wire(ack(2))]);
+        assert_eq!(transfer.state(), State::Dallying(2));
+        assert!(transfer.is_complete());
+        assert!(!transfer.is_finished());
+
+        /* our last ACK was lost, so the peer sends the last block again,
+            which has us dally afresh */
+        assert!(transfer.handle(Event::TimedOut).unwrap().is_empty());
+        let outputs: Vec<Output> = transfer.handle(data(2, 100)).unwrap();
+        assert!(delivered(&outputs).is_empty());
+        assert_eq!(sent(&outputs), vec![wire(ack(2))]);
+        assert_eq!(transfer.duplicates(), 1);
+
+        /* and then goes quiet, having had it */
+        for _ in 0..DALLY_TIMEOUTS {
+            assert!(!transfer.is_finished());
+            assert!(transfer.handle(Event::TimedOut).unwrap().is_empty());
+        }
+
+        assert!(transfer.is_finished());
+        assert_eq!(transfer.retransmissions(), 1);
+        assert_eq!(transfer.bytes(), 612);
+        assert_eq!(transfer.blocks(), 2);
+    }
+
+    #[test]
+    fn an_empty_block_ends_a_file_of_whole_blocks() {
+        let mut transfer: Transfer = writer();
+
+        transfer.handle(data(1, 512)).unwrap();
+        let outputs: Vec<Output> = transfer.handle(data(2, 0)).unwrap();
+        assert_eq!(delivered(&outputs), vec![Vec::<u8>::new()]);
+        assert_eq!(transfer.state(), State::Dallying(2));
+    }
+
+    #[test]
+    fn finishes_at_once_when_told_not_to_dally() {
+        let mut transfer: Transfer = writer();
+        transfer.set_dally(0);
+
+        let outputs: Vec<Output> = transfer.handle(data(1, 10)).unwrap();
+        assert_eq!(delivered(&outputs), vec![vec![1; 10]]);
+        assert_eq!(sent(&outputs), vec![wire(ack(1))]);
+        assert!(transfer.is_finished());
+    }
+
+    #[test]
+    fn an_error_while_dallying_finishes_the_transfer() {
+        let mut transfer: Transfer = writer();
+        transfer.handle(data(1, 10)).unwrap();
+
+        let error: msg::ErrorMessage =
+            msg::ErrorMessage::new(msg::ERROR_NOT_DEFINED, "Bye".to_string());
+        transfer.handle(Event::Received(msg::AnyMessage::Error(error)))
+            .unwrap();
+        assert!(transfer.is_finished());
+    }
+
+    #[test]
+    fn reacknowledges_a_duplicate_block() {
+        let mut transfer: Transfer = writer();
+        transfer.handle(data(1, 512)).unwrap();
+
+        let outputs: Vec<Output> = transfer.handle(data(1, 512)).unwrap();
+        assert!(delivered(&outputs).is_empty());
+        assert_eq!(sent(&outputs), vec![wire(ack(1))]);
+        assert_eq!(transfer.state(), State::Receiving(1));
+        assert_eq!(transfer.duplicates(), 1);
+        assert_eq!(transfer.retransmissions(), 1);
+    }
+
+    #[test]
+    fn ignores_blocks_out_of_order() {
+        let mut transfer: Transfer = writer();
+        transfer.handle(data(1, 512)).unwrap();
+
+        for block in [3, 0, 7] {
+            assert!(transfer.handle(data(block, 512)).unwrap().is_empty());
+            assert_eq!(transfer.state(), State::Receiving(1));
+        }
+
+        /* nor may a block be longer than agreed */
+        assert!(transfer.handle(data(2, 513)).unwrap().is_empty());
+        assert_eq!(transfer.state(), State::Receiving(1));
+    }
+
+    #[test]
+    fn ignores_duplicate_acks() {
+        let mut transfer: Transfer = reader();
+        transfer.handle(ack(1)).unwrap();
+        transfer.send_block(vec![2; 512]);
+
+        /* resending block 2 would be the Sorcerer's Apprentice bug */
+        assert!(transfer.handle(ack(1)).unwrap().is_empty());
+        assert_eq!(transfer.state(), State::AwaitingAck(2));
+        assert_eq!(transfer.duplicates(), 1);
+        assert_eq!(transfer.retransmissions(), 0);
+    }
+
+    #[test]
+    fn a_short_block_is_the_last_to_be_sent() {
+        let mut transfer: Transfer = reader();
+        transfer.handle(ack(1)).unwrap();
+        assert_eq!(transfer.state(), State::Sending(2));
+
+        let outputs: Vec<Output> = transfer.send_block(vec![2; 100]);
+        assert_eq!(sent(&outputs), vec![wire(data(2, 100))]);
+        assert!(!transfer.is_complete());
+
+        transfer.handle(ack(2)).unwrap();
+        assert!(transfer.is_finished());
+        assert_eq!(transfer.bytes(), 612);
+    }
+
+    #[test]
+    fn block_numbers_wrap() {
+        let mut transfer: Transfer = reader();
+
+        for block in 1..=u16::MAX {
+            transfer.handle(ack(block)).unwrap();
+            transfer.send_block(vec![0; 512]);
+        }
+
+        assert_eq!(transfer.state(), State::AwaitingAck(0));
+
+        let mut transfer: Transfer =
+            Transfer::new(Role::Writer, State::Receiving(u16::MAX),
+                          TransferOptions::default(), RETRIES);
+        let outputs: Vec<Output> = transfer.handle(data(0, 512)).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(0))]);
+        assert_eq!(transfer.state(), State::Receiving(0));
+    }
+
+    #[test]
+    fn retransmits_on_timeout_until_the_retries_run_out() {
+        let mut transfer: Transfer = reader();
+
+        for _ in 0..RETRIES {
+            let outputs: Vec<Output> =
+                transfer.handle(Event::TimedOut).unwrap();
+            assert_eq!(sent(&outputs), vec![wire(data(1, 512))]);
+        }
+
+        assert!(matches!(transfer.handle(Event::TimedOut),
+                         Err(TransferError::TimedOut)));
+        assert_eq!(transfer.state(), State::Failed);
+        assert_eq!(transfer.retransmissions(), RETRIES as u64);
+    }
+
+    #[test]
+    fn progress_renews_the_retries() {
+        let mut transfer: Transfer = reader();
+
+        for block in 1..=3 {
+            for _ in 0..RETRIES {
+                transfer.handle(Event::TimedOut).unwrap();
+            }
+
+            transfer.handle(ack(block)).unwrap();
+            transfer.send_block(vec![0; 512]);
+        }
+
+        assert_eq!(transfer.state(), State::AwaitingAck(4));
+    }
+
+    #[test]
+    fn adopts_the_options_in_an_oack() {
+        let rrq: msg::AnyMessage = msg::AnyMessage::Rrq(
+            msg::ReadRequestMessage::with_options(
+                "file".to_string(), msg::ReadWriteRequestMessageMode::Octet,
+                pairs(&[("blksize", "1024"), ("tsize", "0")])));
+        let mut transfer: Transfer = Transfer::request(&rrq, RETRIES);
+
+        /* the server needn't acknowledge everything asked for */
+        let outputs: Vec<Output> =
+            transfer.handle(oack(&[("blksize", "1024")])).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(0))]);
+        assert_eq!(transfer.state(), State::Receiving(0));
+        assert_eq!(transfer.block_size(), 1024);
+        assert_eq!(transfer.options().transfer_size(), None);
+
+        let wrq: msg::AnyMessage = msg::AnyMessage::Wrq(
+            msg::WriteRequestMessage::with_options(
+                "file".to_string(), msg::ReadWriteRequestMessageMode::Octet,
+                pairs(&[("tsize", "612")])));
+        let mut transfer: Transfer = Transfer::request(&wrq, RETRIES);
+
+        assert!(transfer.handle(oack(&[("tsize", "612")])).unwrap()
+                .is_empty());
+        assert_eq!(transfer.state(), State::Sending(1));
+        assert_eq!(transfer.options().transfer_size(), Some(612));
+    }
+
+    #[test]
+    fn rejects_an_oack_it_cannot_accept() {
+        let rrq: msg::AnyMessage = msg::AnyMessage::Rrq(
+            msg::ReadRequestMessage::with_options(
+                "file".to_string(), msg::ReadWriteRequestMessageMode::Octet,
+                pairs(&[("blksize", "1024")])));
+
+        for options in [&[("tsize", "612")][..], &[("blksize", "7")][..]] {
+            let mut transfer: Transfer = Transfer::request(&rrq, RETRIES);
+
+            assert!(matches!(
+                    transfer.handle(oack(options)),
+                    Err(TransferError::Rejected(msg::ERROR_OPTION_NEGOTIATION,
+                                                _))));
+            assert_eq!(transfer.state(), State::Failed);
+        }
+    }
+
+    /* a server that has agreed to send or receive `size` blocks at a time,
+        and has had its OACK acknowledged if sending */
+    fn windowed(role: Role, size: usize) -> Transfer {
+        let mut options: TransferOptions = TransferOptions::default();
+        options.set_window_size(size);
+
+        let size: String = size.to_string();
+        let acknowledged: msg::OptionList = pairs(&[("windowsize", &size)]);
+        let (mut transfer, outputs): (Transfer, Vec<Output>) =
+            Transfer::acknowledge(role, acknowledged.clone(), options,
+                                  RETRIES);
+        assert_eq!(sent(&outputs), vec![msg::AnyMessage::Oack(
+                    msg::OptionAcknowledgementMessage::new(acknowledged))
+                   .to_bytes()]);
+
+        if role == Role::Reader {
+            assert!(transfer.handle(ack(0)).unwrap().is_empty());
+        }
+
+        transfer
+    }
+
+    /* has the transfer send one block of `len` bytes, returning what went
+        out */
+    fn send(transfer: &mut Transfer, len: usize) -> Vec<Vec<u8>> {
+        let block: msg::DataMessageBlockNumber = match transfer.state() {
+            State::Sending(block) => block,
+            state => panic!("expected to be sending, but {:?}", state)
+        };
+
+        sent(&transfer.send_block(vec![block as u8; len]))
+    }
+
+    #[test]
+    fn sends_a_window_of_blocks_before_waiting() {
+        let mut transfer: Transfer = windowed(Role::Reader, 4);
+
+        for block in 1..=4 {
+            assert_eq!(send(&mut transfer, 512), vec![wire(data(block, 512))]);
+        }
+
+        assert_eq!(transfer.state(), State::AwaitingAck(4));
+
+        /* nothing heard, so the whole window goes again */
+        let outputs: Vec<Output> = transfer.handle(Event::TimedOut).unwrap();
+        assert_eq!(sent(&outputs), (1..=4).map(|block| wire(data(block, 512)))
+                   .collect::<Vec<Vec<u8>>>());
+        assert_eq!(transfer.retransmissions(), 4);
+
+        /* an ACK from inside the window isn't taken for the whole of it */
+        assert!(transfer.handle(ack(0)).unwrap().is_empty());
+        assert_eq!(transfer.duplicates(), 1);
+        assert!(transfer.handle(ack(4)).unwrap().is_empty());
+        assert_eq!(transfer.state(), State::Sending(5));
+    }
+
+    #[test]
+    fn starts_a_fresh_window_after_the_last_block_acknowledged() {
+        let mut transfer: Transfer = windowed(Role::Reader, 4);
+
+        for _ in 1..=4 {
+            send(&mut transfer, 512);
+        }
+
+        /* blocks 3 and 4 went astray, so they start the next window, which
+            has room for two more */
+        let outputs: Vec<Output> = transfer.handle(ack(2)).unwrap();
+        assert_eq!(sent(&outputs),
+                   vec![wire(data(3, 512)), wire(data(4, 512))]);
+        assert_eq!(transfer.state(), State::Sending(5));
+
+        assert_eq!(send(&mut transfer, 512), vec![wire(data(5, 512))]);
+        assert_eq!(send(&mut transfer, 100), vec![wire(data(6, 100))]);
+        assert_eq!(transfer.state(), State::AwaitingAck(6));
+
+        assert!(transfer.handle(ack(6)).unwrap().is_empty());
+        assert!(transfer.is_finished());
+        assert_eq!(transfer.blocks(), 6);
+        assert_eq!(transfer.retransmissions(), 2);
+    }
+
+    #[test]
+    fn a_short_block_ends_the_window() {
+        let mut transfer: Transfer = windowed(Role::Reader, 4);
+
+        send(&mut transfer, 512);
+        send(&mut transfer, 100);
+        assert_eq!(transfer.state(), State::AwaitingAck(2));
+
+        assert!(transfer.handle(ack(2)).unwrap().is_empty());
+        assert!(transfer.is_finished());
+    }
+
+    #[test]
+    fn acknowledges_each_window_once() {
+        let mut transfer: Transfer = windowed(Role::Writer, 3);
+
+        for block in 1..=2 {
+            let outputs: Vec<Output> =
+                transfer.handle(data(block, 512)).unwrap();
+            assert_eq!(delivered(&outputs), vec![vec![block as u8; 512]]);
+            assert!(sent(&outputs).is_empty());
+        }
+
+        let outputs: Vec<Output> = transfer.handle(data(3, 512)).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(3))]);
+
+        /* the last block is acknowledged however far into a window */
+        transfer.handle(data(4, 512)).unwrap();
+        let outputs: Vec<Output> = transfer.handle(data(5, 100)).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(5))]);
+        assert!(transfer.is_complete());
+        assert_eq!(transfer.blocks(), 5);
+    }
+
+    #[test]
+    fn acknowledges_what_it_has_when_a_block_goes_astray() {
+        let mut transfer: Transfer = windowed(Role::Writer, 4);
+
+        transfer.handle(data(1, 512)).unwrap();
+
+        /* block 2 is missing, which is said just the once */
+        let outputs: Vec<Output> = transfer.handle(data(3, 512)).unwrap();
+        assert!(delivered(&outputs).is_empty());
+        assert_eq!(sent(&outputs), vec![wire(ack(1))]);
+        assert!(transfer.handle(data(4, 512)).unwrap().is_empty());
+
+        /* and the sender starts again from there */
+        for block in 2..=4 {
+            let outputs: Vec<Output> =
+                transfer.handle(data(block, 512)).unwrap();
+            assert_eq!(delivered(&outputs), vec![vec![block as u8; 512]]);
+            assert!(sent(&outputs).is_empty());
+        }
+
+        let outputs: Vec<Output> = transfer.handle(data(5, 512)).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(5))]);
+        assert_eq!(transfer.blocks(), 5);
+    }
+
+    #[test]
+    fn acknowledges_a_window_cut_short_by_a_timeout() {
+        let mut transfer: Transfer = windowed(Role::Writer, 4);
+
+        transfer.handle(data(1, 512)).unwrap();
+        transfer.handle(data(2, 512)).unwrap();
+
+        let outputs: Vec<Output> = transfer.handle(Event::TimedOut).unwrap();
+        assert_eq!(sent(&outputs), vec![wire(ack(2))]);
+        assert_eq!(transfer.state(), State::Receiving(2));
+    }
+
+    #[test]
+    fn stops_when_the_peer_sends_an_error() {
+        let mut transfer: Transfer = reader();
+        let error: msg::ErrorMessage = msg::ErrorMessage::new(
+            msg::ERROR_DISK_FULL, "Disk full".to_string());
+
+        match transfer.handle(Event::Received(msg::AnyMessage::Error(error))) {
+            Err(TransferError::Aborted(e)) =>
+                assert_eq!(e.code(), msg::ERROR_DISK_FULL),
+            other => panic!("expected the transfer to abort, got {:?}", other)
+        }
+
+        assert_eq!(transfer.state(), State::Failed);
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::msg::{AnyMessage, ParseError};
+
+/*
+ * A representation of messages, and of the state of connections, for
+ * logging, replay and golden-file tests, written as either JSON or CBOR
+ * (RFC 8949).
+ *
+ * A message is a map whose "opcode" says which message it is, alongside
+ * that message's fields:
+ *
+ *     {"opcode": "RRQ", "filename": "boot.img", "mode": "octet",
+ *      "options": [["blksize", "1428"], ["tsize", "0"]]}
+ *     {"opcode": "WRQ", "filename": "boot.img", "mode": "netascii",
+ *      "options": []}
+ *     {"opcode": "DATA", "block_num": 1, "data": [104, 105, 10]}
+ *     {"opcode": "ACK", "block_num": 1}
+ *     {"opcode": "ERROR", "code": 1, "message": "File not found"}
+ *     {"opcode": "OACK", "options": [["blksize", "1428"]]}
+ *
+ * Modes are written as they are on the wire, in lower case. Options are
+ * pairs of name and value, in the order they were given, since a request
+ * may well repeat one. Data is a byte string in CBOR and, for want of
+ * anything better, an array of byte values in JSON.
+ *
+ * A connection is a map of the addresses at either end, its sequence number
+ * and the last message it carried, if any:
+ *
+ *     {"local": "127.0.0.1:69", "remote": "127.0.0.1:50000", "curr_seq": 1,
+ *      "last_msg": {"opcode": "ACK", "block_num": 0}}
+ *
+ * CBOR has the same keys. Addresses there aren't written as strings,
+ * though, but as a map from "V4" or "V6" to a pair of the IP address (an
+ * array of its bytes) and the port.
+ */
+
+/* the formats a dump can be written in */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Cbor
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Cbor => write!(f, "cbor")
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = DumpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(DumpError::InvalidFormat(s.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DumpError {
+    InvalidFormat(String),
+    Json(serde_json::Error),
+    Cbor(String),
+    Malformed(ParseError)   /* the datagram isn't a message */
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpError::InvalidFormat(format) =>
+                write!(f, "Invalid dump format '{}' (expected json or cbor)",
+                       format),
+            DumpError::Json(e) => write!(f, "Invalid JSON dump: {}", e),
+            DumpError::Cbor(e) => write!(f, "Invalid CBOR dump: {}", e),
+            DumpError::Malformed(e) => write!(f, "Malformed datagram: {}", e)
+        }
+    }
+}
+
+pub fn encode<T: Serialize>(value: &T, format: Format) ->
+    Result<Vec<u8>, DumpError> {
+    match format {
+        Format::Json => serde_json::to_vec(value).map_err(DumpError::Json),
+        Format::Cbor => {
+            let mut bytes: Vec<u8> = Vec::new();
+            ciborium::ser::into_writer(value, &mut bytes)
+                .map_err(|e| DumpError::Cbor(e.to_string()))?;
+            Ok(bytes)
+        }
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], format: Format) ->
+    Result<T, DumpError> {
+    match format {
+        Format::Json => serde_json::from_slice(bytes).map_err(DumpError::Json),
+        Format::Cbor => ciborium::de::from_reader(bytes)
+            .map_err(|e| DumpError::Cbor(e.to_string()))
+    }
+}
+
+/* the dump of a datagram as it was on the wire */
+pub fn from_wire(datagram: &[u8], format: Format) ->
+    Result<Vec<u8>, DumpError> {
+    let message: AnyMessage = AnyMessage::from_bytes(datagram.to_vec())
+        .map_err(DumpError::Malformed)?;
+
+    encode(&message, format)
+}
+
+/* the datagram a dumped message would be sent as */
+pub fn to_wire(dump: &[u8], format: Format) -> Result<Vec<u8>, DumpError> {
+    let message: AnyMessage = decode(dump, format)?;
+
+    Ok(message.to_bytes())
+}
@@ -0,0 +1,98 @@
+extern crate clap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use clap::{Arg, App, ArgMatches};
+
+use nettlesoup::dump::{self, Format};
+use nettlesoup::msg::AnyMessage;
+
+/*
+ * Converts messages between the bytes sent on the wire and the dump format
+ * (see dump), reading from a file or standard input and writing to standard
+ * output, so that golden files can be made from captured datagrams and
+ * datagrams made from hand-written dumps.
+ */
+
+fn main() {
+    let input = |about: &'static str| Arg::with_name("input")
+        .value_name("FILE")
+        .help(about);
+    let format = || Arg::with_name("format")
+        .long("format")
+        .short('f')
+        .value_name("format")
+        .help("The dump format, json or cbor (defaults to json)")
+        .takes_value(true);
+
+    let matches = App::new("tftpdump")
+       .version("0.1.0")
+       .about("Converts TFTP messages between wire bytes and JSON or CBOR")
+       .author("Jack McPherson <jmcph4.github@gmail.com>")
+       .subcommand(App::new("decode")
+            .about("Dumps a datagram")
+            .arg(input("The datagram to read (defaults to standard input)"))
+            .arg(format())
+            .arg(Arg::with_name("pretty")
+                 .long("pretty")
+                 .help("Indents JSON so that it reads well and diffs \
+                        cleanly")))
+       .subcommand(App::new("encode")
+            .about("Turns a dumped message back into a datagram")
+            .arg(input("The dump to read (defaults to standard input)"))
+            .arg(format()))
+       .get_matches();
+
+    let (args, decoding): (&ArgMatches, bool) = match matches.subcommand_name()
+    {
+        Some("decode") => (matches.subcommand_matches("decode").unwrap(), true),
+        Some("encode") => (matches.subcommand_matches("encode").unwrap(),
+                           false),
+        _ => fail("Expected a subcommand: decode or encode")
+    };
+
+    let format: Format = match args.value_of("format") {
+        Some(format) => format.parse()
+            .unwrap_or_else(|e: dump::DumpError| fail(&e.to_string())),
+        None => Format::Json
+    };
+
+    let input: Vec<u8> = match args.value_of("input") {
+        Some(path) => fs::read(path)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e))),
+        None => {
+            let mut input: Vec<u8> = Vec::new();
+            io::stdin().read_to_end(&mut input)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            input
+        }
+    };
+
+    let output: Result<Vec<u8>, dump::DumpError> = if !decoding {
+        dump::to_wire(&input, format)
+    } else if format == Format::Json && args.is_present("pretty") {
+        AnyMessage::from_bytes(input)
+            .map_err(dump::DumpError::Malformed)
+            .and_then(|message| serde_json::to_vec_pretty(&message)
+                      .map_err(dump::DumpError::Json))
+    } else {
+        dump::from_wire(&input, format)
+    };
+
+    let mut output: Vec<u8> = output.unwrap_or_else(|e| fail(&e.to_string()));
+
+    /* JSON is text, and text ends with a newline */
+    if decoding && format == Format::Json {
+        output.push(b'\n');
+    }
+
+    if let Err(e) = io::stdout().write_all(&output) {
+        fail(&e.to_string());
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("tftpdump: {}", message);
+    process::exit(1);
+}
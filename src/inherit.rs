@@ -0,0 +1,125 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+
+use thiserror::Error;
+
+/*
+ * Listening sockets handed to us ready-bound, rather than bound ourselves.
+ *
+ * systemd passes sockets as descriptors 3 onwards, saying how many in
+ * LISTEN_FDS and for which process in LISTEN_PID (sd_listen_fds(3)). inetd
+ * passes its socket as standard input, with the request that woke us still
+ * waiting to be read.
+ */
+
+/* the first descriptor systemd passes */
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Error)]
+pub enum InheritError {
+    InvalidCount(String),
+    NotDatagram(RawFd),
+    Io(RawFd, io::Error)
+}
+
+impl fmt::Display for InheritError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InheritError::InvalidCount(count) =>
+                write!(f, "Invalid LISTEN_FDS: {}", count),
+            InheritError::NotDatagram(fd) =>
+                write!(f, "Descriptor {} is not a datagram socket", fd),
+            InheritError::Io(fd, e) =>
+                write!(f, "Unable to use descriptor {}: {}", fd, e)
+        }
+    }
+}
+
+/* the sockets systemd has passed us, if it has passed us any */
+pub fn systemd() -> Result<Option<Vec<UdpSocket>>, InheritError> {
+    let ours: bool = env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == process::id());
+
+    if !ours {
+        return Ok(None);
+    }
+
+    let count: RawFd = listen_fds(&env::var("LISTEN_FDS")
+                                  .unwrap_or_default())?;
+
+    /* so that nothing we start mistakes the sockets for its own */
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(datagram_socket)
+        .collect::<Result<Vec<UdpSocket>, InheritError>>()
+        .map(Some)
+}
+
+/* how many sockets LISTEN_FDS says there are; being passed ours at all, we
+    should have been passed at least one */
+fn listen_fds(count: &str) -> Result<RawFd, InheritError> {
+    match count.parse::<RawFd>() {
+        Ok(count) if count >= 1 => Ok(count),
+        _ => Err(InheritError::InvalidCount(count.to_string()))
+    }
+}
+
+/* the socket inetd has passed us as standard input */
+pub fn inetd() -> Result<UdpSocket, InheritError> {
+    datagram_socket(libc::STDIN_FILENO)
+}
+
+/* takes ownership of a descriptor, having made sure it's a datagram
+    socket */
+fn datagram_socket(fd: RawFd) -> Result<UdpSocket, InheritError> {
+    let mut kind: libc::c_int = 0;
+    let mut len: libc::socklen_t = mem::size_of::<libc::c_int>() as
+        libc::socklen_t;
+
+    let status: libc::c_int = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE,
+                         &mut kind as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+
+    if status != 0 {
+        return Err(InheritError::Io(fd, io::Error::last_os_error()));
+    }
+
+    if kind != libc::SOCK_DGRAM {
+        return Err(InheritError::NotDatagram(fd));
+    }
+
+    let socket: UdpSocket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    /* the listener relies on reads timing out, not failing straight away */
+    socket.set_nonblocking(false).map_err(|e| InheritError::Io(fd, e))?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_sockets_passed() {
+        assert_eq!(listen_fds("1").unwrap(), 1);
+        assert_eq!(listen_fds("3").unwrap(), 3);
+
+        for count in ["", "0", "-1", "two"] {
+            assert!(matches!(listen_fds(count),
+                             Err(InheritError::InvalidCount(c)) if c == count),
+                    "{:?}", count);
+        }
+    }
+}
@@ -1,3 +1,27 @@
 pub mod msg;
 pub mod conn;
+pub mod netascii;
+pub mod options;
+pub mod xfer;
+pub mod stats;
+pub mod acl;
+pub mod policy;
+pub mod upload;
+pub mod remap;
+pub mod mcast;
+pub mod srv;
+pub mod clnt;
+pub mod log;
+pub mod metrics;
+pub mod conf;
+pub mod privs;
+pub mod inherit;
+pub mod limit;
+pub mod throttle;
+pub mod pcap;
+pub mod analysis;
+pub mod lossy;
+pub mod dump;
 
+#[cfg(test)]
+mod testutil;
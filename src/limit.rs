@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::acl;
+
+/*
+ * Protection against being used as a reflector.
+ *
+ * A read request is a few bytes long and can be answered with hundreds, so
+ * an open server will happily bombard whoever a forged request names. Three
+ * things blunt this: each source address may only send so many datagrams
+ * over time (a token bucket per address, refilled at the configured rate
+ * and holding up to one period's worth), each address may only have so many
+ * transfers under way, and so may the server as a whole. Datagrams over the
+ * rate go unanswered; requests over either cap are refused.
+ *
+ * The fourth measure, verification, lives with the transfers themselves: a
+ * read request must negotiate options, so that nothing but an OACK is sent
+ * until the client has acknowledged it, proving that the address is its own.
+ */
+
+/* once this many addresses are being tracked, those that have done nothing
+    for a full period are forgotten */
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum LimitError {
+    InvalidRate(String)
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::InvalidRate(s) =>
+                write!(f, "Invalid rate '{}' (expected <count>[/s|m|h])", s)
+        }
+    }
+}
+
+/* a number of datagrams allowed per period */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rate {
+    count: u32,
+    period: Duration
+}
+
+impl Rate {
+    pub fn new(count: u32, period: Duration) -> Self {
+        Rate {
+            count,
+            period
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/* "<count>" (per second) or "<count>/<s|m|h>" */
+impl FromStr for Rate {
+    type Err = LimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit): (&str, &str) = match s.split_once('/') {
+            Some((count, unit)) => (count, unit),
+            None => (s, "s")
+        };
+
+        let period: Duration = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(LimitError::InvalidRate(s.to_string()))
+        };
+
+        match count.parse::<u32>() {
+            Ok(count) if count > 0 => Ok(Rate::new(count, period)),
+            _ => Err(LimitError::InvalidRate(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.period.as_secs() {
+            60 => write!(f, "{}/m", self.count),
+            3600 => write!(f, "{}/h", self.count),
+            _ => write!(f, "{}/s", self.count)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    rate: Option<Rate>,         /* datagrams per source address */
+    per_client: Option<usize>,  /* concurrent transfers per address */
+    total: Option<usize>,       /* concurrent transfers overall */
+    verify: bool                /* require proof of address before DATA */
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    pub fn rate(&self) -> Option<Rate> {
+        self.rate
+    }
+
+    pub fn per_client(&self) -> Option<usize> {
+        self.per_client
+    }
+
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    pub fn set_rate(&mut self, rate: Option<Rate>) {
+        self.rate = rate;
+    }
+
+    pub fn set_per_client(&mut self, per_client: Option<usize>) {
+        self.per_client = per_client;
+    }
+
+    pub fn set_total(&mut self, total: Option<usize>) {
+        self.total = total;
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+}
+
+/* why a datagram or request was turned away */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    Rate(u64),  /* datagrams dropped since the address was last let in */
+    Client,
+    Total
+}
+
+impl Refusal {
+    /* the label the refusal is counted under */
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Refusal::Rate(_) => "rate",
+            Refusal::Client => "client",
+            Refusal::Total => "total"
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Rate(_) => write!(f, "Request rate exceeded"),
+            Refusal::Client =>
+                write!(f, "Too many transfers from this address"),
+            Refusal::Total => write!(f, "Too many transfers")
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    dropped: u64
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    transfers: HashMap<IpAddr, usize>,
+    total: usize
+}
+
+/* the running tally the limits are enforced against */
+#[derive(Debug, Default)]
+pub struct Limiter {
+    state: Mutex<State>
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter::default()
+    }
+
+    /* takes a token from the source address's bucket, if it has one */
+    pub fn check_rate(&self, limits: &Limits, source: IpAddr) ->
+        Result<(), Refusal> {
+        self.check_rate_at(limits, source, Instant::now())
+    }
+
+    /* as for `check_rate`, as of the given moment */
+    fn check_rate_at(&self, limits: &Limits, source: IpAddr, now: Instant) ->
+        Result<(), Refusal> {
+        let rate: Rate = match limits.rate {
+            Some(rate) => rate,
+            None => return Ok(())
+        };
+
+        let capacity: f64 = rate.count as f64;
+        let per_second: f64 = capacity / rate.period.as_secs_f64();
+        let mut state = self.state.lock().unwrap();
+
+        if state.buckets.len() >= PRUNE_THRESHOLD {
+            state.buckets.retain(|_, bucket| {
+                now.duration_since(bucket.updated) < rate.period
+            });
+        }
+
+        let bucket: &mut Bucket = state.buckets
+            .entry(acl::canonical(source))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                dropped: 0
+            });
+
+        let elapsed: f64 = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            bucket.dropped += 1;
+            return Err(Refusal::Rate(bucket.dropped));
+        }
+
+        bucket.tokens -= 1.0;
+        bucket.dropped = 0;
+
+        Ok(())
+    }
+
+    /* counts a transfer for the client as under way until the returned
+        slot is dropped, provided neither cap has been reached */
+    pub fn admit(self: &Arc<Self>, limits: &Limits, client: IpAddr) ->
+        Result<Slot, Refusal> {
+        let client: IpAddr = acl::canonical(client);
+        let mut state = self.state.lock().unwrap();
+
+        if limits.total.is_some_and(|total| state.total >= total) {
+            return Err(Refusal::Total);
+        }
+
+        let transfers: usize =
+            state.transfers.get(&client).copied().unwrap_or(0);
+
+        if limits.per_client.is_some_and(|limit| transfers >= limit) {
+            return Err(Refusal::Client);
+        }
+
+        state.transfers.insert(client, transfers + 1);
+        state.total += 1;
+
+        Ok(Slot {
+            limiter: self.clone(),
+            client
+        })
+    }
+}
+
+/* a transfer counted against the limits */
+#[derive(Debug)]
+pub struct Slot {
+    limiter: Arc<Limiter>,
+    client: IpAddr
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.total -= 1;
+
+        if let Some(transfers) = state.transfers.get_mut(&self.client) {
+            *transfers -= 1;
+
+            if *transfers == 0 {
+                state.transfers.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rate: Option<&str>, per_client: Option<usize>,
+              total: Option<usize>) -> Limits {
+        let mut limits: Limits = Limits::new();
+        limits.set_rate(rate.map(|rate| rate.parse().unwrap()));
+        limits.set_per_client(per_client);
+        limits.set_total(total);
+        limits
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("10".parse::<Rate>().unwrap(),
+                   Rate::new(10, Duration::from_secs(1)));
+        assert_eq!("30/m".parse::<Rate>().unwrap(),
+                   Rate::new(30, Duration::from_secs(60)));
+        assert_eq!("5/h".parse::<Rate>().unwrap().to_string(), "5/h");
+
+        for rate in ["", "0", "-1", "10/d", "ten/s"] {
+            assert_eq!(rate.parse::<Rate>(),
+                       Err(LimitError::InvalidRate(rate.to_string())));
+        }
+    }
+
+    #[test]
+    fn refills_each_addresss_bucket_at_the_rate() {
+        let limiter: Limiter = Limiter::new();
+        let limits: Limits = limits(Some("4"), None, None);
+        let client: IpAddr = addr("192.0.2.1");
+        let start: Instant = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+
+        /* a full bucket to begin with, and then nothing */
+        for _ in 0..4 {
+            assert_eq!(limiter.check_rate_at(&limits, client, at(0)), Ok(()));
+        }
+
+        assert_eq!(limiter.check_rate_at(&limits, client, at(0)),
+                   Err(Refusal::Rate(1)));
+        assert_eq!(limiter.check_rate_at(&limits, client, at(100)),
+                   Err(Refusal::Rate(2)));
+
+        /* another address has a bucket of its own */
+        assert_eq!(limiter.check_rate_at(&limits, addr("192.0.2.2"), at(100)),
+                   Ok(()));
+
+        /* a token every quarter of a second, and the tally of those dropped
+            starts again once one is let in */
+        assert_eq!(limiter.check_rate_at(&limits, client, at(250)), Ok(()));
+        assert_eq!(limiter.check_rate_at(&limits, client, at(250)),
+                   Err(Refusal::Rate(1)));
+
+        /* but never more than a period's worth */
+        for _ in 0..4 {
+            assert_eq!(limiter.check_rate_at(&limits, client, at(10_000)),
+                       Ok(()));
+        }
+
+        assert!(limiter.check_rate_at(&limits, client, at(10_000)).is_err());
+    }
+
+    #[test]
+    fn takes_an_ipv4_mapped_address_as_its_ipv4_address() {
+        let limiter: Limiter = Limiter::new();
+        let limits: Limits = limits(Some("1/h"), None, None);
+        let now: Instant = Instant::now();
+
+        assert_eq!(limiter.check_rate_at(&limits, addr("192.0.2.1"), now),
+                   Ok(()));
+        assert!(limiter.check_rate_at(&limits, addr("::ffff:192.0.2.1"),
+                                      now).is_err());
+    }
+
+    #[test]
+    fn lets_everything_through_without_a_rate() {
+        let limiter: Limiter = Limiter::new();
+
+        for _ in 0..1000 {
+            assert_eq!(limiter.check_rate(&Limits::new(), addr("192.0.2.1")),
+                       Ok(()));
+        }
+    }
+
+    #[test]
+    fn caps_the_transfers_per_client() {
+        let limiter: Arc<Limiter> = Arc::new(Limiter::new());
+        let limits: Limits = limits(None, Some(2), None);
+        let client: IpAddr = addr("192.0.2.1");
+
+        let first: Slot = limiter.admit(&limits, client).unwrap();
+        let _second: Slot = limiter.admit(&limits, client).unwrap();
+        assert_eq!(limiter.admit(&limits, client).unwrap_err(),
+                   Refusal::Client);
+        assert_eq!(limiter.admit(&limits, addr("::ffff:192.0.2.1"))
+                   .unwrap_err(), Refusal::Client);
+
+        /* others aren't held back, and a slot is freed when dropped */
+        let _other: Slot = limiter.admit(&limits, addr("192.0.2.2")).unwrap();
+        drop(first);
+        let _third: Slot = limiter.admit(&limits, client).unwrap();
+    }
+
+    #[test]
+    fn caps_the_transfers_overall() {
+        let limiter: Arc<Limiter> = Arc::new(Limiter::new());
+        let limits: Limits = limits(None, Some(2), Some(2));
+
+        let first: Slot = limiter.admit(&limits, addr("192.0.2.1")).unwrap();
+        let second: Slot = limiter.admit(&limits, addr("192.0.2.2")).unwrap();
+        assert_eq!(limiter.admit(&limits, addr("192.0.2.3")).unwrap_err(),
+                   Refusal::Total);
+
+        drop(first);
+        let third: Slot = limiter.admit(&limits, addr("192.0.2.3")).unwrap();
+
+        /* once everything is over, nothing is left counted */
+        drop(second);
+        drop(third);
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.total, 0);
+        assert!(state.transfers.is_empty());
+    }
+}
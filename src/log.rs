@@ -0,0 +1,713 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use thiserror::Error;
+use tracing::{info, trace, Level, Metadata};
+use tracing_subscriber::filter::{self, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{self, SubscriberExt};
+use tracing_subscriber::{Layer, Registry};
+
+use crate::conn::TransferError;
+use crate::msg::AnyMessage;
+use crate::pcap;
+use crate::stats::TransferStats;
+
+/*
+ * Where log records go.
+ *
+ * The library reports what it's doing through `tracing`: each transfer runs
+ * in a span naming the client, file and request, inside which progress is
+ * logged at debug level and every datagram sent or received at trace level
+ * under the PACKET target. The outcome of each transfer is logged once,
+ * along with its statistics, at info level under the ACCESS target; these
+ * records make up the access log, which can be given a sink of its own.
+ *
+ * Packet records can likewise be traced to standard error on their own,
+ * whatever the level, so that an exchange can be followed without wading
+ * through everything else.
+ */
+
+pub const ACCESS: &str = "nettlesoup::access";
+pub const PACKET: &str = "nettlesoup::packet";
+
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+/* the facility syslog records are filed under */
+const SYSLOG_DAEMON: u8 = 3;
+
+#[derive(Debug, Error)]
+pub enum LogError {
+    InvalidLevel(String),
+    InvalidFormat(String),
+    Open(PathBuf, io::Error),
+    AlreadyInitialised
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::InvalidLevel(level) =>
+                write!(f, "Invalid log level: {}", level),
+            LogError::InvalidFormat(format) =>
+                write!(f, "Invalid log format: {}", format),
+            LogError::Open(path, e) =>
+                write!(f, "Unable to open log {}: {}", path.display(), e),
+            LogError::AlreadyInitialised =>
+                write!(f, "Logging has already been set up")
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,   /* one human-readable line per record */
+    Json    /* one JSON object per line */
+}
+
+impl FromStr for Format {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(LogError::InvalidFormat(s.to_string()))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    Stdout,
+    Syslog(PathBuf),    /* the local syslog socket */
+    File(PathBuf)       /* appended to */
+}
+
+/* "stdout", "syslog", "syslog:<socket>" or else the path of a file */
+impl FromStr for Sink {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" | "-" => Ok(Sink::Stdout),
+            "syslog" => Ok(Sink::Syslog(PathBuf::from(SYSLOG_SOCKET))),
+            _ => match s.strip_prefix("syslog:") {
+                Some(socket) => Ok(Sink::Syslog(PathBuf::from(socket))),
+                None => Ok(Sink::File(PathBuf::from(s)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Logging {
+    level: LevelFilter,
+    format: Format,
+    sink: Sink,
+    access: Option<Sink>,   /* if set, the access log goes here alone */
+    trace: bool             /* packet records go to standard error */
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging::new()
+    }
+}
+
+impl Logging {
+    pub fn new() -> Self {
+        Logging {
+            level: LevelFilter::WARN,
+            format: Format::Text,
+            sink: Sink::Stdout,
+            access: None,
+            trace: false
+        }
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    pub fn access(&self) -> Option<&Sink> {
+        self.access.as_ref()
+    }
+
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn set_level(&mut self, level: LevelFilter) {
+        self.level = level;
+    }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    pub fn set_sink(&mut self, sink: Sink) {
+        self.sink = sink;
+    }
+
+    pub fn set_access(&mut self, access: Option<Sink>) {
+        self.access = access;
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+}
+
+/* "error", "warn", "info", "debug", "trace" or "off" */
+pub fn parse_level(level: &str) -> Result<LevelFilter, LogError> {
+    level.parse().map_err(|_| LogError::InvalidLevel(level.to_string()))
+}
+
+/* installs the process-wide subscriber; `ident` names the program in syslog
+    records */
+pub fn init(logging: &Logging, ident: &str) -> Result<(), LogError> {
+    tracing::subscriber::set_global_default(
+            Registry::default().with(layers(logging, ident)?))
+        .map_err(|_| LogError::AlreadyInitialised)
+}
+
+type Layers = Vec<Box<dyn Layer<Registry> + Send + Sync>>;
+
+/* the layers making up the subscriber `init` installs */
+fn layers(logging: &Logging, ident: &str) -> Result<Layers, LogError> {
+    let level: LevelFilter = logging.level;
+    let separate: bool = logging.access.is_some();
+    let trace: bool = logging.trace;
+    let mut layers: Layers = Vec::new();
+
+    layers.push(layer(logging.format, Writer::open(&logging.sink, ident)?,
+                      filter::filter_fn(move |metadata| {
+                          *metadata.level() <= level &&
+                              !(separate && metadata.target() == ACCESS) &&
+                              !(trace && metadata.target() == PACKET)
+                      })));
+
+    if let Some(sink) = &logging.access {
+        layers.push(layer(logging.format, Writer::open(sink, ident)?,
+                          filter::filter_fn(|metadata| {
+                              metadata.target() == ACCESS
+                          })));
+    }
+
+    if trace {
+        layers.push(trace_layer(io::stderr));
+    }
+
+    Ok(layers)
+}
+
+/* which way a datagram went */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Sent,
+    Resent,
+    Received
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Flow::Sent => write!(f, "sent"),
+            Flow::Resent => write!(f, "resent"),
+            Flow::Received => write!(f, "received")
+        }
+    }
+}
+
+/* records a datagram sent to or received from a peer over the socket, both
+    in the packet trace (decoded as far as it will go) and in the capture
+    file, if one is being written */
+pub fn packet(flow: Flow, socket: &UdpSocket, peer: SocketAddr,
+              bytes: &[u8]) {
+    if pcap::is_capturing() {
+        if let Ok(local) = socket.local_addr() {
+            match flow {
+                Flow::Received => pcap::record(peer, local, bytes),
+                _ => pcap::record(local, peer, bytes)
+            }
+        }
+    }
+
+    /* spare the decoding when nobody's listening */
+    if !tracing::enabled!(target: PACKET, Level::TRACE) {
+        return;
+    }
+
+    let opcode: Option<u16> = bytes.get(..2)
+        .map(|opcode| u16::from_be_bytes([opcode[0], opcode[1]]));
+
+    match AnyMessage::from_bytes(bytes.to_vec()) {
+        Ok(message) => trace!(target: PACKET, peer = %peer, opcode,
+                              length = bytes.len(), "{} {}", flow, message),
+        Err(e) => trace!(target: PACKET, peer = %peer, opcode,
+                         length = bytes.len(), "{} malformed datagram: {}",
+                         flow, e)
+    }
+}
+
+/* records the outcome of a transfer in the access log, along with its
+    statistics, whether or not it succeeded */
+pub fn access(stats: &TransferStats, result: &Result<(), TransferError>) {
+    let outcome: String = match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string()
+    };
+
+    macro_rules! record {
+        ($($message:tt)+) => {
+            info!(target: ACCESS, client = %stats.peer(),
+                  file = %stats.filename(), direction = %stats.direction(),
+                  result = %outcome, bytes = stats.bytes(),
+                  blocks = stats.blocks(),
+                  retransmissions = stats.retransmissions(),
+                  duplicates = stats.duplicates(),
+                  seconds = stats.duration().as_secs_f64(), $($message)+)
+        };
+    }
+
+    match result {
+        Ok(()) => record!("completed {}", stats),
+        Err(e) => record!("failed {}: {}", stats, e)
+    }
+}
+
+/* packet records alone, timestamped but otherwise bare */
+fn trace_layer<W>(writer: W) -> Box<dyn Layer<Registry> + Send + Sync>
+    where W: for<'a> MakeWriter<'a> + Send + Sync + 'static {
+    tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_level(false)
+        .with_target(false)
+        .with_filter(filter::filter_fn(|metadata| {
+            metadata.target() == PACKET
+        }))
+        .boxed()
+}
+
+fn layer<F>(format: Format, writer: Writer, filter: F) ->
+    Box<dyn Layer<Registry> + Send + Sync>
+    where F: layer::Filter<Registry> + Send + Sync + 'static {
+    /* syslog stamps records itself */
+    let stamped: bool = !matches!(writer, Writer::Syslog(_));
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(writer);
+
+    match (format, stamped) {
+        (Format::Json, _) => layer.json().with_filter(filter).boxed(),
+        (Format::Text, true) => layer.with_filter(filter).boxed(),
+        (Format::Text, false) => layer.without_time().with_filter(filter)
+            .boxed()
+    }
+}
+
+struct Syslog {
+    socket: UnixDatagram,
+    tag: String     /* "ident[pid]" */
+}
+
+impl Syslog {
+    fn send(&self, level: Level, record: &[u8]) {
+        let severity: u8 = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7
+        };
+
+        let mut datagram: Vec<u8> =
+            format!("<{}>{}: ", SYSLOG_DAEMON * 8 + severity, self.tag)
+                .into_bytes();
+        datagram.extend_from_slice(record.strip_suffix(b"\n")
+                                   .unwrap_or(record));
+
+        /* best effort; there's nowhere left to report the failure */
+        let _ = self.socket.send(&datagram);
+    }
+}
+
+#[derive(Clone)]
+enum Writer {
+    Stdout,
+    File(Arc<File>),
+    Syslog(Arc<Syslog>)
+}
+
+impl Writer {
+    fn open(sink: &Sink, ident: &str) -> Result<Self, LogError> {
+        match sink {
+            Sink::Stdout => Ok(Writer::Stdout),
+            Sink::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|file| Writer::File(Arc::new(file)))
+                .map_err(|e| LogError::Open(path.clone(), e)),
+            Sink::Syslog(path) => {
+                let socket: UnixDatagram = UnixDatagram::unbound()
+                    .and_then(|socket| socket.connect(path).map(|_| socket))
+                    .map_err(|e| LogError::Open(path.clone(), e))?;
+
+                Ok(Writer::Syslog(Arc::new(Syslog {
+                    socket,
+                    tag: format!("{}[{}]", ident, process::id())
+                })))
+            }
+        }
+    }
+
+    fn output(&self, level: Level) -> Output<'_> {
+        match self {
+            Writer::Stdout => Output::Stdout(io::stdout()),
+            Writer::File(file) => Output::File(file),
+            Writer::Syslog(syslog) =>
+                Output::Syslog(syslog, level, Vec::new())
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Writer {
+    type Writer = Output<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.output(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> Self::Writer {
+        self.output(*metadata.level())
+    }
+}
+
+/* a single record on its way out; syslog records are gathered up and sent
+    as one datagram once complete */
+enum Output<'a> {
+    Stdout(io::Stdout),
+    File(&'a File),
+    Syslog(&'a Syslog, Level, Vec<u8>)
+}
+
+impl Write for Output<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Syslog(_, _, record) => {
+                record.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+            Output::Syslog(..) => Ok(())
+        }
+    }
+}
+
+impl Drop for Output<'_> {
+    fn drop(&mut self) {
+        if let Output::Syslog(syslog, level, record) = self {
+            if !record.is_empty() {
+                syslog.send(*level, record);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::time::Duration;
+
+    use serde_json::Value;
+    use tracing::{debug, warn};
+
+    use crate::msg::ReadWriteRequestMessageMode;
+    use crate::stats::Direction;
+    use crate::testutil::Scratch;
+
+    /* the sink a test logs to, in its scratch directory */
+    fn sink(scratch: &Scratch) -> Sink {
+        Sink::File(scratch.join("log"))
+    }
+
+    /* what was logged to the sink */
+    fn logged(scratch: &Scratch) -> Vec<String> {
+        fs::read_to_string(scratch.join("log")).unwrap_or_default()
+            .lines().map(str::to_string).collect()
+    }
+
+    /* logs as `init` would have had it, but for the duration of `log`
+        alone */
+    fn with_logging<F: FnOnce()>(logging: &Logging, log: F) {
+        let layers: Layers = layers(logging, "test").unwrap();
+        tracing::subscriber::with_default(Registry::default().with(layers),
+                                          log);
+    }
+
+    fn logging(format: Format, scratch: &Scratch) -> Logging {
+        let mut logging: Logging = Logging::new();
+        logging.set_format(format);
+        logging.set_sink(sink(scratch));
+        logging
+    }
+
+    fn stats() -> TransferStats {
+        let mut stats: TransferStats =
+            TransferStats::new("192.0.2.1:50000".parse().unwrap(),
+                               "boot.img".to_string(), Direction::Read,
+                               ReadWriteRequestMessageMode::Octet);
+        stats.set_bytes(1024);
+        stats.set_blocks(2);
+        stats.set_retransmissions(3);
+        stats.set_duration(Duration::from_secs(2));
+        stats
+    }
+
+    #[test]
+    fn parses_sinks_and_formats() {
+        assert_eq!("stdout".parse::<Sink>().unwrap(), Sink::Stdout);
+        assert_eq!("-".parse::<Sink>().unwrap(), Sink::Stdout);
+        assert_eq!("syslog".parse::<Sink>().unwrap(),
+                   Sink::Syslog(PathBuf::from(SYSLOG_SOCKET)));
+        assert_eq!("syslog:/run/log".parse::<Sink>().unwrap(),
+                   Sink::Syslog(PathBuf::from("/run/log")));
+        assert_eq!("tftpd.log".parse::<Sink>().unwrap(),
+                   Sink::File(PathBuf::from("tftpd.log")));
+
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!(matches!("yaml".parse::<Format>(),
+                         Err(LogError::InvalidFormat(_))));
+        assert!(matches!(parse_level("loud"), Err(LogError::InvalidLevel(_))));
+    }
+
+    #[test]
+    fn writes_text_at_or_above_the_level() {
+        let scratch: Scratch = Scratch::new();
+        let mut logging: Logging = logging(Format::Text, &scratch);
+        logging.set_level(LevelFilter::INFO);
+
+        with_logging(&logging, || {
+            warn!("running low");
+            debug!("not of interest");
+            access(&stats(), &Ok(()));
+        });
+
+        let lines: Vec<String> = logged(&scratch);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("WARN"), "{}", lines[0]);
+        assert!(lines[0].ends_with("running low"), "{}", lines[0]);
+        assert!(lines[1].contains(&format!("{}: completed {}", ACCESS,
+                                           stats())), "{}", lines[1]);
+    }
+
+    #[test]
+    fn access_records_carry_the_statistics() {
+        let scratch: Scratch = Scratch::new();
+        let mut logging: Logging = logging(Format::Text, &scratch);
+        logging.set_level(LevelFilter::INFO);
+
+        with_logging(&logging, || {
+            access(&stats(), &Ok(()));
+            access(&stats(), &Err(TransferError::TimedOut));
+        });
+
+        let lines: Vec<String> = logged(&scratch);
+        assert!(lines[0].contains("result=ok bytes=1024 blocks=2 \
+                                   retransmissions=3 duplicates=0 seconds=2"),
+                "{}", lines[0]);
+        assert!(lines[1].contains(&format!("failed {}: Timed out", stats())),
+                "{}", lines[1]);
+        assert!(lines[1].contains("result=Timed out waiting for peer"),
+                "{}", lines[1]);
+    }
+
+    #[test]
+    fn writes_access_records_as_json_lines() {
+        let scratch: Scratch = Scratch::new();
+        let mut logging: Logging = logging(Format::Json, &scratch);
+        logging.set_level(LevelFilter::INFO);
+
+        with_logging(&logging, || access(&stats(), &Ok(())));
+
+        let lines: Vec<String> = logged(&scratch);
+        assert_eq!(lines.len(), 1);
+
+        let record: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], ACCESS);
+        assert!(record["timestamp"].is_string());
+
+        let fields: &Value = &record["fields"];
+        assert_eq!(fields["message"], format!("completed {}", stats()));
+        assert_eq!(fields["client"], "192.0.2.1:50000");
+        assert_eq!(fields["file"], "boot.img");
+        assert_eq!(fields["direction"], "read");
+        assert_eq!(fields["result"], "ok");
+        assert_eq!(fields["bytes"], 1024);
+        assert_eq!(fields["blocks"], 2);
+        assert_eq!(fields["retransmissions"], 3);
+        assert_eq!(fields["duplicates"], 0);
+        assert_eq!(fields["seconds"], 2.0);
+    }
+
+    #[test]
+    fn separates_the_access_log() {
+        let scratch: Scratch = Scratch::new();
+        let access_scratch: Scratch = Scratch::new();
+        let mut logging: Logging = logging(Format::Text, &scratch);
+        logging.set_access(Some(sink(&access_scratch)));
+
+        /* at the default level, which would otherwise leave the access
+            log out */
+        with_logging(&logging, || {
+            warn!("running low");
+            access(&stats(), &Ok(()));
+        });
+
+        let lines: Vec<String> = logged(&scratch);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("running low"), "{}", lines[0]);
+
+        let lines: Vec<String> = logged(&access_scratch);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("completed"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn sends_syslog_records_as_datagrams() {
+        let scratch: Scratch = Scratch::new();
+        let path: PathBuf = scratch.join("syslog");
+        let socket: UnixDatagram = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut logging: Logging = Logging::new();
+        logging.set_sink(Sink::Syslog(path));
+        logging.set_level(LevelFilter::INFO);
+
+        with_logging(&logging, || {
+            warn!("running low");
+            access(&stats(), &Ok(()));
+        });
+
+        let mut buf: Vec<u8> = vec![0; 4096];
+        let tag: String = format!("test[{}]: ", process::id());
+
+        /* daemon.warning, then daemon.info, each without a timestamp of
+            its own or a trailing newline */
+        let len: usize = socket.recv(&mut buf).unwrap();
+        let record: String = String::from_utf8_lossy(&buf[..len]).into_owned();
+        assert!(record.starts_with(&format!("<28>{} WARN", tag)), "{}", record);
+        assert!(record.ends_with("running low"), "{}", record);
+
+        let len: usize = socket.recv(&mut buf).unwrap();
+        let record: String = String::from_utf8_lossy(&buf[..len]).into_owned();
+        assert!(record.starts_with(&format!("<30>{} INFO", tag)), "{}", record);
+    }
+
+    /* the packet trace of `log`, as it would be printed to standard
+        error */
+    fn traced<F: FnOnce()>(log: F) -> Vec<String> {
+        let scratch: Scratch = Scratch::new();
+        let writer: Writer = Writer::open(&sink(&scratch), "test").unwrap();
+
+        tracing::subscriber::with_default(
+            Registry::default().with(trace_layer(writer)), log);
+
+        logged(&scratch)
+    }
+
+    #[test]
+    fn traces_datagrams_decoded() {
+        let socket: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let rrq: AnyMessage = AnyMessage::Rrq(
+            crate::msg::ReadRequestMessage::with_options(
+                "boot.img".to_string(), ReadWriteRequestMessageMode::Octet,
+                vec![("blksize".to_string(), "1428".to_string())]));
+        let error: AnyMessage = AnyMessage::Error(
+            crate::msg::ErrorMessage::new(1, "File not found".to_string()));
+
+        let lines: Vec<String> = traced(|| {
+            packet(Flow::Received, &socket, peer, &rrq.to_bytes());
+            packet(Flow::Resent, &socket, peer, &error.to_bytes());
+            packet(Flow::Sent, &socket, peer, &[0, 9]);
+            warn!("not a packet record");
+        });
+
+        assert_eq!(lines.len(), 3);
+
+        for line in &lines {
+            /* a timestamp, but neither level nor target */
+            assert!(line.starts_with(char::is_numeric), "{}", line);
+            assert!(!line.contains("TRACE") && !line.contains(PACKET),
+                    "{}", line);
+            assert!(line.contains("peer=192.0.2.1:50000"), "{}", line);
+        }
+
+        assert!(lines[0].contains(&format!("received {} ", rrq)),
+                "{}", lines[0]);
+        assert!(lines[0].ends_with(&format!("opcode=1 length={}",
+                                            rrq.to_bytes().len())),
+                "{}", lines[0]);
+        assert!(lines[1].contains(&format!("resent {}", error)),
+                "{}", lines[1]);
+        assert!(lines[2].contains("sent malformed datagram"), "{}", lines[2]);
+        assert!(lines[2].ends_with("opcode=9 length=2"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn keeps_traced_packets_out_of_the_log() {
+        let scratch: Scratch = Scratch::new();
+        let mut logging: Logging = logging(Format::Text, &scratch);
+        logging.set_level(LevelFilter::TRACE);
+        logging.set_trace(true);
+
+        let socket: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+
+        with_logging(&logging, || {
+            packet(Flow::Sent, &socket, peer, &[0, 4, 0, 1]);
+            debug!("progress");
+        });
+
+        let lines: Vec<String> = logged(&scratch);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("progress"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn fails_to_open_an_unwritable_sink() {
+        let mut logging: Logging = Logging::new();
+        logging.set_sink(Sink::File(PathBuf::from("/nonexistent/tftpd.log")));
+
+        assert!(matches!(layers(&logging, "test"), Err(LogError::Open(..))));
+    }
+}
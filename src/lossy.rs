@@ -0,0 +1,644 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::xfer::RECV_BUFFER_SIZE;
+
+/*
+ * A UDP proxy that stands between a client and a server and mistreats the
+ * datagrams passing through it, for testing how transfers cope with a
+ * network that loses, duplicates, reorders, delays and corrupts them.
+ *
+ * Clients are pointed at the proxy rather than the server. Each client gets
+ * a socket of its own to talk to the server through, and each TID the server
+ * replies from gets a socket of its own to talk to the client through, so
+ * that both ends see the other change TIDs as they would without the proxy.
+ *
+ * Which datagrams fall foul of what is decided by a seeded generator, one
+ * datagram at a time in the order they arrive, so a run can be repeated
+ * exactly for as long as both ends behave the same. Particular datagrams can
+ * also be singled out for a given fault, counting from the first each way,
+ * for reproducing a failure seen in the field.
+ */
+
+/* how often the proxy's threads check whether they should stop */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/* which way a datagram is headed */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Leg {
+    ToServer,
+    ToClient
+}
+
+impl fmt::Display for Leg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Leg::ToServer => write!(f, "to server"),
+            Leg::ToClient => write!(f, "to client")
+        }
+    }
+}
+
+/* what can befall a datagram */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Drop,
+    Duplicate,
+    Reorder,            /* held back until the next datagram the same way */
+    Corrupt,            /* a single bit flipped */
+    Delay(Duration)     /* on top of any other delay */
+}
+
+/* the odds of each fault for datagrams headed one way, along with how long
+    they take to get there */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Impairments {
+    drop: f64,
+    duplicate: f64,
+    reorder: f64,
+    corrupt: f64,
+    delay: Duration,    /* added to every datagram */
+    jitter: Duration    /* up to this much more, at random */
+}
+
+impl Impairments {
+    pub fn new() -> Self {
+        Impairments {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            delay: Duration::from_secs(0),
+            jitter: Duration::from_secs(0)
+        }
+    }
+
+    pub fn drop(&self) -> f64 {
+        self.drop
+    }
+
+    pub fn duplicate(&self) -> f64 {
+        self.duplicate
+    }
+
+    pub fn reorder(&self) -> f64 {
+        self.reorder
+    }
+
+    pub fn corrupt(&self) -> f64 {
+        self.corrupt
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    pub fn set_drop(&mut self, drop: f64) {
+        self.drop = drop;
+    }
+
+    pub fn set_duplicate(&mut self, duplicate: f64) {
+        self.duplicate = duplicate;
+    }
+
+    pub fn set_reorder(&mut self, reorder: f64) {
+        self.reorder = reorder;
+    }
+
+    pub fn set_corrupt(&mut self, corrupt: f64) {
+        self.corrupt = corrupt;
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        self.jitter = jitter;
+    }
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Impairments::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    server: SocketAddr,
+    listen: SocketAddr,
+    seed: u64,
+    to_server: Impairments,
+    to_client: Impairments,
+    script: Vec<(Leg, u64, Fault)>  /* faults for particular datagrams */
+}
+
+impl Config {
+    /* a proxy for the given server, listening on an ephemeral port of the
+        loopback interface and passing everything through untouched */
+    pub fn new(server: SocketAddr) -> Self {
+        let listen: IpAddr = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST)
+        };
+
+        Config {
+            server,
+            listen: SocketAddr::new(listen, 0),
+            seed: 0,
+            to_server: Impairments::new(),
+            to_client: Impairments::new(),
+            script: Vec::new()
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn impairments(&self, leg: Leg) -> &Impairments {
+        match leg {
+            Leg::ToServer => &self.to_server,
+            Leg::ToClient => &self.to_client
+        }
+    }
+
+    /* the faults singled out for particular datagrams, by which way they're
+        headed and where they come among the datagrams headed that way
+        (counting from 1) */
+    pub fn script(&self) -> &[(Leg, u64, Fault)] {
+        &self.script
+    }
+
+    pub fn set_server(&mut self, server: SocketAddr) {
+        self.server = server;
+    }
+
+    pub fn set_listen(&mut self, listen: SocketAddr) {
+        self.listen = listen;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn set_impairments(&mut self, leg: Leg, impairments: Impairments) {
+        match leg {
+            Leg::ToServer => self.to_server = impairments,
+            Leg::ToClient => self.to_client = impairments
+        }
+    }
+
+    /* has the `nth` datagram headed along `leg` suffer `fault`, whatever the
+        odds */
+    pub fn add_fault(&mut self, leg: Leg, nth: u64, fault: Fault) {
+        self.script.push((leg, nth, fault));
+    }
+}
+
+/* what the proxy has done with the datagrams headed one way */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    received: u64,
+    delivered: u64,     /* including duplicates */
+    dropped: u64,
+    duplicated: u64,
+    reordered: u64,
+    corrupted: u64,
+    delayed: u64
+}
+
+impl Tally {
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn duplicated(&self) -> u64 {
+        self.duplicated
+    }
+
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    pub fn corrupted(&self) -> u64 {
+        self.corrupted
+    }
+
+    pub fn delayed(&self) -> u64 {
+        self.delayed
+    }
+}
+
+struct Shared {
+    stopping: AtomicBool,
+    tallies: Mutex<HashMap<Leg, Tally>>
+}
+
+impl Shared {
+    fn count<F: FnOnce(&mut Tally)>(&self, leg: Leg, update: F) {
+        update(self.tallies.lock().unwrap().entry(leg).or_default());
+    }
+}
+
+pub struct Proxy {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Proxy {
+    pub fn start(config: Config) -> io::Result<Self> {
+        let front: UdpSocket = UdpSocket::bind(config.listen())?;
+        let addr: SocketAddr = front.local_addr()?;
+        let shared: Arc<Shared> = Arc::new(Shared {
+            stopping: AtomicBool::new(false),
+            tallies: Mutex::new(HashMap::new())
+        });
+        let (arrivals, received): (Sender<Arrival>, Receiver<Arrival>) =
+            mpsc::channel();
+
+        let mut router: Router = Router {
+            rng: Rng::new(config.seed()),
+            config,
+            addr,
+            shared: shared.clone(),
+            arrivals,
+            sockets: Vec::new(),
+            upstream: HashMap::new(),
+            downstream: HashMap::new(),
+            counts: HashMap::new(),
+            held: HashMap::new(),
+            queue: BTreeMap::new(),
+            sequence: 0
+        };
+        router.add(front, Role::Front)?;
+
+        let thread: JoinHandle<()> =
+            thread::spawn(move || router.run(received));
+
+        Ok(Proxy {
+            addr,
+            shared,
+            thread: Some(thread)
+        })
+    }
+
+    /* where clients should send their requests */
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn tally(&self, leg: Leg) -> Tally {
+        self.shared.tallies.lock().unwrap().get(&leg).copied()
+            .unwrap_or_default()
+    }
+
+    /* stops forwarding, discarding anything still held back or delayed */
+    pub fn stop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/* what each of the proxy's sockets is for */
+#[derive(Clone, Copy, Debug)]
+enum Role {
+    Front,                              /* where requests arrive */
+    Upstream(SocketAddr),               /* talks to the server for a client */
+    Downstream(SocketAddr)              /* talks to clients for a server
+                                            TID */
+}
+
+struct Arrival {
+    socket: usize,
+    from: SocketAddr,
+    bytes: Vec<u8>
+}
+
+#[derive(Clone)]
+struct Outgoing {
+    leg: Leg,
+    socket: usize,
+    to: SocketAddr,
+    bytes: Vec<u8>
+}
+
+/* decides the fate of each datagram and sends those that survive on their
+    way once they're due */
+struct Router {
+    config: Config,
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    arrivals: Sender<Arrival>,
+    sockets: Vec<(Arc<UdpSocket>, Role)>,
+    upstream: HashMap<SocketAddr, usize>,   /* by client */
+    downstream: HashMap<(SocketAddr, SocketAddr), usize>,   /* by client and
+                                                                server TID */
+    rng: Rng,
+    counts: HashMap<Leg, u64>,
+    held: HashMap<Leg, (Instant, Outgoing)>,    /* waiting to be overtaken */
+    queue: BTreeMap<(Instant, u64), Outgoing>,
+    sequence: u64                   /* keeps datagrams due at once in order */
+}
+
+impl Router {
+    fn run(&mut self, received: Receiver<Arrival>) {
+        while !self.shared.stopping.load(Ordering::SeqCst) {
+            let wait: Duration = match self.queue.keys().next() {
+                Some((due, _)) => due.saturating_duration_since(Instant::now())
+                    .min(POLL_INTERVAL),
+                None => POLL_INTERVAL
+            };
+
+            match received.recv_timeout(wait) {
+                Ok(arrival) => self.arrive(arrival),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+
+            self.release(Instant::now());
+        }
+    }
+
+    fn arrive(&mut self, arrival: Arrival) {
+        let route: io::Result<(Leg, usize, SocketAddr)> =
+            match self.sockets[arrival.socket].1 {
+                Role::Front => self.upstream(arrival.from)
+                    .map(|socket| (Leg::ToServer, socket,
+                                   self.config.server())),
+                Role::Upstream(client) => self.downstream(client, arrival.from)
+                    .map(|socket| (Leg::ToClient, socket, client)),
+                /* anyone writing to a server TID is taken for a client,
+                    even if it's not the one the TID belongs to */
+                Role::Downstream(tid) => self.upstream(arrival.from)
+                    .map(|socket| (Leg::ToServer, socket, tid))
+            };
+
+        match route {
+            Ok((leg, socket, to)) => self.impair(Outgoing {
+                leg,
+                socket,
+                to,
+                bytes: arrival.bytes
+            }),
+            Err(e) => debug!("can't forward datagram from {}: {}",
+                             arrival.from, e)
+        }
+    }
+
+    fn impair(&mut self, outgoing: Outgoing) {
+        let leg: Leg = outgoing.leg;
+        let nth: u64 = {
+            let count: &mut u64 = self.counts.entry(leg).or_insert(0);
+            *count += 1;
+            *count
+        };
+        let impairments: Impairments = self.config.impairments(leg).clone();
+
+        /* every draw is made whatever the odds, so that changing one of them
+            doesn't disturb the fates of the rest */
+        let mut drop: bool = self.rng.chance(impairments.drop());
+        let mut duplicate: bool = self.rng.chance(impairments.duplicate());
+        let mut reorder: bool = self.rng.chance(impairments.reorder());
+        let mut corrupt: bool = self.rng.chance(impairments.corrupt());
+        let mut delay: Duration = impairments.delay() +
+            impairments.jitter().mul_f64(self.rng.unit());
+        let bit: u64 = self.rng.next();
+
+        for (_, _, fault) in self.config.script().iter()
+            .filter(|(l, n, _)| *l == leg && *n == nth) {
+            match fault {
+                Fault::Drop => drop = true,
+                Fault::Duplicate => duplicate = true,
+                Fault::Reorder => reorder = true,
+                Fault::Corrupt => corrupt = true,
+                Fault::Delay(extra) => delay += *extra
+            }
+        }
+
+        self.shared.count(leg, |tally| tally.received += 1);
+
+        if drop {
+            debug!("dropped datagram {} {}", nth, leg);
+            self.shared.count(leg, |tally| tally.dropped += 1);
+            return;
+        }
+
+        let mut outgoing: Outgoing = outgoing;
+
+        if corrupt && !outgoing.bytes.is_empty() {
+            let bit: usize = (bit % (outgoing.bytes.len() as u64 * 8)) as usize;
+            outgoing.bytes[bit / 8] ^= 1 << (bit % 8);
+
+            debug!("corrupted datagram {} {} at bit {}", nth, leg, bit);
+            self.shared.count(leg, |tally| tally.corrupted += 1);
+        }
+
+        if delay > Duration::from_secs(0) {
+            self.shared.count(leg, |tally| tally.delayed += 1);
+        }
+
+        let due: Instant = Instant::now() + delay;
+
+        if duplicate {
+            debug!("duplicated datagram {} {}", nth, leg);
+            self.shared.count(leg, |tally| tally.duplicated += 1);
+            self.schedule(due, outgoing.clone());
+        }
+
+        if reorder {
+            debug!("holding back datagram {} {}", nth, leg);
+            self.shared.count(leg, |tally| tally.reordered += 1);
+
+            /* whatever was already held back has been kept waiting long
+                enough */
+            if let Some((due, previous)) = self.held.insert(leg,
+                                                            (due, outgoing)) {
+                self.schedule(due, previous);
+            }
+
+            return;
+        }
+
+        self.schedule(due, outgoing);
+
+        if let Some((held_due, held)) = self.held.remove(&leg) {
+            self.schedule(due.max(held_due), held);
+        }
+    }
+
+    fn schedule(&mut self, due: Instant, outgoing: Outgoing) {
+        self.sequence += 1;
+        self.queue.insert((due, self.sequence), outgoing);
+    }
+
+    /* sends everything due by `now` */
+    fn release(&mut self, now: Instant) {
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > now {
+                return;
+            }
+
+            let outgoing: Outgoing = entry.remove();
+            let socket: &UdpSocket = &self.sockets[outgoing.socket].0;
+
+            /* counted as it's sent, so that whoever receives it sees it in
+                the tally */
+            self.shared.count(outgoing.leg, |tally| {
+                match socket.send_to(&outgoing.bytes, outgoing.to) {
+                    Ok(_) => tally.delivered += 1,
+                    Err(e) => debug!("can't forward datagram to {}: {}",
+                                     outgoing.to, e)
+                }
+            });
+        }
+    }
+
+    /* the socket a client's datagrams are sent to the server from */
+    fn upstream(&mut self, client: SocketAddr) -> io::Result<usize> {
+        if let Some(&socket) = self.upstream.get(&client) {
+            return Ok(socket);
+        }
+
+        let unspecified: IpAddr = match self.config.server() {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket: usize = self.add(
+            UdpSocket::bind(SocketAddr::new(unspecified, 0))?,
+            Role::Upstream(client))?;
+
+        self.upstream.insert(client, socket);
+        Ok(socket)
+    }
+
+    /* the socket a server TID's datagrams are sent to the client from */
+    fn downstream(&mut self, client: SocketAddr, tid: SocketAddr) ->
+        io::Result<usize> {
+        if let Some(&socket) = self.downstream.get(&(client, tid)) {
+            return Ok(socket);
+        }
+
+        let socket: usize = self.add(
+            UdpSocket::bind(SocketAddr::new(self.addr.ip(), 0))?,
+            Role::Downstream(tid))?;
+
+        self.downstream.insert((client, tid), socket);
+        Ok(socket)
+    }
+
+    /* starts listening on a socket, returning what it's known by */
+    fn add(&mut self, socket: UdpSocket, role: Role) -> io::Result<usize> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let id: usize = self.sockets.len();
+        let socket: Arc<UdpSocket> = Arc::new(socket);
+        let listener: Arc<UdpSocket> = socket.clone();
+        let arrivals: Sender<Arrival> = self.arrivals.clone();
+        let shared: Arc<Shared> = self.shared.clone();
+
+        thread::spawn(move || listen(&listener, id, &arrivals, &shared));
+
+        self.sockets.push((socket, role));
+        Ok(id)
+    }
+}
+
+fn listen(socket: &UdpSocket, id: usize, arrivals: &Sender<Arrival>,
+          shared: &Shared) {
+    let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+
+    while !shared.stopping.load(Ordering::SeqCst) {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                let arrival: Arrival = Arrival {
+                    socket: id,
+                    from,
+                    bytes: buf[..len].to_vec()
+                };
+
+                if arrivals.send(arrival).is_err() {
+                    return;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock ||
+                e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => {
+                debug!("proxy socket failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/* SplitMix64, which is plenty for picking faults and, unlike a generator from
+    a crate, won't change its sequence from one release to the next */
+struct Rng {
+    state: u64
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng {
+            state: seed
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z: u64 = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /* uniformly distributed in [0, 1) */
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, odds: f64) -> bool {
+        self.unit() < odds
+    }
+}
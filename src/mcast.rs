@@ -0,0 +1,460 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::Socket;
+use tracing::debug;
+
+use crate::conn::TransferError;
+use crate::log;
+use crate::msg::{AnyMessage, DataMessage, DataMessageBlockNumber,
+                 OptionAcknowledgementMessage, OptionList,
+                 ReadWriteRequestMessageMode};
+use crate::options::{self, MulticastGroup};
+use crate::srv::Config;
+use crate::throttle::Throttle;
+use crate::xfer::{self, RECV_BUFFER_SIZE};
+
+/*
+ * Multicast transfers (RFC 2090).
+ *
+ * Every client reading the same file with the same block size is served by a
+ * single session, which sends each block once to the multicast group. At any
+ * one time exactly one client, the master, acknowledges blocks over unicast
+ * and so sets the pace; the others listen to the group and simply acknowledge
+ * the final block once they have every block. When the master has what it
+ * needs, it leaves and the longest-waiting client is promoted in its place,
+ * asking (by way of its ACKs) for whatever blocks it missed. Blocks sent to
+ * the group, the first time or again, are paced by the master's throttle.
+ *
+ * Since late joiners are sent blocks out of order, a client can't tell one
+ * block from another 65536 blocks further on, so block numbers may not wrap
+ * and a file needing more blocks than that is served by unicast instead.
+ */
+
+/* how often a session wakes to notice new members while idle */
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SessionKey {
+    path: PathBuf,
+    block_size: usize,
+    local: IpAddr       /* the address the session sends from */
+}
+
+/* how a member's part in a session ended */
+type Outcome = Result<(), TransferError>;
+
+/* a client waiting to take part in a session */
+struct Member {
+    peer: SocketAddr,
+    options: OptionList,    /* acknowledged options, bar multicast itself */
+    throttle: Throttle,     /* paces the session while this one is master */
+    done: Sender<Outcome>
+}
+
+/* the sessions currently running, keyed by file, block size and local
+    address */
+pub struct Registry {
+    sessions: Mutex<HashMap<SessionKey, Sender<Member>>>
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            sessions: Mutex::new(HashMap::new())
+        }
+    }
+
+    /* adds a client to the session for the given file, starting one if need
+        be, and waits until the client has either received the whole file or
+        been given up on */
+    #[allow(clippy::too_many_arguments)]
+    pub fn join(registry: &Arc<Registry>, config: &Config, path: &Path,
+                file: File, block_size: usize, options: OptionList,
+                throttle: &Throttle, local: IpAddr, peer: SocketAddr) ->
+        Result<(), TransferError> {
+        let (done, result): (Sender<Outcome>, Receiver<Outcome>) =
+            mpsc::channel();
+        let member: Member = Member {
+            peer,
+            options,
+            throttle: throttle.clone(),
+            done
+        };
+        let key: SessionKey = SessionKey {
+            path: path.to_path_buf(),
+            block_size,
+            local
+        };
+
+        {
+            let mut sessions = registry.sessions.lock().unwrap();
+
+            /* a session that has just finished may not have deregistered
+                yet, in which case the member comes straight back */
+            let member: Option<Member> = match sessions.get(&key) {
+                Some(joins) => joins.send(member).err().map(|e| e.0),
+                None => Some(member)
+            };
+
+            if let Some(member) = member {
+                debug!("starting multicast session for '{}'",
+                       path.display());
+
+                let (joins, queue): (Sender<Member>, Receiver<Member>) =
+                    mpsc::channel();
+                joins.send(member).unwrap();
+
+                let session: Session = Session::new(config, registry.clone(),
+                                                    key.clone(), file, queue)?;
+                sessions.insert(key, joins);
+
+                /* the session outlives the transfer that started it */
+                let span = tracing::info_span!(parent: None, "multicast",
+                                               file = %path.display(),
+                                               blksize = block_size);
+                thread::spawn(move || span.in_scope(|| session.run()));
+            }
+        }
+
+        match result.recv() {
+            Ok(result) => result,
+            Err(_) => Err(TransferError::Io(io::Error::other(
+                        "Multicast session died")))
+        }
+    }
+}
+
+/* why a request for multicast is served by unicast instead */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ineligible {
+    NoGroup,
+    AddressFamily,
+    Mode,
+    TooManyBlocks(u64)      /* the smallest block size that would do */
+}
+
+impl fmt::Display for Ineligible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ineligible::NoGroup => write!(f, "no multicast group configured"),
+            Ineligible::AddressFamily =>
+                write!(f, "the multicast group is of another address family"),
+            Ineligible::Mode => write!(f, "only octet transfers are multicast"),
+            Ineligible::TooManyBlocks(size)
+                if *size <= options::MAX_BLOCK_SIZE as u64 =>
+                write!(f, "the block numbers would wrap (a blksize of at \
+                           least {} would avoid that)", size),
+            Ineligible::TooManyBlocks(_) =>
+                write!(f, "the block numbers would wrap at any blksize")
+        }
+    }
+}
+
+/* whether a read request may be served by a multicast session */
+pub fn eligible(config: &Config, mode: ReadWriteRequestMessageMode,
+                size: u64, block_size: usize, peer: SocketAddr) ->
+    Result<(), Ineligible> {
+    let group: SocketAddr = config.multicast().ok_or(Ineligible::NoGroup)?;
+    let max_blocks: u64 = DataMessageBlockNumber::MAX as u64;
+
+    if group.is_ipv4() != peer.is_ipv4() {
+        Err(Ineligible::AddressFamily)
+    } else if mode != ReadWriteRequestMessageMode::Octet {
+        Err(Ineligible::Mode)
+    } else if size / (block_size as u64) >= max_blocks {
+        /* the last block is the first short one */
+        Err(Ineligible::TooManyBlocks(size / max_blocks + 1))
+    } else {
+        Ok(())
+    }
+}
+
+struct Session {
+    config: Config,
+    registry: Arc<Registry>,
+    key: SessionKey,
+    joins: Receiver<Member>,
+    socket: UdpSocket,
+    group: SocketAddr,
+    file: File,
+    last_block: DataMessageBlockNumber,
+    members: VecDeque<Member>,  /* the front member is the master */
+    sent: Option<Vec<u8>>,      /* what we're awaiting the master's reply to */
+    attempts: usize,
+    deadline: Instant
+}
+
+impl Session {
+    fn new(config: &Config, registry: Arc<Registry>, key: SessionKey,
+           file: File, joins: Receiver<Member>) -> io::Result<Self> {
+        let group: SocketAddr = match config.multicast() {
+            Some(group) => group,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "No multicast group configured"))
+        };
+
+        let socket: UdpSocket =
+            UdpSocket::bind(SocketAddr::new(key.local, 0))?;
+        let socket: UdpSocket = configure(socket, key.local,
+                                          config.multicast_ttl())?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let size: u64 = file.metadata()?.len();
+
+        Ok(Session {
+            config: config.clone(),
+            registry,
+            last_block: (size / key.block_size as u64 + 1) as
+                DataMessageBlockNumber,
+            key,
+            joins,
+            socket,
+            group,
+            file,
+            members: VecDeque::new(),
+            sent: None,
+            attempts: 0,
+            deadline: Instant::now()
+        })
+    }
+
+    fn run(mut self) {
+        let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+
+        loop {
+            while let Ok(member) = self.joins.try_recv() {
+                self.admit(member);
+            }
+
+            if self.members.is_empty() {
+                /* checked again under the lock, so that nobody can join a
+                    session that's about to go away */
+                let mut sessions = self.registry.sessions.lock().unwrap();
+
+                match self.joins.try_recv() {
+                    Ok(member) => {
+                        drop(sessions);
+                        self.admit(member);
+                    },
+                    Err(_) => {
+                        sessions.remove(&self.key);
+                        return;
+                    }
+                }
+            }
+
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    log::packet(log::Flow::Received, &self.socket,
+                                from, &buf[..len]);
+
+                    if let Ok(message) =
+                        AnyMessage::from_bytes(buf[..len].to_vec()) {
+                        self.handle(message, from);
+                    }
+                },
+                Err(e) if xfer::is_timeout(&e) => {},
+                Err(e) => {
+                    self.abandon(&e);
+                    continue;
+                }
+            }
+
+            if !self.members.is_empty() && Instant::now() >= self.deadline {
+                self.retransmit();
+            }
+        }
+    }
+
+    fn admit(&mut self, member: Member) {
+        self.members.push_back(member);
+
+        if self.members.len() == 1 {
+            self.elect();
+        } else {
+            let peer: SocketAddr = self.members.back().unwrap().peer;
+            let _ = self.send_oack(self.members.len() - 1, false);
+
+            debug!(client = %peer, "joined multicast session for '{}'",
+                   self.key.path.display());
+        }
+    }
+
+    /* makes the longest-waiting member the master, if there is one */
+    fn elect(&mut self) {
+        if self.members.is_empty() {
+            return;
+        }
+
+        debug!(client = %self.members[0].peer,
+               "now master of multicast session for '{}'",
+               self.key.path.display());
+
+        self.sent = self.send_oack(0, true).ok();
+        self.attempts = 0;
+        self.deadline = Instant::now() + self.config.timeout();
+    }
+
+    fn handle(&mut self, message: AnyMessage, from: SocketAddr) {
+        let index: usize = match self.members.iter()
+            .position(|member| member.peer == from) {
+            Some(index) => index,
+            None => {
+                xfer::reject_tid(&self.socket, from);
+                return;
+            }
+        };
+
+        match message {
+            AnyMessage::Ack(ack) if ack.block_num() == self.last_block => {
+                self.finish(index, Ok(()));
+
+                if index == 0 {
+                    self.elect();
+                }
+            },
+            /* the master asks for the block after the last one it holds */
+            AnyMessage::Ack(ack) if index == 0 &&
+                ack.block_num() < self.last_block => {
+                match self.send_block(ack.block_num() + 1) {
+                    Ok(bytes) => {
+                        self.sent = Some(bytes);
+                        self.attempts = 0;
+                        self.deadline = Instant::now() + self.config.timeout();
+                    },
+                    Err(e) => self.abandon(&e)
+                }
+            },
+            AnyMessage::Error(e) => {
+                self.finish(index, Err(TransferError::Aborted(e)));
+
+                if index == 0 {
+                    self.elect();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn retransmit(&mut self) {
+        self.attempts += 1;
+
+        if self.attempts > self.config.retries() {
+            self.finish(0, Err(TransferError::TimedOut));
+            self.elect();
+            return;
+        }
+
+        if let Some(bytes) = &self.sent {
+            /* an OACK goes to the master alone, a block to the whole group */
+            let target: SocketAddr = match AnyMessage::from_bytes(bytes.clone())
+            {
+                Ok(AnyMessage::Data(data)) => {
+                    self.pace(data.data().len());
+                    self.group
+                },
+                _ => self.members[0].peer
+            };
+
+            if self.socket.send_to(bytes, target).is_ok() {
+                log::packet(log::Flow::Resent, &self.socket, target, bytes);
+            }
+        }
+
+        self.deadline = Instant::now() + self.config.timeout();
+    }
+
+    fn send_oack(&self, index: usize, master: bool) -> io::Result<Vec<u8>> {
+        let member: &Member = &self.members[index];
+        let mut options: OptionList = member.options.clone();
+        options.push((options::MULTICAST.to_string(),
+                      MulticastGroup::new(self.group, master).to_string()));
+
+        let message: AnyMessage =
+            AnyMessage::Oack(OptionAcknowledgementMessage::new(options));
+        let bytes: Vec<u8> = message.to_bytes();
+        self.socket.send_to(&bytes, member.peer)?;
+        log::packet(log::Flow::Sent, &self.socket, member.peer, &bytes);
+
+        Ok(bytes)
+    }
+
+    fn send_block(&mut self, block: DataMessageBlockNumber) ->
+        io::Result<Vec<u8>> {
+        let offset: u64 = (block as u64 - 1) * self.key.block_size as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let data: Vec<u8> = xfer::read_block(&mut self.file,
+                                             self.key.block_size)?;
+        self.pace(data.len());
+
+        let message: AnyMessage =
+            AnyMessage::Data(DataMessage::new(block, data));
+        let bytes: Vec<u8> = message.to_bytes();
+        self.socket.send_to(&bytes, self.group)?;
+        log::packet(log::Flow::Sent, &self.socket, self.group, &bytes);
+
+        Ok(bytes)
+    }
+
+    /* waits until the master's buckets allow `bytes` more to be sent */
+    fn pace(&self, bytes: usize) {
+        if let Some(master) = self.members.front() {
+            master.throttle.pace(bytes);
+        }
+    }
+
+    fn finish(&mut self, index: usize, result: Outcome) {
+        if let Some(member) = self.members.remove(index) {
+            let _ = member.done.send(result);
+        }
+    }
+
+    /* fails every member after an error that leaves the session unable to
+        carry on */
+    fn abandon(&mut self, e: &io::Error) {
+        while !self.members.is_empty() {
+            let error: io::Error = io::Error::new(e.kind(), e.to_string());
+            self.finish(0, Err(xfer::reject_io(error)));
+        }
+    }
+}
+
+/* sets the outgoing interface, scope and loopback behaviour of the session
+    socket */
+fn configure(socket: UdpSocket, local: IpAddr, ttl: u32) ->
+    io::Result<UdpSocket> {
+    let socket: Socket = Socket::from(socket);
+
+    match local {
+        IpAddr::V4(addr) => {
+            if !addr.is_unspecified() {
+                socket.set_multicast_if_v4(&addr)?;
+            }
+
+            socket.set_multicast_ttl_v4(ttl)?;
+            socket.set_multicast_loop_v4(true)?;
+        },
+        IpAddr::V6(_) => {
+            socket.set_multicast_hops_v6(ttl)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+
+    Ok(socket.into_udp_socket())
+}
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Take, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::conn::TransferError;
+use crate::limit::Refusal;
+use crate::msg::ErrorMessageCode;
+use crate::stats::{Direction, TransferStats};
+
+/*
+ * Server metrics, exposed over HTTP in the Prometheus text format.
+ *
+ * The exporter is deliberately minimal: it answers GET /metrics, one
+ * connection at a time, and nothing else. Since one slow scraper holds up
+ * the rest, each has a few seconds and a few kilobytes in which to make its
+ * request.
+ */
+
+/* upper bounds, in seconds, of the transfer duration histogram buckets */
+const DURATION_BUCKETS: [f64; 10] =
+    [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/* how long a scraper has to send its request, all of it */
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* the most of a request, headers and all, that is read before giving up */
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: [u64; DURATION_BUCKETS.len()],  /* not cumulative */
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter()
+            .position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    errors: Mutex<BTreeMap<ErrorMessageCode, u64>>,
+    limited: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    active: AtomicU64,
+    retransmissions: AtomicU64,
+    durations: Mutex<Histogram>
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /* notes a transfer as under way until the returned guard is dropped */
+    pub fn start(&self) -> Active<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active { metrics: self }
+    }
+
+    /* notes an error message sent to a client */
+    pub fn error_sent(&self, code: ErrorMessageCode) {
+        *self.errors.lock().unwrap().entry(code).or_insert(0) += 1;
+    }
+
+    /* notes a datagram or request turned away by the limits */
+    pub fn limited(&self, refusal: Refusal) {
+        *self.limited.lock().unwrap().entry(refusal.reason()).or_insert(0) +=
+            1;
+    }
+
+    /* notes the outcome of a read or write request */
+    pub fn finish(&self, stats: &TransferStats,
+                  result: &Result<(), TransferError>) {
+        let kind: &'static str = match stats.direction() {
+            Direction::Read => "rrq",
+            Direction::Write => "wrq"
+        };
+
+        *self.requests.lock().unwrap().entry((kind, outcome(result)))
+            .or_insert(0) += 1;
+
+        let bytes: &AtomicU64 = match stats.direction() {
+            Direction::Read => &self.bytes_read,
+            Direction::Write => &self.bytes_written
+        };
+
+        bytes.fetch_add(stats.bytes(), Ordering::Relaxed);
+        self.retransmissions.fetch_add(stats.retransmissions(),
+                                       Ordering::Relaxed);
+        self.durations.lock().unwrap()
+            .observe(stats.duration().as_secs_f64());
+    }
+
+    /* renders every metric in the Prometheus text exposition format */
+    pub fn render(&self) -> String {
+        let mut out: String = String::new();
+
+        /* writing to a String can't fail */
+        let _ = self.render_into(&mut out);
+
+        out
+    }
+
+    fn render_into(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "# HELP tftp_requests_total Read and write requests \
+                       handled, by type and result.")?;
+        writeln!(out, "# TYPE tftp_requests_total counter")?;
+        for ((kind, result), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "tftp_requests_total{{type=\"{}\",result=\"{}\"}} \
+                           {}", kind, result, count)?;
+        }
+
+        writeln!(out, "# HELP tftp_errors_sent_total Error messages sent to \
+                       clients, by error code.")?;
+        writeln!(out, "# TYPE tftp_errors_sent_total counter")?;
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            writeln!(out, "tftp_errors_sent_total{{code=\"{}\"}} {}", code,
+                     count)?;
+        }
+
+        writeln!(out, "# HELP tftp_requests_limited_total Datagrams dropped \
+                       and requests refused by the rate and transfer \
+                       limits, by reason.")?;
+        writeln!(out, "# TYPE tftp_requests_limited_total counter")?;
+        for (reason, count) in self.limited.lock().unwrap().iter() {
+            writeln!(out, "tftp_requests_limited_total{{reason=\"{}\"}} {}",
+                     reason, count)?;
+        }
+
+        writeln!(out, "# HELP tftp_bytes_total File data transferred, by \
+                       direction.")?;
+        writeln!(out, "# TYPE tftp_bytes_total counter")?;
+        writeln!(out, "tftp_bytes_total{{direction=\"read\"}} {}",
+                 self.bytes_read.load(Ordering::Relaxed))?;
+        writeln!(out, "tftp_bytes_total{{direction=\"write\"}} {}",
+                 self.bytes_written.load(Ordering::Relaxed))?;
+
+        writeln!(out, "# HELP tftp_active_transfers Transfers currently in \
+                       progress.")?;
+        writeln!(out, "# TYPE tftp_active_transfers gauge")?;
+        writeln!(out, "tftp_active_transfers {}",
+                 self.active.load(Ordering::Relaxed))?;
+
+        writeln!(out, "# HELP tftp_retransmissions_total Packets sent again \
+                       after a timeout.")?;
+        writeln!(out, "# TYPE tftp_retransmissions_total counter")?;
+        writeln!(out, "tftp_retransmissions_total {}",
+                 self.retransmissions.load(Ordering::Relaxed))?;
+
+        let durations = self.durations.lock().unwrap();
+        let mut cumulative: u64 = 0;
+
+        writeln!(out, "# HELP tftp_transfer_duration_seconds Time taken by \
+                       each request, successful or not.")?;
+        writeln!(out, "# TYPE tftp_transfer_duration_seconds histogram")?;
+        for (bound, count) in DURATION_BUCKETS.iter()
+            .zip(durations.counts.iter()) {
+            cumulative += count;
+            writeln!(out, "tftp_transfer_duration_seconds_bucket\
+                           {{le=\"{}\"}} {}", bound, cumulative)?;
+        }
+        writeln!(out, "tftp_transfer_duration_seconds_bucket{{le=\"+Inf\"}} \
+                       {}", durations.count)?;
+        writeln!(out, "tftp_transfer_duration_seconds_sum {}", durations.sum)?;
+        writeln!(out, "tftp_transfer_duration_seconds_count {}",
+                 durations.count)
+    }
+}
+
+/* a transfer in progress */
+pub struct Active<'a> {
+    metrics: &'a Metrics
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.metrics.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/* the result label recorded for a request */
+fn outcome(result: &Result<(), TransferError>) -> &'static str {
+    match result {
+        Ok(()) => "ok",
+        Err(TransferError::Rejected(..)) => "rejected",
+        Err(TransferError::Aborted(_)) => "aborted",
+        Err(TransferError::TimedOut) => "timed_out",
+        Err(TransferError::Io(_)) => "failed"
+    }
+}
+
+/* answers scrapes until the listener fails */
+pub fn serve(listener: TcpListener, metrics: &Metrics) -> io::Result<()> {
+    loop {
+        let (stream, _): (TcpStream, SocketAddr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+
+        /* a scraper that goes wrong is its own problem */
+        let _ = respond(stream, metrics);
+    }
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut reader: BufReader<Take<Deadline>> = BufReader::new(
+        Deadline::new(&stream, REQUEST_TIMEOUT).take(MAX_REQUEST_SIZE));
+    let mut request_line: String = String::new();
+    read_line(&mut reader, &mut request_line)?;
+
+    /* the headers are of no interest, but are read so that closing the
+        connection doesn't reset it */
+    let mut header: String = String::new();
+    while read_line(&mut reader, &mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+
+    let (status, body): (&str, String) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string())
+    };
+
+    let mut stream: &TcpStream = &stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+/* reads a line of the request, failing should the request end (or reach
+    the limit on its size) first */
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) ->
+    io::Result<usize> {
+    let len: usize = reader.read_line(line)?;
+
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "Request incomplete or too large"));
+    }
+
+    Ok(len)
+}
+
+/* a connection that may only be read from until a deadline, however slowly
+    the other end trickles in its request */
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant
+}
+
+impl<'a> Deadline<'a> {
+    fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        Deadline {
+            stream,
+            deadline: Instant::now() + timeout
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining: Duration =
+            self.deadline.saturating_duration_since(Instant::now());
+
+        if remaining == Duration::ZERO {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      "Request took too long"));
+        }
+
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /* an endpoint serving metrics that live as long as the test binary */
+    fn endpoint() -> SocketAddr {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+
+        thread::spawn(move || serve(listener, metrics));
+        addr
+    }
+
+    /* sends the request given and reads what comes back until the endpoint
+        closes the connection */
+    fn scrape(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream: TcpStream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT / 2)).unwrap();
+        /* the endpoint may hang up before it's been sent everything */
+        let _ = stream.write_all(request);
+
+        let mut response: Vec<u8> = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn answers_a_scrape() {
+        let response: String = scrape(endpoint(),
+                                      b"GET /metrics HTTP/1.1\r\n\
+                                        Host: localhost\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE tftp_requests_total counter"));
+    }
+
+    #[test]
+    fn gives_up_on_an_endless_header() {
+        let addr: SocketAddr = endpoint();
+        let mut request: Vec<u8> = b"GET /metrics HTTP/1.1\r\nX-Pad: ".to_vec();
+        request.resize(2 * MAX_REQUEST_SIZE as usize, b'a');
+
+        /* the connection is dropped once the limit is reached, without
+            waiting out the deadline */
+        let started: Instant = Instant::now();
+        assert!(!scrape(addr, &request).starts_with("HTTP"));
+        assert!(started.elapsed() < REQUEST_TIMEOUT / 2);
+
+        /* and the endpoint is free for the next scraper */
+        assert!(scrape(addr, b"GET /metrics HTTP/1.1\r\n\r\n")
+                .starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn gives_up_on_a_request_that_never_ends() {
+        let mut reader: BufReader<Take<&[u8]>> =
+            BufReader::new(b"GET /metrics HTTP/1.1".take(MAX_REQUEST_SIZE));
+        let mut line: String = String::new();
+
+        assert_eq!(read_line(&mut reader, &mut line).unwrap_err().kind(),
+                   io::ErrorKind::InvalidData);
+    }
+}
@@ -12,33 +12,63 @@ pub enum MessageType {
     WriteRequest,
     Data,
     Acknowledgement,
-    Error
+    Error,
+    OptionAcknowledgement
 }
 
 impl MessageType {
-    fn to_opcode(msg_type: Self) -> MessageOpcode {
+    pub fn to_opcode(msg_type: Self) -> MessageOpcode {
         match msg_type {
             MessageType::ReadRequest => 0x01,
             MessageType::WriteRequest => 0x02,
             MessageType::Data => 0x03,
             MessageType::Acknowledgement => 0x04,
-            MessageType::Error => 0x05
+            MessageType::Error => 0x05,
+            MessageType::OptionAcknowledgement => 0x06
         }
     }
 
-    fn from_opcode(opcode: MessageOpcode) -> Option<Self> {
+    pub fn from_opcode(opcode: MessageOpcode) -> Option<Self> {
         match opcode {
             0x01 => Some(MessageType::ReadRequest),
             0x02 => Some(MessageType::WriteRequest),
             0x03 => Some(MessageType::Data),
             0x04 => Some(MessageType::Acknowledgement),
             0x05 => Some(MessageType::Error),
+            0x06 => Some(MessageType::OptionAcknowledgement),
             _ => None
         }
     }
+
+    /* the type of each message, which is left out when it's serialised as
+        it follows from the opcode (see dump) */
+    fn read_request() -> Self {
+        MessageType::ReadRequest
+    }
+
+    fn write_request() -> Self {
+        MessageType::WriteRequest
+    }
+
+    fn data() -> Self {
+        MessageType::Data
+    }
+
+    fn acknowledgement() -> Self {
+        MessageType::Acknowledgement
+    }
+
+    fn error() -> Self {
+        MessageType::Error
+    }
+
+    fn option_acknowledgement() -> Self {
+        MessageType::OptionAcknowledgement
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ReadWriteRequestMessageMode {
     NetAscii,
     Octet,
@@ -46,7 +76,7 @@ pub enum ReadWriteRequestMessageMode {
 }
 
 impl ReadWriteRequestMessageMode {
-    fn to_string(mode: Self) -> String {
+    pub fn to_string(mode: Self) -> String {
         match mode {
             ReadWriteRequestMessageMode::NetAscii => "netascii".to_string(),
             ReadWriteRequestMessageMode::Octet => "octet".to_string(),
@@ -54,7 +84,7 @@ impl ReadWriteRequestMessageMode {
         }
     }
 
-    fn from_string(mode_string: String) ->
+    pub fn from_string(mode_string: String) ->
     Option<Self> {
         match mode_string.as_str() {
             "netascii" | "NETASCII" | "NetAscii" =>
@@ -78,7 +108,8 @@ pub enum ParseError {
     InvalidMode,
     InvalidErrorCode,
     NoErrorMessage,
-    InvalidErrorMessage 
+    InvalidErrorMessage,
+    InvalidOption
 }
 
 impl fmt::Display for ParseError {
@@ -106,13 +137,19 @@ impl fmt::Display for ParseError {
                  message string when it should have one",
             ParseError::InvalidErrorMessage => "Specified message has an  \
                 invalid error message string (likely improperly terminated \
-                 or contains forbidden characters)"
+                 or contains forbidden characters)",
+            ParseError::InvalidOption => "Specified message has an invalid \
+                 option (likely improperly terminated or lacking a value)"
         };
 
         write!(f, "{}", msg)
     }
 }
 
+/* options as per RFC 2347, as (name, value) pairs in the order they appear on
+    the wire */
+pub type OptionList = Vec<(String, String)>;
+
 pub trait Message {
     fn opcode(&self) -> MessageOpcode;
     fn to_bytes(&self) -> Vec<u8>;
@@ -123,17 +160,25 @@ pub trait Message {
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ReadRequestMessage {
+    #[serde(skip, default = "MessageType::read_request")]
     msg_type: MessageType,
     filename: String,
-    mode: ReadWriteRequestMessageMode
+    mode: ReadWriteRequestMessageMode,
+    options: OptionList
 }
 
 impl ReadRequestMessage {
     pub fn new(filename: String, mode: ReadWriteRequestMessageMode) -> Self {
+        ReadRequestMessage::with_options(filename, mode, Vec::new())
+    }
+
+    pub fn with_options(filename: String, mode: ReadWriteRequestMessageMode,
+                        options: OptionList) -> Self {
         ReadRequestMessage {
             msg_type: MessageType::ReadRequest,
-            filename: filename.clone(),
-            mode: mode
+            filename,
+            mode,
+            options
         }
     }
 
@@ -144,6 +189,10 @@ impl ReadRequestMessage {
     pub fn mode(&self) -> ReadWriteRequestMessageMode {
         self.mode
     }
+
+    pub fn options(&self) -> OptionList {
+        self.options.clone()
+    }
 }
 
 impl Message for ReadRequestMessage {
@@ -170,6 +219,11 @@ impl Message for ReadRequestMessage {
             bytes.push(ch);
         }
 
+        bytes.push(b'\0'); /* null terminate */
+
+        /* options */
+        write_options(&mut bytes, &self.options);
+
         bytes
     }
 
@@ -204,7 +258,7 @@ impl Message for ReadRequestMessage {
         /* iterate over bytes, grabbing characters until null byte (we can do
             this because of the encoding of TFTP strings) */
         while curr_char != '\0' {
-            if c >= bytes.len() { /* bounds check */
+            if c + 1 >= bytes.len() { /* bounds check */
                 return Err(ParseError::InvalidFilename);
             }
 
@@ -228,7 +282,7 @@ impl Message for ReadRequestMessage {
         /* iterate over bytes, grabbing characters until null byte (we can do
             this because of the encoding of TFTP strings) */
         while curr_char != '\0' {
-            if c >= bytes.len() { /* bounds check */
+            if c + 1 >= bytes.len() { /* bounds check */
                 if mode_string.len() == 0 {
                     return Err(ParseError::NoMode);
                 } else {
@@ -260,9 +314,12 @@ impl Message for ReadRequestMessage {
             return Err(ParseError::InvalidMode);
         }
         
+        /* parse options (RFC 2347) */
+        let options: OptionList = parse_options(&bytes[c + 1..])?;
+
         /* actually construct the message object */ 
         let message: ReadRequestMessage =
-            ReadRequestMessage::new(filename, mode.unwrap());
+            ReadRequestMessage::with_options(filename, mode.unwrap(), options);
 
         Ok(message)
     }
@@ -272,17 +329,25 @@ impl Message for ReadRequestMessage {
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WriteRequestMessage {
+    #[serde(skip, default = "MessageType::write_request")]
     msg_type: MessageType,
     filename: String,
-    mode: ReadWriteRequestMessageMode
+    mode: ReadWriteRequestMessageMode,
+    options: OptionList
 }
 
 impl WriteRequestMessage {
     pub fn new(filename: String, mode: ReadWriteRequestMessageMode) -> Self {
+        WriteRequestMessage::with_options(filename, mode, Vec::new())
+    }
+
+    pub fn with_options(filename: String, mode: ReadWriteRequestMessageMode,
+                        options: OptionList) -> Self {
         WriteRequestMessage {
             msg_type: MessageType::WriteRequest,
-            filename: filename.clone(),
-            mode: mode
+            filename,
+            mode,
+            options
         }
     }
 
@@ -293,6 +358,10 @@ impl WriteRequestMessage {
     pub fn mode(&self) -> ReadWriteRequestMessageMode {
         self.mode
     }
+
+    pub fn options(&self) -> OptionList {
+        self.options.clone()
+    }
 }
 
 impl Message for WriteRequestMessage {
@@ -319,6 +388,11 @@ impl Message for WriteRequestMessage {
             bytes.push(ch);
         }
 
+        bytes.push(b'\0'); /* null terminate */
+
+        /* options */
+        write_options(&mut bytes, &self.options);
+
         bytes
     }
 
@@ -353,7 +427,7 @@ impl Message for WriteRequestMessage {
         /* iterate over bytes, grabbing characters until null byte (we can do
             this because of the encoding of TFTP strings) */
         while curr_char != '\0' {
-            if c >= bytes.len() { /* bounds check */
+            if c + 1 >= bytes.len() { /* bounds check */
                 return Err(ParseError::InvalidFilename);
             }
 
@@ -377,7 +451,7 @@ impl Message for WriteRequestMessage {
         /* iterate over bytes, grabbing characters until null byte (we can do
             this because of the encoding of TFTP strings) */
         while curr_char != '\0' {
-            if c >= bytes.len() { /* bounds check */
+            if c + 1 >= bytes.len() { /* bounds check */
                 if mode_string.len() == 0 {
                     return Err(ParseError::NoMode);
                 } else {
@@ -409,9 +483,12 @@ impl Message for WriteRequestMessage {
             return Err(ParseError::InvalidMode);
         }
         
+        /* parse options (RFC 2347) */
+        let options: OptionList = parse_options(&bytes[c + 1..])?;
+
         /* actually construct the message object */ 
         let message: WriteRequestMessage =
-            WriteRequestMessage::new(filename, mode.unwrap());
+            WriteRequestMessage::with_options(filename, mode.unwrap(), options);
 
         Ok(message)
     }
@@ -421,10 +498,15 @@ impl Message for WriteRequestMessage {
 
 pub type DataMessageBlockNumber = u16;
 
+/* the largest block size negotiable via the blksize option (RFC 2348) */
+pub const MAX_BLOCK_SIZE: usize = 65464;
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DataMessage {
+    #[serde(skip, default = "MessageType::data")]
     msg_type: MessageType,
     block_num: DataMessageBlockNumber,
+    #[serde(with = "serde_bytes")]
     data: Vec<u8>
 }
 
@@ -468,11 +550,11 @@ impl Message for DataMessage {
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Result<Self, ParseError> {
-        if bytes.len() < 5 { /* bounds check */
+        if bytes.len() < 4 { /* bounds check */
             return Err(ParseError::TooShort);
         }
 
-        if bytes.len() > 516 { /* bounds check */
+        if bytes.len() > 4 + MAX_BLOCK_SIZE { /* bounds check */
             return Err(ParseError::TooLong);
         }
 
@@ -511,6 +593,7 @@ impl Message for DataMessage {
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AcknowledgementMessage {
+    #[serde(skip, default = "MessageType::acknowledgement")]
     msg_type: MessageType,
     block_num: DataMessageBlockNumber
 }
@@ -586,8 +669,20 @@ impl Message for AcknowledgementMessage {
 
 pub type ErrorMessageCode = u16;
 
+/* error codes as defined by RFC 1350 */
+pub const ERROR_NOT_DEFINED: ErrorMessageCode = 0;
+pub const ERROR_FILE_NOT_FOUND: ErrorMessageCode = 1;
+pub const ERROR_ACCESS_VIOLATION: ErrorMessageCode = 2;
+pub const ERROR_DISK_FULL: ErrorMessageCode = 3;
+pub const ERROR_ILLEGAL_OPERATION: ErrorMessageCode = 4;
+pub const ERROR_UNKNOWN_TID: ErrorMessageCode = 5;
+pub const ERROR_FILE_EXISTS: ErrorMessageCode = 6;
+pub const ERROR_NO_SUCH_USER: ErrorMessageCode = 7;
+pub const ERROR_OPTION_NEGOTIATION: ErrorMessageCode = 8; /* RFC 2347 */
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ErrorMessage {
+    #[serde(skip, default = "MessageType::error")]
     msg_type: MessageType,
     code: ErrorMessageCode,
     message: String
@@ -662,10 +757,11 @@ impl Message for ErrorMessage {
         let error_code: ErrorMessageCode = ((bytes[2] as u16) << 8) |
                                                 bytes[3] as u16;
 
-        /* parse error message */
+        /* parse error message (the low byte of the error code may well be
+            zero, so it can't be used to prime the loop) */
         let mut error_msg: String = String::new();
         let mut c: usize = 3;
-        let mut curr_char: char = bytes[c] as char;
+        let mut curr_char: char = '\x01';
 
         if c + 2 >= bytes.len() { /* bounds check */
             return Err(ParseError::NoErrorMessage);
@@ -696,14 +792,213 @@ impl Message for ErrorMessage {
     }
 }
 
+/************************** OPTION ACKNOWLEDGEMENT ****************************/
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OptionAcknowledgementMessage {
+    #[serde(skip, default = "MessageType::option_acknowledgement")]
+    msg_type: MessageType,
+    options: OptionList
+}
+
+impl OptionAcknowledgementMessage {
+    pub fn new(options: OptionList) -> Self {
+        OptionAcknowledgementMessage {
+            msg_type: MessageType::OptionAcknowledgement,
+            options
+        }
+    }
+
+    pub fn options(&self) -> OptionList {
+        self.options.clone()
+    }
+}
+
+impl Message for OptionAcknowledgementMessage {
+    fn opcode(&self) -> MessageOpcode {
+        MessageType::to_opcode(self.msg_type)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        /* opcode */
+        bytes.extend_from_slice(
+            &MessageType::to_opcode(self.msg_type).to_be_bytes());
+
+        /* options */
+        write_options(&mut bytes, &self.options);
+
+        bytes
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ParseError> {
+        if bytes.len() < 2 { /* bounds check */
+            return Err(ParseError::TooShort);
+        }
+
+        /* parse opcode */
+        let opcode: MessageOpcode = ((bytes[0] as u16) << 8) |
+                                            bytes[1] as u16;
+
+        /* check the opcode actually matches the message type */
+        if MessageType::from_opcode(opcode) !=
+            Some(MessageType::OptionAcknowledgement) {
+            return Err(ParseError::InvalidOpcode);
+        }
+
+        let options: OptionList = parse_options(&bytes[2..])?;
+
+        Ok(OptionAcknowledgementMessage::new(options))
+    }
+}
+
+/* appends each option as a pair of null-terminated strings */
+fn write_options(bytes: &mut Vec<u8>, options: &[(String, String)]) {
+    for (name, value) in options {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(b'\0'); /* null terminate */
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(b'\0'); /* null terminate */
+    }
+}
+
+/* parses a (possibly empty) sequence of null-terminated name and value
+    strings */
+fn parse_options(bytes: &[u8]) -> Result<OptionList, ParseError> {
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    /* every string must be terminated, including the last */
+    if bytes[bytes.len() - 1] != b'\0' {
+        return Err(ParseError::InvalidOption);
+    }
+
+    let strings: Vec<String> = bytes[..bytes.len() - 1]
+        .split(|&byte| byte == b'\0')
+        .map(|string| string.iter().map(|&byte| byte as char).collect())
+        .collect();
+
+    let pairs = strings.chunks_exact(2);
+
+    if !pairs.remainder().is_empty() { /* name without a value */
+        return Err(ParseError::InvalidOption);
+    }
+
+    let mut options: OptionList = Vec::new();
+
+    for pair in pairs {
+        if pair[0].is_empty() {
+            return Err(ParseError::InvalidOption);
+        }
+
+        options.push((pair[0].clone(), pair[1].clone()));
+    }
+
+    Ok(options)
+}
+
 /******************************************************************************/
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "opcode", rename_all = "UPPERCASE")]
 pub enum AnyMessage {
     Rrq(ReadRequestMessage),
     Wrq(WriteRequestMessage),
     Data(DataMessage),
     Ack(AcknowledgementMessage),
-    Error(ErrorMessage)
+    Error(ErrorMessage),
+    Oack(OptionAcknowledgementMessage)
+}
+
+
+impl AnyMessage {
+    pub fn msg_type(&self) -> MessageType {
+        match self {
+            AnyMessage::Rrq(_) => MessageType::ReadRequest,
+            AnyMessage::Wrq(_) => MessageType::WriteRequest,
+            AnyMessage::Data(_) => MessageType::Data,
+            AnyMessage::Ack(_) => MessageType::Acknowledgement,
+            AnyMessage::Error(_) => MessageType::Error,
+            AnyMessage::Oack(_) => MessageType::OptionAcknowledgement
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            AnyMessage::Rrq(message) => message.to_bytes(),
+            AnyMessage::Wrq(message) => message.to_bytes(),
+            AnyMessage::Data(message) => message.to_bytes(),
+            AnyMessage::Ack(message) => message.to_bytes(),
+            AnyMessage::Error(message) => message.to_bytes(),
+            AnyMessage::Oack(message) => message.to_bytes()
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ParseError> {
+        if bytes.len() < 2 { /* bounds check */
+            return Err(ParseError::TooShort);
+        }
+
+        /* parse opcode to determine which parser to dispatch to */
+        let opcode: MessageOpcode = ((bytes[0] as u16) << 8) |
+                                            bytes[1] as u16;
+
+        match MessageType::from_opcode(opcode) {
+            Some(MessageType::ReadRequest) =>
+                Ok(AnyMessage::Rrq(ReadRequestMessage::from_bytes(bytes)?)),
+            Some(MessageType::WriteRequest) =>
+                Ok(AnyMessage::Wrq(WriteRequestMessage::from_bytes(bytes)?)),
+            Some(MessageType::Data) =>
+                Ok(AnyMessage::Data(DataMessage::from_bytes(bytes)?)),
+            Some(MessageType::Acknowledgement) =>
+                Ok(AnyMessage::Ack(AcknowledgementMessage::from_bytes(bytes)?)),
+            Some(MessageType::Error) =>
+                Ok(AnyMessage::Error(ErrorMessage::from_bytes(bytes)?)),
+            Some(MessageType::OptionAcknowledgement) =>
+                Ok(AnyMessage::Oack(
+                        OptionAcknowledgementMessage::from_bytes(bytes)?)),
+            None => Err(ParseError::InvalidOpcode)
+        }
+    }
 }
 
+/* a one-line summary of a message, leaving out any data it carries */
+impl fmt::Display for AnyMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options: OptionList = match self {
+            AnyMessage::Rrq(message) => {
+                write!(f, "RRQ '{}' ({})", message.filename(),
+                       ReadWriteRequestMessageMode::to_string(message.mode()))?;
+                message.options()
+            },
+            AnyMessage::Wrq(message) => {
+                write!(f, "WRQ '{}' ({})", message.filename(),
+                       ReadWriteRequestMessageMode::to_string(message.mode()))?;
+                message.options()
+            },
+            AnyMessage::Data(message) => {
+                return write!(f, "DATA {} ({} bytes)", message.block_num(),
+                              message.data().len());
+            },
+            AnyMessage::Ack(message) => {
+                return write!(f, "ACK {}", message.block_num());
+            },
+            AnyMessage::Error(message) => {
+                return write!(f, "ERROR {} ({})", message.code(),
+                              message.message());
+            },
+            AnyMessage::Oack(message) => {
+                write!(f, "OACK")?;
+                message.options()
+            }
+        };
+
+        for (name, value) in options {
+            write!(f, " {}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Read};
+
+/* carriage return and line feed, as used by the netascii encoding */
+pub const CR: u8 = b'\r';
+pub const LF: u8 = b'\n';
+pub const NUL: u8 = b'\0';
+
+/*
+ * Wraps a reader of local (Unix) text, producing netascii as per RFC 764:
+ * every LF becomes CR LF and every bare CR becomes CR NUL.
+ *
+ * The inner reader is read a byte at a time, so callers will almost always
+ * want to hand us a `BufReader`.
+ */
+pub struct NetAsciiReader<R: Read> {
+    inner: R,
+    pending: Option<u8>
+}
+
+impl<R: Read> NetAsciiReader<R> {
+    pub fn new(inner: R) -> Self {
+        NetAsciiReader {
+            inner,
+            pending: None
+        }
+    }
+}
+
+impl<R: Read> Read for NetAsciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n: usize = 0;
+
+        while n < buf.len() {
+            /* emit the second half of any translated sequence first */
+            if let Some(byte) = self.pending.take() {
+                buf[n] = byte;
+                n += 1;
+                continue;
+            }
+
+            let mut byte: [u8; 1] = [0];
+
+            if self.inner.read(&mut byte)? == 0 { /* EOF */
+                break;
+            }
+
+            buf[n] = match byte[0] {
+                LF => {
+                    self.pending = Some(LF);
+                    CR
+                },
+                CR => {
+                    self.pending = Some(NUL);
+                    CR
+                },
+                other => other
+            };
+            n += 1;
+        }
+
+        Ok(n)
+    }
+}
+
+/*
+ * Translates netascii received from the network back into local (Unix) text:
+ * CR LF becomes LF and CR NUL becomes CR.
+ *
+ * A CR may arrive as the final byte of one block with its partner at the
+ * start of the next, so the decoder carries that state between calls. Call
+ * `finish` once the final block has been decoded to flush any trailing CR.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct NetAsciiDecoder {
+    pending_cr: bool
+}
+
+impl NetAsciiDecoder {
+    pub fn new() -> Self {
+        NetAsciiDecoder {
+            pending_cr: false
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut decoded: Vec<u8> = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            if self.pending_cr {
+                self.pending_cr = false;
+
+                match byte {
+                    LF => decoded.push(LF),
+                    NUL => decoded.push(CR),
+                    CR => { /* malformed, but keep the first CR */
+                        decoded.push(CR);
+                        self.pending_cr = true;
+                    },
+                    other => {
+                        decoded.push(CR);
+                        decoded.push(other);
+                    }
+                }
+            } else if byte == CR {
+                self.pending_cr = true;
+            } else {
+                decoded.push(byte);
+            }
+        }
+
+        decoded
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        if self.pending_cr {
+            self.pending_cr = false;
+            vec![CR]
+        } else {
+            Vec::new()
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::msg;
+
+/* option names as registered by RFCs 2348, 2349, 2090 and 7440 */
+pub const BLKSIZE: &str = "blksize";
+pub const TIMEOUT: &str = "timeout";
+pub const TSIZE: &str = "tsize";
+pub const MULTICAST: &str = "multicast";
+pub const WINDOWSIZE: &str = "windowsize";
+
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+pub const MIN_BLOCK_SIZE: usize = 8;
+pub const MAX_BLOCK_SIZE: usize = msg::MAX_BLOCK_SIZE;
+pub const MIN_TIMEOUT_SECS: u64 = 1;
+pub const MAX_TIMEOUT_SECS: u64 = 255;
+pub const DEFAULT_WINDOW_SIZE: usize = 1;
+pub const MIN_WINDOW_SIZE: usize = 1;
+pub const MAX_WINDOW_SIZE: usize = 65535;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum OptionError {
+    InvalidValue(String, String),
+    Unrequested(String)
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionError::InvalidValue(name, value) =>
+                write!(f, "Invalid value '{}' for option '{}'", value, name),
+            OptionError::Unrequested(name) =>
+                write!(f, "Peer acknowledged unrequested option '{}'", name)
+        }
+    }
+}
+
+/* looks up an option by name (option names are case-insensitive) */
+pub fn find<'a>(options: &'a [(String, String)], name: &str) ->
+    Option<&'a str> {
+    options.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+pub fn parse_block_size(value: &str) -> Option<usize> {
+    match value.parse::<usize>() {
+        Ok(size) if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size) =>
+            Some(size),
+        _ => None
+    }
+}
+
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    match value.parse::<u64>() {
+        Ok(secs) if (MIN_TIMEOUT_SECS..=MAX_TIMEOUT_SECS).contains(&secs) =>
+            Some(Duration::from_secs(secs)),
+        _ => None
+    }
+}
+
+pub fn parse_window_size(value: &str) -> Option<usize> {
+    match value.parse::<usize>() {
+        Ok(size) if (MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&size) =>
+            Some(size),
+        _ => None
+    }
+}
+
+/*
+ * The value of the RFC 2090 multicast option as sent by a server: the group
+ * address and port data will be sent to, and whether the recipient is the
+ * master client (the one responsible for acknowledging blocks).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MulticastGroup {
+    group: SocketAddr,
+    master: bool
+}
+
+impl MulticastGroup {
+    pub fn new(group: SocketAddr, master: bool) -> Self {
+        MulticastGroup {
+            group,
+            master
+        }
+    }
+
+    pub fn group(&self) -> SocketAddr {
+        self.group
+    }
+
+    pub fn master(&self) -> bool {
+        self.master
+    }
+}
+
+impl fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.group.ip(), self.group.port(),
+               if self.master { 1 } else { 0 })
+    }
+}
+
+impl FromStr for MulticastGroup {
+    type Err = OptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || OptionError::InvalidValue(MULTICAST.to_string(),
+                                                   s.to_string());
+        let fields: Vec<&str> = s.split(',').collect();
+
+        if fields.len() != 3 {
+            return Err(invalid());
+        }
+
+        let addr: IpAddr = fields[0].parse().map_err(|_| invalid())?;
+        let port: u16 = fields[1].parse().map_err(|_| invalid())?;
+        let master: bool = match fields[2] {
+            "0" => false,
+            "1" => true,
+            _ => return Err(invalid())
+        };
+
+        Ok(MulticastGroup::new(SocketAddr::new(addr, port), master))
+    }
+}
+
+/* the parameters a transfer runs with once options have been negotiated */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferOptions {
+    block_size: usize,
+    timeout: Option<Duration>,
+    transfer_size: Option<u64>,
+    window_size: usize,     /* blocks sent for each ACK (RFC 7440) */
+    multicast: Option<MulticastGroup>
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            timeout: None,
+            transfer_size: None,
+            window_size: DEFAULT_WINDOW_SIZE,
+            multicast: None
+        }
+    }
+}
+
+impl TransferOptions {
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn transfer_size(&self) -> Option<u64> {
+        self.transfer_size
+    }
+
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    pub fn multicast(&self) -> Option<MulticastGroup> {
+        self.multicast
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_transfer_size(&mut self, transfer_size: Option<u64>) {
+        self.transfer_size = transfer_size;
+    }
+
+    pub fn set_window_size(&mut self, window_size: usize) {
+        self.window_size = window_size;
+    }
+
+    pub fn set_multicast(&mut self, multicast: Option<MulticastGroup>) {
+        self.multicast = multicast;
+    }
+
+    /* interprets the options acknowledged by a server, checking each was
+        actually among those requested */
+    pub fn from_oack(requested: &[(String, String)],
+                     acknowledged: &[(String, String)]) ->
+        Result<Self, OptionError> {
+        let mut negotiated: TransferOptions = TransferOptions::default();
+
+        for (name, value) in acknowledged {
+            if find(requested, name).is_none() {
+                return Err(OptionError::Unrequested(name.clone()));
+            }
+
+            let invalid = || OptionError::InvalidValue(name.clone(),
+                                                       value.clone());
+            let name: String = name.to_ascii_lowercase();
+
+            match name.as_str() {
+                BLKSIZE => negotiated.block_size =
+                    parse_block_size(value).ok_or_else(invalid)?,
+                TIMEOUT => negotiated.timeout =
+                    Some(parse_timeout(value).ok_or_else(invalid)?),
+                TSIZE => negotiated.transfer_size =
+                    Some(value.parse().map_err(|_| invalid())?),
+                WINDOWSIZE => negotiated.window_size =
+                    parse_window_size(value).ok_or_else(invalid)?,
+                MULTICAST => negotiated.multicast = Some(value.parse()?),
+                _ => {} /* requested, so presumably understood by the caller */
+            }
+        }
+
+        Ok(negotiated)
+    }
+}
@@ -0,0 +1,786 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+/*
+ * Packet captures in the classic pcap format.
+ *
+ * What we write holds each datagram as a raw IP packet (link type 101) with
+ * IP and UDP headers made up from the addresses either side of the socket,
+ * so a socket bound to the wildcard address appears to send from it. What we
+ * read may equally come from tcpdump or Wireshark, over Ethernet, Linux
+ * cooked capture, BSD loopback or raw IP. Anything but UDP is skipped, as
+ * are datagrams the capture cut short; fragmented IPv4 datagrams are put
+ * back together, but fragmented IPv6 ones are skipped too.
+ */
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+
+/* link-layer header types */
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_DSTOPTS: u8 = 60;
+
+const TTL: u8 = 64;
+
+/* once this many IPv4 datagrams are part way through being reassembled,
+    the lot are given up on */
+const MAX_REASSEMBLIES: usize = 1024;
+
+/* the capture that every datagram sent or received is recorded in */
+static CAPTURE: OnceLock<Mutex<Writer<BufWriter<File>>>> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum PcapError {
+    Io(io::Error),
+    Open(PathBuf, io::Error),
+    NotPcap(u32),
+    UnsupportedLinkType(u32),
+    Truncated,
+    AlreadyCapturing
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PcapError::Io(e) => write!(f, "{}", e),
+            PcapError::Open(path, e) =>
+                write!(f, "Unable to open capture {}: {}", path.display(), e),
+            PcapError::NotPcap(magic) =>
+                write!(f, "Not a pcap file (magic number {:#010x})", magic),
+            PcapError::UnsupportedLinkType(link_type) =>
+                write!(f, "Unsupported link type {}", link_type),
+            PcapError::Truncated => write!(f, "Capture is cut short"),
+            PcapError::AlreadyCapturing =>
+                write!(f, "Already capturing to a file")
+        }
+    }
+}
+
+impl From<io::Error> for PcapError {
+    fn from(e: io::Error) -> Self {
+        PcapError::Io(e)
+    }
+}
+
+/* a UDP datagram, as captured */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    time: SystemTime,
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>
+}
+
+impl Datagram {
+    pub fn new(time: SystemTime, source: SocketAddr, destination: SocketAddr,
+               payload: Vec<u8>) -> Self {
+        Datagram {
+            time,
+            source,
+            destination,
+            payload
+        }
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    pub fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+pub struct Writer<W: Write> {
+    inner: W
+}
+
+impl<W: Write> Writer<W> {
+    /* starts a capture, writing its header */
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut header: Vec<u8> = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());      /* UTC */
+        header.extend_from_slice(&0u32.to_le_bytes());      /* accuracy */
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        inner.write_all(&header)?;
+
+        Ok(Writer {
+            inner
+        })
+    }
+
+    pub fn write(&mut self, datagram: &Datagram) -> io::Result<()> {
+        let packet: Vec<u8> = ip_packet(datagram.source,
+                                        datagram.destination,
+                                        &datagram.payload);
+        let since: Duration = datagram.time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record: Vec<u8> = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+
+        self.inner.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* an IPv4 datagram part way through being reassembled */
+#[derive(Debug, Default)]
+struct Reassembly {
+    parts: Vec<(usize, Vec<u8>)>,   /* by offset */
+    length: Option<usize>           /* known once the last part arrives */
+}
+
+pub struct Reader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+    reassemblies: HashMap<(Ipv4Addr, Ipv4Addr, u16), Reassembly>,
+    truncated: u64
+}
+
+impl<R: Read> Reader<R> {
+    /* opens a capture, reading its header */
+    pub fn new(mut inner: R) -> Result<Self, PcapError> {
+        let mut header: [u8; 24] = [0; 24];
+
+        if read_fully(&mut inner, &mut header)? < header.len() {
+            return Err(PcapError::Truncated);
+        }
+
+        let magic: u32 = u32::from_le_bytes([header[0], header[1], header[2],
+                                             header[3]]);
+        let (big_endian, nanos): (bool, bool) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(PcapError::NotPcap(magic))
+        };
+
+        let mut reader: Reader<R> = Reader {
+            inner,
+            big_endian,
+            nanos,
+            link_type: 0,
+            reassemblies: HashMap::new(),
+            truncated: 0
+        };
+
+        /* the upper bits may carry the FCS length, which doesn't concern
+            us */
+        reader.link_type = reader.u32(&header[20..24]) & 0x0fff_ffff;
+
+        match reader.link_type {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW |
+            LINKTYPE_LINUX_SLL | LINKTYPE_IPV4 | LINKTYPE_IPV6 |
+            LINKTYPE_LINUX_SLL2 => Ok(reader),
+            link_type => Err(PcapError::UnsupportedLinkType(link_type))
+        }
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    /* how many UDP datagrams have been skipped for being cut short */
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    /* the next UDP datagram in the capture, if there are any more */
+    pub fn next_datagram(&mut self) -> Result<Option<Datagram>, PcapError> {
+        loop {
+            let mut header: [u8; 16] = [0; 16];
+
+            match read_fully(&mut self.inner, &mut header)? {
+                0 => return Ok(None),
+                16 => {},
+                _ => return Err(PcapError::Truncated)
+            }
+
+            let secs: u32 = self.u32(&header[0..4]);
+            let fraction: u32 = self.u32(&header[4..8]);
+            let captured: usize = self.u32(&header[8..12]) as usize;
+            let original: usize = self.u32(&header[12..16]) as usize;
+
+            let mut packet: Vec<u8> = vec![0; captured];
+
+            if read_fully(&mut self.inner, &mut packet)? < captured {
+                return Err(PcapError::Truncated);
+            }
+
+            let fraction: Duration = if self.nanos {
+                Duration::from_nanos(fraction as u64)
+            } else {
+                Duration::from_micros(fraction as u64)
+            };
+            let time: SystemTime =
+                UNIX_EPOCH + Duration::from_secs(secs as u64) + fraction;
+
+            if captured < original {
+                self.truncated += 1;
+                continue;
+            }
+
+            if let Some(datagram) = self.decode(time, &packet) {
+                return Ok(Some(datagram));
+            }
+        }
+    }
+
+    /* the UDP datagram in a link-layer frame, if there is one */
+    fn decode(&mut self, time: SystemTime, frame: &[u8]) -> Option<Datagram> {
+        let packet: &[u8] = match self.link_type {
+            LINKTYPE_NULL => frame.get(4..)?,
+            LINKTYPE_ETHERNET => {
+                let mut offset: usize = 12;
+
+                while matches!(be16(frame, offset)?,
+                               ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+                    offset += 4;
+                }
+
+                match be16(frame, offset)? {
+                    ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(offset + 2..)?,
+                    _ => return None
+                }
+            },
+            LINKTYPE_LINUX_SLL => match be16(frame, 14)? {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(16..)?,
+                _ => return None
+            },
+            LINKTYPE_LINUX_SLL2 => match be16(frame, 0)? {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(20..)?,
+                _ => return None
+            },
+            _ => frame
+        };
+
+        match packet.first()? >> 4 {
+            4 => self.decode_ipv4(time, packet),
+            6 => self.decode_ipv6(time, packet),
+            _ => None
+        }
+    }
+
+    fn decode_ipv4(&mut self, time: SystemTime, packet: &[u8]) ->
+        Option<Datagram> {
+        if packet.len() < 20 {
+            return None;
+        }
+
+        let header: usize = (packet[0] & 0x0f) as usize * 4;
+        let length: usize = (be16(packet, 2)? as usize).min(packet.len());
+        let id: u16 = be16(packet, 4)?;
+        let fragment: u16 = be16(packet, 6)?;
+        let protocol: u8 = packet[9];
+        let source: Ipv4Addr = Ipv4Addr::new(packet[12], packet[13],
+                                             packet[14], packet[15]);
+        let destination: Ipv4Addr = Ipv4Addr::new(packet[16], packet[17],
+                                                  packet[18], packet[19]);
+
+        if protocol != IPPROTO_UDP {
+            return None;
+        }
+
+        let body: &[u8] = packet.get(header..length)?;
+        let more: bool = fragment & 0x2000 != 0;
+        let offset: usize = (fragment & 0x1fff) as usize * 8;
+
+        if !more && offset == 0 {
+            return self.decode_udp(time, IpAddr::V4(source),
+                                   IpAddr::V4(destination), body);
+        }
+
+        let body: Vec<u8> = self.reassemble((source, destination, id), offset,
+                                            more, body)?;
+        self.decode_udp(time, IpAddr::V4(source), IpAddr::V4(destination),
+                        &body)
+    }
+
+    /* adds a fragment to its datagram, returning the whole thing once every
+        fragment is in */
+    fn reassemble(&mut self, key: (Ipv4Addr, Ipv4Addr, u16), offset: usize,
+                  more: bool, body: &[u8]) -> Option<Vec<u8>> {
+        if self.reassemblies.len() >= MAX_REASSEMBLIES {
+            self.reassemblies.clear();
+        }
+
+        let reassembly: &mut Reassembly =
+            self.reassemblies.entry(key).or_default();
+        reassembly.parts.push((offset, body.to_vec()));
+
+        if !more {
+            reassembly.length = Some(offset + body.len());
+        }
+
+        let length: usize = reassembly.length?;
+        reassembly.parts.sort_by_key(|(offset, _)| *offset);
+
+        let mut whole: Vec<u8> = Vec::with_capacity(length);
+
+        for (offset, part) in &reassembly.parts {
+            if *offset > whole.len() {
+                return None;
+            }
+
+            let overlap: usize = whole.len() - offset;
+
+            if overlap < part.len() {
+                whole.extend_from_slice(&part[overlap..]);
+            }
+        }
+
+        if whole.len() < length {
+            return None;
+        }
+
+        self.reassemblies.remove(&key);
+        whole.truncate(length);
+        Some(whole)
+    }
+
+    fn decode_ipv6(&mut self, time: SystemTime, packet: &[u8]) ->
+        Option<Datagram> {
+        let length: usize = be16(packet, 4)? as usize;
+        let source: Ipv6Addr = ipv6(packet.get(8..24)?);
+        let destination: Ipv6Addr = ipv6(packet.get(24..40)?);
+        let mut next: u8 = packet[6];
+        let mut body: &[u8] = packet.get(40..(40 + length).min(packet.len()))?;
+
+        loop {
+            match next {
+                IPPROTO_UDP => break,
+                IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                    let size: usize = (*body.get(1)? as usize + 1) * 8;
+                    next = body[0];
+                    body = body.get(size..)?;
+                },
+                IPPROTO_FRAGMENT => {
+                    /* only a datagram that was never split up will do */
+                    if be16(body, 2)? & 0xfff9 != 0 {
+                        return None;
+                    }
+
+                    next = body[0];
+                    body = body.get(8..)?;
+                },
+                _ => return None
+            }
+        }
+
+        self.decode_udp(time, IpAddr::V6(source), IpAddr::V6(destination),
+                        body)
+    }
+
+    fn decode_udp(&mut self, time: SystemTime, source: IpAddr,
+                  destination: IpAddr, segment: &[u8]) -> Option<Datagram> {
+        let length: usize = be16(segment, 4)? as usize;
+
+        if length < 8 {
+            return None;
+        }
+
+        let payload: &[u8] = match segment.get(8..length) {
+            Some(payload) => payload,
+            None => {
+                self.truncated += 1;
+                return None;
+            }
+        };
+
+        Some(Datagram::new(time,
+                           SocketAddr::new(source, be16(segment, 0)?),
+                           SocketAddr::new(destination, be16(segment, 2)?),
+                           payload.to_vec()))
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes: [u8; 4] = [bytes[0], bytes[1], bytes[2], bytes[3]];
+
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Datagram, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().transpose()
+    }
+}
+
+/* starts recording every datagram sent or received from here on in a new
+    capture file */
+pub fn capture(path: &Path) -> Result<(), PcapError> {
+    let file: File = File::create(path)
+        .map_err(|e| PcapError::Open(path.to_path_buf(), e))?;
+    let mut writer: Writer<BufWriter<File>> = Writer::new(BufWriter::new(file))
+        .map_err(|e| PcapError::Open(path.to_path_buf(), e))?;
+    writer.flush()?;
+
+    CAPTURE.set(Mutex::new(writer))
+        .map_err(|_| PcapError::AlreadyCapturing)
+}
+
+pub fn is_capturing() -> bool {
+    CAPTURE.get().is_some()
+}
+
+/* adds a datagram to the capture file, if there is one */
+pub fn record(source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+    if let Some(capture) = CAPTURE.get() {
+        let datagram: Datagram = Datagram::new(SystemTime::now(), source,
+                                               destination, payload.to_vec());
+        let mut writer = capture.lock().unwrap();
+
+        /* a capture is no reason to fail a transfer; each datagram is
+            flushed so that nothing is lost if we're killed */
+        let _ = writer.write(&datagram).and_then(|_| writer.flush());
+    }
+}
+
+/* reads as much of `buf` as there is to read, returning how much that was */
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read: usize = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(read)
+}
+
+fn be16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes: &[u8] = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let mut octets: [u8; 16] = [0; 16];
+    octets.copy_from_slice(bytes);
+    Ipv6Addr::from(octets)
+}
+
+/* a UDP datagram inside an IP packet; addresses of different families are
+    both given as IPv6 */
+fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) ->
+    Vec<u8> {
+    let length: usize = 8 + payload.len();
+    let mut udp: Vec<u8> = Vec::with_capacity(length);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&(length as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet: Vec<u8> = Vec::with_capacity(40 + length);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            let pseudo: Vec<u8> = [&from.octets()[..], &to.octets()[..],
+                                   &[0, IPPROTO_UDP],
+                                   &(length as u16).to_be_bytes()].concat();
+            let sum: u16 = udp_checksum(&pseudo, &udp);
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + length) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&from.octets());
+            packet.extend_from_slice(&to.octets());
+
+            let sum: u16 = !fold(sum_words(&packet[..20]));
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        },
+        (from, to) => {
+            let from: Ipv6Addr = as_ipv6(from);
+            let to: Ipv6Addr = as_ipv6(to);
+            let pseudo: Vec<u8> = [&from.octets()[..], &to.octets()[..],
+                                   &(length as u32).to_be_bytes(),
+                                   &[0, 0, 0, IPPROTO_UDP]].concat();
+            let sum: u16 = udp_checksum(&pseudo, &udp);
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(length as u16).to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, TTL]);
+            packet.extend_from_slice(&from.octets());
+            packet.extend_from_slice(&to.octets());
+        }
+    }
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn as_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr
+    }
+}
+
+/* the Internet checksum of a UDP segment under its pseudo-header; zero is
+    sent as all ones, zero meaning that there isn't one */
+fn udp_checksum(pseudo: &[u8], segment: &[u8]) -> u16 {
+    match !fold(sum_words(pseudo) + sum_words(segment)) {
+        0 => 0xffff,
+        sum => sum
+    }
+}
+
+fn sum_words(bytes: &[u8]) -> u32 {
+    bytes.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])
+             as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(micros: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(micros)
+    }
+
+    fn datagrams() -> Vec<Datagram> {
+        vec![
+            Datagram::new(at(1_700_000_000_123_456),
+                          "192.0.2.1:50000".parse().unwrap(),
+                          "192.0.2.2:69".parse().unwrap(),
+                          b"\x00\x01boot.img\x00octet\x00".to_vec()),
+            Datagram::new(at(1_700_000_000_234_567),
+                          "[2001:db8::2]:40000".parse().unwrap(),
+                          "[2001:db8::1]:50000".parse().unwrap(),
+                          vec![0, 3, 0, 1, 1, 2, 3]),
+            Datagram::new(at(1_700_000_001_000_000),
+                          "192.0.2.1:50000".parse().unwrap(),
+                          "192.0.2.2:40000".parse().unwrap(),
+                          vec![])
+        ]
+    }
+
+    fn written(datagrams: &[Datagram]) -> Vec<u8> {
+        let mut writer: Writer<Vec<u8>> = Writer::new(Vec::new()).unwrap();
+
+        for datagram in datagrams {
+            writer.write(datagram).unwrap();
+        }
+
+        writer.inner
+    }
+
+    fn read(capture: &[u8]) -> Result<Vec<Datagram>, PcapError> {
+        Reader::new(capture)?.collect()
+    }
+
+    /* a capture header in either byte order */
+    fn header(magic: u32, link_type: u32, big_endian: bool) -> Vec<u8> {
+        let u16s = |value: u16| if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+        let u32s = |value: u32| if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+
+        [&u32s(magic)[..], &u16s(VERSION_MAJOR), &u16s(VERSION_MINOR),
+         &[0; 8], &u32s(SNAPLEN), &u32s(link_type)].concat()
+    }
+
+    #[test]
+    fn writes_a_raw_ip_capture_header() {
+        let capture: Vec<u8> = written(&[]);
+
+        assert_eq!(capture, header(MAGIC_MICROS, LINKTYPE_RAW, false));
+        assert_eq!(&capture[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(Reader::new(&capture[..]).unwrap().link_type(),
+                   LINKTYPE_RAW);
+    }
+
+    #[test]
+    fn round_trips_datagrams() {
+        let datagrams: Vec<Datagram> = datagrams();
+
+        assert_eq!(read(&written(&datagrams)).unwrap(), datagrams);
+    }
+
+    #[test]
+    fn writes_valid_checksums() {
+        let datagrams: Vec<Datagram> = datagrams();
+        let packet: Vec<u8> = ip_packet(datagrams[0].source(),
+                                        datagrams[0].destination(),
+                                        datagrams[0].payload());
+
+        /* a header or segment summed with its checksum comes to all ones */
+        assert_eq!(fold(sum_words(&packet[..20])), 0xffff);
+
+        let pseudo: Vec<u8> = [&packet[12..20], &[0, IPPROTO_UDP],
+                               &packet[24..26]].concat();
+        assert_eq!(fold(sum_words(&pseudo) + sum_words(&packet[20..])),
+                   0xffff);
+    }
+
+    #[test]
+    fn reads_big_endian_nanosecond_ethernet_captures() {
+        let datagram: Datagram = datagrams().remove(0);
+        let packet: Vec<u8> = ip_packet(datagram.source(),
+                                        datagram.destination(),
+                                        datagram.payload());
+
+        /* addresses, a VLAN tag and the IPv4 ethertype */
+        let mut frame: Vec<u8> = vec![0; 12];
+        frame.extend_from_slice(&[0x81, 0x00, 0, 1, 0x08, 0x00]);
+        frame.extend_from_slice(&packet);
+
+        let mut capture: Vec<u8> = header(MAGIC_NANOS, LINKTYPE_ETHERNET,
+                                          true);
+        capture.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+        capture.extend_from_slice(&123_456_789u32.to_be_bytes());
+        capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        capture.extend_from_slice(&frame);
+
+        let read: Vec<Datagram> = read(&capture).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].time(),
+                   UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789));
+        assert_eq!(read[0].source(), datagram.source());
+        assert_eq!(read[0].payload(), datagram.payload());
+    }
+
+    #[test]
+    fn reassembles_fragmented_ipv4_datagrams() {
+        let datagram: Datagram = Datagram::new(
+            at(1_700_000_000_000_000), "192.0.2.1:50000".parse().unwrap(),
+            "192.0.2.2:69".parse().unwrap(), vec![7; 100]);
+        let packet: Vec<u8> = ip_packet(datagram.source(),
+                                        datagram.destination(),
+                                        datagram.payload());
+        let (header, body): (&[u8], &[u8]) = packet.split_at(20);
+
+        /* the second half first, then the first */
+        let mut capture: Vec<u8> = written(&[]);
+
+        for (offset, part, more) in [(64, &body[64..], false),
+                                     (0, &body[..64], true)] {
+            let flags: u16 = (offset / 8) | if more { 0x2000 } else { 0 };
+
+            let mut fragment: Vec<u8> = header.to_vec();
+            fragment[2..4].copy_from_slice(&((20 + part.len()) as u16)
+                                           .to_be_bytes());
+            fragment[4..6].copy_from_slice(&1234u16.to_be_bytes());
+            fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+            fragment.extend_from_slice(part);
+
+            capture.extend_from_slice(&[0; 8]);
+            capture.extend_from_slice(&(fragment.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&(fragment.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&fragment);
+        }
+
+        let read: Vec<Datagram> = read(&capture).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].payload(), datagram.payload());
+    }
+
+    #[test]
+    fn skips_datagrams_the_capture_cut_short() {
+        let mut capture: Vec<u8> = written(&datagrams()[..1]);
+
+        /* the recorded length is what was on the wire; say there was more */
+        let original: u32 = u32::from_le_bytes([capture[36], capture[37],
+                                                capture[38], capture[39]]);
+        capture[36..40].copy_from_slice(&(original + 10).to_le_bytes());
+
+        let mut reader: Reader<&[u8]> = Reader::new(&capture[..]).unwrap();
+        assert!(reader.next_datagram().unwrap().is_none());
+        assert_eq!(reader.truncated(), 1);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_capture() {
+        assert!(matches!(read(b"this is not a capture at all"),
+                         Err(PcapError::NotPcap(_))));
+        assert!(matches!(read(&header(MAGIC_MICROS, 147, false)),
+                         Err(PcapError::UnsupportedLinkType(147))));
+        assert!(matches!(read(&written(&[])[..20]),
+                         Err(PcapError::Truncated)));
+
+        let capture: Vec<u8> = written(&datagrams());
+        assert!(matches!(read(&capture[..capture.len() - 1]),
+                         Err(PcapError::Truncated)));
+        assert!(matches!(read(&capture[..24 + 10]),
+                         Err(PcapError::Truncated)));
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use crate::msg::{self, ErrorMessageCode};
+use crate::upload;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+    Disabled,       /* reject every WRQ */
+    CreateOnly,     /* only permit uploads that create new files */
+    Overwrite       /* permit uploads to create or replace files */
+}
+
+impl FromStr for WriteMode {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(WriteMode::Disabled),
+            "create" => Ok(WriteMode::CreateOnly),
+            "overwrite" => Ok(WriteMode::Overwrite),
+            _ => Err(PolicyError::InvalidWriteMode(s.to_string()))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum PolicyError {
+    InvalidWriteMode(String),
+    InvalidSize(String)
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::InvalidWriteMode(s) =>
+                write!(f, "Invalid write mode '{}' (expected none, create or \
+                       overwrite)", s),
+            PolicyError::InvalidSize(s) =>
+                write!(f, "Invalid size '{}' (expected a number of bytes, \
+                       optionally suffixed with K, M or G)", s)
+        }
+    }
+}
+
+/* reasons an upload may be refused, either up front or part way through */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Error)]
+pub enum Violation {
+    UploadsDisabled,
+    FileExists,
+    FileTooLarge,
+    QuotaExceeded
+}
+
+impl Violation {
+    pub fn code(&self) -> ErrorMessageCode {
+        match self {
+            Violation::UploadsDisabled => msg::ERROR_ACCESS_VIOLATION,
+            Violation::FileExists => msg::ERROR_FILE_EXISTS,
+            Violation::FileTooLarge => msg::ERROR_DISK_FULL,
+            Violation::QuotaExceeded => msg::ERROR_DISK_FULL
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg: &str = match self {
+            Violation::UploadsDisabled => "Uploads are not permitted",
+            Violation::FileExists => "File already exists",
+            Violation::FileTooLarge => "File exceeds maximum permitted size",
+            Violation::QuotaExceeded => "Directory quota exceeded"
+        };
+
+        write!(f, "{}", msg)
+    }
+}
+
+/*
+ * The uploads under way in each directory with a quota, and what the
+ * directory holds counting the bytes they've reserved.
+ *
+ * A directory's usage is read from disk when its first upload is admitted
+ * and kept from then on by the uploads themselves, each reserving bytes as
+ * it writes them (or up front, given its size), so that uploads running
+ * side by side can't each claim all of what's left.
+ */
+#[derive(Debug, Default)]
+pub struct Ledger {
+    directories: Mutex<HashMap<PathBuf, Usage>>
+}
+
+#[derive(Debug)]
+struct Usage {
+    used: u64,      /* bytes on disk, plus those reserved */
+    uploads: usize
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Ledger::default()
+    }
+
+    /* the bytes counted against the given directory's quota, if it has an
+        upload under way */
+    pub fn usage(&self, directory: &Path) -> Option<u64> {
+        self.directories.lock().unwrap().get(directory)
+            .map(|usage| usage.used)
+    }
+}
+
+/* a quota's claim on the directory an upload is written to */
+#[derive(Debug)]
+struct Reservation {
+    ledger: Arc<Ledger>,
+    directory: PathBuf,
+    quota: u64,
+    replaced: u64,      /* the size of the file being overwritten */
+    reserved: u64,
+    written: u64,
+    committed: bool
+}
+
+impl Reservation {
+    fn reserve(&mut self, size: u64) -> Result<(), Violation> {
+        if size <= self.reserved {
+            return Ok(());
+        }
+
+        let mut directories = self.ledger.directories.lock().unwrap();
+        let usage: &mut Usage = directories.get_mut(&self.directory)
+            .expect("reservation outlived its directory");
+        let needed: u64 = size - self.reserved;
+
+        /* the file being replaced goes when this one takes its place */
+        if usage.used + needed > self.quota + self.replaced {
+            return Err(Violation::QuotaExceeded);
+        }
+
+        usage.used += needed;
+        self.reserved = size;
+
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut directories = self.ledger.directories.lock().unwrap();
+
+        if let Some(usage) = directories.get_mut(&self.directory) {
+            /* a committed upload keeps what it wrote, less the file it
+                replaced; any other gives back all it reserved */
+            let released: u64 = if self.committed {
+                self.replaced + (self.reserved - self.written)
+            } else {
+                self.reserved
+            };
+
+            usage.used = usage.used.saturating_sub(released);
+            usage.uploads -= 1;
+
+            if usage.uploads == 0 {
+                directories.remove(&self.directory);
+            }
+        }
+    }
+}
+
+/* the number of bytes an admitted upload may write, and the share of its
+    directory's quota it has reserved (given back when it's dropped) */
+#[derive(Debug)]
+pub struct Allowance {
+    limit: Option<u64>,     /* the maximum file size */
+    reservation: Option<Reservation>
+}
+
+impl Allowance {
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /* the bytes set aside for the upload against its directory's quota */
+    pub fn reserved(&self) -> u64 {
+        self.reservation.as_ref()
+            .map_or(0, |reservation| reservation.reserved)
+    }
+
+    /* sets aside room for an upload that has announced its size */
+    pub fn reserve(&mut self, size: u64) -> Result<(), Violation> {
+        if self.limit.is_some_and(|limit| size > limit) {
+            return Err(Violation::FileTooLarge);
+        }
+
+        match &mut self.reservation {
+            Some(reservation) => reservation.reserve(size),
+            None => Ok(())
+        }
+    }
+
+    /* records that the upload has written the given number of bytes in all,
+        reserving more of the quota should it need to */
+    pub fn check(&mut self, written: u64) -> Result<(), Violation> {
+        self.reserve(written)?;
+
+        if let Some(reservation) = &mut self.reservation {
+            reservation.written = written;
+        }
+
+        Ok(())
+    }
+
+    /* marks the upload as having taken its place on disk */
+    pub fn commit(&mut self) {
+        if let Some(reservation) = &mut self.reservation {
+            reservation.committed = true;
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WritePolicy {
+    mode: WriteMode,
+    max_file_size: Option<u64>,
+    quota: Option<u64>      /* total bytes permitted per directory */
+}
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy::new(WriteMode::Disabled)
+    }
+}
+
+impl WritePolicy {
+    pub fn new(mode: WriteMode) -> Self {
+        WritePolicy {
+            mode,
+            max_file_size: None,
+            quota: None
+        }
+    }
+
+    pub fn mode(&self) -> WriteMode {
+        self.mode
+    }
+
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    pub fn set_max_file_size(&mut self, max_file_size: Option<u64>) {
+        self.max_file_size = max_file_size;
+    }
+
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+    }
+
+    /* decides whether an upload to the given path may begin, and if so, how
+        many bytes it may write, counting it against the directory's quota
+        in the ledger until the allowance is dropped */
+    pub fn admit(&self, ledger: &Arc<Ledger>, path: &Path) ->
+        Result<Allowance, Violation> {
+        let existing: Option<u64> = match fs::metadata(path) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(_) => None
+        };
+
+        match (self.mode, existing) {
+            (WriteMode::Disabled, _) => return Err(Violation::UploadsDisabled),
+            (WriteMode::CreateOnly, Some(_)) =>
+                return Err(Violation::FileExists),
+            _ => {}
+        }
+
+        let quota: u64 = match self.quota {
+            Some(quota) => quota,
+            None => return Ok(Allowance {
+                limit: self.max_file_size,
+                reservation: None
+            })
+        };
+
+        let directory: &Path = path.parent().unwrap_or(path);
+        let replaced: u64 = existing.unwrap_or(0);
+        let mut directories = ledger.directories.lock().unwrap();
+
+        let usage: &mut Usage = directories.entry(directory.to_path_buf())
+            .or_insert_with(|| Usage {
+                used: directory_usage(directory).unwrap_or(0),
+                uploads: 0
+            });
+
+        /* the file being replaced (if any) doesn't count against us */
+        if usage.used.saturating_sub(replaced) >= quota {
+            if usage.uploads == 0 {
+                directories.remove(directory);
+            }
+
+            return Err(Violation::QuotaExceeded);
+        }
+
+        usage.uploads += 1;
+
+        Ok(Allowance {
+            limit: self.max_file_size,
+            reservation: Some(Reservation {
+                ledger: ledger.clone(),
+                directory: directory.to_path_buf(),
+                quota,
+                replaced,
+                reserved: 0,
+                written: 0,
+                committed: false
+            })
+        })
+    }
+}
+
+/* total size of the regular files directly within the given directory,
+    leaving out uploads in progress */
+fn directory_usage(directory: &Path) -> io::Result<u64> {
+    let mut total: u64 = 0;
+
+    for entry in fs::read_dir(directory)? {
+        let entry: fs::DirEntry = entry?;
+        let metadata: fs::Metadata = entry.metadata()?;
+
+        if metadata.is_file() && !upload::is_temp_file(&entry.path()) {
+            total += metadata.len();
+        }
+    }
+
+    Ok(total)
+}
+
+/* parses a byte count such as "1048576", "512K", "100M" or "2G" (suffixes are
+    binary multiples) */
+pub fn parse_size(s: &str) -> Result<u64, PolicyError> {
+    let invalid = || PolicyError::InvalidSize(s.to_string());
+
+    let (digits, multiplier): (&str, u64) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1)
+    };
+
+    let value: u64 = digits.parse().map_err(|_| invalid())?;
+    value.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Scratch;
+
+    /* puts a file of `size` bytes in the scratch directory */
+    fn create(scratch: &Scratch, filename: &str, size: usize) -> PathBuf {
+        scratch.write(filename, &vec![0; size])
+    }
+
+    fn policy(mode: WriteMode, max_file_size: Option<u64>,
+              quota: Option<u64>) -> WritePolicy {
+        let mut policy: WritePolicy = WritePolicy::new(mode);
+        policy.set_max_file_size(max_file_size);
+        policy.set_quota(quota);
+        policy
+    }
+
+    #[test]
+    fn disabled_refuses_every_upload() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = WritePolicy::default();
+
+        assert_eq!(policy.admit(&ledger, &scratch.join("new")).unwrap_err(),
+                   Violation::UploadsDisabled);
+    }
+
+    #[test]
+    fn create_only_refuses_existing_files() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::CreateOnly, None, None);
+        let existing: PathBuf = create(&scratch, "existing", 10);
+
+        assert_eq!(policy.admit(&ledger, &existing).unwrap_err(),
+                   Violation::FileExists);
+        assert!(policy.admit(&ledger, &scratch.join("new")).is_ok());
+    }
+
+    #[test]
+    fn overwrite_admits_existing_files() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::Overwrite, None, None);
+        let existing: PathBuf = create(&scratch, "existing", 10);
+
+        assert!(policy.admit(&ledger, &existing).is_ok());
+        assert!(policy.admit(&ledger, &scratch.join("new")).is_ok());
+    }
+
+    #[test]
+    fn enforces_the_maximum_file_size() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy =
+            policy(WriteMode::Overwrite, Some(100), Some(1000));
+        let mut allowance: Allowance =
+            policy.admit(&ledger, &scratch.join("new")).unwrap();
+
+        assert_eq!(allowance.reserve(101), Err(Violation::FileTooLarge));
+        assert_eq!(allowance.check(100), Ok(()));
+        assert_eq!(allowance.check(101), Err(Violation::FileTooLarge));
+    }
+
+    #[test]
+    fn refuses_uploads_to_a_full_directory() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::Overwrite, None, Some(100));
+        let existing: PathBuf = create(&scratch, "existing", 100);
+
+        assert_eq!(policy.admit(&ledger, &scratch.join("new")).unwrap_err(),
+                   Violation::QuotaExceeded);
+
+        /* replacing a file frees up its share */
+        let mut allowance: Allowance = policy.admit(&ledger, &existing)
+            .unwrap();
+        assert_eq!(allowance.check(100), Ok(()));
+        assert_eq!(allowance.check(101), Err(Violation::QuotaExceeded));
+    }
+
+    #[test]
+    fn concurrent_uploads_share_the_quota() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::Overwrite, None, Some(100));
+        create(&scratch, "existing", 40);
+
+        let mut first: Allowance = policy.admit(&ledger, &scratch.join("a"))
+            .unwrap();
+        let mut second: Allowance = policy.admit(&ledger, &scratch.join("b"))
+            .unwrap();
+
+        assert_eq!(first.reserve(50), Ok(()));
+        assert_eq!(second.check(11), Err(Violation::QuotaExceeded));
+        assert_eq!(second.check(10), Ok(()));
+        assert_eq!(ledger.usage(scratch.path()), Some(100));
+
+        /* nor can a third start while the directory is spoken for */
+        assert_eq!(policy.admit(&ledger, &scratch.join("c")).unwrap_err(),
+                   Violation::QuotaExceeded);
+
+        /* writing less than was reserved doesn't take any more */
+        assert_eq!(first.check(30), Ok(()));
+        assert_eq!(ledger.usage(scratch.path()), Some(100));
+    }
+
+    #[test]
+    fn failed_uploads_give_back_their_reservation() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::Overwrite, None, Some(100));
+
+        let mut first: Allowance = policy.admit(&ledger, &scratch.join("a"))
+            .unwrap();
+        let second: Allowance = policy.admit(&ledger, &scratch.join("b"))
+            .unwrap();
+        first.check(60).unwrap();
+
+        drop(first);
+        assert_eq!(ledger.usage(scratch.path()), Some(0));
+
+        /* the last upload out leaves the directory to be read afresh */
+        drop(second);
+        assert_eq!(ledger.usage(scratch.path()), None);
+    }
+
+    #[test]
+    fn committed_uploads_keep_what_they_wrote() {
+        let scratch: Scratch = Scratch::new();
+        let ledger: Arc<Ledger> = Arc::new(Ledger::new());
+        let policy: WritePolicy = policy(WriteMode::Overwrite, None, Some(100));
+        let existing: PathBuf = create(&scratch, "existing", 30);
+
+        let mut replacing: Allowance = policy.admit(&ledger, &existing)
+            .unwrap();
+        let _other: Allowance = policy.admit(&ledger, &scratch.join("b"))
+            .unwrap();
+        replacing.reserve(50).unwrap();
+        replacing.check(20).unwrap();
+        assert_eq!(ledger.usage(scratch.path()), Some(80));
+
+        /* 20 bytes written where 30 were, with the rest of the 50 handed
+            back */
+        replacing.commit();
+        drop(replacing);
+        assert_eq!(ledger.usage(scratch.path()), Some(20));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1048576));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("100m"), Ok(100 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("lots"),
+                   Err(PolicyError::InvalidSize("lots".to_string())));
+        assert!(parse_size("99999999999G").is_err());
+    }
+}
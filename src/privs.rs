@@ -0,0 +1,286 @@
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use libc::{gid_t, uid_t};
+use thiserror::Error;
+
+/*
+ * Giving up the privileges needed to bind a low port, once it's bound.
+ *
+ * Users and groups are looked up before anything else is done, since the
+ * files they're looked up in are unlikely to be reachable after a chroot.
+ * Every step is checked, and a failure at any of them is reported rather
+ * than carried on from: a server that meant to drop root but didn't mustn't
+ * start serving.
+ */
+
+/* big enough for any reasonable passwd or group entry */
+const LOOKUP_BUFFER_SIZE: usize = 16384;
+
+#[derive(Debug, Error)]
+pub enum PrivError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    NoGroup(String),    /* a user without an entry, and so a primary group */
+    GroupWithoutUser,
+    Lookup(String, io::Error),
+    Chroot(io::Error),
+    Drop(io::Error),
+    Regained     /* root could be regained after supposedly dropping it */
+}
+
+impl fmt::Display for PrivError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrivError::UnknownUser(name) =>
+                write!(f, "No such user: {}", name),
+            PrivError::UnknownGroup(name) =>
+                write!(f, "No such group: {}", name),
+            PrivError::NoGroup(user) =>
+                write!(f, "User {} has no primary group, so one must be \
+                           given", user),
+            PrivError::GroupWithoutUser =>
+                write!(f, "A group can only be given along with a user"),
+            PrivError::Lookup(name, e) =>
+                write!(f, "Unable to look up {}: {}", name, e),
+            PrivError::Chroot(e) => write!(f, "Unable to chroot: {}", e),
+            PrivError::Drop(e) =>
+                write!(f, "Unable to drop privileges: {}", e),
+            PrivError::Regained =>
+                write!(f, "Privileges were not dropped: root is still \
+                           attainable")
+        }
+    }
+}
+
+/* whom to run as once privileges are dropped */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    uid: uid_t,
+    gid: gid_t
+}
+
+impl Identity {
+    pub fn new(uid: uid_t, gid: gid_t) -> Self {
+        Identity {
+            uid,
+            gid
+        }
+    }
+
+    pub fn uid(&self) -> uid_t {
+        self.uid
+    }
+
+    pub fn gid(&self) -> gid_t {
+        self.gid
+    }
+}
+
+/* whom the user and group asked for, if anyone, amount to: a user, by name
+    or number, with either the given group or else the user's primary
+    group */
+pub fn identity(user: Option<&str>, group: Option<&str>) ->
+    Result<Option<Identity>, PrivError> {
+    resolve(user, group, lookup_user, lookup_group)
+}
+
+/* as for `identity`, looking users and groups up with the functions
+    given */
+fn resolve<U, G>(user: Option<&str>, group: Option<&str>, lookup_user: U,
+                 lookup_group: G) -> Result<Option<Identity>, PrivError>
+    where U: Fn(&str) -> Result<(uid_t, Option<gid_t>), PrivError>,
+          G: Fn(&str) -> Result<gid_t, PrivError> {
+    let user: &str = match (user, group) {
+        (Some(user), _) => user,
+        (None, Some(_)) => return Err(PrivError::GroupWithoutUser),
+        (None, None) => return Ok(None)
+    };
+
+    let (uid, primary): (uid_t, Option<gid_t>) = lookup_user(user)?;
+
+    let gid: gid_t = match (group, primary) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some(primary)) => primary,
+        (None, None) => return Err(PrivError::NoGroup(user.to_string()))
+    };
+
+    Ok(Some(Identity::new(uid, gid)))
+}
+
+/* confines the process to the given directory */
+pub fn chroot(root: &Path) -> Result<(), PrivError> {
+    let path: CString = CString::new(root.as_os_str().as_bytes())
+        .map_err(|e| PrivError::Chroot(io::Error::new(
+                    io::ErrorKind::InvalidInput, e)))?;
+
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(PrivError::Chroot(io::Error::last_os_error()));
+    }
+
+    std::env::set_current_dir("/").map_err(PrivError::Chroot)
+}
+
+/* becomes the given user and group for good, shedding any supplementary
+    groups */
+pub fn drop_to(identity: Identity) -> Result<(), PrivError> {
+    /* the group must go first, while we still have the right to change it */
+    unsafe {
+        if libc::setgroups(1, &identity.gid) != 0 ||
+            libc::setgid(identity.gid) != 0 ||
+            libc::setuid(identity.uid) != 0 {
+            return Err(PrivError::Drop(io::Error::last_os_error()));
+        }
+    }
+
+    let (uid, euid, gid, egid): (uid_t, uid_t, gid_t, gid_t) = unsafe {
+        (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid())
+    };
+
+    if uid != identity.uid || euid != identity.uid || gid != identity.gid ||
+        egid != identity.gid {
+        return Err(PrivError::Regained);
+    }
+
+    if identity.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(PrivError::Regained);
+    }
+
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<(uid_t, Option<gid_t>), PrivError> {
+    let c_name: CString = CString::new(name)
+        .map_err(|_| PrivError::UnknownUser(name.to_string()))?;
+    let mut entry: MaybeUninit<libc::passwd> = MaybeUninit::uninit();
+    let mut buf: Vec<libc::c_char> = vec![0; LOOKUP_BUFFER_SIZE];
+    let mut found: *mut libc::passwd = ptr::null_mut();
+
+    let status: libc::c_int = unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), entry.as_mut_ptr(),
+                         buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+
+    if status != 0 {
+        return Err(PrivError::Lookup(name.to_string(),
+                                     io::Error::from_raw_os_error(status)));
+    }
+
+    if found.is_null() {
+        /* a number will do for a user without an entry */
+        return match name.parse::<uid_t>() {
+            Ok(uid) => Ok((uid, None)),
+            Err(_) => Err(PrivError::UnknownUser(name.to_string()))
+        };
+    }
+
+    let entry: libc::passwd = unsafe { entry.assume_init() };
+    Ok((entry.pw_uid, Some(entry.pw_gid)))
+}
+
+fn lookup_group(name: &str) -> Result<gid_t, PrivError> {
+    let c_name: CString = CString::new(name)
+        .map_err(|_| PrivError::UnknownGroup(name.to_string()))?;
+    let mut entry: MaybeUninit<libc::group> = MaybeUninit::uninit();
+    let mut buf: Vec<libc::c_char> = vec![0; LOOKUP_BUFFER_SIZE];
+    let mut found: *mut libc::group = ptr::null_mut();
+
+    let status: libc::c_int = unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), entry.as_mut_ptr(),
+                         buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+
+    if status != 0 {
+        return Err(PrivError::Lookup(name.to_string(),
+                                     io::Error::from_raw_os_error(status)));
+    }
+
+    if found.is_null() {
+        return name.parse::<gid_t>()
+            .map_err(|_| PrivError::UnknownGroup(name.to_string()));
+    }
+
+    let entry: libc::group = unsafe { entry.assume_init() };
+    Ok(entry.gr_gid)
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a user database of one user, "tftp", and one group, "tftp" */
+    fn fake_user(name: &str) -> Result<(uid_t, Option<gid_t>), PrivError> {
+        match name {
+            "tftp" => Ok((500, Some(500))),
+            _ => match name.parse::<uid_t>() {
+                Ok(uid) => Ok((uid, None)),
+                Err(_) => Err(PrivError::UnknownUser(name.to_string()))
+            }
+        }
+    }
+
+    fn fake_group(name: &str) -> Result<gid_t, PrivError> {
+        match name {
+            "tftp" => Ok(500),
+            _ => name.parse::<gid_t>()
+                .map_err(|_| PrivError::UnknownGroup(name.to_string()))
+        }
+    }
+
+    fn fake(user: Option<&str>, group: Option<&str>) ->
+        Result<Option<Identity>, PrivError> {
+        resolve(user, group, fake_user, fake_group)
+    }
+
+    #[test]
+    fn takes_the_users_primary_group_unless_given_one() {
+        assert_eq!(fake(None, None).unwrap(), None);
+        assert_eq!(fake(Some("tftp"), None).unwrap(),
+                   Some(Identity::new(500, 500)));
+        assert_eq!(fake(Some("tftp"), Some("600")).unwrap(),
+                   Some(Identity::new(500, 600)));
+        assert_eq!(fake(Some("700"), Some("tftp")).unwrap(),
+                   Some(Identity::new(700, 500)));
+    }
+
+    #[test]
+    fn refuses_what_cannot_be_resolved() {
+        assert!(matches!(fake(None, Some("tftp")),
+                         Err(PrivError::GroupWithoutUser)));
+        assert!(matches!(fake(Some("nobody-here"), None),
+                         Err(PrivError::UnknownUser(name))
+                         if name == "nobody-here"));
+        assert!(matches!(fake(Some("tftp"), Some("nobody-here")),
+                         Err(PrivError::UnknownGroup(name))
+                         if name == "nobody-here"));
+
+        /* a user known only by number has no primary group to fall back
+            on */
+        assert!(matches!(fake(Some("700"), None),
+                         Err(PrivError::NoGroup(name)) if name == "700"));
+    }
+
+    #[test]
+    fn looks_up_the_system_databases() {
+        assert_eq!(identity(Some("root"), None).unwrap(),
+                   Some(Identity::new(0, 0)));
+        assert_eq!(lookup_user("4000000000").unwrap(), (4000000000, None));
+        assert_eq!(lookup_group("4000000000").unwrap(), 4000000000);
+
+        assert!(matches!(lookup_user("no such user"),
+                         Err(PrivError::UnknownUser(_))));
+        assert!(matches!(lookup_group("no such group"),
+                         Err(PrivError::UnknownGroup(_))));
+        assert!(matches!(lookup_user("nul\0inside"),
+                         Err(PrivError::UnknownUser(_))));
+    }
+}
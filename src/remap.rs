@@ -0,0 +1,494 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use regex::{Captures, Regex, RegexBuilder};
+use thiserror::Error;
+
+use crate::msg::MessageType;
+
+/* upper bound on the number of times rules may restart processing (via the
+    `s` flag) before we give up and treat the request as a remapping loop */
+pub const MAX_RESTARTS: usize = 1000;
+
+/*
+ * Filename remapping rules, compatible with the map files understood by
+ * tftp-hpa's `--map-file` option.
+ *
+ * Each non-blank line of a map file is of the form
+ *
+ *     <flags> <regex> [<replacement>]
+ *
+ * where `#` introduces a comment that runs to the end of the line and a
+ * backslash may be used to escape whitespace within a field. Flags are:
+ *
+ *     r   replace the matched portion of the filename with the replacement
+ *         (an omitted replacement deletes the matched portion)
+ *     g   in conjunction with `r`, replace every match rather than the first
+ *     i   match case-insensitively
+ *     e   stop processing rules if this rule matches
+ *     s   restart processing from the first rule if this rule matches
+ *     a   reject the request if this rule matches (the replacement, if any,
+ *         is sent to the client as the error message)
+ *     G   only apply this rule to read requests
+ *     P   only apply this rule to write requests
+ *     ~   invert the sense of the match (cannot be combined with `r`)
+ *
+ * Replacements may refer to `\0` (the entire match), `\1` through `\9`
+ * (subexpressions), `\i` (the client's IP address), `\x` (the client's IP
+ * address in hexadecimal) and `\\` (a literal backslash).
+ */
+
+#[derive(Debug, Error)]
+pub enum RemapError {
+    Io(io::Error),
+    UnknownFlag(usize, char),
+    MissingRegex(usize),
+    InvertedReplace(usize),
+    TrailingGarbage(usize),
+    InvalidRegex(usize, regex::Error)
+}
+
+impl fmt::Display for RemapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemapError::Io(e) => write!(f, "Unable to read map file: {}", e),
+            RemapError::UnknownFlag(line, flag) =>
+                write!(f, "Line {}: unknown remapping flag '{}'", line, flag),
+            RemapError::MissingRegex(line) =>
+                write!(f, "Line {}: rule lacks a regular expression", line),
+            RemapError::InvertedReplace(line) =>
+                write!(f, "Line {}: the '~' flag cannot be combined with 'r'",
+                       line),
+            RemapError::TrailingGarbage(line) =>
+                write!(f, "Line {}: unexpected text after replacement", line),
+            RemapError::InvalidRegex(line, e) =>
+                write!(f, "Line {}: invalid regular expression: {}", line, e)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct RuleFlags {
+    replace: bool,
+    global: bool,
+    case_insensitive: bool,
+    end: bool,
+    restart: bool,
+    abort: bool,
+    get_only: bool,
+    put_only: bool,
+    invert: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    line: usize,
+    flags: RuleFlags,
+    regex: Regex,
+    replacement: Option<String>
+}
+
+impl Rule {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    fn applies_to(&self, request: MessageType) -> bool {
+        match request {
+            MessageType::ReadRequest => !self.flags.put_only,
+            MessageType::WriteRequest => !self.flags.get_only,
+            _ => false
+        }
+    }
+
+    fn matches(&self, filename: &str) -> bool {
+        self.regex.is_match(filename) != self.flags.invert
+    }
+
+    fn rewrite(&self, filename: &str, client: IpAddr) -> String {
+        let replacement: &str = match &self.replacement {
+            Some(replacement) => replacement,
+            None => ""
+        };
+
+        let expand = |caps: &Captures| expand(replacement, caps, client);
+
+        if self.flags.global {
+            self.regex.replace_all(filename, expand).into_owned()
+        } else {
+            self.regex.replace(filename, expand).into_owned()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Remapped {
+    /* the (possibly rewritten) filename, along with the line numbers of each
+        rule that rewrote it, in the order they fired */
+    Filename { filename: String, rules: Vec<usize> },
+    /* the request was rejected by the rule on the given line */
+    Denied { rule: usize, message: String }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        RuleSet {
+            rules: Vec::new()
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, RemapError> {
+        let contents: String = fs::read_to_string(path)
+            .map_err(RemapError::Io)?;
+
+        RuleSet::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, RemapError> {
+        let mut rules: Vec<Rule> = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            if let Some(rule) = parse_rule(index + 1, line)? {
+                rules.push(rule);
+            }
+        }
+
+        Ok(RuleSet { rules })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn apply(&self, filename: &str, request: MessageType,
+                 client: IpAddr) -> Remapped {
+        let mut filename: String = filename.to_string();
+        let mut fired: Vec<usize> = Vec::new();
+        let mut restarts: usize = 0;
+        let mut i: usize = 0;
+
+        while i < self.rules.len() {
+            let rule: &Rule = &self.rules[i];
+            i += 1;
+
+            if !rule.applies_to(request) || !rule.matches(&filename) {
+                continue;
+            }
+
+            if rule.flags.abort {
+                return Remapped::Denied {
+                    rule: rule.line,
+                    message: match &rule.replacement {
+                        Some(message) => message.clone(),
+                        None => "Access denied by remapping rule".to_string()
+                    }
+                };
+            }
+
+            if rule.flags.replace {
+                filename = rule.rewrite(&filename, client);
+                fired.push(rule.line);
+            }
+
+            if rule.flags.end {
+                break;
+            }
+
+            if rule.flags.restart {
+                restarts += 1;
+
+                if restarts > MAX_RESTARTS {
+                    return Remapped::Denied {
+                        rule: rule.line,
+                        message: "Remapping loop detected".to_string()
+                    };
+                }
+
+                i = 0;
+            }
+        }
+
+        Remapped::Filename { filename, rules: fired }
+    }
+}
+
+fn parse_rule(line_num: usize, line: &str) -> Result<Option<Rule>, RemapError> {
+    let fields: Vec<String> = tokenise(line);
+    let mut fields = fields.iter();
+
+    let flag_string: &str = match fields.next() {
+        Some(field) => field,
+        None => return Ok(None) /* blank line */
+    };
+
+    let mut flags: RuleFlags = RuleFlags::default();
+
+    for flag in flag_string.chars() {
+        match flag {
+            'r' => flags.replace = true,
+            'g' => flags.global = true,
+            'i' => flags.case_insensitive = true,
+            'e' => flags.end = true,
+            's' => flags.restart = true,
+            'a' => flags.abort = true,
+            'G' => flags.get_only = true,
+            'P' => flags.put_only = true,
+            '~' => flags.invert = true,
+            _ => return Err(RemapError::UnknownFlag(line_num, flag))
+        }
+    }
+
+    if flags.invert && flags.replace {
+        return Err(RemapError::InvertedReplace(line_num));
+    }
+
+    let pattern: &str = match fields.next() {
+        Some(field) => field,
+        None => return Err(RemapError::MissingRegex(line_num))
+    };
+
+    let regex: Regex = RegexBuilder::new(pattern)
+        .case_insensitive(flags.case_insensitive)
+        .build()
+        .map_err(|e| RemapError::InvalidRegex(line_num, e))?;
+
+    let replacement: Option<String> = fields.next().cloned();
+
+    if fields.next().is_some() {
+        return Err(RemapError::TrailingGarbage(line_num));
+    }
+
+    Ok(Some(Rule {
+        line: line_num,
+        flags,
+        regex,
+        replacement
+    }))
+}
+
+/* splits a line into whitespace-separated fields, honouring backslash-escaped
+    whitespace and stripping comments */
+fn tokenise(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut field: String = String::new();
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '#' => break,
+            '\\' => match chars.next() {
+                Some(next) if next.is_whitespace() => field.push(next),
+                Some(next) => {
+                    /* leave other escapes for the regex engine and the
+                        replacement expander to interpret */
+                    field.push('\\');
+                    field.push(next);
+                },
+                None => field.push('\\')
+            },
+            _ if ch.is_whitespace() => {
+                if !field.is_empty() {
+                    fields.push(field);
+                    field = String::new();
+                }
+            },
+            _ => field.push(ch)
+        }
+    }
+
+    if !field.is_empty() {
+        fields.push(field);
+    }
+
+    fields
+}
+
+fn expand(replacement: &str, caps: &Captures, client: IpAddr) -> String {
+    let mut expanded: String = String::new();
+    let mut chars = replacement.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            expanded.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some(digit @ '0'..='9') => {
+                let group: usize = digit as usize - '0' as usize;
+
+                if let Some(m) = caps.get(group) {
+                    expanded.push_str(m.as_str());
+                }
+            },
+            Some('i') => expanded.push_str(&client.to_string()),
+            Some('x') => expanded.push_str(&hex_address(client)),
+            Some(other) => expanded.push(other),
+            None => expanded.push('\\')
+        }
+    }
+
+    expanded
+}
+
+fn hex_address(addr: IpAddr) -> String {
+    let octets: Vec<u8> = match addr {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec()
+    };
+
+    octets.iter().map(|octet| format!("{:02X}", octet)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const RRQ: MessageType = MessageType::ReadRequest;
+    const WRQ: MessageType = MessageType::WriteRequest;
+
+    fn renamed(filename: &str, rules: &[usize]) -> Remapped {
+        Remapped::Filename {
+            filename: filename.to_string(),
+            rules: rules.to_vec()
+        }
+    }
+
+    fn denied(rule: usize, message: &str) -> Remapped {
+        Remapped::Denied {
+            rule,
+            message: message.to_string()
+        }
+    }
+
+    #[test]
+    fn applies_rules() {
+        /* map file, request, filename, outcome */
+        let cases: Vec<(&str, MessageType, &str, Remapped)> = vec![
+            /* r, with and without a replacement */
+            (r"r ^/ ", RRQ, "/boot/img", renamed("boot/img", &[1])),
+            (r"r img image", RRQ, "boot/img", renamed("boot/image", &[1])),
+            (r"r img image", RRQ, "boot/elf", renamed("boot/elf", &[])),
+            /* g */
+            (r"r a b", RRQ, "aaa", renamed("baa", &[1])),
+            (r"rg a b", RRQ, "aaa", renamed("bbb", &[1])),
+            /* i */
+            (r"r BOOT boot", RRQ, "Boot/img", renamed("Boot/img", &[])),
+            (r"ri BOOT boot", RRQ, "Boot/img", renamed("boot/img", &[1])),
+            /* e */
+            ("r ^a b\nr b c", RRQ, "a", renamed("c", &[1, 2])),
+            ("re ^a b\nr b c", RRQ, "a", renamed("b", &[1])),
+            /* s */
+            ("r ^b$ c\nrs ^a$ b", RRQ, "a", renamed("c", &[2, 1])),
+            /* a, with and without a message */
+            (r"a ^secret", RRQ, "secret/key",
+             denied(1, "Access denied by remapping rule")),
+            (r"a ^secret No\ peeking", RRQ, "secret/key",
+             denied(1, "No peeking")),
+            ("r ^ pub/\na ^secret", RRQ, "secret/key",
+             renamed("pub/secret/key", &[1])),
+            /* G and P */
+            (r"rG ^ get/", RRQ, "x", renamed("get/x", &[1])),
+            (r"rG ^ get/", WRQ, "x", renamed("x", &[])),
+            (r"rP ^ put/", RRQ, "x", renamed("x", &[])),
+            (r"rP ^ put/", WRQ, "x", renamed("put/x", &[1])),
+            /* ~ */
+            (r"a~ ^pub/", RRQ, "etc/passwd",
+             denied(1, "Access denied by remapping rule")),
+            (r"a~ ^pub/", RRQ, "pub/x", renamed("pub/x", &[])),
+            /* blank lines and comments still count towards line numbers */
+            ("# comment\n\nr a b # comment", RRQ, "a", renamed("b", &[3]))
+        ];
+
+        for (map, request, filename, expected) in cases {
+            let rules: RuleSet = RuleSet::parse(map).unwrap();
+
+            assert_eq!(rules.apply(filename, request, CLIENT), expected,
+                       "{:?} applied to {}", map, filename);
+        }
+    }
+
+    #[test]
+    fn expands_replacements() {
+        /* replacement, filename, expansion */
+        let cases: Vec<(&str, &str, &str)> = vec![
+            (r"\0", "boot.img", "boot.img"),
+            (r"\2/\1", "boot.img", "img/boot"),
+            (r"\3\4\5\6\7\8\9", "boot.img", ""),
+            (r"\i/\0", "boot.img", "192.0.2.1/boot.img"),
+            (r"\x/\0", "boot.img", "C0000201/boot.img"),
+            (r"\\\0", "boot.img", r"\boot.img"),
+            (r"\q", "boot.img", "q")
+        ];
+
+        for (replacement, filename, expected) in cases {
+            let rules: RuleSet =
+                RuleSet::parse(&format!(r"r ^(\w+)\.(\w+)$ {}", replacement))
+                    .unwrap();
+
+            assert_eq!(rules.apply(filename, RRQ, CLIENT),
+                       renamed(expected, &[1]), "{}", replacement);
+        }
+    }
+
+    #[test]
+    fn expands_ipv6_addresses() {
+        let rules: RuleSet = RuleSet::parse(r"r ^ \x/").unwrap();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(rules.apply("x", RRQ, client),
+                   renamed("20010DB8000000000000000000000001/x", &[1]));
+    }
+
+    #[test]
+    fn gives_up_on_remapping_loops() {
+        let rules: RuleSet = RuleSet::parse("r ^a b\nrs ^b a").unwrap();
+
+        assert_eq!(rules.apply("a", RRQ, CLIENT),
+                   denied(2, "Remapping loop detected"));
+    }
+
+    #[test]
+    fn allows_restarts_up_to_the_limit() {
+        /* every restart strips one character, so a name one character
+            longer than the limit needs one restart too many */
+        let rules: RuleSet = RuleSet::parse("rs ^x ").unwrap();
+
+        let filename: String = "x".repeat(MAX_RESTARTS);
+        assert_eq!(rules.apply(&filename, RRQ, CLIENT),
+                   renamed("", &vec![1; MAX_RESTARTS]));
+
+        let filename: String = "x".repeat(MAX_RESTARTS + 1);
+        assert_eq!(rules.apply(&filename, RRQ, CLIENT),
+                   denied(1, "Remapping loop detected"));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert!(matches!(RuleSet::parse("q a"),
+                         Err(RemapError::UnknownFlag(1, 'q'))));
+        assert!(matches!(RuleSet::parse("\nr~ a b"),
+                         Err(RemapError::InvertedReplace(2))));
+        assert!(matches!(RuleSet::parse("r"),
+                         Err(RemapError::MissingRegex(1))));
+        assert!(matches!(RuleSet::parse("r ( b"),
+                         Err(RemapError::InvalidRegex(1, _))));
+        assert!(matches!(RuleSet::parse("r a b c"),
+                         Err(RemapError::TrailingGarbage(1))));
+    }
+}
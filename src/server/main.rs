@@ -1,31 +1,787 @@
 extern crate clap;
-use clap::{Arg, App};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use clap::{Arg, App, ArgMatches};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use toml::Spanned;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+
+use nettlesoup::acl::{AccessList, Rule};
+use nettlesoup::conf::{ConfigFile, Setting};
+use nettlesoup::inherit;
+use nettlesoup::limit::{self, Limits};
+use nettlesoup::log::{self, Logging, Sink};
+use nettlesoup::options;
+use nettlesoup::pcap;
+use nettlesoup::policy::{self, WriteMode, WritePolicy};
+use nettlesoup::privs::{self, Identity};
+use nettlesoup::remap::RuleSet;
+use nettlesoup::srv::{self, Config, Handle, Server};
+use nettlesoup::throttle::{self, Bandwidth};
+
+/* the unit tests' scaffolding, which the library keeps to its own tests */
+#[cfg(test)]
+#[path = "../testutil.rs"]
+mod testutil;
+
+/* how long to linger under inetd, in case more requests follow the first */
+const INETD_IDLE_TIMEOUT: Duration = Duration::from_secs(900);
 
 fn main() {
+    let matches: ArgMatches = app().get_matches();
+
+    let settings: Settings = Settings::new(&matches)
+        .unwrap_or_else(|e| fail(&e));
+    let (config, logging): (Config, Logging) = load(&settings)
+        .unwrap_or_else(|e| fail(&e));
+
+    /* looked up now, while the user and group databases are within reach */
+    let identity: Option<Identity> = identity(&settings)
+        .unwrap_or_else(|e| fail(&e));
+    let chroot: bool = settings.flag("chroot").unwrap_or_else(|e| fail(&e));
+    let inetd: bool = settings.flag("inetd").unwrap_or_else(|e| fail(&e));
+    let capture: Option<PathBuf> = settings.get("capture", |path| {
+        Ok(PathBuf::from(path))
+    }).unwrap_or_else(|e| fail(&e));
+    drop(settings);
+
+    /* sockets passed down by inetd or systemd stand in for the configured
+        listening addresses */
+    let sockets: Option<Vec<UdpSocket>> = if inetd {
+        inherit::inetd().map(|socket| Some(vec![socket]))
+    } else {
+        inherit::systemd()
+    }.unwrap_or_else(|e| fail(&e.to_string()));
+    let inherited: bool = sockets.is_some();
+
+    if let Err(e) = log::init(&logging, "tftpd") {
+        fail(&e.to_string());
+    }
+
+    if let Some(path) = &capture {
+        if let Err(e) = pcap::capture(path) {
+            fail(&e.to_string());
+        }
+    }
+
+    let root: PathBuf = config.root().to_path_buf();
+    let server: Server = match sockets {
+        Some(sockets) => Server::with_sockets(config, sockets),
+        None => Server::bind(config)
+    }.unwrap_or_else(|e| fail(&e.to_string()));
+    let handle: Handle = server.handle();
+    let listen: Vec<SocketAddr> = server.config().listen().to_vec();
+
+    /* everything that needs privileges has been done by now, so failing to
+        give them up is fatal */
+    if chroot {
+        if let Err(e) = privs::chroot(&root) {
+            fail(&e.to_string());
+        }
+
+        let mut config: Config = (*server.config()).clone();
+        config.set_root(PathBuf::from("/"));
+        handle.reload(config);
+    }
+
+    if let Some(identity) = identity {
+        if let Err(e) = privs::drop_to(identity) {
+            fail(&e.to_string());
+        }
+    } else if privs::is_root() {
+        warn!("running as root; consider --user");
+    }
+
+    let mut signals: Signals = Signals::new([SIGHUP, SIGINT, SIGTERM])
+        .unwrap_or_else(|e| {
+            fail(&format!("Unable to install signal handlers: {}", e))
+        });
+
+    thread::spawn(move || {
+        let mut stopping: bool = false;
+
+        for signal in signals.forever() {
+            match signal {
+                /* the logging and listening settings are fixed at startup,
+                    as is the root once chrooted into */
+                SIGHUP => match Settings::new(&matches)
+                    .and_then(|settings| load(&settings)) {
+                    Ok((mut config, _)) => {
+                        if chroot {
+                            config.set_root(PathBuf::from("/"));
+                        }
+
+                        if inherited {
+                            config.set_listen(listen.clone());
+                        }
+
+                        handle.reload(config);
+                        info!("reloaded configuration");
+                    },
+                    Err(e) => error!("configuration not reloaded: {}", e)
+                },
+                /* a second request to stop is taken as impatience */
+                _ if stopping => process::exit(1),
+                _ => {
+                    info!("shutting down");
+                    handle.shutdown();
+                    stopping = true;
+                }
+            }
+        }
+    });
+
+    if let Err(e) = server.run() {
+        fail(&e.to_string());
+    }
+}
+
+fn app() -> App<'static> {
     App::new("tftpd")
        .version("0.1.0")
        .about("The NettleSoup TFTP server")
        .author("Jack McPherson <jmcph4.github@gmail.com>")
        .arg(Arg::with_name("root")
-            .required(true)
             .value_name("ROOT")
-            .help("The root of the filesystem tree to confine requests to"))
+            .help("The root of the filesystem tree to confine requests to \
+                   (required unless given in the configuration file)"))
+       .arg(Arg::with_name("config")
+            .long("config")
+            .short('c')
+            .value_name("file")
+            .help("A TOML file of settings, named after these flags; any \
+                   flags given override it, and it is read again on SIGHUP")
+            .takes_value(true))
+       .arg(Arg::with_name("inetd")
+            .long("inetd")
+            .help("Serves the socket on standard input, as started by inetd \
+                   with the request waiting (\"wait\" in inetd.conf), \
+                   exiting once idle; logs to syslog unless told otherwise")
+            .conflicts_with_all(&["listen", "port"]))
+       .arg(Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .value_name("seconds")
+            .help("Exits after this long without a request or transfer \
+                   (defaults to 900 with --inetd, and to never otherwise)")
+            .takes_value(true))
+       .arg(Arg::with_name("chroot")
+            .long("chroot")
+            .short('s')
+            .help("Confines the server to ROOT once it's listening; the \
+                   configuration file and remapping rules are then looked \
+                   for within ROOT when reloaded"))
+       .arg(Arg::with_name("user")
+            .long("user")
+            .short('u')
+            .value_name("user")
+            .help("The user, by name or number, to run as once listening")
+            .takes_value(true))
+       .arg(Arg::with_name("group")
+            .long("group")
+            .short('g')
+            .value_name("group")
+            .help("The group to run as once listening (defaults to the \
+                   user's primary group)")
+            .takes_value(true))
        .arg(Arg::with_name("listen")
             .long("listen")
             .short('l')
             .value_name("address")
-            .help("The local address to listen on")
-            .takes_value(true))
+            .help("A local address to listen on, optionally with a port \
+                   (e.g. 192.0.2.1, [2001:db8::1]:6969); may be given more \
+                   than once, and defaults to every IPv4 and IPv6 address")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
        .arg(Arg::with_name("port")
             .long("port")
             .short('p')
             .value_name("port")
             .help("The local UDP port to listen on")
             .takes_value(true))
+       .arg(Arg::with_name("map-file")
+            .long("map-file")
+            .short('m')
+            .value_name("file")
+            .help("A file of regular expression rules used to remap \
+                   requested filenames (tftp-hpa format)")
+            .takes_value(true))
+       .arg(Arg::with_name("acl")
+            .long("acl")
+            .short('a')
+            .value_name("rule")
+            .help("An access control rule of the form \"<allow|deny> \
+                   <rrq|wrq|any> <network|any> [<path prefix>]\"; rules are \
+                   evaluated in order and the first match wins")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+       .arg(Arg::with_name("write-mode")
+            .long("write-mode")
+            .short('w')
+            .value_name("mode")
+            .help("Governs uploads: none (the default) rejects every write \
+                   request, create only permits creating new files and \
+                   overwrite also permits replacing existing ones")
+            .takes_value(true)
+            .possible_values(&["none", "create", "overwrite"]))
+       .arg(Arg::with_name("max-file-size")
+            .long("max-file-size")
+            .value_name("bytes")
+            .help("The largest file a client may upload (accepts K, M and G \
+                   suffixes)")
+            .takes_value(true))
+       .arg(Arg::with_name("quota")
+            .long("quota")
+            .value_name("bytes")
+            .help("The maximum total size of the files in any one directory \
+                   that uploads may bring about (accepts K, M and G suffixes)")
+            .takes_value(true))
+       .arg(Arg::with_name("fsync")
+            .long("fsync")
+            .help("Flushes uploads to disk before moving them into place"))
+       .arg(Arg::with_name("max-blksize")
+            .long("max-blksize")
+            .value_name("bytes")
+            .help("The largest block size a client may negotiate (RFC 2348)")
+            .takes_value(true))
+       .arg(Arg::with_name("max-windowsize")
+            .long("max-windowsize")
+            .value_name("blocks")
+            .help("The most blocks a client may negotiate being sent for \
+                   each acknowledgement (RFC 7440)")
+            .takes_value(true))
+       .arg(Arg::with_name("multicast")
+            .long("multicast")
+            .value_name("group:port")
+            .help("Serves clients that ask for it by multicasting to this \
+                   group (RFC 2090)")
+            .takes_value(true))
+       .arg(Arg::with_name("multicast-ttl")
+            .long("multicast-ttl")
+            .value_name("hops")
+            .help("The time-to-live of multicast datagrams (defaults to 1)")
+            .takes_value(true))
+       .arg(Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .value_name("rate")
+            .help("The most datagrams any one address may send, as a count \
+                   per second or <count>/<s|m|h>; those over the rate go \
+                   unanswered")
+            .takes_value(true))
+       .arg(Arg::with_name("max-client-transfers")
+            .long("max-client-transfers")
+            .value_name("count")
+            .help("The most transfers any one address may have under way")
+            .takes_value(true))
+       .arg(Arg::with_name("max-transfers")
+            .long("max-transfers")
+            .value_name("count")
+            .help("The most transfers that may be under way at once")
+            .takes_value(true))
+       .arg(Arg::with_name("verify-client")
+            .long("verify-client")
+            .help("Refuses read requests that don't negotiate options, so \
+                   that no data is sent before the client has acknowledged \
+                   an OACK from the address it claims"))
+       .arg(Arg::with_name("transfer-bandwidth")
+            .long("transfer-bandwidth")
+            .value_name("bytes")
+            .help("The most bytes per second any one transfer may move \
+                   (accepts K, M and G suffixes)")
+            .takes_value(true))
+       .arg(Arg::with_name("subnet-bandwidth")
+            .long("subnet-bandwidth")
+            .value_name("bytes")
+            .help("The most bytes per second the transfers of any one \
+                   client subnet may move between them (see \
+                   --subnet-prefix)")
+            .takes_value(true))
+       .arg(Arg::with_name("bandwidth")
+            .long("bandwidth")
+            .value_name("bytes")
+            .help("The most bytes per second all transfers may move between \
+                   them")
+            .takes_value(true))
+       .arg(Arg::with_name("subnet-prefix")
+            .long("subnet-prefix")
+            .value_name("v4[,v6]")
+            .help("The prefix lengths that group clients into subnets for \
+                   --subnet-bandwidth (defaults to 24,64)")
+            .takes_value(true))
+       .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("address")
+            .help("Serves Prometheus metrics over HTTP at /metrics on this \
+                   port of localhost, or on this address and port")
+            .takes_value(true))
+       .arg(Arg::with_name("shutdown-timeout")
+            .long("shutdown-timeout")
+            .value_name("seconds")
+            .help("How long transfers under way are given to finish once \
+                   the server has been told to stop (defaults to 30)")
+            .takes_value(true))
+       .arg(Arg::with_name("log")
+            .long("log")
+            .value_name("sink")
+            .help("Where to send the log: stdout (the default), syslog (the \
+                   local syslog socket), syslog:<socket> or the path of a \
+                   file to append to")
+            .takes_value(true))
+       .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("format")
+            .help("Writes log records as lines of text (the default) or as \
+                   JSON objects, one per line")
+            .takes_value(true)
+            .possible_values(&["text", "json"]))
+       .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("level")
+            .help("The least severe records to log: error, warn (the \
+                   default), info (adds the access log), debug (adds each \
+                   transfer's progress) or trace (adds every packet)")
+            .takes_value(true)
+            .possible_values(&["off", "error", "warn", "info", "debug",
+                               "trace"])
+            .conflicts_with("verbose"))
+       .arg(Arg::with_name("access-log")
+            .long("access-log")
+            .value_name("sink")
+            .help("Sends the access log, a record of the client, file and \
+                   result of every transfer, to a sink of its own (taking \
+                   the same values as --log) regardless of --log-level")
+            .takes_value(true))
        .arg(Arg::with_name("verbose")
             .long("verbose")
             .short('v')
-            .help("Enables verbose output to STDOUT"))
-       .get_matches();
+            .help("Logs each transfer's progress (the same as --log-level \
+                   debug)"))
+       .arg(Arg::with_name("capture")
+            .long("capture")
+            .value_name("file")
+            .help("Writes every datagram sent and received to a pcap file, \
+                   which tftpcap can analyse")
+            .takes_value(true))
+       .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Prints every datagram sent and received, decoded and \
+                   timestamped, to standard error rather than to the log"))
+}
+
+/* builds the server's configuration from its settings */
+fn load(settings: &Settings) -> Result<(Config, Logging), String> {
+    let matches: &ArgMatches = settings.matches;
+
+    let root: PathBuf = settings.get("root", |root| Ok(PathBuf::from(root)))?
+        .ok_or("No root given, either as an argument or in the configuration \
+                file")?;
+
+    let port: u16 = settings.get("port", |port| {
+        port.parse().map_err(|_| format!("Invalid port: {}", port))
+    })?.unwrap_or(srv::DEFAULT_PORT);
+
+    let listen: Vec<SocketAddr> = settings.get_all("listen", |address| {
+        listen_addr(address, port)
+            .ok_or_else(|| format!("Invalid listen address: {}", address))
+    })?.unwrap_or_else(|| {
+        vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+             SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)]
+    });
+
+    let inetd: bool = settings.flag("inetd")?;
+    let mut logging: Logging = Logging::new();
+
+    /* under inetd, standard output is the socket */
+    if inetd {
+        logging.set_sink(Sink::Syslog(PathBuf::from(log::SYSLOG_SOCKET)));
+    }
+
+    /* a level given on the command line outweighs verbosity asked for in
+        the configuration file */
+    if matches.is_present("verbose") ||
+        (!matches.is_present("log-level") && settings.flag("verbose")?) {
+        logging.set_level(LevelFilter::DEBUG);
+    } else if let Some(level) = settings.get("log-level", |level| {
+        log::parse_level(level).map_err(|e| e.to_string())
+    })? {
+        logging.set_level(level);
+    }
+
+    if let Some(format) = settings.get("log-format", |format| {
+        format.parse().map_err(|e: log::LogError| e.to_string())
+    })? {
+        logging.set_format(format);
+    }
+
+    let sink = |sink: &str| match sink.parse() {
+        Ok(Sink::Stdout) if inetd =>
+            Err("Can't log to standard output under inetd".to_string()),
+        parsed => parsed.map_err(|e: log::LogError| e.to_string())
+    };
+
+    if let Some(sink) = settings.get("log", sink)? {
+        logging.set_sink(sink);
+    }
+
+    logging.set_access(settings.get("access-log", sink)?);
+
+    if settings.flag("trace")? {
+        /* likewise standard error */
+        if inetd {
+            return Err("Can't trace packets under inetd".to_string());
+        }
+
+        logging.set_trace(true);
+    }
+
+    let mut config: Config = Config::new(root, listen);
+
+    if let Some(rules) = settings.get("map-file", |map_file| {
+        RuleSet::from_file(Path::new(map_file))
+            .map_err(|e| format!("{}: {}", map_file, e))
+    })? {
+        config.set_remap(rules);
+    }
+
+    if let Some(rules) = settings.get_all("acl", |rule| {
+        rule.parse::<Rule>()
+            .map_err(|e| format!("Invalid ACL rule '{}': {}", rule, e))
+    })? {
+        let mut acl: AccessList = AccessList::new();
+
+        for rule in rules {
+            acl.push(rule);
+        }
+
+        config.set_acl(acl);
+    }
+
+    let write_mode: WriteMode = settings.get("write-mode", |mode| {
+        mode.parse().map_err(|e: policy::PolicyError| e.to_string())
+    })?.unwrap_or(WriteMode::Disabled);
+
+    let mut write_policy: WritePolicy = WritePolicy::new(write_mode);
+    write_policy.set_max_file_size(settings.get("max-file-size",
+                                                parse_size)?);
+    write_policy.set_quota(settings.get("quota", parse_size)?);
+    config.set_write_policy(write_policy);
+    config.set_fsync(settings.flag("fsync")?);
+
+    if let Some(size) = settings.get("max-blksize", |size| {
+        options::parse_block_size(size).ok_or_else(|| {
+            format!("Invalid block size: {} (expected {} to {})", size,
+                    options::MIN_BLOCK_SIZE, options::MAX_BLOCK_SIZE)
+        })
+    })? {
+        config.set_max_block_size(size);
+    }
+
+    if let Some(size) = settings.get("max-windowsize", |size| {
+        options::parse_window_size(size).ok_or_else(|| {
+            format!("Invalid window size: {} (expected {} to {})", size,
+                    options::MIN_WINDOW_SIZE, options::MAX_WINDOW_SIZE)
+        })
+    })? {
+        config.set_max_window_size(size);
+    }
+
+    config.set_multicast(settings.get("multicast", |group| {
+        match group.parse::<SocketAddr>() {
+            Ok(group) if group.ip().is_multicast() => Ok(group),
+            _ => Err(format!("Invalid multicast group: {}", group))
+        }
+    })?);
+
+    if let Some(ttl) = settings.get("multicast-ttl", |ttl| {
+        ttl.parse().map_err(|_| format!("Invalid multicast TTL: {}", ttl))
+    })? {
+        config.set_multicast_ttl(ttl);
+    }
+
+    let mut limits: Limits = Limits::new();
+    limits.set_rate(settings.get("rate-limit", |rate| {
+        rate.parse().map_err(|e: limit::LimitError| e.to_string())
+    })?);
+    limits.set_per_client(settings.get("max-client-transfers",
+                                       parse_count)?);
+    limits.set_total(settings.get("max-transfers", parse_count)?);
+    limits.set_verify(settings.flag("verify-client")?);
+    config.set_limits(limits);
+
+    let mut bandwidth: Bandwidth = Bandwidth::new();
+    bandwidth.set_per_transfer(settings.get("transfer-bandwidth",
+                                            parse_rate)?);
+    bandwidth.set_per_subnet(settings.get("subnet-bandwidth", parse_rate)?);
+    bandwidth.set_total(settings.get("bandwidth", parse_rate)?);
+
+    if let Some((v4, v6)) = settings.get("subnet-prefix", |prefix| {
+        let invalid = || format!("Invalid subnet prefix: {}", prefix);
+        let (v4, v6): (&str, Option<&str>) = match prefix.split_once(',') {
+            Some((v4, v6)) => (v4, Some(v6)),
+            None => (prefix, None)
+        };
+        let v4: u8 = v4.parse().ok().filter(|v4| *v4 <= 32)
+            .ok_or_else(invalid)?;
+        let v6: u8 = match v6 {
+            Some(v6) => v6.parse().ok().filter(|v6| *v6 <= 128)
+                .ok_or_else(invalid)?,
+            None => throttle::DEFAULT_SUBNET_PREFIX_V6
+        };
+
+        Ok((v4, v6))
+    })? {
+        bandwidth.set_subnet_prefix(v4, v6);
+    }
+
+    config.set_bandwidth(bandwidth);
+
+    config.set_metrics(settings.get("metrics", |address| {
+        match address.parse::<u16>() {
+            Ok(port) =>
+                Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+            Err(_) => address.parse().map_err(|_| {
+                format!("Invalid metrics address: {}", address)
+            })
+        }
+    })?);
+
+    if let Some(timeout) = settings.get("shutdown-timeout", |timeout| {
+        timeout.parse().map(Duration::from_secs)
+            .map_err(|_| format!("Invalid shutdown timeout: {}", timeout))
+    })? {
+        config.set_shutdown_timeout(timeout);
+    }
+
+    config.set_idle_timeout(settings.get("idle-timeout", |timeout| {
+        timeout.parse().map(Duration::from_secs)
+            .map_err(|_| format!("Invalid idle timeout: {}", timeout))
+    })?.or(if inetd { Some(INETD_IDLE_TIMEOUT) } else { None }));
+
+    Ok((config, logging))
+}
+
+/* whom to run as once the listening sockets are bound, if anyone else */
+fn identity(settings: &Settings) -> Result<Option<Identity>, String> {
+    let user: Option<String> = settings.get("user", |user| {
+        Ok(user.to_string())
+    })?;
+    let group: Option<String> = settings.get("group", |group| {
+        Ok(group.to_string())
+    })?;
+
+    privs::identity(user.as_deref(), group.as_deref())
+        .map_err(|e| e.to_string())
+}
+
+/* where settings come from: the command line, or failing that the
+    configuration file */
+struct Settings<'a> {
+    matches: &'a ArgMatches,
+    file: Option<ConfigFile>
 }
 
+impl<'a> Settings<'a> {
+    fn new(matches: &'a ArgMatches) -> Result<Self, String> {
+        let file: Option<ConfigFile> = match matches.value_of("config") {
+            Some(path) => Some(ConfigFile::load(Path::new(path))
+                               .map_err(|e| e.to_string())?),
+            None => None
+        };
+
+        Ok(Settings {
+            matches,
+            file
+        })
+    }
+
+    /* the setting for a flag, checked by `parse`; a bad value in the
+        configuration file is reported against its line */
+    fn get<T, P>(&self, name: &str, parse: P) -> Result<Option<T>, String>
+        where P: Fn(&str) -> Result<T, String> {
+        if let Some(value) = self.matches.value_of(name) {
+            return parse(value).map(Some);
+        }
+
+        let file: &ConfigFile = match &self.file {
+            Some(file) => file,
+            None => return Ok(None)
+        };
+
+        match file.get(name) {
+            Some(value) => parse(value.get_ref().as_str()).map(Some)
+                .map_err(|e| file.invalid(value, &e).to_string()),
+            None => Ok(None)
+        }
+    }
+
+    /* as for `get`, but for a flag that may be given more than once */
+    fn get_all<T, P>(&self, name: &str, parse: P) ->
+        Result<Option<Vec<T>>, String>
+        where P: Fn(&str) -> Result<T, String> {
+        if let Some(values) = self.matches.values_of(name) {
+            return values.map(&parse).collect::<Result<_, _>>()
+                .map(Some);
+        }
+
+        let file: &ConfigFile = match &self.file {
+            Some(file) => file,
+            None => return Ok(None)
+        };
+
+        match file.get_all(name) {
+            Some(values) => values.iter().map(|value: &Spanned<Setting>| {
+                parse(value.get_ref().as_str())
+                    .map_err(|e| file.invalid(value, &e).to_string())
+            }).collect::<Result<_, _>>().map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /* whether a switch is on, either on the command line or in the
+        configuration file */
+    fn flag(&self, name: &str) -> Result<bool, String> {
+        if self.matches.is_present(name) {
+            return Ok(true);
+        }
+
+        Ok(self.get(name, |value| {
+            value.parse().map_err(|_| {
+                format!("Invalid value for {}: {} (expected true or false)",
+                        name, value)
+            })
+        })?.unwrap_or(false))
+    }
+}
+
+/* accepts either a bare address, which listens on the default port, or an
+    address and port */
+fn listen_addr(address: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Some(address);
+    }
+
+    address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()
+        .ok()
+        .map(|address| SocketAddr::new(address, port))
+}
+
+fn parse_count(count: &str) -> Result<usize, String> {
+    match count.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("Invalid count: {}", count))
+    }
+}
+
+/* bytes per second, with the same suffixes as sizes */
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    match policy::parse_size(rate) {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(format!("Invalid bandwidth: {}", rate))
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    policy::parse_size(size).map_err(|e| e.to_string())
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("tftpd: {}", message);
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Scratch;
+
+    /* the configuration and logging that the file and flags make */
+    fn loaded(file: &str, flags: &[&str]) ->
+        Result<(Config, Logging), String> {
+        let scratch: Scratch = Scratch::new();
+        let path: PathBuf = scratch.write("tftpd.toml", file.as_bytes());
+        let mut args: Vec<&str> = vec!["tftpd", "--config",
+                                       path.to_str().unwrap()];
+        args.extend_from_slice(flags);
+
+        let matches: ArgMatches = app().try_get_matches_from(args)
+            .map_err(|e| e.to_string())?;
+        load(&Settings::new(&matches)?)
+    }
+
+    #[test]
+    fn takes_settings_from_the_file() {
+        let (config, logging): (Config, Logging) = loaded("\
+root = \"/srv/tftp\"
+port = 6969
+listen = [\"127.0.0.1\", \"[::1]:7070\"]
+fsync = true
+
+[log]
+verbose = true
+trace = true
+", &[]).unwrap();
+
+        assert_eq!(config.root(), Path::new("/srv/tftp"));
+        assert_eq!(config.listen(),
+                   &["127.0.0.1:6969".parse().unwrap(),
+                     "[::1]:7070".parse().unwrap()]);
+        assert!(config.fsync());
+        assert_eq!(logging.level(), LevelFilter::DEBUG);
+        assert!(logging.trace());
+    }
+
+    #[test]
+    fn flags_outweigh_the_file() {
+        let file: &str = "\
+root = \"/srv/tftp\"
+port = 6969
+listen = [\"127.0.0.1\"]
+
+[log]
+level = \"error\"
+verbose = true
+";
+
+        let (config, logging): (Config, Logging) =
+            loaded(file, &["/srv/other", "--port", "7070", "--log-level",
+                           "info"]).unwrap();
+        assert_eq!(config.root(), Path::new("/srv/other"));
+        assert_eq!(config.listen(), &["127.0.0.1:7070".parse().unwrap()]);
+        assert_eq!(logging.level(), LevelFilter::INFO);
+
+        /* a listen flag replaces every address in the file */
+        let (config, _): (Config, Logging) =
+            loaded(file, &["--listen", "[::1]"]).unwrap();
+        assert_eq!(config.listen(), &["[::1]:6969".parse().unwrap()]);
+
+        /* a switch can only be turned on from the command line */
+        let (_, logging): (Config, Logging) =
+            loaded("root = \"/srv\"\n[log]\ntrace = false\n", &["--trace"])
+            .unwrap();
+        assert!(logging.trace());
+    }
+
+    #[test]
+    fn reports_bad_values_against_their_lines() {
+        let e: String = loaded("root = \"/srv\"\n\nport = \"x\"\n", &[])
+            .unwrap_err();
+        assert!(e.ends_with(": line 3: Invalid port: x"), "{}", e);
+
+        let e: String = loaded("root = \"/srv\"\n[log]\nverbose = \"yes\"\n",
+                               &[]).unwrap_err();
+        assert!(e.ends_with(": line 3: Invalid value for verbose: yes \
+                             (expected true or false)"), "{}", e);
+
+        /* but a flag standing in for a bad value hides it */
+        assert!(loaded("root = \"/srv\"\nport = \"x\"\n", &["--port", "69"])
+                .is_ok());
+    }
+}
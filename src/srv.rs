@@ -0,0 +1,1049 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::acl::AccessList;
+use crate::limit::{Limiter, Limits, Refusal, Slot};
+use crate::log;
+use crate::conn::{Connection, Output, Role, Transfer, TransferError};
+use crate::mcast::{self, Ineligible, Registry};
+use crate::metrics::{self, Metrics};
+use crate::msg::{self, AnyMessage, ErrorMessage, ErrorMessageCode,
+                 OptionList, ReadRequestMessage, ReadWriteRequestMessageMode,
+                 WriteRequestMessage};
+use crate::netascii::{NetAsciiDecoder, NetAsciiReader};
+use crate::options::{self, MulticastGroup, TransferOptions};
+use crate::policy::{Allowance, Ledger, Violation, WriteMode, WritePolicy};
+use crate::remap::{Remapped, RuleSet};
+use crate::stats::{Direction, TransferStats};
+use crate::throttle::{Bandwidth, Throttle, Throttler};
+use crate::upload::{self, PendingUpload};
+use crate::xfer::{self, RECV_BUFFER_SIZE};
+
+pub const DEFAULT_PORT: u16 = 69;
+pub const BLOCK_SIZE: usize = 512;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RETRIES: usize = 5;
+pub const DEFAULT_MULTICAST_TTL: u32 = 1;
+/* the most blocks a client may have sent before each ACK, unless configured
+    otherwise; enough to fill a fast link without flooding a slow one */
+pub const DEFAULT_MAX_WINDOW_SIZE: usize = 64;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/* how often the server checks whether it has been asked to stop */
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    Bind(SocketAddr, io::Error),
+    Io(io::Error)
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Bind(addr, e) =>
+                write!(f, "Unable to bind to {}: {}", addr, e),
+            ServerError::Io(e) => write!(f, "I/O error: {}", e)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    root: PathBuf,
+    listen: Vec<SocketAddr>,
+    timeout: Duration,
+    retries: usize,
+    remap: RuleSet,
+    acl: AccessList,
+    write_policy: WritePolicy,
+    fsync: bool,
+    max_block_size: usize,
+    max_window_size: usize,
+    multicast: Option<SocketAddr>,  /* group offered to RFC 2090 clients */
+    multicast_ttl: u32,
+    metrics: Option<SocketAddr>,    /* where to serve Prometheus metrics */
+    shutdown_timeout: Duration,     /* how long to let transfers finish */
+    idle_timeout: Option<Duration>, /* how long to wait for work, if not
+                                        forever */
+    limits: Limits,
+    bandwidth: Bandwidth
+}
+
+impl Config {
+    pub fn new(root: PathBuf, listen: Vec<SocketAddr>) -> Self {
+        Config {
+            root,
+            listen,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            remap: RuleSet::new(),
+            acl: AccessList::new(),
+            write_policy: WritePolicy::default(),
+            fsync: false,
+            max_block_size: options::MAX_BLOCK_SIZE,
+            max_window_size: DEFAULT_MAX_WINDOW_SIZE,
+            multicast: None,
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
+            metrics: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            idle_timeout: None,
+            limits: Limits::new(),
+            bandwidth: Bandwidth::new()
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn listen(&self) -> &[SocketAddr] {
+        &self.listen
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    pub fn remap(&self) -> &RuleSet {
+        &self.remap
+    }
+
+    pub fn acl(&self) -> &AccessList {
+        &self.acl
+    }
+
+    pub fn write_policy(&self) -> &WritePolicy {
+        &self.write_policy
+    }
+
+    pub fn fsync(&self) -> bool {
+        self.fsync
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+
+    pub fn max_window_size(&self) -> usize {
+        self.max_window_size
+    }
+
+    pub fn multicast(&self) -> Option<SocketAddr> {
+        self.multicast
+    }
+
+    pub fn multicast_ttl(&self) -> u32 {
+        self.multicast_ttl
+    }
+
+    pub fn metrics(&self) -> Option<SocketAddr> {
+        self.metrics
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    pub fn set_root(&mut self, root: PathBuf) {
+        self.root = root;
+    }
+
+    pub fn set_listen(&mut self, listen: Vec<SocketAddr>) {
+        self.listen = listen;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    pub fn set_remap(&mut self, remap: RuleSet) {
+        self.remap = remap;
+    }
+
+    pub fn set_acl(&mut self, acl: AccessList) {
+        self.acl = acl;
+    }
+
+    pub fn set_write_policy(&mut self, write_policy: WritePolicy) {
+        self.write_policy = write_policy;
+    }
+
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fsync = fsync;
+    }
+
+    pub fn set_max_block_size(&mut self, max_block_size: usize) {
+        self.max_block_size = max_block_size;
+    }
+
+    pub fn set_max_window_size(&mut self, max_window_size: usize) {
+        self.max_window_size = max_window_size;
+    }
+
+    pub fn set_multicast(&mut self, multicast: Option<SocketAddr>) {
+        self.multicast = multicast;
+    }
+
+    pub fn set_multicast_ttl(&mut self, multicast_ttl: u32) {
+        self.multicast_ttl = multicast_ttl;
+    }
+
+    pub fn set_metrics(&mut self, metrics: Option<SocketAddr>) {
+        self.metrics = metrics;
+    }
+
+    pub fn set_shutdown_timeout(&mut self, shutdown_timeout: Duration) {
+        self.shutdown_timeout = shutdown_timeout;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+}
+
+/* what a running server shares with its listeners and handles */
+struct Shared {
+    config: RwLock<Arc<Config>>,    /* replaced wholesale on reload */
+    stopping: AtomicBool,
+    in_flight: AtomicUsize,         /* requests being handled */
+    last_active: Mutex<Instant>,    /* when a request last came or went */
+    limiter: Arc<Limiter>,
+    throttler: Throttler,
+    ledger: Arc<Ledger>             /* uploads counted against quotas */
+}
+
+impl Shared {
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /* whether there's been nothing to do for longer than the idle timeout */
+    fn idle(&self) -> bool {
+        match self.config().idle_timeout() {
+            Some(timeout) => self.in_flight.load(Ordering::SeqCst) == 0 &&
+                self.last_active.lock().unwrap().elapsed() >= timeout,
+            None => false
+        }
+    }
+}
+
+pub struct Server {
+    shared: Arc<Shared>,
+    listeners: Vec<Listener>,
+    metrics: Arc<Metrics>,
+    exporter: Option<TcpListener>   /* serves the metrics, if asked to */
+}
+
+impl Server {
+    pub fn bind(config: Config) -> Result<Self, ServerError> {
+        let sockets: Vec<UdpSocket> = config.listen().iter()
+            .map(|addr| bind_listener(*addr)
+                 .map_err(|e| ServerError::Bind(*addr, e)))
+            .collect::<Result<_, _>>()?;
+
+        Server::with_sockets(config, sockets)
+    }
+
+    /* serves requests arriving on sockets bound elsewhere (see inherit),
+        whose addresses take the place of those configured */
+    pub fn with_sockets(config: Config, sockets: Vec<UdpSocket>) ->
+        Result<Self, ServerError> {
+        let mut config: Config = config;
+        config.set_listen(sockets.iter()
+                          .map(|socket| socket.local_addr())
+                          .collect::<io::Result<_>>()
+                          .map_err(ServerError::Io)?);
+
+        let shared: Arc<Shared> = Arc::new(Shared {
+            config: RwLock::new(Arc::new(config)),
+            stopping: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            last_active: Mutex::new(Instant::now()),
+            limiter: Arc::new(Limiter::new()),
+            throttler: Throttler::new(),
+            ledger: Arc::new(Ledger::new())
+        });
+        let config: Arc<Config> = shared.config();
+        let sessions: Arc<Registry> = Arc::new(Registry::new());
+        let metrics: Arc<Metrics> = Arc::new(Metrics::new());
+        let listeners: Vec<Listener> = sockets.into_iter()
+            .map(|socket| Listener {
+                shared: shared.clone(),
+                socket,
+                sessions: sessions.clone(),
+                metrics: metrics.clone()
+            })
+            .collect();
+
+        let exporter: Option<TcpListener> = match config.metrics() {
+            Some(addr) => Some(TcpListener::bind(addr)
+                               .map_err(|e| ServerError::Bind(addr, e))?),
+            None => None
+        };
+
+        /* any uploads still in progress when a previous instance died can
+            never be completed (and there are none to find if uploads are
+            off) */
+        if config.write_policy().mode() != WriteMode::Disabled {
+            for path in upload::remove_stale(config.root()) {
+                info!("removed stale upload {}", path.display());
+            }
+        }
+
+        Ok(Server {
+            shared,
+            listeners,
+            metrics,
+            exporter
+        })
+    }
+
+    /* the configuration new requests are served under */
+    pub fn config(&self) -> Arc<Config> {
+        self.shared.config()
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle {
+            shared: self.shared.clone()
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, ServerError> {
+        self.listeners.iter()
+            .map(|listener| listener.socket.local_addr()
+                 .map_err(ServerError::Io))
+            .collect()
+    }
+
+    /* serves every listening address until one of them fails, the server
+        is shut down (see Handle) or it has been idle for the idle timeout;
+        when shut down, transfers under way are given until the shutdown
+        timeout to finish */
+    pub fn run(&self) -> Result<(), ServerError> {
+        let (errors, failure): (Sender<ServerError>, Receiver<ServerError>) =
+            mpsc::channel();
+
+        for listener in &self.listeners {
+            let listener: Listener = listener.try_clone()
+                .map_err(ServerError::Io)?;
+            let errors: Sender<ServerError> = errors.clone();
+
+            thread::spawn(move || {
+                if let Err(e) = listener.run() {
+                    let _ = errors.send(e);
+                }
+            });
+        }
+
+        if let Some(exporter) = &self.exporter {
+            let exporter: TcpListener = exporter.try_clone()
+                .map_err(ServerError::Io)?;
+            let metrics: Arc<Metrics> = self.metrics.clone();
+            let errors: Sender<ServerError> = errors.clone();
+
+            thread::spawn(move || {
+                if let Err(e) = metrics::serve(exporter, &metrics) {
+                    let _ = errors.send(ServerError::Io(e));
+                }
+            });
+        }
+
+        loop {
+            match failure.recv_timeout(POLL_INTERVAL) {
+                Ok(e) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.shared.stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if self.shared.idle() {
+                        info!("exiting after being idle");
+                        self.shared.stopping.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                },
+                /* every listener has stopped, or there were none */
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        }
+
+        self.drain();
+        Ok(())
+    }
+
+    /* waits for requests being handled to finish, up to the shutdown
+        timeout */
+    fn drain(&self) {
+        let deadline: Instant = Instant::now() +
+            self.shared.config().shutdown_timeout();
+        let pending: usize = self.shared.in_flight.load(Ordering::SeqCst);
+
+        if pending > 0 {
+            info!("waiting for {} transfers to finish", pending);
+        }
+
+        loop {
+            let pending: usize = self.shared.in_flight.load(Ordering::SeqCst);
+
+            if pending == 0 {
+                return;
+            }
+
+            if Instant::now() >= deadline {
+                warn!("abandoning {} unfinished transfers", pending);
+                return;
+            }
+
+            thread::sleep(POLL_INTERVAL.min(deadline - Instant::now()));
+        }
+    }
+}
+
+/* controls a running server from elsewhere, such as a signal handler */
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>
+}
+
+impl Handle {
+    /* serves new requests under the given configuration; those already
+        being served carry on under the old one. The listening addresses and
+        metrics endpoint are fixed when the server is bound and so are left
+        as they are */
+    pub fn reload(&self, config: Config) {
+        let mut current = self.shared.config.write().unwrap();
+
+        if config.listen() != current.listen() ||
+            config.metrics() != current.metrics() {
+            warn!("listening addresses can only be changed by a restart");
+        }
+
+        *current = Arc::new(config);
+    }
+
+    /* stops taking new requests, so that Server::run returns once those
+        being served have finished */
+    pub fn shutdown(&self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+    }
+}
+
+/* a socket on which requests are received */
+struct Listener {
+    shared: Arc<Shared>,
+    socket: UdpSocket,
+    sessions: Arc<Registry>,
+    metrics: Arc<Metrics>
+}
+
+impl Listener {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Listener {
+            shared: self.shared.clone(),
+            socket: self.socket.try_clone()?,
+            sessions: self.sessions.clone(),
+            metrics: self.metrics.clone()
+        })
+    }
+
+    fn run(&self) -> Result<(), ServerError> {
+        let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+        let local: IpAddr = self.socket.local_addr()
+            .map_err(ServerError::Io)?.ip();
+        self.socket.set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(ServerError::Io)?;
+
+        loop {
+            let received: io::Result<(usize, SocketAddr)> =
+                self.socket.recv_from(&mut buf);
+
+            /* anything that arrives once we've been told to stop goes
+                unanswered */
+            if self.shared.stopping.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let (len, peer): (usize, SocketAddr) = match received {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted ||
+                    xfer::is_timeout(&e) => continue,
+                Err(e) => return Err(ServerError::Io(e))
+            };
+
+            self.shared.touch();
+            log::packet(log::Flow::Received, &self.socket, peer, &buf[..len]);
+
+            let config: Arc<Config> = self.shared.config();
+
+            /* anything over the rate, well-formed or not, goes unanswered */
+            if let Err(refusal) = self.shared.limiter
+                .check_rate(config.limits(), peer.ip()) {
+                self.metrics.limited(refusal);
+
+                match refusal {
+                    Refusal::Rate(1) => warn!(client = %peer,
+                                              "over the rate limit; \
+                                               dropping its datagrams"),
+                    _ => debug!(client = %peer, "dropped datagram: {}",
+                                refusal)
+                }
+
+                continue;
+            }
+
+            self.dispatch(config, buf[..len].to_vec(), local, peer);
+        }
+    }
+
+    fn dispatch(&self, config: Arc<Config>, bytes: Vec<u8>, local: IpAddr,
+                peer: SocketAddr) {
+        match AnyMessage::from_bytes(bytes) {
+            Ok(request @ AnyMessage::Rrq(_)) |
+            Ok(request @ AnyMessage::Wrq(_)) => {
+                let slot: Slot = match self.shared.limiter
+                    .admit(config.limits(), peer.ip()) {
+                    Ok(slot) => slot,
+                    Err(refusal) => return self.refuse(&request, peer,
+                                                       refusal)
+                };
+                let throttle: Throttle = self.shared.throttler
+                    .throttle(config.bandwidth(), peer.ip());
+                let sessions: Arc<Registry> = self.sessions.clone();
+                let metrics: Arc<Metrics> = self.metrics.clone();
+                let ledger: Arc<Ledger> = self.shared.ledger.clone();
+                let in_flight: InFlight = InFlight::new(&self.shared);
+
+                thread::spawn(move || {
+                    handle_request(&config, &sessions, &metrics, &throttle,
+                                   &ledger, request, local, peer);
+                    drop(slot);
+                    drop(in_flight);
+                });
+            },
+            Ok(_) => self.reject(peer, msg::ERROR_ILLEGAL_OPERATION,
+                                 "Expected a read or write request"),
+            Err(e) => {
+                debug!(client = %peer, "malformed request: {}", e);
+
+                self.reject(peer, msg::ERROR_ILLEGAL_OPERATION,
+                            "Malformed request");
+            }
+        }
+    }
+
+    /* turns away a request over one of the caps on concurrent transfers */
+    fn refuse(&self, request: &AnyMessage, peer: SocketAddr,
+              refusal: Refusal) {
+        let (filename, direction): (String, Direction) = match request {
+            AnyMessage::Wrq(wrq) => (wrq.filename(), Direction::Write),
+            AnyMessage::Rrq(rrq) => (rrq.filename(), Direction::Read),
+            _ => return
+        };
+
+        self.metrics.limited(refusal);
+        info!(target: log::ACCESS, client = %peer, file = %filename,
+              direction = %direction, result = "limited", bytes = 0,
+              "{} of '{}' refused: {}", direction, filename, refusal);
+
+        self.reject(peer, msg::ERROR_NOT_DEFINED, &refusal.to_string());
+    }
+
+    fn reject(&self, peer: SocketAddr, code: ErrorMessageCode, message: &str) {
+        let error: ErrorMessage = ErrorMessage::new(code, message.to_string());
+        self.metrics.error_sent(code);
+
+        let bytes: Vec<u8> = AnyMessage::Error(error).to_bytes();
+
+        /* best effort; the peer isn't obliged to be listening */
+        if self.socket.send_to(&bytes, peer).is_ok() {
+            log::packet(log::Flow::Sent, &self.socket, peer, &bytes);
+        }
+    }
+}
+
+/* counts a request as being handled for as long as it's held */
+struct InFlight {
+    shared: Arc<Shared>
+}
+
+impl InFlight {
+    fn new(shared: &Arc<Shared>) -> Self {
+        shared.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        InFlight {
+            shared: shared.clone()
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.shared.touch();
+        self.shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/* binds a listening socket; IPv6 sockets are kept to IPv6 alone so that an
+    IPv4 socket can share their port */
+fn bind_listener(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket: Socket = match addr {
+        SocketAddr::V4(_) => Socket::new(Domain::ipv4(), Type::dgram(),
+                                         Some(Protocol::udp()))?,
+        SocketAddr::V6(_) => {
+            let socket: Socket = Socket::new(Domain::ipv6(), Type::dgram(),
+                                             Some(Protocol::udp()))?;
+            socket.set_only_v6(true)?;
+            socket
+        }
+    };
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into_udp_socket())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_request(config: &Config, sessions: &Arc<Registry>,
+                  metrics: &Metrics, throttle: &Throttle, ledger: &Arc<Ledger>,
+                  request: AnyMessage, local: IpAddr, peer: SocketAddr) {
+    let (kind, filename, mode): (&str, String, ReadWriteRequestMessageMode) =
+        match &request {
+            AnyMessage::Rrq(rrq) => ("RRQ", rrq.filename(), rrq.mode()),
+            AnyMessage::Wrq(wrq) => ("WRQ", wrq.filename(), wrq.mode()),
+            _ => return
+        };
+
+    let span = tracing::info_span!("transfer", client = %peer,
+                                   request = kind, file = %filename);
+    let _entered = span.enter();
+
+    let socket: UdpSocket =
+        match UdpSocket::bind(SocketAddr::new(local, 0)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("unable to allocate TID: {}", e);
+                return;
+            }
+        };
+
+    debug!("{} for '{}' ({})", kind, filename,
+           ReadWriteRequestMessageMode::to_string(mode));
+
+    let direction: Direction = match request {
+        AnyMessage::Wrq(_) => Direction::Write,
+        _ => Direction::Read
+    };
+
+    let mut stats: TransferStats = TransferStats::new(peer, filename.clone(),
+                                                      direction, mode);
+    let started: Instant = Instant::now();
+    let active: metrics::Active = metrics.start();
+
+    let result: Result<(), TransferError> = match request {
+        AnyMessage::Rrq(rrq) => serve_rrq(config, sessions, &socket, &rrq,
+                                          peer, throttle, &mut stats),
+        AnyMessage::Wrq(wrq) => serve_wrq(config, ledger, &socket, &wrq,
+                                          peer, throttle, &mut stats),
+        _ => return
+    };
+
+    stats.set_duration(started.elapsed());
+
+    if let Err(TransferError::Rejected(code, message)) = &result {
+        let error: ErrorMessage = ErrorMessage::new(*code, message.clone());
+        let bytes: Vec<u8> = AnyMessage::Error(error).to_bytes();
+
+        if socket.send_to(&bytes, peer).is_ok() {
+            log::packet(log::Flow::Sent, &socket, peer, &bytes);
+        }
+
+        metrics.error_sent(*code);
+    }
+
+    drop(active);
+    metrics.finish(&stats, &result);
+
+    /* the access log: one record per transfer */
+    log::access(&stats, &result);
+}
+
+fn serve_rrq(config: &Config, sessions: &Arc<Registry>, socket: &UdpSocket,
+             request: &ReadRequestMessage, peer: SocketAddr,
+             throttle: &Throttle, stats: &mut TransferStats) ->
+    Result<(), TransferError> {
+    socket.set_read_timeout(Some(config.timeout()))?;
+
+    let mut conn: Connection =
+        Connection::new(socket.local_addr()?, peer);
+
+    let path: PathBuf = authorise(config, request.filename(),
+                                  msg::MessageType::ReadRequest, peer)?;
+
+    let file: File = File::open(&path).map_err(xfer::reject_io)?;
+    let metadata: fs::Metadata = file.metadata().map_err(xfer::reject_io)?;
+
+    if metadata.is_dir() {
+        return Err(TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION,
+                                           "Not a regular file".to_string()));
+    }
+
+    let requested: OptionList = request.options();
+
+    /* the size of a netascii transfer isn't known until it's been converted,
+        so tsize is only honoured for octet transfers */
+    let transfer_size: Option<u64> = match request.mode() {
+        ReadWriteRequestMessageMode::Octet => Some(metadata.len()),
+        _ => None
+    };
+
+    let (acknowledged, negotiated): (OptionList, TransferOptions) =
+        negotiate(config, &requested, transfer_size);
+
+    let multicast: Option<Result<(), Ineligible>> =
+        options::find(&requested, options::MULTICAST).map(|_| {
+            mcast::eligible(config, request.mode(), metadata.len(),
+                            negotiated.block_size(), peer)
+        });
+
+    /* the option is simply left unacknowledged, as RFC 2347 has it, but
+        whoever asked for it may want to know why */
+    match multicast {
+        Some(Err(Ineligible::NoGroup)) =>
+            debug!("serving '{}' by unicast: {}", request.filename(),
+                   Ineligible::NoGroup),
+        Some(Err(reason)) =>
+            info!("serving '{}' by unicast: {}", request.filename(), reason),
+        _ => {}
+    }
+
+    if let Some(Ok(())) = multicast {
+        let block_size: usize = negotiated.block_size();
+
+        /* a session's counters aren't broken down by client, so all that's
+            known is that the whole file arrived */
+        let mut options: TransferOptions = negotiated.clone();
+        options.set_multicast(config.multicast()
+                              .map(|group| MulticastGroup::new(group, false)));
+        stats.set_options(options);
+
+        /* the group is sent one block at a time, whatever was asked for */
+        let acknowledged: OptionList = acknowledged.into_iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case(options::WINDOWSIZE))
+            .collect();
+
+        Registry::join(sessions, config, &path, file, block_size, acknowledged,
+                       throttle, socket.local_addr()?.ip(), peer)?;
+
+        stats.set_bytes(metadata.len());
+        stats.set_blocks(metadata.len() / block_size as u64 + 1);
+
+        return Ok(());
+    }
+
+    /* without an OACK to be acknowledged first, the first block would go to
+        whoever the request claims to be from */
+    if config.limits().verify() && acknowledged.is_empty() {
+        return Err(TransferError::Rejected(
+                msg::ERROR_OPTION_NEGOTIATION,
+                "Option negotiation is required".to_string()));
+    }
+
+    let mut reader: Box<dyn Read> = match request.mode() {
+        ReadWriteRequestMessageMode::Octet => Box::new(BufReader::new(file)),
+        ReadWriteRequestMessageMode::NetAscii =>
+            Box::new(NetAsciiReader::new(BufReader::new(file))),
+        ReadWriteRequestMessageMode::Mail =>
+            return Err(TransferError::Rejected(
+                    msg::ERROR_ILLEGAL_OPERATION,
+                    "Mail mode is not supported".to_string()))
+    };
+
+    if let Some(timeout) = negotiated.timeout() {
+        socket.set_read_timeout(Some(timeout))?;
+    }
+
+    let (mut transfer, outputs): (Transfer, Vec<Output>) =
+        begin(Role::Reader, acknowledged, negotiated, config.retries());
+
+    let result: Result<u64, TransferError> =
+        xfer::send_file(socket, &mut conn, &mut transfer, outputs, &mut reader,
+                        throttle);
+    stats.record(&transfer);
+
+    result.map(|_| ())
+}
+
+fn serve_wrq(config: &Config, ledger: &Arc<Ledger>, socket: &UdpSocket,
+             request: &WriteRequestMessage, peer: SocketAddr,
+             throttle: &Throttle, stats: &mut TransferStats) ->
+    Result<(), TransferError> {
+    socket.set_read_timeout(Some(config.timeout()))?;
+
+    let mut conn: Connection =
+        Connection::new(socket.local_addr()?, peer);
+
+    let path: PathBuf = authorise(config, request.filename(),
+                                  msg::MessageType::WriteRequest, peer)?;
+
+    let mut decoder: Option<NetAsciiDecoder> = match request.mode() {
+        ReadWriteRequestMessageMode::Octet => None,
+        ReadWriteRequestMessageMode::NetAscii => Some(NetAsciiDecoder::new()),
+        ReadWriteRequestMessageMode::Mail =>
+            return Err(TransferError::Rejected(
+                    msg::ERROR_ILLEGAL_OPERATION,
+                    "Mail mode is not supported".to_string()))
+    };
+
+    let mut allowance: Allowance = config.write_policy().admit(ledger, &path)
+        .map_err(reject_violation)?;
+
+    let requested: OptionList = request.options();
+    let transfer_size: Option<u64> =
+        options::find(&requested, options::TSIZE)
+            .and_then(|size| size.parse().ok());
+
+    /* an upload that has announced its size can be turned away before any of
+        it is sent, or else have room set aside for it (netascii uploads
+        shrink as they're decoded, so theirs is only an upper bound) */
+    if let (Some(size), None) = (transfer_size, &decoder) {
+        allowance.reserve(size).map_err(reject_violation)?;
+    }
+
+    let (acknowledged, negotiated): (OptionList, TransferOptions) =
+        negotiate(config, &requested, transfer_size);
+
+    if let Some(timeout) = negotiated.timeout() {
+        socket.set_read_timeout(Some(timeout))?;
+    }
+
+    /* the upload is written to a temporary file and only moved into place
+        once the final block is in, so a transfer that dies part way through
+        never clobbers the destination */
+    let upload: PendingUpload = PendingUpload::create(
+            &path, config.write_policy().mode() == WriteMode::CreateOnly)
+        .map_err(xfer::reject_io)?;
+
+    let mut writer: BufWriter<PendingUpload> = BufWriter::new(upload);
+
+    let (mut transfer, outputs): (Transfer, Vec<Output>) =
+        begin(Role::Writer, acknowledged, negotiated, config.retries());
+
+    let result: Result<u64, TransferError> =
+        xfer::receive_file(socket, &mut conn, &mut transfer, outputs,
+                           &mut writer, &mut decoder,
+                           &mut |written| allowance.check(written)
+                               .map_err(reject_violation),
+                           &mut |writer| commit(writer, config.fsync()),
+                           throttle);
+    stats.record(&transfer);
+    result?;
+    allowance.commit();
+
+    Ok(())
+}
+
+/* puts a received upload in place, which is done before its last block is
+    acknowledged so that a client told its upload succeeded can count on it
+    being there */
+fn commit(writer: &mut BufWriter<PendingUpload>, fsync: bool) ->
+    Result<(), TransferError> {
+    writer.flush().map_err(xfer::reject_io)?;
+
+    writer.get_mut().commit(fsync).map_err(|e| match e.kind() {
+        /* someone else created the file while we were receiving ours */
+        io::ErrorKind::AlreadyExists => reject_violation(Violation::FileExists),
+        _ => xfer::reject_io(e)
+    })
+}
+
+/*
+ * Decides which of the requested options (RFCs 2348, 2349 and 7440) to accept,
+ * returning those to be acknowledged along with the parameters the transfer
+ * will run with. Options that are unknown or carry invalid values are simply
+ * left out of the acknowledgement, as RFC 2347 permits. The multicast option
+ * is left to the caller.
+ */
+fn negotiate(config: &Config, requested: &[(String, String)],
+             transfer_size: Option<u64>) -> (OptionList, TransferOptions) {
+    let mut acknowledged: OptionList = Vec::new();
+    let mut negotiated: TransferOptions = TransferOptions::default();
+
+    for (name, value) in requested {
+        match name.to_ascii_lowercase().as_str() {
+            options::BLKSIZE => if let Some(size) =
+                options::parse_block_size(value) {
+                /* a client may ask for more than we're willing to send, in
+                    which case it gets our maximum instead */
+                let size: usize = size.min(config.max_block_size());
+                negotiated.set_block_size(size);
+                acknowledged.push((name.clone(), size.to_string()));
+            },
+            options::TIMEOUT => if let Some(timeout) =
+                options::parse_timeout(value) {
+                negotiated.set_timeout(Some(timeout));
+                acknowledged.push((name.clone(), value.clone()));
+            },
+            options::WINDOWSIZE => if let Some(size) =
+                options::parse_window_size(value) {
+                let size: usize = size.min(config.max_window_size());
+                negotiated.set_window_size(size);
+                acknowledged.push((name.clone(), size.to_string()));
+            },
+            options::TSIZE => if let Some(size) = transfer_size {
+                negotiated.set_transfer_size(Some(size));
+                acknowledged.push((name.clone(), size.to_string()));
+            },
+            _ => {}
+        }
+    }
+
+    (acknowledged, negotiated)
+}
+
+/* starts a transfer, acknowledging any options that were accepted (an OACK
+    takes the place of the ACK of block zero, and is itself acknowledged with
+    an ACK of block zero when we're the one sending) */
+fn begin(role: Role, acknowledged: OptionList, negotiated: TransferOptions,
+         retries: usize) -> (Transfer, Vec<Output>) {
+    if acknowledged.is_empty() {
+        Transfer::accept(role, retries)
+    } else {
+        Transfer::acknowledge(role, acknowledged, negotiated, retries)
+    }
+}
+
+/* maps a requested filename onto a path beneath the server root, subject to
+    remapping rules and the access list */
+fn authorise(config: &Config, filename: String, request: msg::MessageType,
+             peer: SocketAddr) -> Result<PathBuf, TransferError> {
+    let filename: String = remap_filename(config, filename, request, peer)?;
+
+    if !config.acl().permits(request, peer.ip(), &filename) {
+        debug!("'{}' denied by access list", filename);
+
+        return Err(TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION,
+                                           "Access denied".to_string()));
+    }
+
+    match resolve(config.root(), &filename) {
+        /* in-progress uploads are off limits */
+        Some(path) if upload::is_temp_file(&path) =>
+            Err(TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION,
+                                        "Access denied".to_string())),
+        Some(path) => Ok(path),
+        None => Err(TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION,
+                                            "Path escapes root".to_string()))
+    }
+}
+
+/* applies any remapping rules to the requested filename, logging which rules
+    fired */
+fn remap_filename(config: &Config, filename: String,
+                  request: msg::MessageType, peer: SocketAddr) ->
+    Result<String, TransferError> {
+    if config.remap().is_empty() {
+        return Ok(filename);
+    }
+
+    match config.remap().apply(&filename, request, peer.ip()) {
+        Remapped::Filename { filename: remapped, rules } => {
+            if !rules.is_empty() {
+                let lines: Vec<String> =
+                    rules.iter().map(|line| line.to_string()).collect();
+
+                debug!("remapped '{}' to '{}' (rules on lines {})", filename,
+                       remapped, lines.join(", "));
+            }
+
+            Ok(remapped)
+        },
+        Remapped::Denied { rule, message } => {
+            debug!("'{}' denied by remapping rule on line {}", filename,
+                   rule);
+
+            Err(TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION, message))
+        }
+    }
+}
+
+fn reject_violation(violation: Violation) -> TransferError {
+    TransferError::Rejected(violation.code(), violation.to_string())
+}
+
+/* the requested filename as a path relative to the server root: leading and
+    repeated separators and '.' components are dropped and each '..' undoes
+    the component before it, or None should that climb out of the root */
+pub fn normalise(filename: &str) -> Option<PathBuf> {
+    let mut path: PathBuf = PathBuf::new();
+
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {},
+            Component::ParentDir if path.pop() => {},
+            Component::ParentDir | Component::Prefix(_) => return None
+        }
+    }
+
+    Some(path)
+}
+
+/* confines the requested filename to the server root, refusing any path that
+    would escape it */
+pub fn resolve(root: &Path, filename: &str) -> Option<PathBuf> {
+    match normalise(filename) {
+        Some(path) if path.as_os_str().is_empty() => None,
+        Some(path) => Some(root.join(path)),
+        None => None
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::conn::Transfer;
+use crate::msg::ReadWriteRequestMessageMode;
+use crate::options::TransferOptions;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Read,   /* the file travels from server to client (an RRQ) */
+    Write   /* the file travels from client to server (a WRQ) */
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Read => write!(f, "read"),
+            Direction::Write => write!(f, "write")
+        }
+    }
+}
+
+/* a summary of a completed transfer */
+#[derive(Clone, Debug)]
+pub struct TransferStats {
+    peer: SocketAddr,
+    filename: String,
+    direction: Direction,
+    mode: ReadWriteRequestMessageMode,
+    options: TransferOptions,
+    bytes: u64,             /* payload carried, as sent on the wire */
+    blocks: u64,
+    retransmissions: u64,
+    duplicates: u64,
+    duration: Duration
+}
+
+impl TransferStats {
+    pub fn new(peer: SocketAddr, filename: String, direction: Direction,
+               mode: ReadWriteRequestMessageMode) -> Self {
+        TransferStats {
+            peer,
+            filename,
+            direction,
+            mode,
+            options: TransferOptions::default(),
+            bytes: 0,
+            blocks: 0,
+            retransmissions: 0,
+            duplicates: 0,
+            duration: Duration::from_secs(0)
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn mode(&self) -> ReadWriteRequestMessageMode {
+        self.mode
+    }
+
+    pub fn options(&self) -> &TransferOptions {
+        &self.options
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /* bytes per second over the whole transfer */
+    pub fn throughput(&self) -> f64 {
+        let secs: f64 = self.duration.as_secs_f64();
+
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn set_options(&mut self, options: TransferOptions) {
+        self.options = options;
+    }
+
+    pub fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = bytes;
+    }
+
+    pub fn set_blocks(&mut self, blocks: u64) {
+        self.blocks = blocks;
+    }
+
+    pub fn set_retransmissions(&mut self, retransmissions: u64) {
+        self.retransmissions = retransmissions;
+    }
+
+    pub fn set_duplicates(&mut self, duplicates: u64) {
+        self.duplicates = duplicates;
+    }
+
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    /* takes the negotiated options and counters from a transfer */
+    pub fn record(&mut self, transfer: &Transfer) {
+        self.options = transfer.options().clone();
+        self.bytes = transfer.bytes();
+        self.blocks = transfer.blocks();
+        self.retransmissions = transfer.retransmissions();
+        self.duplicates = transfer.duplicates();
+    }
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of '{}' ({}, blksize {}", self.direction,
+               self.filename,
+               ReadWriteRequestMessageMode::to_string(self.mode),
+               self.options.block_size())?;
+
+        if let Some(timeout) = self.options.timeout() {
+            write!(f, ", timeout {}", timeout.as_secs())?;
+        }
+
+        if let Some(size) = self.options.transfer_size() {
+            write!(f, ", tsize {}", size)?;
+        }
+
+        if let Some(group) = self.options.multicast() {
+            write!(f, ", multicast {}", group.group())?;
+        }
+
+        write!(f, "): {} bytes in {} blocks, {} retransmitted, {} duplicates, \
+               {:.3}s, {:.1} KiB/s", self.bytes, self.blocks,
+               self.retransmissions, self.duplicates,
+               self.duration.as_secs_f64(), self.throughput() / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::conn::{Event, Role};
+    use crate::msg::{AcknowledgementMessage, AnyMessage};
+
+    fn stats() -> TransferStats {
+        TransferStats::new("192.0.2.1:50000".parse().unwrap(),
+                           "boot.img".to_string(), Direction::Read,
+                           ReadWriteRequestMessageMode::Octet)
+    }
+
+    #[test]
+    fn summarises_a_transfer() {
+        let mut stats: TransferStats = stats();
+        stats.set_bytes(3072);
+        stats.set_blocks(7);
+        stats.set_retransmissions(2);
+        stats.set_duplicates(1);
+        stats.set_duration(Duration::from_millis(1500));
+
+        assert_eq!(stats.throughput(), 2048.0);
+        assert_eq!(stats.to_string(),
+                   "read of 'boot.img' (octet, blksize 512): 3072 bytes in 7 \
+                    blocks, 2 retransmitted, 1 duplicates, 1.500s, 2.0 KiB/s");
+    }
+
+    #[test]
+    fn summarises_the_negotiated_options() {
+        let mut options: TransferOptions = TransferOptions::default();
+        options.set_block_size(1428);
+        options.set_timeout(Some(Duration::from_secs(3)));
+        options.set_transfer_size(Some(0));
+
+        let mut stats: TransferStats = stats();
+        stats.set_options(options);
+
+        assert!(stats.to_string().starts_with(
+                "read of 'boot.img' (octet, blksize 1428, timeout 3, \
+                 tsize 0): "));
+    }
+
+    #[test]
+    fn an_instant_transfer_has_no_throughput() {
+        let mut stats: TransferStats = stats();
+        stats.set_bytes(512);
+
+        assert_eq!(stats.throughput(), 0.0);
+        assert!(stats.to_string().ends_with(", 0.000s, 0.0 KiB/s"));
+    }
+
+    #[test]
+    fn records_the_counters_of_a_transfer() {
+        let (mut transfer, _): (Transfer, _) = Transfer::accept(Role::Reader,
+                                                                3);
+        transfer.send_block(vec![0; 512]);
+        transfer.handle(Event::TimedOut).unwrap();
+        transfer.handle(Event::Received(AnyMessage::Ack(
+                    AcknowledgementMessage::new(1)))).unwrap();
+        transfer.send_block(vec![0; 10]);
+
+        let mut stats: TransferStats = stats();
+        stats.record(&transfer);
+
+        assert_eq!(stats.bytes(), 522);
+        assert_eq!(stats.blocks(), 2);
+        assert_eq!(stats.retransmissions(), 1);
+        assert_eq!(stats.duplicates(), 0);
+    }
+}
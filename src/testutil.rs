@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Scaffolding shared by the unit tests. The server binary includes this file
+ * for its own tests, so not everything here is used by every crate.
+ */
+
+/* tells apart the directories of tests running side by side */
+static DIRECTORIES: AtomicUsize = AtomicUsize::new(0);
+
+/* an empty directory of a test's own, removed along with everything in it
+    when dropped */
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new() -> Self {
+        let path: PathBuf = env::temp_dir()
+            .join(format!("nettlesoup-unit-{}-{}", process::id(),
+                          DIRECTORIES.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&path).unwrap();
+        Scratch(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /* puts a file in the directory, returning its path */
+    pub fn write(&self, filename: &str, contents: &[u8]) -> PathBuf {
+        let path: PathBuf = self.join(filename);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /* the names of everything in the directory */
+    pub fn files(&self) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&self.0).unwrap()
+            .map(|entry| entry.unwrap().file_name()
+                 .to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
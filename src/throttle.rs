@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::acl;
+
+/*
+ * Bandwidth throttling.
+ *
+ * Each limit is a token bucket of bytes, refilled at its rate and holding at
+ * most a second's worth. A transfer passes every block it sends through the
+ * buckets that apply to it (its own, its client's subnet's and the server's)
+ * before sending it, taking the block's size from each. A bucket may go into
+ * debt, in which case the transfer sleeps until the debt would be paid off;
+ * transfers sharing a bucket thereby take turns at its rate.
+ *
+ * When receiving, the acknowledgement of each block is held back instead,
+ * which slows the sender just the same.
+ *
+ * Time is told, and waited out, by a clock that tests may stand in for.
+ */
+
+pub const DEFAULT_SUBNET_PREFIX_V4: u8 = 24;
+pub const DEFAULT_SUBNET_PREFIX_V6: u8 = 64;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/* the time as it passes */
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[derive(Debug)]
+pub struct Bucket {
+    rate: u64,          /* bytes per second */
+    tokens: f64,        /* negative when in debt */
+    updated: Instant
+}
+
+impl Bucket {
+    /* a full bucket, as of `now` */
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            updated: now
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /* takes the given number of bytes, returning how long it will be until
+        the bucket is out of debt */
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 || self.rate == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed: f64 = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64)
+            .min(self.rate as f64);
+        self.updated = now;
+    }
+}
+
+/* the buckets a single transfer is subject to; with none, it's unlimited */
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    buckets: Vec<Arc<Mutex<Bucket>>>,
+    clock: Arc<dyn Clock>
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new()
+    }
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Throttle::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Throttle {
+            buckets: Vec::new(),
+            clock
+        }
+    }
+
+    /* a throttle of its own, at the given rate in bytes per second */
+    pub fn with_rate(rate: u64) -> Self {
+        let mut throttle: Throttle = Throttle::new();
+        let now: Instant = throttle.clock.now();
+        throttle.push(Arc::new(Mutex::new(Bucket::new(rate, now))));
+        throttle
+    }
+
+    pub fn push(&mut self, bucket: Arc<Mutex<Bucket>>) {
+        self.buckets.push(bucket);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /* waits until the given number of bytes may be sent */
+    pub fn pace(&self, bytes: usize) {
+        let now: Instant = self.clock.now();
+        let wait: Duration = self.buckets.iter()
+            .map(|bucket| bucket.lock().unwrap().take(bytes as u64, now))
+            .max()
+            .unwrap_or_default();
+
+        if wait > Duration::from_secs(0) {
+            self.clock.sleep(wait);
+        }
+    }
+}
+
+/* the server's bandwidth limits, each in bytes per second */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bandwidth {
+    per_transfer: Option<u64>,
+    per_subnet: Option<u64>,
+    total: Option<u64>,
+    subnet_prefix_v4: u8,   /* how clients are grouped into subnets */
+    subnet_prefix_v6: u8
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth::new()
+    }
+}
+
+impl Bandwidth {
+    pub fn new() -> Self {
+        Bandwidth {
+            per_transfer: None,
+            per_subnet: None,
+            total: None,
+            subnet_prefix_v4: DEFAULT_SUBNET_PREFIX_V4,
+            subnet_prefix_v6: DEFAULT_SUBNET_PREFIX_V6
+        }
+    }
+
+    pub fn per_transfer(&self) -> Option<u64> {
+        self.per_transfer
+    }
+
+    pub fn per_subnet(&self) -> Option<u64> {
+        self.per_subnet
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    pub fn subnet_prefix_v4(&self) -> u8 {
+        self.subnet_prefix_v4
+    }
+
+    pub fn subnet_prefix_v6(&self) -> u8 {
+        self.subnet_prefix_v6
+    }
+
+    pub fn set_per_transfer(&mut self, per_transfer: Option<u64>) {
+        self.per_transfer = per_transfer;
+    }
+
+    pub fn set_per_subnet(&mut self, per_subnet: Option<u64>) {
+        self.per_subnet = per_subnet;
+    }
+
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.total = total;
+    }
+
+    /* lengths beyond an address's size are taken as the whole address */
+    pub fn set_subnet_prefix(&mut self, v4: u8, v6: u8) {
+        self.subnet_prefix_v4 = v4.min(32);
+        self.subnet_prefix_v6 = v6.min(128);
+    }
+
+    /* the subnet a client's bandwidth is shared with */
+    pub fn subnet(&self, client: IpAddr) -> IpAddr {
+        match acl::canonical(client) {
+            IpAddr::V4(addr) => {
+                let mask: u32 = u32::MAX.checked_shl(
+                    32 - self.subnet_prefix_v4 as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            },
+            IpAddr::V6(addr) => {
+                let mask: u128 = u128::MAX.checked_shl(
+                    128 - self.subnet_prefix_v6 as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        }
+    }
+}
+
+/* the buckets shared between transfers */
+#[derive(Debug)]
+pub struct Throttler {
+    clock: Arc<dyn Clock>,
+    total: Mutex<Option<Arc<Mutex<Bucket>>>>,
+    subnets: Mutex<HashMap<IpAddr, Arc<Mutex<Bucket>>>>
+}
+
+impl Default for Throttler {
+    fn default() -> Self {
+        Throttler::new()
+    }
+}
+
+impl Throttler {
+    pub fn new() -> Self {
+        Throttler::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Throttler {
+            clock,
+            total: Mutex::new(None),
+            subnets: Mutex::new(HashMap::new())
+        }
+    }
+
+    /* the throttle a new transfer for the client is subject to; the shared
+        buckets take on any change in their rates */
+    pub fn throttle(&self, bandwidth: &Bandwidth, client: IpAddr) ->
+        Throttle {
+        let now: Instant = self.clock.now();
+        let mut throttle: Throttle = Throttle::with_clock(self.clock.clone());
+
+        if let Some(rate) = bandwidth.per_transfer {
+            throttle.push(Arc::new(Mutex::new(Bucket::new(rate, now))));
+        }
+
+        if let Some(rate) = bandwidth.per_subnet {
+            let mut subnets = self.subnets.lock().unwrap();
+
+            /* subnets with nothing under way start afresh */
+            subnets.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+
+            let bucket: &Arc<Mutex<Bucket>> = subnets
+                .entry(bandwidth.subnet(client))
+                .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(rate,
+                                                                   now))));
+            bucket.lock().unwrap().set_rate(rate, now);
+            throttle.push(bucket.clone());
+        }
+
+        let mut total = self.total.lock().unwrap();
+
+        match (bandwidth.total, total.as_ref()) {
+            (Some(rate), Some(bucket)) =>
+                bucket.lock().unwrap().set_rate(rate, now),
+            (Some(rate), None) =>
+                *total = Some(Arc::new(Mutex::new(Bucket::new(rate, now)))),
+            (None, _) => *total = None
+        }
+
+        if let Some(bucket) = total.as_ref() {
+            throttle.push(bucket.clone());
+        }
+
+        throttle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a clock that only moves when slept on or told to */
+    #[derive(Debug)]
+    struct FakeClock {
+        now: Mutex<Instant>,
+        slept: Mutex<Vec<Duration>>
+    }
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(FakeClock {
+                now: Mutex::new(Instant::now()),
+                slept: Mutex::new(Vec::new())
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+
+        /* the sleeps since last asked */
+        fn slept(&self) -> Vec<Duration> {
+            self.slept.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+            self.advance(duration);
+        }
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn bandwidth(per_transfer: Option<u64>, per_subnet: Option<u64>,
+                 total: Option<u64>) -> Bandwidth {
+        let mut bandwidth: Bandwidth = Bandwidth::new();
+        bandwidth.set_per_transfer(per_transfer);
+        bandwidth.set_per_subnet(per_subnet);
+        bandwidth.set_total(total);
+        bandwidth
+    }
+
+    #[test]
+    fn refills_a_bucket_at_its_rate() {
+        let start: Instant = Instant::now();
+        let mut bucket: Bucket = Bucket::new(1000, start);
+
+        /* a second's worth to begin with, and then debt */
+        assert_eq!(bucket.take(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), millis(500));
+
+        /* paid off at the rate */
+        assert_eq!(bucket.take(0, start + millis(250)), millis(250));
+        assert_eq!(bucket.take(100, start + millis(600)), Duration::ZERO);
+
+        /* but never holding more than a second's worth */
+        let later: Instant = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(1000, later), Duration::ZERO);
+        assert_eq!(bucket.take(100, later), millis(100));
+
+        /* nor more than a second's worth at a new, lower rate */
+        let later: Instant = later + Duration::from_secs(60);
+        bucket.set_rate(100, later);
+        assert_eq!(bucket.take(100, later), Duration::ZERO);
+        assert_eq!(bucket.take(100, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn paces_to_the_slowest_bucket() {
+        let clock: Arc<FakeClock> = FakeClock::new();
+        let now: Instant = clock.now();
+        let mut throttle: Throttle = Throttle::with_clock(clock.clone());
+        throttle.push(Arc::new(Mutex::new(Bucket::new(2048, now))));
+        throttle.push(Arc::new(Mutex::new(Bucket::new(1024, now))));
+
+        /* after the first second's worth, a block every half second */
+        for _ in 0..12 {
+            throttle.pace(512);
+        }
+
+        assert_eq!(clock.slept(), vec![millis(500); 10]);
+        assert!(Throttle::new().is_empty());
+    }
+
+    #[test]
+    fn gives_each_transfer_a_bucket_of_its_own() {
+        let clock: Arc<FakeClock> = FakeClock::new();
+        let throttler: Throttler = Throttler::with_clock(clock.clone());
+        let bandwidth: Bandwidth = bandwidth(Some(1000), None, None);
+
+        let first: Throttle = throttler.throttle(&bandwidth,
+                                                 addr("192.0.2.1"));
+        let second: Throttle = throttler.throttle(&bandwidth,
+                                                  addr("192.0.2.1"));
+
+        first.pace(1500);
+        second.pace(1000);
+        assert_eq!(clock.slept(), vec![millis(500)]);
+
+        assert!(throttler.throttle(&Bandwidth::new(), addr("192.0.2.1"))
+                .is_empty());
+    }
+
+    #[test]
+    fn shares_a_bucket_within_each_subnet() {
+        let clock: Arc<FakeClock> = FakeClock::new();
+        let throttler: Throttler = Throttler::with_clock(clock.clone());
+        let bandwidth: Bandwidth = bandwidth(None, Some(1000), None);
+
+        let first: Throttle = throttler.throttle(&bandwidth,
+                                                 addr("192.0.2.1"));
+        let neighbour: Throttle = throttler.throttle(&bandwidth,
+                                                     addr("192.0.2.200"));
+        let stranger: Throttle = throttler.throttle(&bandwidth,
+                                                    addr("198.51.100.1"));
+
+        first.pace(1000);
+        stranger.pace(1000);
+        assert!(clock.slept().is_empty());
+
+        neighbour.pace(250);
+        assert_eq!(clock.slept(), vec![millis(250)]);
+
+        /* a subnet with nothing under way starts afresh */
+        drop(first);
+        drop(neighbour);
+        throttler.throttle(&bandwidth, addr("192.0.2.1")).pace(1000);
+        assert!(clock.slept().is_empty());
+    }
+
+    #[test]
+    fn shares_a_bucket_among_every_transfer() {
+        let clock: Arc<FakeClock> = FakeClock::new();
+        let throttler: Throttler = Throttler::with_clock(clock.clone());
+
+        let first: Throttle = throttler.throttle(
+            &bandwidth(None, None, Some(1000)), addr("192.0.2.1"));
+        first.pace(1000);
+
+        /* a change of rate is taken on by the bucket already shared */
+        let second: Throttle = throttler.throttle(
+            &bandwidth(None, None, Some(2000)), addr("2001:db8::1"));
+        second.pace(500);
+        first.pace(500);
+        assert_eq!(clock.slept(), vec![millis(250), millis(250)]);
+
+        /* and without a total, new transfers are let be */
+        assert!(throttler.throttle(&Bandwidth::new(), addr("192.0.2.1"))
+                .is_empty());
+    }
+
+    #[test]
+    fn groups_clients_into_subnets() {
+        let mut bandwidth: Bandwidth = Bandwidth::new();
+
+        assert_eq!(bandwidth.subnet(addr("192.0.2.77")), addr("192.0.2.0"));
+        assert_eq!(bandwidth.subnet(addr("::ffff:192.0.2.77")),
+                   addr("192.0.2.0"));
+        assert_eq!(bandwidth.subnet(addr("2001:db8:1:2:3:4:5:6")),
+                   addr("2001:db8:1:2::"));
+
+        bandwidth.set_subnet_prefix(0, 200);
+        assert_eq!(bandwidth.subnet_prefix_v6(), 128);
+        assert_eq!(bandwidth.subnet(addr("192.0.2.77")), addr("0.0.0.0"));
+        assert_eq!(bandwidth.subnet(addr("2001:db8::1")),
+                   addr("2001:db8::1"));
+    }
+}
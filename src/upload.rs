@@ -0,0 +1,288 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::warn;
+
+/* every in-progress upload is written to a hidden file alongside its
+    destination whose name begins and ends with these */
+pub const TEMP_PREFIX: &str = ".nettlesoup-upload.";
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/*
+ * An upload that is being written to a temporary file, to be moved over its
+ * destination only once it has been received in full.
+ *
+ * Dropping a pending upload without committing it removes the temporary
+ * file, so an interrupted transfer never disturbs the destination. The
+ * client receives its downloads the same way.
+ */
+pub struct PendingUpload {
+    dest: PathBuf,
+    temp: PathBuf,
+    file: Option<File>,     /* None once closed for committing */
+    create_only: bool,
+    committed: bool
+}
+
+impl PendingUpload {
+    pub fn create(dest: &Path, create_only: bool) -> io::Result<Self> {
+        let directory: &Path = dest.parent().unwrap_or_else(|| Path::new("."));
+        let name: String = match dest.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "Destination has no filename"))
+        };
+
+        loop {
+            let temp: PathBuf = directory.join(format!(
+                    "{}{}.{}.{}{}", TEMP_PREFIX, name, process::id(),
+                    TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+                    TEMP_SUFFIX));
+
+            match OpenOptions::new().write(true).create_new(true).open(&temp) {
+                Ok(file) => return Ok(PendingUpload {
+                    dest: dest.to_path_buf(),
+                    temp,
+                    file: Some(file),
+                    create_only,
+                    committed: false
+                }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    pub fn temp(&self) -> &Path {
+        &self.temp
+    }
+
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+
+    /* moves the upload into place, optionally ensuring both its contents and
+        the directory entry have reached the disk first */
+    pub fn commit(&mut self, fsync: bool) -> io::Result<()> {
+        let file: File = self.file.take().expect("upload already committed");
+
+        if fsync {
+            file.sync_all()?;
+        }
+
+        drop(file);
+
+        if self.create_only {
+            /* unlike a rename, linking fails rather than replacing a file
+                that has appeared at the destination in the meantime */
+            fs::hard_link(&self.temp, &self.dest)?;
+        } else {
+            fs::rename(&self.temp, &self.dest)?;
+        }
+
+        self.committed = true;
+
+        if fsync {
+            if let Some(directory) = self.dest.parent() {
+                File::open(directory)?.sync_all()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for PendingUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("Upload already committed"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+impl Seek for PendingUpload {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.file {
+            Some(file) => file.seek(pos),
+            None => Err(io::Error::other("Upload already committed"))
+        }
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        /* a committed upload has been renamed away or linked into place,
+            in which case this just removes the spare name */
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+pub fn is_temp_file(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with(TEMP_PREFIX) &&
+            name.ends_with(TEMP_SUFFIX),
+        None => false
+    }
+}
+
+/* removes temporary files left behind by uploads that were in progress when
+    a previous server process died, returning the paths removed. Anything
+    that can't be read or removed is logged and passed over, as it's no
+    reason to keep the server from starting */
+pub fn remove_stale(root: &Path) -> Vec<PathBuf> {
+    let mut removed: Vec<PathBuf> = Vec::new();
+    let mut pending: Vec<PathBuf> = vec![root.to_path_buf()];
+
+    while let Some(directory) = pending.pop() {
+        let entries: fs::ReadDir = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("unable to look for stale uploads in {}: {}",
+                      directory.display(), e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let (path, file_type): (PathBuf, fs::FileType) =
+                match entry.and_then(|entry| Ok((entry.path(),
+                                                 entry.file_type()?))) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("unable to look for stale uploads in {}: {}",
+                              directory.display(), e);
+                        continue;
+                    }
+                };
+
+            /* symbolic links are deliberately not followed */
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() && is_temp_file(&path) {
+                match fs::remove_file(&path) {
+                    Ok(()) => removed.push(path),
+                    Err(e) => warn!("unable to remove stale upload {}: {}",
+                                    path.display(), e)
+                }
+            }
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Scratch;
+
+    #[test]
+    fn leaves_the_destination_alone_until_committed() {
+        let scratch: Scratch = Scratch::new();
+        let dest: PathBuf = scratch.join("file");
+        fs::write(&dest, b"original").unwrap();
+
+        let mut upload: PendingUpload = PendingUpload::create(&dest, false)
+            .unwrap();
+        upload.write_all(b"replacement").unwrap();
+
+        assert!(is_temp_file(upload.temp()));
+        assert_eq!(fs::read(&dest).unwrap(), b"original");
+
+        upload.commit(false).unwrap();
+        assert!(upload.is_committed());
+        assert_eq!(fs::read(&dest).unwrap(), b"replacement");
+
+        drop(upload);
+        assert_eq!(scratch.files(), vec!["file"]);
+    }
+
+    #[test]
+    fn removes_abandoned_uploads() {
+        let scratch: Scratch = Scratch::new();
+        let dest: PathBuf = scratch.join("file");
+        fs::write(&dest, b"original").unwrap();
+
+        let mut upload: PendingUpload = PendingUpload::create(&dest, false)
+            .unwrap();
+        upload.write_all(b"partial").unwrap();
+        drop(upload);
+
+        assert_eq!(scratch.files(), vec!["file"]);
+        assert_eq!(fs::read(&dest).unwrap(), b"original");
+    }
+
+    #[test]
+    fn create_only_never_replaces_a_file() {
+        let scratch: Scratch = Scratch::new();
+        let dest: PathBuf = scratch.join("file");
+
+        let mut upload: PendingUpload = PendingUpload::create(&dest, true)
+            .unwrap();
+        upload.write_all(b"late").unwrap();
+
+        /* someone else gets there first */
+        fs::write(&dest, b"early").unwrap();
+
+        assert_eq!(upload.commit(false).unwrap_err().kind(),
+                   io::ErrorKind::AlreadyExists);
+        drop(upload);
+
+        assert_eq!(fs::read(&dest).unwrap(), b"early");
+        assert_eq!(scratch.files(), vec!["file"]);
+    }
+
+    #[test]
+    fn removes_stale_uploads_throughout_the_root() {
+        let scratch: Scratch = Scratch::new();
+        let nested: PathBuf = scratch.join("nested");
+        fs::create_dir(&nested).unwrap();
+
+        let stale: Vec<PathBuf> = vec![
+            scratch.join(format!("{}a.1.0{}", TEMP_PREFIX, TEMP_SUFFIX)),
+            nested.join(format!("{}b.1.0{}", TEMP_PREFIX, TEMP_SUFFIX))
+        ];
+        let kept: Vec<PathBuf> = vec![
+            scratch.join("a"),
+            nested.join(format!("{}b", TEMP_PREFIX)),
+            nested.join(format!("b{}", TEMP_SUFFIX))
+        ];
+
+        for path in stale.iter().chain(&kept) {
+            fs::write(path, b"").unwrap();
+        }
+
+        let mut removed: Vec<PathBuf> = remove_stale(scratch.path());
+        removed.sort();
+        assert_eq!(removed, stale);
+
+        for path in &kept {
+            assert!(path.exists(), "{} was removed", path.display());
+        }
+    }
+
+    #[test]
+    fn carries_on_past_what_it_cannot_read() {
+        let scratch: Scratch = Scratch::new();
+
+        assert!(remove_stale(&scratch.join("missing")).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::conn::{Connection, Event, Output, State, Transfer, TransferError};
+use crate::log;
+use crate::msg::{self, AnyMessage, ErrorMessage};
+use crate::netascii::NetAsciiDecoder;
+use crate::throttle::Throttle;
+
+/*
+ * Drives transfers (see conn::Transfer) over a blocking socket, for both the
+ * client and the server.
+ *
+ * Every function here expects a socket that has been given a read timeout,
+ * the lapse of which is passed on to the transfer. Only the peer recorded in
+ * the connection is listened to: anything arriving from elsewhere is told it
+ * has the wrong TID and otherwise ignored (RFC 1350, section 4). The timeout
+ * runs from whenever we last sent something, not from the last datagram to
+ * arrive, so that strays can't put off retransmission indefinitely.
+ */
+
+/* large enough for any UDP datagram */
+pub const RECV_BUFFER_SIZE: usize = 65536;
+
+/* sends the contents of the reader as a sequence of blocks, each paced by
+    the throttle, returning the number of bytes sent; `outputs` are any the
+    transfer has already yielded */
+pub fn send_file(socket: &UdpSocket, conn: &mut Connection,
+                 transfer: &mut Transfer, outputs: Vec<Output>,
+                 reader: &mut dyn Read, throttle: &Throttle) ->
+    Result<u64, TransferError> {
+    let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+    let mut sent: u64 = 0;
+    let mut outputs: Vec<Output> = outputs;
+    let mut deadline: Deadline = Deadline::new(socket)?;
+
+    loop {
+        for output in outputs.drain(..) {
+            if let Output::Send(message) = output {
+                emit(socket, conn, message)?;
+                deadline.reset();
+            }
+        }
+
+        outputs = match transfer.state() {
+            State::Finished => return Ok(sent),
+            State::Sending(_) => {
+                let data: Vec<u8> = read_block(reader, transfer.block_size())
+                    .map_err(reject_io)?;
+                sent += data.len() as u64;
+                throttle.pace(data.len());
+
+                transfer.send_block(data)
+            },
+            _ => match deadline.next_event(conn.remote_addr(), &mut buf)? {
+                Some(event) => transfer.handle(event)?,
+                None => continue
+            }
+        };
+    }
+}
+
+/*
+ * Receives a sequence of blocks, writing them out as they arrive and
+ * returning the number of bytes written; `outputs` are any the transfer has
+ * already yielded. `check` is consulted with the running total before each
+ * write and may abort the transfer, and `complete` is called once the last
+ * block has been written, before it is acknowledged. Each block's
+ * acknowledgement is held back as the throttle demands. Returns once the
+ * transfer has finished dallying over the last block.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn receive_file<W: Write>(socket: &UdpSocket, conn: &mut Connection,
+                              transfer: &mut Transfer, outputs: Vec<Output>,
+                              writer: &mut W,
+                              decoder: &mut Option<NetAsciiDecoder>,
+                              check: &mut dyn FnMut(u64) ->
+                                  Result<(), TransferError>,
+                              complete: &mut dyn FnMut(&mut W) ->
+                                  Result<(), TransferError>,
+                              throttle: &Throttle) ->
+    Result<u64, TransferError> {
+    let mut buf: Vec<u8> = vec![0; RECV_BUFFER_SIZE];
+    let mut written: u64 = 0;
+    let mut outputs: Vec<Output> = outputs;
+    let mut deadline: Deadline = Deadline::new(socket)?;
+
+    loop {
+        /* a block is written out before it's acknowledged, so that a
+            failure to write it is reported in place of the ACK */
+        for output in outputs.drain(..) {
+            match output {
+                Output::Send(message) => {
+                    emit(socket, conn, message)?;
+                    deadline.reset();
+                },
+                Output::Deliver(data) => {
+                    /* within a window, the blocks that aren't acknowledged
+                        still show the sender is there */
+                    deadline.reset();
+                    throttle.pace(data.len());
+
+                    let mut decoded: Vec<u8> = match decoder {
+                        Some(decoder) => decoder.decode(&data),
+                        None => data
+                    };
+
+                    if transfer.is_complete() {
+                        if let Some(decoder) = decoder {
+                            decoded.extend(decoder.finish());
+                        }
+                    }
+
+                    written += decoded.len() as u64;
+                    check(written)?;
+                    writer.write_all(&decoded).map_err(reject_io)?;
+
+                    if transfer.is_complete() {
+                        complete(writer)?;
+                    }
+                }
+            }
+        }
+
+        if transfer.is_finished() {
+            return Ok(written);
+        }
+
+        outputs = match deadline.next_event(conn.remote_addr(), &mut buf)? {
+            Some(event) => transfer.handle(event)?,
+            None => continue
+        };
+    }
+}
+
+/*
+ * When the reply to whatever was last sent falls due. Each wait is cut down
+ * to what's left of the socket's read timeout, which is put back as it was
+ * once the transfer is done with the socket.
+ */
+struct Deadline<'a> {
+    socket: &'a UdpSocket,
+    timeout: Option<Duration>,
+    due: Option<Instant>
+}
+
+impl<'a> Deadline<'a> {
+    fn new(socket: &'a UdpSocket) -> io::Result<Self> {
+        let timeout: Option<Duration> = socket.read_timeout()?;
+
+        Ok(Deadline {
+            socket,
+            timeout,
+            due: timeout.map(|timeout| Instant::now() + timeout)
+        })
+    }
+
+    /* starts the wait for a reply to something just sent */
+    fn reset(&mut self) {
+        self.due = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /* as for `next_event`, timing out when the reply falls due however much
+        else arrives in the meantime */
+    fn next_event(&mut self, peer: SocketAddr, buf: &mut [u8]) ->
+        Result<Option<Event>, TransferError> {
+        if let Some(due) = self.due {
+            let now: Instant = Instant::now();
+
+            if now >= due {
+                /* whatever the transfer does about it, the next wait is a
+                    fresh one */
+                self.reset();
+                return Ok(Some(Event::TimedOut));
+            }
+
+            self.socket.set_read_timeout(Some(due - now))?;
+        }
+
+        let event: Option<Event> = next_event(self.socket, peer, buf)?;
+
+        if let Some(Event::TimedOut) = event {
+            self.reset();
+        }
+
+        Ok(event)
+    }
+}
+
+impl Drop for Deadline<'_> {
+    fn drop(&mut self) {
+        let _ = self.socket.set_read_timeout(self.timeout);
+    }
+}
+
+/* sends a message to the peer, noting it against the connection */
+pub fn emit(socket: &UdpSocket, conn: &mut Connection,
+            message: AnyMessage) -> io::Result<()> {
+    let bytes: Vec<u8> = message.to_bytes();
+    socket.send_to(&bytes, conn.remote_addr())?;
+    log::packet(log::Flow::Sent, socket, conn.remote_addr(), &bytes);
+    conn.add_msg(message);
+    Ok(())
+}
+
+/* waits for the next thing to happen: a message from the peer or a timeout
+    (None means something arrived that can be disregarded) */
+pub fn next_event(socket: &UdpSocket, peer: SocketAddr, buf: &mut [u8]) ->
+    Result<Option<Event>, TransferError> {
+    match recv_message(socket, peer, buf) {
+        Ok(Some(message)) => Ok(Some(Event::Received(message))),
+        Ok(None) => Ok(None),
+        Err(e) if is_timeout(&e) => Ok(Some(Event::TimedOut)),
+        Err(e) => Err(TransferError::Io(e))
+    }
+}
+
+/* receives a single datagram from the peer, yielding None for anything
+    unparseable or sent from elsewhere */
+pub fn recv_message(socket: &UdpSocket, peer: SocketAddr, buf: &mut [u8]) ->
+    io::Result<Option<AnyMessage>> {
+    let (len, from): (usize, SocketAddr) = socket.recv_from(buf)?;
+    log::packet(log::Flow::Received, socket, from, &buf[..len]);
+
+    if from != peer {
+        debug!("rejected datagram from unknown TID {}", from);
+        reject_tid(socket, from);
+        return Ok(None);
+    }
+
+    Ok(AnyMessage::from_bytes(buf[..len].to_vec()).ok())
+}
+
+/* tells a stray sender that it isn't part of this transfer, without
+    otherwise disturbing it */
+pub fn reject_tid(socket: &UdpSocket, from: SocketAddr) {
+    let error: ErrorMessage = ErrorMessage::new(msg::ERROR_UNKNOWN_TID,
+                                                "Unknown transfer ID"
+                                                    .to_string());
+
+    let bytes: Vec<u8> = AnyMessage::Error(error).to_bytes();
+
+    /* best effort; the sender isn't obliged to be listening */
+    if socket.send_to(&bytes, from).is_ok() {
+        log::packet(log::Flow::Sent, socket, from, &bytes);
+    }
+}
+
+/* reads up to `size` bytes, stopping short only at EOF */
+pub fn read_block(reader: &mut dyn Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut block: Vec<u8> = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+/* maps local filesystem errors onto the closest TFTP error code, so that the
+    peer can be told why the transfer failed */
+pub fn reject_io(e: io::Error) -> TransferError {
+    match e.kind() {
+        io::ErrorKind::NotFound =>
+            TransferError::Rejected(msg::ERROR_FILE_NOT_FOUND,
+                                    "File not found".to_string()),
+        io::ErrorKind::PermissionDenied =>
+            TransferError::Rejected(msg::ERROR_ACCESS_VIOLATION,
+                                    "Access violation".to_string()),
+        _ => TransferError::Rejected(msg::ERROR_NOT_DEFINED, e.to_string())
+    }
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
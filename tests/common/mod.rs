@@ -0,0 +1,229 @@
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nettlesoup::msg::{AcknowledgementMessage, AnyMessage, DataMessage,
+                      ErrorMessage, OptionAcknowledgementMessage, OptionList,
+                      ReadRequestMessage, ReadWriteRequestMessageMode,
+                      WriteRequestMessage};
+use nettlesoup::policy::{WriteMode, WritePolicy};
+use nettlesoup::srv::{Config, Handle, Server, ServerError};
+
+/*
+ * Scaffolding shared by the integration tests: a server listening on the
+ * loopback interface with a root of its own, and a bare UDP socket for
+ * playing the part of a client a datagram at a time.
+ */
+
+/* how long the server waits for a reply before retransmitting, kept short
+    so that tests of lost packets don't drag on */
+pub const TIMEOUT: Duration = Duration::from_millis(200);
+pub const RETRIES: usize = 3;
+
+/* how long a peer waits for a datagram before concluding none is coming */
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+/* tells apart the roots of servers started by the same test binary */
+static ROOTS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestServer {
+    root: PathBuf,
+    addr: SocketAddr,
+    handle: Handle,
+    thread: Option<JoinHandle<Result<(), ServerError>>>
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        TestServer::with_config(|_| {})
+    }
+
+    /* starts a server whose configuration is adjusted by `configure` after
+        the defaults for testing have been applied */
+    pub fn with_config<F: FnOnce(&mut Config)>(configure: F) -> Self {
+        let root: PathBuf = env::temp_dir()
+            .join(format!("nettlesoup-test-{}-{}", process::id(),
+                          ROOTS.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&root).unwrap();
+        let root: PathBuf = root.canonicalize().unwrap();
+
+        let mut config: Config =
+            Config::new(root.clone(), vec!["127.0.0.1:0".parse().unwrap()]);
+        config.set_timeout(TIMEOUT);
+        config.set_retries(RETRIES);
+        config.set_write_policy(WritePolicy::new(WriteMode::Overwrite));
+        /* tests often leave transfers unfinished on purpose */
+        config.set_shutdown_timeout(TIMEOUT);
+        configure(&mut config);
+
+        let server: Server = Server::bind(config).unwrap();
+        let addr: SocketAddr = server.local_addrs().unwrap()[0];
+        let handle: Handle = server.handle();
+        let thread: JoinHandle<Result<(), ServerError>> =
+            thread::spawn(move || server.run());
+
+        TestServer {
+            root,
+            addr,
+            handle,
+            thread: Some(thread)
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /* puts a file in the server's root */
+    pub fn create(&self, filename: &str, contents: &[u8]) {
+        fs::write(self.root.join(filename), contents).unwrap();
+    }
+
+    /* the contents of a file in the server's root, if it's there */
+    pub fn contents(&self, filename: &str) -> Option<Vec<u8>> {
+        fs::read(self.root.join(filename)).ok()
+    }
+}
+
+/* the contents of an upload, which the server puts in place before it
+    acknowledges the last block */
+pub fn uploaded(server: &TestServer, filename: &str) -> Vec<u8> {
+    server.contents(filename)
+        .unwrap_or_else(|| panic!("{} was never put in place", filename))
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/* a client driven by hand, for saying exactly what the server gets to see */
+pub struct Peer {
+    socket: UdpSocket,
+    server: SocketAddr
+}
+
+impl Peer {
+    pub fn new(server: SocketAddr) -> Self {
+        let socket: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(PATIENCE)).unwrap();
+
+        Peer {
+            socket,
+            server
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /* sends to the server's well-known port */
+    pub fn request(&self, message: &AnyMessage) {
+        self.send_to(message, self.server);
+    }
+
+    pub fn send_to(&self, message: &AnyMessage, addr: SocketAddr) {
+        self.send_bytes(&message.to_bytes(), addr);
+    }
+
+    pub fn send_bytes(&self, bytes: &[u8], addr: SocketAddr) {
+        self.socket.send_to(bytes, addr).unwrap();
+    }
+
+    /* the next datagram to arrive, along with where it came from, failing
+        the test if there's none */
+    pub fn recv(&self) -> (AnyMessage, SocketAddr) {
+        self.recv_within(PATIENCE)
+            .expect("timed out waiting for a datagram")
+    }
+
+    /* the next datagram to arrive within `wait`, if any does */
+    pub fn recv_within(&self, wait: Duration) ->
+        Option<(AnyMessage, SocketAddr)> {
+        let mut buf: Vec<u8> = vec![0; 65536];
+        self.socket.set_read_timeout(Some(wait)).unwrap();
+
+        let (len, peer): (usize, SocketAddr) =
+            match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => return None
+            };
+
+        buf.truncate(len);
+        Some((AnyMessage::from_bytes(buf).expect("malformed datagram"), peer))
+    }
+}
+
+/* bytes that can't be mistaken for one another at any offset */
+pub fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+pub fn rrq(filename: &str, options: OptionList) -> AnyMessage {
+    AnyMessage::Rrq(ReadRequestMessage::with_options(
+        filename.to_string(), ReadWriteRequestMessageMode::Octet, options))
+}
+
+pub fn wrq(filename: &str, options: OptionList) -> AnyMessage {
+    AnyMessage::Wrq(WriteRequestMessage::with_options(
+        filename.to_string(), ReadWriteRequestMessageMode::Octet, options))
+}
+
+pub fn ack(block_num: u16) -> AnyMessage {
+    AnyMessage::Ack(AcknowledgementMessage::new(block_num))
+}
+
+pub fn data(block_num: u16, data: &[u8]) -> AnyMessage {
+    AnyMessage::Data(DataMessage::new(block_num, data.to_vec()))
+}
+
+pub fn option(name: &str, value: &str) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+pub fn expect_data(message: AnyMessage) -> DataMessage {
+    match message {
+        AnyMessage::Data(data) => data,
+        other => panic!("expected DATA, got {:?}", other)
+    }
+}
+
+pub fn expect_ack(message: AnyMessage) -> AcknowledgementMessage {
+    match message {
+        AnyMessage::Ack(ack) => ack,
+        other => panic!("expected ACK, got {:?}", other)
+    }
+}
+
+pub fn expect_error(message: AnyMessage) -> ErrorMessage {
+    match message {
+        AnyMessage::Error(e) => e,
+        other => panic!("expected ERROR, got {:?}", other)
+    }
+}
+
+pub fn expect_oack(message: AnyMessage) -> OptionAcknowledgementMessage {
+    match message {
+        AnyMessage::Oack(oack) => oack,
+        other => panic!("expected OACK, got {:?}", other)
+    }
+}
@@ -0,0 +1,845 @@
+mod common;
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::conn::TransferError;
+use nettlesoup::msg::{self, AnyMessage, ErrorMessage, ReadRequestMessage,
+                      ReadWriteRequestMessageMode};
+use nettlesoup::options;
+use nettlesoup::policy::{WriteMode, WritePolicy};
+
+use common::*;
+
+/*
+ * Conformance with RFC 1350 (the protocol itself), RFC 2347 (option
+ * negotiation), RFC 2348 (blksize), RFC 2349 (timeout and tsize) and
+ * RFC 7440 (windowsize), checked against a server on the loopback interface.
+ */
+
+fn client(server: &TestServer) -> Client {
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client
+}
+
+fn get(client: &Client, filename: &str) -> Result<Vec<u8>, TransferError> {
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get(filename, &mut output)?;
+    Ok(output.into_inner())
+}
+
+/* reads a file by hand, acknowledging every block, and returns the sizes of
+    the blocks it came in */
+fn read_blocks(peer: &Peer, first: AnyMessage, tid: SocketAddr) ->
+    Vec<usize> {
+    let mut sizes: Vec<usize> = vec![];
+    let mut message: AnyMessage = first;
+
+    loop {
+        let data: msg::DataMessage = expect_data(message);
+        assert_eq!(data.block_num() as usize, sizes.len() + 1);
+        sizes.push(data.data().len());
+        peer.send_to(&ack(data.block_num()), tid);
+
+        if data.data().len() < options::DEFAULT_BLOCK_SIZE {
+            return sizes;
+        }
+
+        let (next, from): (AnyMessage, SocketAddr) = peer.recv();
+        assert_eq!(from, tid);
+        message = next;
+    }
+}
+
+fn assert_error(message: AnyMessage, code: msg::ErrorMessageCode) {
+    let e: ErrorMessage = expect_error(message);
+    assert_eq!(e.code(), code, "unexpected error: {}", e.message());
+}
+
+fn assert_silent(peer: &Peer, wait: Duration) {
+    if let Some((message, from)) = peer.recv_within(wait) {
+        panic!("unexpected {:?} from {}", message, from);
+    }
+}
+
+/******************************** RFC 1350 ************************************/
+
+#[test]
+fn reads_a_small_file() {
+    let server: TestServer = TestServer::start();
+    server.create("small.txt", b"hello, world\n");
+
+    assert_eq!(get(&client(&server), "small.txt").unwrap(),
+               b"hello, world\n");
+}
+
+#[test]
+fn reads_a_multiple_of_the_block_size_with_an_empty_final_block() {
+    let server: TestServer = TestServer::start();
+    server.create("exact.bin", &contents(1024));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("exact.bin", vec![]));
+    let (first, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    assert_ne!(tid, server.addr(), "replied from the well-known port");
+    assert_eq!(read_blocks(&peer, first, tid), vec![512, 512, 0]);
+    assert_eq!(get(&client(&server), "exact.bin").unwrap(), contents(1024));
+}
+
+#[test]
+fn reads_a_zero_byte_file_as_a_single_empty_block() {
+    let server: TestServer = TestServer::start();
+    server.create("empty", b"");
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("empty", vec![]));
+    let (first, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    assert_eq!(read_blocks(&peer, first, tid), vec![0]);
+    assert_silent(&peer, TIMEOUT * 3);
+}
+
+#[test]
+fn reads_a_file_spanning_many_blocks() {
+    let server: TestServer = TestServer::start();
+    server.create("large.bin", &contents(100_000));
+
+    assert_eq!(get(&client(&server), "large.bin").unwrap(),
+               contents(100_000));
+}
+
+#[test]
+fn reads_netascii_with_line_endings_converted() {
+    let server: TestServer = TestServer::start();
+    server.create("text.txt", b"one\ntwo\rthree\n");
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&AnyMessage::Rrq(ReadRequestMessage::new(
+        "text.txt".to_string(), ReadWriteRequestMessageMode::NetAscii)));
+    let (message, _): (AnyMessage, SocketAddr) = peer.recv();
+
+    assert_eq!(expect_data(message).data(), b"one\r\ntwo\r\0three\r\n");
+}
+
+#[test]
+fn writes_a_small_file() {
+    let server: TestServer = TestServer::start();
+
+    client(&server).put("small.txt", &b"hello, world\n"[..], None).unwrap();
+
+    assert_eq!(uploaded(&server, "small.txt"), b"hello, world\n");
+}
+
+#[test]
+fn writes_a_multiple_of_the_block_size() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+    let file: Vec<u8> = contents(1024);
+
+    peer.request(&wrq("exact.bin", vec![]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_ack(reply).block_num(), 0);
+
+    for (block, chunk) in [&file[..512], &file[512..], &[]].iter().enumerate()
+    {
+        let block: u16 = block as u16 + 1;
+        peer.send_to(&data(block, chunk), tid);
+
+        let (reply, from): (AnyMessage, SocketAddr) = peer.recv();
+        assert_eq!(from, tid);
+        assert_eq!(expect_ack(reply).block_num(), block);
+    }
+
+    assert_eq!(uploaded(&server, "exact.bin"), file);
+}
+
+#[test]
+fn writes_a_zero_byte_file() {
+    let server: TestServer = TestServer::start();
+
+    client(&server).put("empty", &b""[..], Some(0)).unwrap();
+
+    assert_eq!(uploaded(&server, "empty"), b"");
+}
+
+#[test]
+fn writes_and_reads_back_a_large_file() {
+    let server: TestServer = TestServer::start();
+
+    client(&server).put("large.bin", &contents(70_000)[..], Some(70_000))
+        .unwrap();
+
+    assert_eq!(uploaded(&server, "large.bin"), contents(70_000));
+    assert_eq!(get(&client(&server), "large.bin").unwrap(), contents(70_000));
+}
+
+#[test]
+fn rolls_block_numbers_over_past_65535() {
+    let server: TestServer = TestServer::start();
+    let len: usize = 8 * 65_540;
+    server.create("rollover.bin", &contents(len));
+
+    let mut client: Client = client(&server);
+    client.set_block_size(Some(8));
+
+    assert_eq!(get(&client, "rollover.bin").unwrap(), contents(len));
+}
+
+#[test]
+fn rejects_a_missing_file() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("missing", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_FILE_NOT_FOUND);
+
+    match get(&client(&server), "missing") {
+        Err(TransferError::Aborted(e)) =>
+            assert_eq!(e.code(), msg::ERROR_FILE_NOT_FOUND),
+        other => panic!("expected the transfer to be refused: {:?}", other)
+    }
+}
+
+#[test]
+fn rejects_paths_escaping_the_root() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("../escape", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_ACCESS_VIOLATION);
+
+    peer.request(&wrq("../escape", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_ACCESS_VIOLATION);
+}
+
+#[test]
+fn rejects_reading_a_directory() {
+    let server: TestServer = TestServer::start();
+    std::fs::create_dir(server.root().join("dir")).unwrap();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("dir", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_ACCESS_VIOLATION);
+}
+
+#[test]
+fn rejects_writes_when_uploads_are_disabled() {
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_write_policy(WritePolicy::new(WriteMode::Disabled));
+    });
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("upload", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_ACCESS_VIOLATION);
+    assert!(server.contents("upload").is_none());
+}
+
+#[test]
+fn rejects_anything_but_a_request_at_the_well_known_port() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&ack(1));
+    assert_error(peer.recv().0, msg::ERROR_ILLEGAL_OPERATION);
+
+    peer.request(&data(1, b"stray"));
+    assert_error(peer.recv().0, msg::ERROR_ILLEGAL_OPERATION);
+
+    peer.send_bytes(&[0, 42, 1, 2, 3], server.addr());
+    assert_error(peer.recv().0, msg::ERROR_ILLEGAL_OPERATION);
+}
+
+#[test]
+fn answers_a_stranger_with_unknown_tid_and_carries_on() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+    let stranger: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let (first, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_data(first).block_num(), 1);
+
+    stranger.send_to(&ack(1), tid);
+    let (reply, from): (AnyMessage, SocketAddr) = stranger.recv();
+    assert_eq!(from, tid);
+    assert_error(reply, msg::ERROR_UNKNOWN_TID);
+
+    /* the stranger's ACK mustn't have moved the transfer along */
+    let (resent, _): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_data(resent).block_num(), 1);
+
+    peer.send_to(&ack(1), tid);
+    let (second, _): (AnyMessage, SocketAddr) = peer.recv();
+    let second: msg::DataMessage = expect_data(second);
+    assert_eq!(second.block_num(), 2);
+    assert_eq!(second.data(), contents(700)[512..]);
+}
+
+#[test]
+fn strangers_do_not_hold_off_retransmission() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let started: Instant = Instant::now();
+    let (first, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_data(first).block_num(), 1);
+
+    /* a stranger chattering away at the transfer more often than the
+        server's timeout */
+    let done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let stranger: JoinHandle<()> = {
+        let stranger: Peer = Peer::new(server.addr());
+        let done: Arc<AtomicBool> = done.clone();
+
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                stranger.send_to(&ack(1), tid);
+                thread::sleep(TIMEOUT / 4);
+            }
+        })
+    };
+
+    /* the retransmissions come on time all the same, and so does the
+        server giving up */
+    for _ in 0..RETRIES {
+        assert_eq!(expect_data(peer.recv().0).block_num(), 1);
+    }
+
+    assert!(started.elapsed() < TIMEOUT * (RETRIES as u32 + 2));
+
+    if let Some((message, _)) = peer.recv_within(TIMEOUT * 3) {
+        expect_error(message);
+    }
+
+    assert_silent(&peer, TIMEOUT * 3);
+
+    done.store(true, Ordering::SeqCst);
+    stranger.join().unwrap();
+}
+
+#[test]
+fn ignores_stale_duplicates_without_holding_off_retransmission() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(1300));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let (_, tid): (AnyMessage, SocketAddr) = peer.recv();
+    peer.send_to(&ack(1), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 2);
+
+    /* the ACK for the last block, over and over, while block 2 goes
+        unacknowledged */
+    let started: Instant = Instant::now();
+
+    while started.elapsed() < TIMEOUT * 2 {
+        peer.send_to(&ack(1), tid);
+
+        if let Some((message, _)) = peer.recv_within(TIMEOUT / 4) {
+            assert_eq!(expect_data(message).block_num(), 2);
+            return;
+        }
+    }
+
+    panic!("block 2 was never retransmitted");
+}
+
+#[test]
+fn retransmits_data_when_an_ack_is_lost() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let (first, tid): (AnyMessage, SocketAddr) = peer.recv();
+    let first: msg::DataMessage = expect_data(first);
+
+    /* withholding the ACK is as good as losing it */
+    let (resent, from): (AnyMessage, SocketAddr) = peer.recv();
+    let resent: msg::DataMessage = expect_data(resent);
+    assert_eq!(from, tid);
+    assert_eq!(resent.block_num(), first.block_num());
+    assert_eq!(resent.data(), first.data());
+}
+
+#[test]
+fn retransmits_an_ack_when_data_is_lost() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("upload", vec![]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_ack(reply).block_num(), 0);
+
+    let (resent, from): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(from, tid);
+    assert_eq!(expect_ack(resent).block_num(), 0);
+}
+
+#[test]
+fn reacknowledges_duplicate_data() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+    let file: Vec<u8> = contents(600);
+
+    peer.request(&wrq("upload", vec![]));
+    let (_, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    peer.send_to(&data(1, &file[..512]), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+
+    /* as if that ACK had been lost */
+    peer.send_to(&data(1, &file[..512]), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+
+    peer.send_to(&data(2, &file[512..]), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 2);
+
+    assert_eq!(uploaded(&server, "upload"), file);
+}
+
+#[test]
+fn ignores_duplicate_acks() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(1300));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let (_, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    /* answering each duplicate ACK with the block again is the Sorcerer's
+        Apprentice bug, which would show up as block 2 arriving twice */
+    peer.send_to(&ack(1), tid);
+    peer.send_to(&ack(1), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 2);
+
+    peer.send_to(&ack(2), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 3);
+}
+
+#[test]
+fn gives_up_after_the_configured_retries() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+
+    for _ in 0..=RETRIES {
+        assert_eq!(expect_data(peer.recv().0).block_num(), 1);
+    }
+
+    /* the server may say that it's giving up, but must send nothing more
+        after that */
+    if let Some((message, _)) = peer.recv_within(TIMEOUT * 3) {
+        expect_error(message);
+    }
+
+    assert_silent(&peer, TIMEOUT * 3);
+}
+
+#[test]
+fn stops_when_the_client_aborts() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![]));
+    let (_, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    peer.send_to(&AnyMessage::Error(ErrorMessage::new(
+        msg::ERROR_NOT_DEFINED, "Changed my mind".to_string())), tid);
+
+    assert_silent(&peer, TIMEOUT * 3);
+}
+
+/******************************** RFC 2347 ************************************/
+
+#[test]
+fn answers_options_with_an_oack_and_waits_for_ack_zero() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::BLKSIZE, "600")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    expect_oack(reply);
+
+    peer.send_to(&ack(0), tid);
+    let (first, _): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_data(first).data(), contents(700)[..600]);
+}
+
+#[test]
+fn answers_options_on_a_write_with_an_oack_instead_of_ack_zero() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("upload", vec![option(options::BLKSIZE, "16")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    expect_oack(reply);
+
+    peer.send_to(&data(1, &contents(16)), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+    peer.send_to(&data(2, b"end"), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 2);
+
+    let mut expected: Vec<u8> = contents(16);
+    expected.extend_from_slice(b"end");
+    assert_eq!(uploaded(&server, "upload"), expected);
+}
+
+#[test]
+fn ignores_unknown_options() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(100));
+
+    /* with nothing it understands, the server carries on as in RFC 1350 */
+    let peer: Peer = Peer::new(server.addr());
+    peer.request(&rrq("file.bin", vec![option("frobnicate", "yes")]));
+    assert_eq!(expect_data(peer.recv().0).data(), contents(100));
+
+    let peer: Peer = Peer::new(server.addr());
+    peer.request(&rrq("file.bin", vec![option("frobnicate", "yes"),
+                                       option(options::TSIZE, "0")]));
+    let oack: msg::OptionAcknowledgementMessage = expect_oack(peer.recv().0);
+    assert!(options::find(&oack.options(), "frobnicate").is_none());
+    assert_eq!(options::find(&oack.options(), options::TSIZE), Some("100"));
+}
+
+#[test]
+fn stops_when_the_client_declines_the_oack() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::BLKSIZE, "1024")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    expect_oack(reply);
+
+    peer.send_to(&AnyMessage::Error(ErrorMessage::new(
+        msg::ERROR_OPTION_NEGOTIATION, "Options refused".to_string())), tid);
+
+    assert_silent(&peer, TIMEOUT * 3);
+}
+
+/******************************** RFC 2348 ************************************/
+
+#[test]
+fn negotiates_the_block_size() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(1500));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::BLKSIZE, "1024")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(), options::BLKSIZE),
+               Some("1024"));
+
+    peer.send_to(&ack(0), tid);
+    assert_eq!(expect_data(peer.recv().0).data().len(), 1024);
+    peer.send_to(&ack(1), tid);
+    assert_eq!(expect_data(peer.recv().0).data().len(), 476);
+    peer.send_to(&ack(2), tid);
+}
+
+#[test]
+fn clamps_the_block_size_to_the_configured_maximum() {
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_max_block_size(1024);
+    });
+    server.create("file.bin", &contents(5000));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::BLKSIZE, "4096")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(), options::BLKSIZE),
+               Some("1024"));
+
+    peer.send_to(&ack(0), tid);
+    assert_eq!(expect_data(peer.recv().0).data().len(), 1024);
+
+    let mut client: Client = client(&server);
+    client.set_block_size(Some(4096));
+    assert_eq!(get(&client, "file.bin").unwrap(), contents(5000));
+}
+
+#[test]
+fn declines_block_sizes_out_of_range() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+
+    for size in ["4", "70000", "lots"] {
+        let peer: Peer = Peer::new(server.addr());
+        peer.request(&rrq("file.bin", vec![option(options::BLKSIZE, size)]));
+        assert_eq!(expect_data(peer.recv().0).data().len(),
+                   options::DEFAULT_BLOCK_SIZE);
+    }
+}
+
+#[test]
+fn transfers_with_the_smallest_block_size() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(100));
+
+    let mut client: Client = client(&server);
+    client.set_block_size(Some(options::MIN_BLOCK_SIZE));
+
+    assert_eq!(get(&client, "file.bin").unwrap(), contents(100));
+    client.put("upload", &contents(100)[..], Some(100)).unwrap();
+    assert_eq!(uploaded(&server, "upload"), contents(100));
+}
+
+/******************************** RFC 2349 ************************************/
+
+#[test]
+fn reports_the_transfer_size_of_a_read() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(12_345));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::TSIZE, "0")]));
+    let oack: msg::OptionAcknowledgementMessage = expect_oack(peer.recv().0);
+
+    assert_eq!(options::find(&oack.options(), options::TSIZE),
+               Some("12345"));
+}
+
+#[test]
+fn echoes_the_transfer_size_of_a_write() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("upload", vec![option(options::TSIZE, "4321")]));
+    let oack: msg::OptionAcknowledgementMessage = expect_oack(peer.recv().0);
+
+    assert_eq!(options::find(&oack.options(), options::TSIZE), Some("4321"));
+}
+
+#[test]
+fn retransmits_after_the_negotiated_timeout() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(700));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::TIMEOUT, "1")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(), options::TIMEOUT),
+               Some("1"));
+
+    peer.send_to(&ack(0), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 1);
+
+    /* well past the server's own timeout, but short of the one asked for */
+    let sent: Instant = Instant::now();
+    assert_silent(&peer, TIMEOUT * 3);
+
+    assert_eq!(expect_data(peer.recv().0).block_num(), 1);
+    assert!(sent.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn declines_timeouts_out_of_range() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(100));
+
+    for timeout in ["0", "256", "soon"] {
+        let peer: Peer = Peer::new(server.addr());
+        peer.request(&rrq("file.bin",
+                          vec![option(options::TIMEOUT, timeout)]));
+        assert_eq!(expect_data(peer.recv().0).data(), contents(100));
+    }
+}
+
+/******************************** RFC 7440 ************************************/
+
+#[test]
+fn sends_a_window_of_blocks_for_each_ack() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(8 * 512 + 100));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::WINDOWSIZE, "4")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(),
+                             options::WINDOWSIZE),
+               Some("4"));
+    peer.send_to(&ack(0), tid);
+
+    for window in [1..=4, 5..=8] {
+        for block in window.clone() {
+            assert_eq!(expect_data(peer.recv().0).block_num(), block);
+        }
+
+        /* and then waits for the window to be acknowledged */
+        assert_silent(&peer, TIMEOUT / 2);
+        peer.send_to(&ack(*window.end()), tid);
+    }
+
+    let data: msg::DataMessage = expect_data(peer.recv().0);
+    assert_eq!((data.block_num(), data.data().len()), (9, 100));
+    peer.send_to(&ack(9), tid);
+    assert_silent(&peer, TIMEOUT * 2);
+}
+
+#[test]
+fn acknowledges_each_window_of_a_write_once() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+    let file: Vec<u8> = contents(4 * 512 + 100);
+
+    peer.request(&wrq("upload", vec![option(options::WINDOWSIZE, "4")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(),
+                             options::WINDOWSIZE),
+               Some("4"));
+
+    for (index, block) in file.chunks(512).take(4).enumerate() {
+        peer.send_to(&data(index as u16 + 1, block), tid);
+
+        if index < 3 {
+            assert_silent(&peer, TIMEOUT / 2);
+        }
+    }
+
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 4);
+
+    /* the last block is acknowledged as soon as it arrives */
+    peer.send_to(&data(5, &file[4 * 512..]), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 5);
+    assert_eq!(uploaded(&server, "upload"), file);
+}
+
+#[test]
+fn resends_from_a_block_lost_within_a_window() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(6 * 512 + 100));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::WINDOWSIZE, "4")]));
+    let tid: SocketAddr = peer.recv().1;
+    peer.send_to(&ack(0), tid);
+
+    for block in 1..=4 {
+        assert_eq!(expect_data(peer.recv().0).block_num(), block);
+    }
+
+    /* as though block 3 never arrived, so a fresh window starts with it */
+    peer.send_to(&ack(2), tid);
+
+    for block in 3..=6 {
+        assert_eq!(expect_data(peer.recv().0).block_num(), block);
+    }
+
+    peer.send_to(&ack(6), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 7);
+    peer.send_to(&ack(7), tid);
+}
+
+#[test]
+fn asks_for_a_block_lost_within_a_window_of_a_write() {
+    let server: TestServer = TestServer::start();
+    let peer: Peer = Peer::new(server.addr());
+    let file: Vec<u8> = contents(4 * 512 + 100);
+    let blocks: Vec<&[u8]> = file.chunks(512).collect();
+
+    peer.request(&wrq("upload", vec![option(options::WINDOWSIZE, "4")]));
+    let tid: SocketAddr = peer.recv().1;
+
+    /* block 2 goes astray, which the server points out just the once */
+    for block in [1, 3, 4] {
+        peer.send_to(&data(block, blocks[block as usize - 1]), tid);
+    }
+
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+    assert_silent(&peer, TIMEOUT / 2);
+
+    for block in 2..=5 {
+        peer.send_to(&data(block, blocks[block as usize - 1]), tid);
+    }
+
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 5);
+    assert_eq!(uploaded(&server, "upload"), file);
+}
+
+#[test]
+fn resends_the_whole_window_when_its_ack_is_lost() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(3 * 512 + 100));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::WINDOWSIZE, "4")]));
+    let tid: SocketAddr = peer.recv().1;
+    peer.send_to(&ack(0), tid);
+
+    for _ in 0..2 {
+        for block in 1..=4 {
+            assert_eq!(expect_data(peer.recv().0).block_num(), block);
+        }
+    }
+
+    peer.send_to(&ack(4), tid);
+    assert_silent(&peer, TIMEOUT * 2);
+}
+
+#[test]
+fn clamps_the_window_size_to_the_configured_maximum() {
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_max_window_size(2);
+    });
+    server.create("file.bin", &contents(5000));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("file.bin", vec![option(options::WINDOWSIZE, "16")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(options::find(&expect_oack(reply).options(),
+                             options::WINDOWSIZE),
+               Some("2"));
+
+    peer.send_to(&ack(0), tid);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 1);
+    assert_eq!(expect_data(peer.recv().0).block_num(), 2);
+    assert_silent(&peer, TIMEOUT / 2);
+}
+
+#[test]
+fn declines_window_sizes_out_of_range() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(2000));
+
+    for size in ["0", "65536", "many"] {
+        let peer: Peer = Peer::new(server.addr());
+        peer.request(&rrq("file.bin",
+                          vec![option(options::WINDOWSIZE, size)]));
+
+        /* without a window, each block waits on the ACK of the one before */
+        let tid: SocketAddr = peer.recv().1;
+        assert_silent(&peer, TIMEOUT / 2);
+        peer.send_to(&ack(1), tid);
+        assert_eq!(expect_data(peer.recv().0).block_num(), 2);
+    }
+}
+
+#[test]
+fn transfers_a_large_file_a_window_at_a_time() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(1_000_000));
+
+    let mut client: Client = client(&server);
+    client.set_window_size(Some(16));
+
+    assert_eq!(get(&client, "file.bin").unwrap(), contents(1_000_000));
+    client.put("upload", &contents(1_000_000)[..], Some(1_000_000)).unwrap();
+    assert_eq!(uploaded(&server, "upload"), contents(1_000_000));
+}
@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+
+use serde_json::{json, Value};
+
+use nettlesoup::conn::Connection;
+use nettlesoup::dump::{self, DumpError, Format};
+use nettlesoup::msg::{AcknowledgementMessage, AnyMessage, DataMessage,
+                      ErrorMessage, OptionAcknowledgementMessage,
+                      ReadRequestMessage, ReadWriteRequestMessageMode,
+                      WriteRequestMessage};
+
+/*
+ * The dump format, checked against the documented representation of each
+ * message and for surviving the round trip from the wire and back.
+ */
+
+fn option(name: &str, value: &str) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+/* one of every message, along with its documented JSON */
+fn messages() -> Vec<(AnyMessage, Value)> {
+    vec![
+        (AnyMessage::Rrq(ReadRequestMessage::with_options(
+            "boot.img".to_string(), ReadWriteRequestMessageMode::Octet,
+            vec![option("blksize", "1428"), option("tsize", "0")])),
+         json!({"opcode": "RRQ", "filename": "boot.img", "mode": "octet",
+                "options": [["blksize", "1428"], ["tsize", "0"]]})),
+        (AnyMessage::Wrq(WriteRequestMessage::new(
+            "boot.img".to_string(), ReadWriteRequestMessageMode::NetAscii)),
+         json!({"opcode": "WRQ", "filename": "boot.img", "mode": "netascii",
+                "options": []})),
+        (AnyMessage::Data(DataMessage::new(1, b"hi\n".to_vec())),
+         json!({"opcode": "DATA", "block_num": 1, "data": [104, 105, 10]})),
+        (AnyMessage::Ack(AcknowledgementMessage::new(1)),
+         json!({"opcode": "ACK", "block_num": 1})),
+        (AnyMessage::Error(ErrorMessage::new(1,
+                                             "File not found".to_string())),
+         json!({"opcode": "ERROR", "code": 1, "message": "File not found"})),
+        (AnyMessage::Oack(OptionAcknowledgementMessage::new(
+            vec![option("blksize", "1428")])),
+         json!({"opcode": "OACK", "options": [["blksize", "1428"]]}))
+    ]
+}
+
+#[test]
+fn dumps_messages_as_documented() {
+    for (message, expected) in messages() {
+        let dumped: Vec<u8> = dump::encode(&message, Format::Json).unwrap();
+        let dumped: Value = serde_json::from_slice(&dumped).unwrap();
+
+        assert_eq!(dumped, expected);
+    }
+}
+
+#[test]
+fn round_trips_messages_through_the_wire() {
+    for format in [Format::Json, Format::Cbor] {
+        for (message, _) in messages() {
+            let wire: Vec<u8> = message.to_bytes();
+            let dumped: Vec<u8> = dump::from_wire(&wire, format).unwrap();
+
+            assert_eq!(dump::to_wire(&dumped, format).unwrap(), wire,
+                       "{} in {}", message, format);
+        }
+    }
+}
+
+#[test]
+fn dumps_data_as_a_cbor_byte_string() {
+    let message: AnyMessage =
+        AnyMessage::Data(DataMessage::new(1, b"hi\n".to_vec()));
+    let dumped: Vec<u8> = dump::encode(&message, Format::Cbor).unwrap();
+
+    /* a byte string of three bytes (major type 2), rather than an array */
+    assert!(dumped.windows(4)
+            .any(|window| window == [0x43, b'h', b'i', b'\n']));
+}
+
+#[test]
+fn round_trips_connections() {
+    let local: SocketAddr = "127.0.0.1:69".parse().unwrap();
+    let remote: SocketAddr = "[::1]:50000".parse().unwrap();
+    let mut connection: Connection = Connection::new(local, remote);
+    connection.add_msg(AnyMessage::Ack(AcknowledgementMessage::new(0)));
+
+    let dumped: Vec<u8> = dump::encode(&connection, Format::Json).unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&dumped).unwrap(),
+               json!({"local": "127.0.0.1:69", "remote": "[::1]:50000",
+                      "curr_seq": 1,
+                      "last_msg": {"opcode": "ACK", "block_num": 0}}));
+
+    for format in [Format::Json, Format::Cbor] {
+        let dumped: Vec<u8> = dump::encode(&connection, format).unwrap();
+        let restored: Connection = dump::decode(&dumped, format).unwrap();
+
+        assert_eq!(restored.local_addr(), local);
+        assert_eq!(restored.remote_addr(), remote);
+        assert_eq!(restored.curr_seq(), connection.curr_seq());
+        assert_eq!(restored.last_msg().unwrap().to_bytes(),
+                   connection.last_msg().unwrap().to_bytes());
+    }
+}
+
+#[test]
+fn rejects_what_is_not_a_message() {
+    assert!(matches!(dump::from_wire(&[0, 9], Format::Json),
+                     Err(DumpError::Malformed(_))));
+    assert!(matches!(dump::to_wire(br#"{"opcode": "NAK"}"#, Format::Json),
+                     Err(DumpError::Json(_))));
+    assert!(matches!(dump::to_wire(&[0xff], Format::Cbor),
+                     Err(DumpError::Cbor(_))));
+    assert!(matches!("yaml".parse::<Format>(),
+                     Err(DumpError::InvalidFormat(_))));
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nettlesoup::limit::Limits;
+use nettlesoup::msg::{self, AnyMessage, ErrorMessage};
+use nettlesoup::options;
+
+use common::*;
+
+/*
+ * The limits against being used as a reflector, end to end: datagrams over
+ * the rate going unanswered, requests over the cap on each client's
+ * transfers being refused, and verification holding back the first block
+ * until the client has acknowledged an OACK. Every client here shares the
+ * loopback address, so the overall cap is left to the unit tests.
+ */
+
+fn server(configure: impl FnOnce(&mut Limits)) -> TestServer {
+    let mut limits: Limits = Limits::new();
+    configure(&mut limits);
+
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_limits(limits);
+    });
+    server.create("image", &contents(2 * options::DEFAULT_BLOCK_SIZE));
+    server
+}
+
+fn assert_refused(message: AnyMessage, reason: &str) {
+    let e: ErrorMessage = expect_error(message);
+    assert_eq!(e.code(), msg::ERROR_NOT_DEFINED);
+    assert_eq!(e.message(), reason);
+}
+
+#[test]
+fn leaves_requests_over_the_rate_unanswered() {
+    let server: TestServer = server(|limits| {
+        limits.set_rate(Some("2/h".parse().unwrap()));
+    });
+    let peer: Peer = Peer::new(server.addr());
+
+    for _ in 0..2 {
+        peer.request(&rrq("missing", vec![]));
+        expect_error(peer.recv().0);
+    }
+
+    peer.request(&rrq("missing", vec![]));
+    assert!(peer.recv_within(TIMEOUT * 2).is_none());
+}
+
+#[test]
+fn refuses_transfers_over_the_per_client_cap() {
+    let server: TestServer = server(|limits| {
+        limits.set_per_client(Some(1));
+    });
+    let first: Peer = Peer::new(server.addr());
+    let second: Peer = Peer::new(server.addr());
+
+    /* the first transfer is left waiting on the acknowledgement of its
+        first block */
+    first.request(&rrq("image", vec![]));
+    let (reply, tid): (AnyMessage, SocketAddr) = first.recv();
+    assert_eq!(expect_data(reply).block_num(), 1);
+
+    /* every peer here shares the loopback address */
+    second.request(&rrq("image", vec![]));
+    assert_refused(second.recv().0, "Too many transfers from this address");
+
+    /* and once it's done, there's room again */
+    first.send_to(&ack(1), tid);
+    expect_data(first.recv().0);
+    first.send_to(&ack(2), tid);
+    expect_data(first.recv().0);
+    first.send_to(&ack(3), tid);
+
+    let deadline: Instant = Instant::now() + PATIENCE;
+
+    loop {
+        second.request(&rrq("image", vec![]));
+
+        match second.recv().0 {
+            AnyMessage::Data(data) => {
+                assert_eq!(data.block_num(), 1);
+                break;
+            },
+            /* the server may not yet have wound the transfer up */
+            refusal if Instant::now() < deadline => {
+                assert_refused(refusal,
+                               "Too many transfers from this address");
+                thread::sleep(Duration::from_millis(10));
+            },
+            other => panic!("still refused: {}", other)
+        }
+    }
+}
+
+#[test]
+fn verification_requires_options_to_read() {
+    let server: TestServer = server(|limits| limits.set_verify(true));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("image", vec![]));
+    let e: ErrorMessage = expect_error(peer.recv().0);
+    assert_eq!(e.code(), msg::ERROR_OPTION_NEGOTIATION);
+
+    /* an OACK goes to the claimed address first, and the file only once
+        that's been acknowledged */
+    peer.request(&rrq("image", vec![option(options::BLKSIZE, "1024")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    expect_oack(reply);
+    assert!(peer.recv_within(TIMEOUT / 2).is_none());
+
+    peer.send_to(&ack(0), tid);
+    let data = expect_data(peer.recv().0);
+    assert_eq!(data.block_num(), 1);
+    assert_eq!(data.data().len(), 1024);
+}
+
+#[test]
+fn verification_leaves_writes_alone() {
+    let server: TestServer = server(|limits| limits.set_verify(true));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("upload", vec![]));
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 0);
+}
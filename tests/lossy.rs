@@ -0,0 +1,298 @@
+mod common;
+
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::lossy::{Config, Fault, Impairments, Leg, Proxy};
+
+use common::*;
+
+/*
+ * The lossy proxy itself, checked with bare sockets at either end, and
+ * transfers made through it to show that retransmission and the handling
+ * of duplicates hold up on a bad network.
+ */
+
+fn socket() -> UdpSocket {
+    let socket: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(PATIENCE)).unwrap();
+    socket
+}
+
+fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buf: Vec<u8> = vec![0; 65536];
+    let (len, from): (usize, SocketAddr) = socket.recv_from(&mut buf)
+        .expect("timed out waiting for a datagram");
+    buf.truncate(len);
+    (buf, from)
+}
+
+/* everything that arrives until the socket has been quiet for a while */
+fn drain(socket: &UdpSocket) -> Vec<Vec<u8>> {
+    let mut received: Vec<Vec<u8>> = vec![];
+    let mut buf: Vec<u8> = vec![0; 65536];
+    socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+    while let Ok((len, _)) = socket.recv_from(&mut buf) {
+        received.push(buf[..len].to_vec());
+    }
+
+    received
+}
+
+/* a proxy in front of a bare socket, with the faults given */
+fn proxy(server: &UdpSocket, configure: impl FnOnce(&mut Config)) -> Proxy {
+    let mut config: Config = Config::new(server.local_addr().unwrap());
+    configure(&mut config);
+    Proxy::start(config).unwrap()
+}
+
+fn client(proxy: &Proxy) -> Client {
+    let mut client: Client = Client::new(proxy.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(10);
+    client
+}
+
+fn get(client: &Client, filename: &str) -> Vec<u8> {
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get(filename, &mut output).unwrap();
+    output.into_inner()
+}
+
+/********************************* PROXY **************************************/
+
+#[test]
+fn forwards_both_ways_with_a_socket_per_tid() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |_| {});
+    let client: UdpSocket = socket();
+
+    client.send_to(b"request", proxy.addr()).unwrap();
+    let (request, upstream): (Vec<u8>, SocketAddr) = recv(&server);
+    assert_eq!(request, b"request");
+
+    server.send_to(b"reply", upstream).unwrap();
+    let (reply, downstream): (Vec<u8>, SocketAddr) = recv(&client);
+    assert_eq!(reply, b"reply");
+    assert_ne!(downstream, proxy.addr());
+
+    /* replying to the server's TID reaches the server's TID */
+    client.send_to(b"onwards", downstream).unwrap();
+    let (onwards, from): (Vec<u8>, SocketAddr) = recv(&server);
+    assert_eq!(onwards, b"onwards");
+    assert_eq!(from, upstream);
+
+    assert_eq!(proxy.tally(Leg::ToServer).delivered(), 2);
+    assert_eq!(proxy.tally(Leg::ToClient).delivered(), 1);
+}
+
+#[test]
+fn drops_a_datagram() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |config| {
+        config.add_fault(Leg::ToServer, 1, Fault::Drop);
+    });
+    let client: UdpSocket = socket();
+
+    client.send_to(b"one", proxy.addr()).unwrap();
+    client.send_to(b"two", proxy.addr()).unwrap();
+
+    assert_eq!(drain(&server), vec![b"two".to_vec()]);
+    assert_eq!(proxy.tally(Leg::ToServer).dropped(), 1);
+}
+
+#[test]
+fn duplicates_a_datagram() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |config| {
+        config.add_fault(Leg::ToServer, 1, Fault::Duplicate);
+    });
+    let client: UdpSocket = socket();
+
+    client.send_to(b"one", proxy.addr()).unwrap();
+
+    assert_eq!(drain(&server), vec![b"one".to_vec(), b"one".to_vec()]);
+}
+
+#[test]
+fn reorders_a_datagram() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |config| {
+        config.add_fault(Leg::ToServer, 1, Fault::Reorder);
+    });
+    let client: UdpSocket = socket();
+
+    client.send_to(b"one", proxy.addr()).unwrap();
+    client.send_to(b"two", proxy.addr()).unwrap();
+
+    assert_eq!(drain(&server), vec![b"two".to_vec(), b"one".to_vec()]);
+}
+
+#[test]
+fn corrupts_a_single_bit() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |config| {
+        config.add_fault(Leg::ToServer, 1, Fault::Corrupt);
+    });
+    let client: UdpSocket = socket();
+    let sent: Vec<u8> = contents(64);
+
+    client.send_to(&sent, proxy.addr()).unwrap();
+    let (received, _): (Vec<u8>, SocketAddr) = recv(&server);
+
+    let flipped: u32 = sent.iter().zip(&received)
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    assert_eq!(received.len(), sent.len());
+    assert_eq!(flipped, 1);
+}
+
+#[test]
+fn delays_a_datagram() {
+    let server: UdpSocket = socket();
+    let proxy: Proxy = proxy(&server, |config| {
+        config.add_fault(Leg::ToServer, 1,
+                         Fault::Delay(Duration::from_millis(300)));
+    });
+    let client: UdpSocket = socket();
+
+    let sent: Instant = Instant::now();
+    client.send_to(b"one", proxy.addr()).unwrap();
+    recv(&server);
+
+    assert!(sent.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn repeats_itself_given_the_same_seed() {
+    let run = |seed: u64| -> Vec<Vec<u8>> {
+        let server: UdpSocket = socket();
+        let proxy: Proxy = proxy(&server, |config| {
+            let mut impairments: Impairments = Impairments::new();
+            impairments.set_drop(0.3);
+            impairments.set_duplicate(0.3);
+            impairments.set_corrupt(0.3);
+
+            config.set_seed(seed);
+            config.set_impairments(Leg::ToServer, impairments);
+        });
+        let client: UdpSocket = socket();
+
+        for i in 0..50u8 {
+            client.send_to(&[i; 8], proxy.addr()).unwrap();
+        }
+
+        drain(&server)
+    };
+
+    assert_eq!(run(42), run(42));
+    assert_ne!(run(42), run(43));
+}
+
+/******************************* TRANSFERS ************************************/
+
+#[test]
+fn transfers_survive_lost_datagrams() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(30_000));
+
+    let mut impairments: Impairments = Impairments::new();
+    impairments.set_drop(0.1);
+
+    let mut config: Config = Config::new(server.addr());
+    config.set_seed(1);
+    config.set_impairments(Leg::ToServer, impairments.clone());
+    config.set_impairments(Leg::ToClient, impairments);
+    let proxy: Proxy = Proxy::start(config).unwrap();
+
+    assert_eq!(get(&client(&proxy), "file.bin"), contents(30_000));
+    client(&proxy).put("upload", &contents(30_000)[..], Some(30_000))
+        .unwrap();
+    assert_eq!(uploaded(&server, "upload"), contents(30_000));
+
+    assert!(proxy.tally(Leg::ToServer).dropped() > 0);
+    assert!(proxy.tally(Leg::ToClient).dropped() > 0);
+}
+
+#[test]
+fn transfers_survive_reordering_and_jitter() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(30_000));
+
+    let mut impairments: Impairments = Impairments::new();
+    impairments.set_reorder(0.2);
+    impairments.set_duplicate(0.1);
+    impairments.set_jitter(Duration::from_millis(20));
+
+    let mut config: Config = Config::new(server.addr());
+    config.set_seed(7);
+    config.set_impairments(Leg::ToServer, impairments.clone());
+    config.set_impairments(Leg::ToClient, impairments);
+    let proxy: Proxy = Proxy::start(config).unwrap();
+
+    assert_eq!(get(&client(&proxy), "file.bin"), contents(30_000));
+    client(&proxy).put("upload", &contents(30_000)[..], Some(30_000))
+        .unwrap();
+    assert_eq!(uploaded(&server, "upload"), contents(30_000));
+
+    assert!(proxy.tally(Leg::ToServer).reordered() > 0);
+    assert!(proxy.tally(Leg::ToClient).reordered() > 0);
+}
+
+#[test]
+fn windowed_transfers_survive_loss_and_reordering() {
+    let server: TestServer = TestServer::start();
+    server.create("file.bin", &contents(60_000));
+
+    let mut impairments: Impairments = Impairments::new();
+    impairments.set_drop(0.05);
+    impairments.set_reorder(0.1);
+    impairments.set_jitter(Duration::from_millis(5));
+
+    let mut config: Config = Config::new(server.addr());
+    config.set_seed(3);
+    config.set_impairments(Leg::ToServer, impairments.clone());
+    config.set_impairments(Leg::ToClient, impairments);
+    let proxy: Proxy = Proxy::start(config).unwrap();
+
+    let mut client: Client = client(&proxy);
+    client.set_window_size(Some(8));
+
+    assert_eq!(get(&client, "file.bin"), contents(60_000));
+    client.put("upload", &contents(60_000)[..], Some(60_000)).unwrap();
+    assert_eq!(uploaded(&server, "upload"), contents(60_000));
+
+    assert!(proxy.tally(Leg::ToClient).dropped() > 0);
+    assert!(proxy.tally(Leg::ToClient).reordered() > 0);
+}
+
+#[test]
+fn duplicates_never_multiply() {
+    /* long enough that nothing is retransmitted for want of a reply */
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_timeout(Duration::from_secs(5));
+    });
+    server.create("file.bin", &contents(10_000));
+
+    let mut impairments: Impairments = Impairments::new();
+    impairments.set_duplicate(1.0);
+
+    let mut config: Config = Config::new(server.addr());
+    config.set_impairments(Leg::ToServer, impairments.clone());
+    config.set_impairments(Leg::ToClient, impairments);
+    let proxy: Proxy = Proxy::start(config).unwrap();
+
+    let mut client: Client = client(&proxy);
+    client.set_timeout(Duration::from_secs(5));
+    assert_eq!(get(&client, "file.bin"), contents(10_000));
+
+    /* every DATA arrives twice and so is acknowledged twice, but were the
+        server to answer each ACK (the Sorcerer's Apprentice bug) the blocks
+        it sent would double with every round. The request arrives twice
+        too, and so starts a second transfer whose first block the client
+        turns away */
+    assert_eq!(proxy.tally(Leg::ToClient).received(), 10_000 / 512 + 2);
+}
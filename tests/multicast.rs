@@ -0,0 +1,247 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::msg::{AnyMessage, DataMessageBlockNumber, OptionList};
+use nettlesoup::options::{self, MulticastGroup};
+use nettlesoup::throttle::Bandwidth;
+
+use common::*;
+
+/*
+ * Multicast transfers (RFC 2090) over the loopback interface: a lone client
+ * fetching a file through the group, the handing over of the session from
+ * one master to the next, both when the master leaves having what it wants
+ * and when it goes quiet, the pacing of the group by the bandwidth limits,
+ * and the falling back to unicast for a file with more blocks than there
+ * are block numbers.
+ */
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 0, 1);
+
+/* ten full blocks and a short one */
+const FILE_SIZE: usize = 10 * options::DEFAULT_BLOCK_SIZE + 100;
+const LAST_BLOCK: DataMessageBlockNumber = 11;
+
+/* a group of its own for each test, so that tests running side by side
+    don't hear one another's blocks */
+fn group() -> SocketAddr {
+    let probe: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    SocketAddr::new(GROUP.into(), probe.local_addr().unwrap().port())
+}
+
+fn server(group: SocketAddr) -> TestServer {
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_multicast(Some(group));
+    });
+    server.create("image", &contents(FILE_SIZE));
+    server
+}
+
+/* a socket listening to the group on the loopback interface */
+fn listen(group: SocketAddr) -> UdpSocket {
+    let socket: UdpSocket =
+        UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(),
+                                        group.port())).unwrap();
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::LOCALHOST).unwrap();
+    socket.set_read_timeout(Some(PATIENCE)).unwrap();
+    socket
+}
+
+/* asks to join the session for the image, returning the session's TID and
+    what the server made of us */
+fn join(peer: &Peer) -> (SocketAddr, MulticastGroup) {
+    peer.request(&rrq("image", vec![option(options::MULTICAST, "")]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+
+    (tid, membership(reply))
+}
+
+fn membership(message: AnyMessage) -> MulticastGroup {
+    options::find(&expect_oack(message).options(), options::MULTICAST)
+        .expect("no multicast option in the OACK")
+        .parse().unwrap()
+}
+
+/* gathers blocks from the group until every one in `wanted` has arrived */
+fn gather(listener: &UdpSocket, wanted: &[DataMessageBlockNumber],
+          blocks: &mut BTreeMap<DataMessageBlockNumber, Vec<u8>>) {
+    let mut buf: Vec<u8> = vec![0; 65536];
+
+    while !wanted.iter().all(|block| blocks.contains_key(block)) {
+        let (len, _): (usize, SocketAddr) = listener.recv_from(&mut buf)
+            .expect("timed out waiting for the group");
+        let data = expect_data(AnyMessage::from_bytes(buf[..len].to_vec())
+                               .unwrap());
+        blocks.insert(data.block_num(), data.data());
+    }
+}
+
+#[test]
+fn a_lone_client_receives_a_file_through_the_group() {
+    let server: TestServer = server(group());
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client.set_multicast(true);
+
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get("image", &mut output).unwrap();
+
+    assert_eq!(output.into_inner(), contents(FILE_SIZE));
+}
+
+#[test]
+fn declines_a_window_size_for_the_group() {
+    let server: TestServer = server(group());
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&rrq("image", vec![option(options::MULTICAST, ""),
+                                    option(options::WINDOWSIZE, "8")]));
+    let acknowledged: OptionList = expect_oack(peer.recv().0).options();
+    assert!(options::find(&acknowledged, options::MULTICAST).is_some());
+    assert!(options::find(&acknowledged, options::WINDOWSIZE).is_none());
+}
+
+#[test]
+fn hands_the_session_to_the_next_client_when_the_master_is_done() {
+    let group: SocketAddr = group();
+    let server: TestServer = server(group);
+    let first: Peer = Peer::new(server.addr());
+    let second: Peer = Peer::new(server.addr());
+
+    let (tid, joined): (SocketAddr, MulticastGroup) = join(&first);
+    assert_eq!(joined, MulticastGroup::new(group, true));
+
+    /* the first client takes the first five blocks on its own */
+    for block in 0..5 {
+        first.send_to(&ack(block), tid);
+    }
+
+    let listener: UdpSocket = listen(group);
+    let (second_tid, joined): (SocketAddr, MulticastGroup) = join(&second);
+    assert_eq!(second_tid, tid);
+    assert_eq!(joined, MulticastGroup::new(group, false));
+
+    /* the second listens in on the rest of the first's transfer... */
+    for block in 5..=LAST_BLOCK {
+        first.send_to(&ack(block), tid);
+    }
+
+    let mut blocks: BTreeMap<DataMessageBlockNumber, Vec<u8>> =
+        BTreeMap::new();
+    gather(&listener, &[6, 7, 8, 9, 10, 11], &mut blocks);
+
+    /* ...and is then made master, to ask for the blocks it missed */
+    let promoted: MulticastGroup = membership(second.recv().0);
+    assert_eq!(promoted, MulticastGroup::new(group, true));
+
+    for block in 0..5 {
+        second.send_to(&ack(block), tid);
+        gather(&listener, &[block + 1], &mut blocks);
+    }
+
+    second.send_to(&ack(LAST_BLOCK), tid);
+
+    let received: Vec<u8> = blocks.into_values().flatten().collect();
+    assert_eq!(received, contents(FILE_SIZE));
+}
+
+#[test]
+fn promotes_a_client_when_the_master_goes_quiet() {
+    let group: SocketAddr = group();
+    let server: TestServer = server(group);
+    let first: Peer = Peer::new(server.addr());
+
+    let (tid, _): (SocketAddr, MulticastGroup) = join(&first);
+
+    /* the first client asks for a few blocks and is never heard from
+        again, leaving the session to time it out */
+    for block in 0..3 {
+        first.send_to(&ack(block), tid);
+    }
+
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client.set_multicast(true);
+
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get("image", &mut output).unwrap();
+
+    assert_eq!(output.into_inner(), contents(FILE_SIZE));
+}
+
+#[test]
+fn paces_the_group_by_the_bandwidth_limits() {
+    const RATE: u64 = 4096;
+    const SIZE: usize = 4 * RATE as usize;
+
+    let group: SocketAddr = group();
+    let server: TestServer = TestServer::with_config(|config| {
+        let mut bandwidth: Bandwidth = Bandwidth::new();
+        bandwidth.set_total(Some(RATE));
+
+        config.set_multicast(Some(group));
+        config.set_bandwidth(bandwidth);
+    });
+    server.create("image", &contents(SIZE));
+
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client.set_multicast(true);
+
+    let started: Instant = Instant::now();
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get("image", &mut output).unwrap();
+
+    /* the bucket starts out with a second's worth, and the rest comes at
+        the rate */
+    assert!(started.elapsed() >= Duration::from_secs(3));
+    assert_eq!(output.into_inner(), contents(SIZE));
+}
+
+#[test]
+fn serves_by_unicast_a_file_whose_block_numbers_would_wrap() {
+    let server: TestServer = server(group());
+    let size: usize =
+        (DataMessageBlockNumber::MAX as usize + 1) * options::MIN_BLOCK_SIZE;
+    server.create("large", &contents(size));
+    let peer: Peer = Peer::new(server.addr());
+
+    /* the multicast option goes unacknowledged, and the blocks come to us
+        alone */
+    peer.request(&rrq("large", vec![
+        option(options::MULTICAST, ""),
+        option(options::BLKSIZE, &options::MIN_BLOCK_SIZE.to_string())
+    ]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    let acknowledged: OptionList = expect_oack(reply).options();
+    assert!(options::find(&acknowledged, options::MULTICAST).is_none());
+    assert_eq!(options::find(&acknowledged, options::BLKSIZE), Some("8"));
+
+    peer.send_to(&ack(0), tid);
+    let (reply, from): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(from, tid);
+    assert_eq!(expect_data(reply).block_num(), 1);
+    peer.send_to(&ack(1), tid);
+    drop(peer);
+
+    /* and a client that asked for multicast gets the whole file, past the
+        point at which the block numbers wrap */
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client.set_block_size(Some(options::MIN_BLOCK_SIZE));
+    client.set_multicast(true);
+
+    let mut output: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    client.get("large", &mut output).unwrap();
+
+    assert_eq!(output.into_inner(), contents(size));
+}
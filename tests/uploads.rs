@@ -0,0 +1,205 @@
+mod common;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nettlesoup::clnt::Client;
+use nettlesoup::msg::{self, AnyMessage, ErrorMessage};
+use nettlesoup::options;
+use nettlesoup::policy::{WriteMode, WritePolicy};
+use nettlesoup::upload;
+
+use common::*;
+
+/*
+ * The write policy and the handling of uploads, end to end: which uploads
+ * are let in, how much they may write, that an upload is in place by the
+ * time it's acknowledged and that one that fails part way through leaves
+ * the file it was to replace as it was, and that what uploads cut short
+ * leave behind is cleared away.
+ */
+
+fn client(server: &TestServer) -> Client {
+    let mut client: Client = Client::new(server.addr());
+    client.set_timeout(TIMEOUT);
+    client.set_retries(RETRIES);
+    client
+}
+
+fn server(mode: WriteMode, quota: Option<u64>) -> TestServer {
+    TestServer::with_config(|config| {
+        let mut policy: WritePolicy = WritePolicy::new(mode);
+        policy.set_quota(quota);
+        config.set_write_policy(policy);
+    })
+}
+
+/* the names of everything in the server's root */
+fn files(server: &TestServer) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(server.root()).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/* waits for the server to tidy up after an upload, which it does a moment
+    after the transfer ends */
+fn settle(server: &TestServer, expected: &[&str]) {
+    let deadline: Instant = Instant::now() + PATIENCE;
+
+    while files(server) != expected {
+        assert!(Instant::now() < deadline, "root holds {:?}", files(server));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/* starts an upload by hand, returning the TID it is to be sent to */
+fn begin(peer: &Peer, filename: &str) -> SocketAddr {
+    peer.request(&wrq(filename, vec![]));
+    let (reply, tid): (AnyMessage, SocketAddr) = peer.recv();
+    assert_eq!(expect_ack(reply).block_num(), 0);
+    tid
+}
+
+fn assert_error(message: AnyMessage, code: msg::ErrorMessageCode) {
+    let e: ErrorMessage = expect_error(message);
+    assert_eq!(e.code(), code, "unexpected error: {}", e.message());
+}
+
+#[test]
+fn create_only_refuses_to_replace_a_file() {
+    let server: TestServer = server(WriteMode::CreateOnly, None);
+    server.create("existing", b"original");
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("existing", vec![]));
+    assert_error(peer.recv().0, msg::ERROR_FILE_EXISTS);
+    assert_eq!(server.contents("existing").unwrap(), b"original");
+
+    client(&server).put("new", &b"fresh"[..], None).unwrap();
+    assert_eq!(uploaded(&server, "new"), b"fresh");
+}
+
+#[test]
+fn overwrite_replaces_a_file() {
+    let server: TestServer = server(WriteMode::Overwrite, None);
+    server.create("existing", &contents(2000));
+
+    client(&server).put("existing", &b"replaced"[..], None).unwrap();
+
+    settle(&server, &["existing"]);
+    assert_eq!(server.contents("existing").unwrap(), b"replaced");
+}
+
+#[test]
+fn a_failed_overwrite_leaves_the_original() {
+    let server: TestServer = server(WriteMode::Overwrite, None);
+    server.create("existing", b"original");
+    let peer: Peer = Peer::new(server.addr());
+
+    let tid: SocketAddr = begin(&peer, "existing");
+    peer.send_to(&data(1, &contents(options::DEFAULT_BLOCK_SIZE)), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+    peer.send_to(&AnyMessage::Error(ErrorMessage::new(
+        msg::ERROR_NOT_DEFINED, "Changed my mind".to_string())), tid);
+
+    settle(&server, &["existing"]);
+    assert_eq!(server.contents("existing").unwrap(), b"original");
+}
+
+#[test]
+fn quota_refuses_an_announced_upload_that_would_not_fit() {
+    let server: TestServer = server(WriteMode::Overwrite, Some(1000));
+    server.create("existing", &contents(600));
+    let peer: Peer = Peer::new(server.addr());
+
+    peer.request(&wrq("new", vec![option("tsize", "500")]));
+    assert_error(peer.recv().0, msg::ERROR_DISK_FULL);
+
+    client(&server).put("new", &contents(400)[..], Some(400)).unwrap();
+    assert_eq!(uploaded(&server, "new"), contents(400));
+}
+
+#[test]
+fn quota_stops_an_upload_that_outgrows_it() {
+    let server: TestServer = server(WriteMode::Overwrite, Some(1000));
+    let peer: Peer = Peer::new(server.addr());
+    let block: Vec<u8> = contents(options::DEFAULT_BLOCK_SIZE);
+
+    let tid: SocketAddr = begin(&peer, "new");
+    peer.send_to(&data(1, &block), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+    peer.send_to(&data(2, &block), tid);
+    assert_error(peer.recv().0, msg::ERROR_DISK_FULL);
+
+    settle(&server, &[]);
+}
+
+#[test]
+fn concurrent_uploads_share_the_quota() {
+    let server: TestServer = server(WriteMode::Overwrite, Some(1000));
+    let first: Peer = Peer::new(server.addr());
+    let second: Peer = Peer::new(server.addr());
+    let block: Vec<u8> = contents(options::DEFAULT_BLOCK_SIZE);
+
+    /* both are admitted to an empty directory, but only one can have the
+        room for its first block */
+    let first_tid: SocketAddr = begin(&first, "first");
+    let second_tid: SocketAddr = begin(&second, "second");
+
+    first.send_to(&data(1, &block), first_tid);
+    assert_eq!(expect_ack(first.recv().0).block_num(), 1);
+    second.send_to(&data(1, &block), second_tid);
+    assert_error(second.recv().0, msg::ERROR_DISK_FULL);
+
+    first.send_to(&data(2, &block[..100]), first_tid);
+    assert_eq!(expect_ack(first.recv().0).block_num(), 2);
+    assert_eq!(uploaded(&server, "first").len(), 612);
+}
+
+#[test]
+fn an_upload_is_in_place_once_its_last_block_is_acknowledged() {
+    let server: TestServer = server(WriteMode::Overwrite, None);
+    server.create("existing", b"original");
+    let peer: Peer = Peer::new(server.addr());
+
+    let tid: SocketAddr = begin(&peer, "existing");
+    peer.send_to(&data(1, b"replacement"), tid);
+    assert_eq!(expect_ack(peer.recv().0).block_num(), 1);
+
+    /* no waiting about: the ACK is the server's word that it's done */
+    assert_eq!(server.contents("existing").unwrap(), b"replacement");
+}
+
+/* a name like those of the temporary files uploads are written to */
+fn stale_name(filename: &str) -> String {
+    format!("{}{}.1.0{}", upload::TEMP_PREFIX, filename, upload::TEMP_SUFFIX)
+}
+
+#[test]
+fn removes_stale_uploads_on_starting() {
+    let server: TestServer = TestServer::with_config(|config| {
+        fs::create_dir(config.root().join("nested")).unwrap();
+        fs::write(config.root().join(stale_name("a")), b"").unwrap();
+        fs::write(config.root().join("nested").join(stale_name("b")), b"")
+            .unwrap();
+        fs::write(config.root().join("kept"), b"").unwrap();
+    });
+
+    assert_eq!(files(&server), vec!["kept", "nested"]);
+    assert!(fs::read_dir(server.root().join("nested")).unwrap()
+            .next().is_none());
+}
+
+#[test]
+fn leaves_the_root_alone_when_uploads_are_off() {
+    let server: TestServer = TestServer::with_config(|config| {
+        config.set_write_policy(WritePolicy::new(WriteMode::Disabled));
+        fs::write(config.root().join(stale_name("a")), b"").unwrap();
+    });
+
+    assert_eq!(files(&server), vec![stale_name("a")]);
+}